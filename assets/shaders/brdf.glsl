@@ -227,4 +227,43 @@ vec4 sampleDisneyBRDF(vec3 v, vec3 n, Material mat, inout vec3 l) {
     return brdf;
 }
 
+// pdf with which sampleDisneyBRDF picks l, used to weight explicit light samples against it
+float pdfDisneyBRDF(vec3 v, vec3 n, vec3 l, Material mat) {
+    float NoL = dot(n,l);
+    float NoV = dot(n,v);
+    if ( NoL <= 0. || NoV <= 0. ) { return 0.; }
+
+    float roughness = pow(mat.roughness, 2.);
+    vec3 h = normalize(l+v);
+    float NoH = min(dot(n,h),.99);
+
+    vec3 f0 = mix(vec3(0.04), mat.albedo, mat.metallic);
+    vec3 F = F_Schlick(f0, dot(v,h));
+
+    float diffW = (1.-mat.metallic);
+    float specW = luma(F);
+    float invW = 1./(diffW + specW);
+
+    return (diffW * NoL/PI + specW * GGXVNDFPdf(NoH, NoV, roughness)) * invW;
+}
+
+// brdf * NoL for a known light direction, used for explicit light samples
+vec3 evalDisneyBRDF(vec3 v, vec3 n, vec3 l, Material mat) {
+    float NoL = dot(n,l);
+    float NoV = dot(n,v);
+    if ( NoL <= 0. || NoV <= 0. ) { return vec3(0.); }
+
+    float roughness = pow(mat.roughness, 2.);
+    vec3 h = normalize(l+v);
+    float NoH = min(dot(n,h),.99);
+    float LoH = dot(l,h);
+
+    vec3 f0 = mix(vec3(0.04), mat.albedo, mat.metallic);
+    vec3 F = F_Schlick(f0, dot(v,h));
+
+    vec3 diff = evalDisneyDiffuse(mat, NoL, NoV, LoH, roughness) * (1.-F) * (1.-mat.metallic);
+    vec3 spec = evalDisneySpecular(mat, F, NoH, NoV, NoL);
+    return (diff + spec) * NoL;
+}
+
 #endif
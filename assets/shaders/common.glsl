@@ -9,6 +9,7 @@ const float INVPI = 1.0f / 3.141592653589793f;
 const float EPS = 0.001f;
 
 float max3(in vec3 v) { return max(v.x, max(v.y, v.z)); }
+float luminance(in vec3 c) { return dot(c, vec3(0.2126, 0.7152, 0.0722)); }
 
 mat3 fromAxisAngle(vec3 axis, float angle)
 {
@@ -45,11 +46,20 @@ struct HitPayload {
   vec3 surface_normal;
   vec3 normal;
   vec3 emission;
-  float absorption;
+  vec3 absorption;
   float metallic;
   float roughness;
   float transmission;
   float refract_index;
+  // set by the caller before every trace, drives the stochastic transparency in hit.rahit
+  uint seed;
+  // width and spread angle of the ray cone at the ray origin, used for texture lod
+  float cone_width;
+  float cone_spread;
+  // luminance of the emissive factor when the hit triangle is in the emissive light list, 0 otherwise
+  float emissive_luminance;
+  // custom index of the hit instance, where raygen finds its InstanceMaterial
+  uint instance;
 };
 
 
@@ -75,19 +85,40 @@ struct Vertex {
   vec3 pos;
   vec3 normal;
   vec2 uv;
+  vec2 uv1;
 };
 
+struct MaterialTexture {
+  uint index;
+  uint uv_set;
+  mat3x2 transform;
+};
+
+vec2 materialUV(in MaterialTexture tex, in vec2 uv0, in vec2 uv1) {
+  return tex.transform * vec3(tex.uv_set == 0 ? uv0 : uv1, 1.0);
+}
+
 struct GltfMaterial {
   vec4 diffuse_factor;
-  uint diffuse_texture;
-  uint normal_texture;
+  MaterialTexture diffuse_texture;
+  MaterialTexture normal_texture;
   float metallic_factor;
   float roughness_factor;
-  uint metallic_roughness_texture;
+  MaterialTexture metallic_roughness_texture;
   vec3 emissive_factor;
-  uint emissive_texture;
+  MaterialTexture emissive_texture;
+  uint alpha_mode;
+  float alpha_cutoff;
+  float transmission_factor;
+  MaterialTexture transmission_texture;
+  float ior;
+  vec3 absorption;
 };
 
+#define ALPHA_MODE_OPAQUE 0
+#define ALPHA_MODE_MASK 1
+#define ALPHA_MODE_BLEND 2
+
 
 layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer VertexData {
   Vertex vertices[];
@@ -105,19 +136,147 @@ layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer Ma
   GltfMaterial materials[];
 };
 
-layout(buffer_reference, scalar, buffer_reference_align = 8) readonly buffer SphereData
-{
-	AABB aabbs[];
+#define LIGHT_POINT 0
+#define LIGHT_SPOT 1
+#define LIGHT_DIRECTIONAL 2
+
+struct PunctualLight {
+  vec3 position;
+  uint type;
+  vec3 direction;
+  float range;
+  vec3 radiance;
+  float inner_cone_cos;
+  float outer_cone_cos;
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer LightData {
+  PunctualLight lights[];
+};
+
+struct EmissiveTriangle {
+  vec3 p0;
+  vec3 p1;
+  vec3 p2;
+  vec3 emission;
+  vec2 uv0;
+  vec2 uv1;
+  vec2 uv2;
+  uint texture_index;
+  float cdf;
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer EmissiveTriangleData {
+  EmissiveTriangle triangles[];
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer EnvironmentCdf {
+  // the marginal cdf over the rows followed by the conditional cdf of every row
+  float cdf[];
+};
+
+#define SKY_ENVIRONMENT 0
+#define SKY_FALLBACK 1
+
+layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer SobolMatrices {
+  // 32 direction numbers for each of the SOBOL_DIMENSIONS dimensions
+  uint directions[];
+};
+
+#define SAMPLER_PCG 0
+#define SAMPLER_SOBOL 1
+#define SOBOL_DIMENSIONS 4
+// dimensions reserved for the camera ray, the path of every sample starts after them
+#define CAMERA_DIMENSIONS 16
+
+// equirectangular lookup of the skybox, rotated around the up axis
+vec2 skyboxUV(in vec3 dir, in float rotation) {
+  return vec2((atan(dir.x, dir.z) + rotation) / (2 * PI), acos(clamp(dir.y, -1.0, 1.0)) / PI);
+}
+
+vec3 skyboxDirection(in vec2 uv, in float rotation) {
+  const float phi = uv.x * 2 * PI - rotation;
+  const float theta = uv.y * PI;
+  return vec3(sin(theta) * sin(phi), cos(theta), sin(theta) * cos(phi));
+}
+
+// the bits of RayVisibility in instance_material.rs, rays only hit instances that have theirs set
+#define RAY_MASK_CAMERA 0x01
+#define RAY_MASK_SHADOW 0x02
+#define RAY_MASK_REFLECTION 0x04
+
+// InstanceMaterialOverride of the instance, negative factors keep the ones of the material
+struct InstanceMaterial {
+  vec3 tint;
+  float roughness;
+  vec3 emission;
+  float metallic;
+  float transmission;
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer InstanceMaterialData {
+  InstanceMaterial materials[];
+};
+
+void applyInstanceMaterial(in InstanceMaterial material, inout HitPayload payload) {
+  payload.color.rgb *= material.tint;
+  // not part of emissive_luminance, the light list does not know about it
+  payload.emission += material.emission;
+  if (material.roughness >= 0.0) {
+    payload.roughness = material.roughness;
+  }
+  if (material.metallic >= 0.0) {
+    payload.metallic = material.metallic;
+  }
+  if (material.transmission >= 0.0) {
+    payload.transmission = material.transmission * (1.0 - payload.metallic);
+  }
+}
+
+// GpuPrimitiveMaterial in procedural.rs
+struct PrimitiveMaterial {
+  vec3 color;
+  float roughness;
+  vec3 emission;
+  float metallic;
+  vec3 absorption;
+  float transmission;
+  float ior;
+  float emissive_luminance;
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 4) readonly buffer PrimitiveMaterialData {
+  PrimitiveMaterial materials[];
 };
 
 layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer UniformData {
   mat4 inverse_view;
   mat4 inverse_proj;
-  uint entropy;
-  uint should_clear;
+  uint frame;
+  uint sample_count;
+  uint samples_per_frame;
+  uint seed;
+  uint sampler;
   uint mouse_x;
   uint mouse_y;
   float exposure;
+  float aperture;
+  LightData light_buffer;
+  uint light_count;
+  uint emissive_count;
+  EmissiveTriangleData emissive_buffer;
+  float emissive_power;
+  uint environment_width;
+  EnvironmentCdf environment_cdf;
+  uint environment_height;
+  uint sky_mode;
+  float sky_rotation;
+  float sky_intensity;
+  vec3 sky_horizon;
+  vec3 sky_zenith;
+  SobolMatrices sobol_matrices;
+  InstanceMaterialData instance_materials;
+  PrimitiveMaterialData primitive_materials;
 };
 
 layout (buffer_reference, std430, buffer_reference_align = 16) buffer QueryData {
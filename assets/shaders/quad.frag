@@ -8,6 +8,7 @@ layout (set=0, binding=0) uniform sampler2D test;
 
 layout(push_constant, std430) uniform Registers {
   UniformData uniforms;
+  uint srgb_target;
 };
 
 layout(location = 0) out vec4 oColor;
@@ -43,23 +44,30 @@ vec3 toneMapUncharted2Impl(vec3 color)
   return ((color * (A * color + C * B) + D * E) / (color * (A * color + B) + D * F)) - E / F;
 }
 
+// the sRGB transfer function is baked into this curve, its output is display encoded
 vec3 tonemapFilmic(const vec3 color) {
 	vec3 x = max(vec3(0.0), color - 0.004);
 	return (x * (6.2 * x + 0.5)) / (x * (6.2 * x + 1.7) + 0.06);
 }
 
+vec3 srgbToLinear(const vec3 color) {
+  return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)), greaterThan(color, vec3(0.04045)));
+}
+
 void main() {
-    const float gamma = 2.2f;
     const float exposure = uniforms.exposure * uniforms.exposure;
 
     vec4 bufferVal = texture(test, uv);
 
     vec3 hdrColor = bufferVal.xyz / bufferVal.w;
     vec3 mapped = vec3(1.0) - exp(-hdrColor * exposure);
-    // gamma correction
-//    mapped = pow(mapped, vec3(1.0f / gamma));
     mapped = tonemapFilmic(mapped);
 
+    // sRGB targets encode on write, so they expect linear values
+    if (srgb_target != 0) {
+        mapped = srgbToLinear(clamp(mapped, 0.0, 1.0));
+    }
+
     oColor = vec4(mapped, 1.0f);
 }
 
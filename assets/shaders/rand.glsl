@@ -29,9 +29,75 @@ uint rand()
   return (word >> 22u) ^ word;
 }
 
+#ifdef LOW_DISCREPANCY_SAMPLER
+// Owen scrambled Sobol points for randf, see "Practical Hash-based Owen Scrambling" (Burley 2020).
+// Every SOBOL_DIMENSIONS consecutive dimensions share a Sobol sequence whose order is shuffled by
+// the block they are in. Needs common.glsl included first.
+uint g_sampler = SAMPLER_PCG;
+SobolMatrices g_sobol_matrices;
+uint g_scramble_seed = 0;
+uint g_sample_index = 0;
+uint g_dimension = 0;
+
+uint hashCombine(uint seed, uint v)
+{
+  return seed ^ (v + (seed << 6) + (seed >> 2));
+}
+
+uint laineKarrasPermutation(uint x, uint seed)
+{
+  x += seed;
+  x ^= x * 0x6c50b47cu;
+  x ^= x * 0xb82f1e52u;
+  x ^= x * 0xc7afe638u;
+  x ^= x * 0x8d22f6e6u;
+  return x;
+}
+
+uint nestedUniformScramble(uint x, uint seed)
+{
+  return bitfieldReverse(laineKarrasPermutation(bitfieldReverse(x), seed));
+}
+
+uint sobol(uint index, uint dimension)
+{
+  uint x = 0;
+  for (uint bit = 0; bit < 32; bit++) {
+    if (((index >> bit) & 1) != 0) {
+      x ^= g_sobol_matrices.directions[dimension * 32 + bit];
+    }
+  }
+  return x;
+}
+
+void startSample(uint sample_index, uint dimension)
+{
+  g_sample_index = sample_index;
+  g_dimension = dimension;
+}
+
+float sobolRandf()
+{
+  const uint dimension = g_dimension;
+  g_dimension++;
+
+  const uint seed = hashCombine(g_scramble_seed, wang_hash(dimension / SOBOL_DIMENSIONS));
+  const uint index = nestedUniformScramble(g_sample_index, seed);
+  const uint component = dimension % SOBOL_DIMENSIONS;
+  const uint x = nestedUniformScramble(sobol(index, component), hashCombine(seed, component));
+  // 24 bits, more would round up to 1
+  return float(x >> 8) / 16777216.0;
+}
+#endif
+
 
 float randf()
 {
+#ifdef LOW_DISCREPANCY_SAMPLER
+    if (g_sampler == SAMPLER_SOBOL) {
+      return sobolRandf();
+    }
+#endif
     uint r = rand();
     return uintBitsToFloat(0x3f800000 | (r >> 9)) - 1.0f;
 }
@@ -0,0 +1,57 @@
+// The intersection shader of the .sdf files. The shader loader puts the snippet of one after this
+// file, all it has to define is
+//   float sdf(vec3 p);
+// the signed distance to a shape inside the unit box around the origin, negative inside it.
+#extension GL_EXT_ray_tracing : require
+#extension GL_EXT_buffer_reference : require
+#extension GL_EXT_scalar_block_layout : require
+
+#include "common.glsl"
+
+// the outward normal in object space
+hitAttributeEXT vec3 object_normal;
+
+float sdf(vec3 p);
+
+const int SDF_MAX_STEPS = 128;
+// Has to stay below the tmin of the rays, or the ones leaving the surface hit it again right away.
+// Rays leaving at a grazing angle still start closer than this and hit the surface they left.
+const float SDF_EPSILON = 1e-5;
+
+vec3 sdfNormal(vec3 p)
+{
+  const vec2 e = vec2(1e-4, 0.0);
+  return vec3(
+    sdf(p + e.xyy) - sdf(p - e.xyy),
+    sdf(p + e.yxy) - sdf(p - e.yxy),
+    sdf(p + e.yyx) - sdf(p - e.yyx));
+}
+
+void main()
+{
+  // the BLAS scales uniformly, so distances along the normalized direction are distances of sdf
+  vec3 orig = gl_ObjectRayOriginEXT;
+  float dir_length = length(gl_ObjectRayDirectionEXT);
+  vec3 dir = gl_ObjectRayDirectionEXT / dir_length;
+
+  // only march the part of the ray inside the unit box
+  vec3 t0 = (vec3(-0.5) - orig) / dir;
+  vec3 t1 = (vec3(0.5) - orig) / dir;
+  vec3 t_near = min(t0, t1);
+  vec3 t_far = max(t0, t1);
+  float t = max(max(max(t_near.x, t_near.y), t_near.z), gl_RayTminEXT * dir_length);
+  float t_end = min(min(min(t_far.x, t_far.y), t_far.z), gl_RayTmaxEXT * dir_length);
+
+  // rays starting inside the shape, like refracted ones, march to where they leave it
+  float side = sdf(orig + t * dir) < 0.0 ? -1.0 : 1.0;
+  for (int i = 0; i < SDF_MAX_STEPS && t <= t_end; i++) {
+    vec3 p = orig + t * dir;
+    float d = side * sdf(p);
+    if (d < SDF_EPSILON) {
+      object_normal = sdfNormal(p);
+      reportIntersectionEXT(t / dir_length, 0);
+      return;
+    }
+    t += d;
+  }
+}
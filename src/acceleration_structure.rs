@@ -1,28 +1,87 @@
+use std::sync::Arc;
+
 use ash::vk;
+use bevy::math::Vec2;
 
 use crate::{
+    gltf_assets::GltfTextures,
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
-    render_image::VkImage,
+    render_error::RenderResult,
 };
 
 #[repr(C)]
+#[derive(Clone, Copy, Default)]
 pub struct Vertex {
     pub pos: [f32; 3],
     pub normal: [f32; 3],
     pub uv: [f32; 2],
+    /// TEXCOORD_1, zero when the primitive only has one uv set
+    pub uv1: [f32; 2],
+}
+
+/// A texture used by a `TriangleMaterial`, matches `MaterialTexture` in common.glsl
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MaterialTexture {
+    /// Index into the bindless texture array, 0xFFFFFFFF when there is no texture
+    pub index: u32,
+    /// 0 samples with `Vertex::uv`, 1 with `Vertex::uv1`
+    pub uv_set: u32,
+    /// Column major 3x2 matrix applied to the uv, from `KHR_texture_transform`
+    pub transform: [[f32; 2]; 3],
+}
+
+impl MaterialTexture {
+    pub const NONE: Self = Self {
+        index: 0xFFFFFFFF,
+        uv_set: 0,
+        transform: [[1.0, 0.0], [0.0, 1.0], [0.0, 0.0]],
+    };
+
+    /// materialUV in common.glsl
+    pub fn uv(&self, uv0: Vec2, uv1: Vec2) -> Vec2 {
+        let [c0, c1, c2] = self.transform.map(Vec2::from);
+        let uv = if self.uv_set == 0 { uv0 } else { uv1 };
+        c0 * uv.x + c1 * uv.y + c2
+    }
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct TriangleMaterial {
     pub diffuse_factor: [f32; 4],
-    pub diffuse_texture: u32,
-    pub normal_texture: u32,
+    pub diffuse_texture: MaterialTexture,
+    pub normal_texture: MaterialTexture,
     pub metallic_factor: f32,
     pub roughness_factor: f32,
-    pub metallic_roughness_texture: u32,
+    pub metallic_roughness_texture: MaterialTexture,
     pub emmisive_factor: [f32; 3],
-    pub emmisive_texture: u32,
+    pub emmisive_texture: MaterialTexture,
+    pub alpha_mode: u32,
+    /// Only used by `ALPHA_MODE_MASK`
+    pub alpha_cutoff: f32,
+    pub transmission_factor: f32,
+    pub transmission_texture: MaterialTexture,
+    pub ior: f32,
+    /// Beer-Lambert coefficient per unit of distance travelled inside the volume
+    pub absorption: [f32; 3],
+}
+
+pub const ALPHA_MODE_OPAQUE: u32 = 0;
+pub const ALPHA_MODE_MASK: u32 = 1;
+pub const ALPHA_MODE_BLEND: u32 = 2;
+
+/// A triangle with an emissive material in object space, the `Scene` moves these to world space
+/// to build the light list raygen.rgen samples.
+#[derive(Clone, Copy)]
+pub struct EmissiveTriangle {
+    pub positions: [[f32; 3]; 3],
+    /// Already transformed for the emissive texture
+    pub uvs: [[f32; 2]; 3],
+    pub emission: [f32; 3],
+    /// Index of the emissive texture, 0xFFFFFFFF when there is none
+    pub texture: u32,
 }
 
 pub struct TriangleBLAS {
@@ -30,7 +89,9 @@ pub struct TriangleBLAS {
     pub index_buffer: Buffer<u32>,
     pub geometry_to_index_offset: Buffer<u32>,
     pub geometry_to_material: Buffer<TriangleMaterial>,
-    pub textures: Vec<VkImage>,
+    /// The textures the materials index, `GltfTextureOwner` frees them once no BLAS holds them
+    pub textures: Arc<GltfTextures>,
+    pub emissive_triangles: Vec<EmissiveTriangle>,
     pub acceleration_structure: AccelerationStructure,
 }
 
@@ -63,11 +124,11 @@ pub fn allocate_acceleration_structure(
     device: &RenderDevice,
     ty: vk::AccelerationStructureTypeKHR,
     build_size: &vk::AccelerationStructureBuildSizesInfoKHR,
-) -> AccelerationStructure {
+) -> RenderResult<AccelerationStructure> {
     let buffer: Buffer<u8> = device.create_device_buffer(
         build_size.acceleration_structure_size,
         vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
-    );
+    )?;
 
     let acceleration_structure = unsafe {
         device.exts.rt_acc_struct.create_acceleration_structure(
@@ -77,8 +138,14 @@ pub fn allocate_acceleration_structure(
                 .buffer(buffer.handle),
             None,
         )
-    }
-    .unwrap();
+    };
+    let acceleration_structure = match acceleration_structure {
+        Ok(acceleration_structure) => acceleration_structure,
+        Err(e) => {
+            device.destroy_buffer(buffer);
+            return Err(e.into());
+        }
+    };
 
     let address = unsafe {
         device.exts.rt_acc_struct.get_acceleration_structure_device_address(
@@ -88,9 +155,9 @@ pub fn allocate_acceleration_structure(
         )
     };
 
-    AccelerationStructure {
+    Ok(AccelerationStructure {
         handle: acceleration_structure,
         buffer,
         address,
-    }
+    })
 }
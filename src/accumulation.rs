@@ -0,0 +1,71 @@
+use bevy::prelude::*;
+
+/// Progressive accumulation into the render target: how many samples are traced per frame, when to
+/// stop, and how far along the current image is.
+#[derive(Resource, Debug, Clone)]
+pub struct Accumulation {
+    pub samples_per_frame: u32,
+    /// Tracing stops once the image has this many samples per pixel, None keeps refining forever
+    pub target_samples: Option<u32>,
+    samples: u32,
+    frame: u32,
+}
+
+impl Default for Accumulation {
+    fn default() -> Self {
+        Self::new(4, None)
+    }
+}
+
+impl Accumulation {
+    pub fn new(samples_per_frame: u32, target_samples: Option<u32>) -> Self {
+        Self {
+            samples_per_frame: samples_per_frame.max(1),
+            target_samples,
+            samples: 0,
+            frame: 0,
+        }
+    }
+
+    /// Starts over with an empty image, e.g. after the camera moved
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.frame = 0;
+    }
+
+    /// Samples per pixel accumulated since the last reset
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Frames traced since the last reset, the shaders seed their random numbers with it
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.target_samples.is_some_and(|target| self.samples >= target)
+    }
+
+    /// Fraction of the target reached, None without a target
+    pub fn progress(&self) -> Option<f32> {
+        self.target_samples
+            .map(|target| (self.samples as f32 / target.max(1) as f32).min(1.0))
+    }
+
+    /// Samples per pixel the next frame should trace, 0 once converged. The first frame after a
+    /// reset traces a single sample so a moving camera stays responsive.
+    pub fn next_frame_samples(&self) -> u32 {
+        let samples = if self.samples == 0 { 1 } else { self.samples_per_frame };
+        match self.target_samples {
+            Some(target) => samples.min(target.saturating_sub(self.samples)),
+            None => samples,
+        }
+    }
+
+    /// Records a traced frame of `samples` samples per pixel
+    pub fn advance(&mut self, samples: u32) {
+        self.samples += samples;
+        self.frame += 1;
+    }
+}
@@ -2,6 +2,9 @@ use std::f32::consts::PI;
 
 use bevy::prelude::*;
 
+/// Focal distance of the lens until the user focuses on something by clicking.
+pub const DEFAULT_FOCAL_DISTANCE: f32 = 7.0;
+
 #[derive(Component, Debug, Clone)]
 pub struct Camera3d {
     pub fov: f32,
@@ -9,6 +12,8 @@ pub struct Camera3d {
     pub max_t: f32,
     pub moved: bool,
     pub exposure: f32,
+    /// Size of the hexagonal lens used for depth of field
+    pub aperture: f32,
 }
 
 impl PartialEq for Camera3d {
@@ -16,9 +21,54 @@ impl PartialEq for Camera3d {
         self.fov == other.fov
             && self.min_t == other.min_t
             && self.max_t == other.max_t
+            && self.aperture == other.aperture
+    }
+}
+
+impl Camera3d {
+    /// The inverse view and projection matrices as consumed by raygen.rgen
+    pub fn inverse_view_proj(&self, transform: &GlobalTransform, aspect_ratio: f32) -> (Mat4, Mat4) {
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let view = Mat4::from_quat(rotation) * Mat4::from_translation(translation);
+        let projection = Mat4::perspective_rh(self.fov, aspect_ratio, self.min_t, self.max_t);
+        (view.inverse(), projection.inverse())
+    }
+}
+
+/// A perspective camera node imported from a gltf file. Selecting its `index` through
+/// `GltfCameraSelection` moves the `Camera3d` to it.
+#[derive(Component, Debug, Clone)]
+pub struct GltfCamera {
+    pub index: usize,
+    pub fov: f32,
+    pub min_t: f32,
+    pub max_t: f32,
+}
+
+impl GltfCamera {
+    pub fn from_gltf(camera: &gltf::Camera) -> Option<Self> {
+        match camera.projection() {
+            gltf::camera::Projection::Perspective(perspective) => Some(Self {
+                index: camera.index(),
+                fov: perspective.yfov(),
+                min_t: perspective.znear(),
+                max_t: perspective.zfar().unwrap_or(Camera3d::default().max_t),
+            }),
+            gltf::camera::Projection::Orthographic(_) => {
+                println!(
+                    "WARNING: Orthographic gltf camera {} is not supported, ignoring...",
+                    camera.index()
+                );
+                None
+            }
+        }
     }
 }
 
+/// The gltf camera index the `Camera3d` looks through, cycled with C.
+#[derive(Resource, Default)]
+pub struct GltfCameraSelection(pub Option<usize>);
+
 #[derive(Default, Component)]
 pub struct PitchYaw {
     pub pitch: f32,
@@ -33,6 +83,7 @@ impl Default for Camera3d {
             max_t: 100.0,
             moved: false,
             exposure: 1.0,
+            aperture: 0.005,
         }
     }
 }
@@ -49,8 +100,76 @@ pub struct Camera3dPlugin;
 
 impl Plugin for Camera3dPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<GltfCameraSelection>();
         app.add_system(check_moved);
+        app.add_system(cycle_gltf_cameras);
+        // after propagation, so freshly spawned gltf cameras already have their global transform
+        app.add_system(
+            apply_gltf_camera
+                .in_base_set(CoreSet::PostUpdate)
+                .after(bevy::transform::TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+fn cycle_gltf_cameras(
+    input: Res<Input<KeyCode>>,
+    mut selection: ResMut<GltfCameraSelection>,
+    gltf_cameras: Query<&GltfCamera>,
+) {
+    if !input.just_pressed(KeyCode::C) {
+        return;
     }
+
+    let mut indices = gltf_cameras.iter().map(|camera| camera.index).collect::<Vec<_>>();
+    indices.sort();
+    let Some(first) = indices.first() else {
+        return;
+    };
+
+    let next = match selection.0 {
+        Some(current) => *indices.iter().find(|index| **index > current).unwrap_or(first),
+        None => *first,
+    };
+    println!("Switching to gltf camera {}", next);
+    selection.0 = Some(next);
+}
+
+fn apply_gltf_camera(
+    selection: Res<GltfCameraSelection>,
+    gltf_cameras: Query<(&GltfCamera, &GlobalTransform)>,
+    added: Query<(), Added<GltfCamera>>,
+    mut camera: Query<(&mut Camera3d, &mut Transform, &mut GlobalTransform, &mut PitchYaw), Without<GltfCamera>>,
+) {
+    if !selection.is_changed() && added.is_empty() {
+        return;
+    }
+
+    let Some(index) = selection.0 else {
+        return;
+    };
+    let Some((gltf_camera, node_transform)) = gltf_cameras.iter().find(|(camera, _)| camera.index == index) else {
+        return;
+    };
+    let Ok((mut camera, mut transform, mut global_transform, mut pitch_yaw)) = camera.get_single_mut() else {
+        return;
+    };
+
+    // the Camera3d transform holds the view rotation and the negated position, see inverse_view_proj
+    let (_, rotation, translation) = node_transform.to_scale_rotation_translation();
+    let view_rotation = rotation.inverse();
+
+    // player_controls rebuilds the rotation from pitch and yaw, any roll is lost
+    let (pitch, yaw, _) = view_rotation.to_euler(EulerRot::XYZ);
+    pitch_yaw.pitch = -pitch;
+    pitch_yaw.yaw = yaw;
+
+    camera.fov = gltf_camera.fov;
+    camera.min_t = gltf_camera.min_t;
+    camera.max_t = gltf_camera.max_t;
+    *transform = Transform::from_translation(-translation).with_rotation(view_rotation);
+    // the camera has no parent, keep the global transform in sync for the renderers running this frame
+    *global_transform = GlobalTransform::from(*transform);
 }
 
 fn check_moved(
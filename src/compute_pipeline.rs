@@ -0,0 +1,389 @@
+use ash::util::read_spv;
+use ash::vk;
+use bevy::asset::HandleId;
+use bevy::ecs::system::lifetimeless::SRes;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::HashMap;
+use std::io::Cursor;
+
+use crate::composed_asset::{ComposedAsset, ComposedAssetAppExtension};
+use crate::render_device::RenderDevice;
+use crate::render_error::{RenderError, RenderResult};
+use crate::render_graph::{Access, GraphImage, PassContext, RenderGraph};
+use crate::render_image::VkImage;
+use crate::render_plugin::FRAMES_IN_FLIGHT;
+use crate::shader::Shader;
+use crate::shader_reflection::{reflect, ReflectedBinding, ShaderReflection};
+use crate::vk_utils;
+use crate::vulkan_assets::{AddVulkanAsset, VulkanAsset, VulkanAssets};
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+/// Set 1 of every shader is the device wide bindless texture array, set 0 is the pipeline's own
+const BINDLESS_SET: u32 = 1;
+
+/// Descriptor sets every frame has per pipeline, a pipeline can be dispatched with this many
+/// different sets of bindings per frame
+pub const SETS_PER_FRAME: usize = 4;
+
+/// A single compute shader. The pipeline layout comes from the shader itself, so editing the shader
+/// is all it takes to add a binding or push constant.
+#[derive(Default, TypeUuid)]
+#[uuid = "2e7f9c14-58a3-4d61-b0c2-7a1e6f3d8b95"]
+pub struct ComputePipeline {
+    pub shader: Handle<Shader>,
+}
+
+impl ComposedAsset for ComputePipeline {
+    type DepType = Shader;
+
+    fn get_deps(&self) -> Vec<&Handle<Self::DepType>> {
+        vec![&self.shader]
+    }
+}
+
+impl VulkanAsset for ComputePipeline {
+    type ExtractedAsset = Shader;
+    type PreparedAsset = VkComputePipeline;
+    type ExtractParam = SRes<Assets<Shader>>;
+
+    fn extract_asset(
+        &self,
+        shaders: &mut bevy::ecs::system::SystemParamItem<Self::ExtractParam>,
+    ) -> Option<Self::ExtractedAsset> {
+        shaders.get(&self.shader).cloned()
+    }
+
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
+        println!("creating compute pipeline for {}", asset.path);
+        create_compute_pipeline(device, &asset)
+    }
+
+    fn destroy_asset(asset: VkComputePipeline, cleanup: &VkCleanup) {
+        cleanup.send(VkCleanupEvent::Pipeline(asset.vk_pipeline));
+        cleanup.send(VkCleanupEvent::PipelineLayout(asset.pipeline_layout));
+        cleanup.send(VkCleanupEvent::DescriptorSetLayout(asset.descriptor_set_layout));
+    }
+}
+
+pub struct VkComputePipeline {
+    pub vk_pipeline: vk::Pipeline,
+    pub pipeline_layout: vk::PipelineLayout,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    /// SETS_PER_FRAME for every frame in flight
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    pub reflection: ShaderReflection,
+    uses_bindless: bool,
+}
+
+/// A resource bound to a compute shader descriptor
+#[derive(Clone)]
+pub enum ComputeBinding {
+    StorageImage(GraphImage),
+}
+
+impl ComputeBinding {
+    fn descriptor_type(&self) -> vk::DescriptorType {
+        match self {
+            ComputeBinding::StorageImage(_) => vk::DescriptorType::STORAGE_IMAGE,
+        }
+    }
+}
+
+impl VkComputePipeline {
+    pub fn descriptor_set(&self, frame_idx: usize, slot: usize) -> vk::DescriptorSet {
+        self.descriptor_sets[frame_idx * SETS_PER_FRAME + slot]
+    }
+
+    /// Fails when `bindings` no longer match the reflected layout, e.g. after a shader hot reload
+    pub fn check_bindings(&self, bindings: &[(u32, ComputeBinding)]) -> RenderResult<()> {
+        for (binding, resource) in bindings {
+            let declared = self.reflection.set_bindings(0).find(|b| b.binding == *binding);
+            if declared.map(|b| b.descriptor_type) != Some(resource.descriptor_type()) {
+                return Err(RenderError::Shader(format!(
+                    "binding {} does not match the shader, it declares {:?}",
+                    binding,
+                    declared.map(|b| b.descriptor_type)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `bindings` into `descriptor_set` and binds it together with the pipeline,
+    /// the bindings must have passed [`Self::check_bindings`]
+    pub unsafe fn bind(
+        &self,
+        context: &PassContext,
+        descriptor_set: vk::DescriptorSet,
+        bindings: &[(u32, ComputeBinding)],
+    ) {
+        let device = context.device;
+        let infos = bindings
+            .iter()
+            .map(|(binding, resource)| match resource {
+                ComputeBinding::StorageImage(image) => {
+                    let image: &VkImage = context.image(image);
+                    debug_assert_eq!(
+                        image.layout(),
+                        vk::ImageLayout::GENERAL,
+                        "binding {} is in the wrong layout",
+                        binding
+                    );
+                    vk::DescriptorImageInfo::builder()
+                        .image_layout(vk::ImageLayout::GENERAL)
+                        .image_view(image.view)
+                        .build()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let writes = bindings
+            .iter()
+            .zip(&infos)
+            .map(|((binding, resource), image_info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_set)
+                    .dst_binding(*binding)
+                    .descriptor_type(resource.descriptor_type())
+                    .image_info(std::slice::from_ref(image_info))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        device.device.update_descriptor_sets(&writes, &[]);
+
+        device
+            .device
+            .cmd_bind_pipeline(context.cmd_buffer, vk::PipelineBindPoint::COMPUTE, self.vk_pipeline);
+
+        let mut sets = vec![descriptor_set];
+        if self.uses_bindless {
+            sets.push(device.g_descriptor_set);
+        }
+        device.device.cmd_bind_descriptor_sets(
+            context.cmd_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout,
+            0,
+            &sets,
+            &[],
+        );
+    }
+
+    /// Dispatches enough workgroups to cover `invocations`, after `bind`
+    pub unsafe fn dispatch(&self, context: &PassContext, push_constants: &[u8], invocations: [u32; 3]) {
+        if !push_constants.is_empty() {
+            context.device.device.cmd_push_constants(
+                context.cmd_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
+
+        let local_size = self.reflection.local_size.unwrap_or([1, 1, 1]);
+        context.device.device.cmd_dispatch(
+            context.cmd_buffer,
+            invocations[0].div_ceil(local_size[0]),
+            invocations[1].div_ceil(local_size[1]),
+            invocations[2].div_ceil(local_size[2]),
+        );
+    }
+}
+
+fn create_compute_pipeline(device: &RenderDevice, shader: &Shader) -> RenderResult<VkComputePipeline> {
+    let code = read_spv(&mut Cursor::new(&shader.spirv))
+        .map_err(|e| RenderError::Shader(format!("failed to read {}: {}", shader.path, e)))?;
+    let reflection =
+        reflect(&code).map_err(|e| RenderError::Shader(format!("failed to reflect {}: {}", shader.path, e)))?;
+    if let Some(binding) = reflection.bindings.iter().find(|b| b.set != 0 && b.set != BINDLESS_SET) {
+        return Err(RenderError::Shader(format!(
+            "{} uses descriptor set {}, only 0 and the bindless set are supported",
+            shader.path, binding.set
+        )));
+    }
+
+    let bindings = reflection
+        .set_bindings(0)
+        .map(
+            |&ReflectedBinding {
+                 binding,
+                 descriptor_type,
+                 count,
+                 ..
+             }| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(descriptor_type)
+                    .descriptor_count(count.max(1))
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            },
+        )
+        .collect::<Vec<_>>();
+
+    let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+    let descriptor_set_layout = unsafe { device.device.create_descriptor_set_layout(&layout_info, None) }?;
+
+    let layouts = vec![descriptor_set_layout; FRAMES_IN_FLIGHT * SETS_PER_FRAME];
+    let descriptor_sets = unsafe {
+        device.device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(device.descriptor_pool)
+                .set_layouts(&layouts),
+        )
+    }?;
+
+    let uses_bindless = reflection.set_bindings(BINDLESS_SET).next().is_some();
+    let mut set_layouts = vec![descriptor_set_layout];
+    if uses_bindless {
+        set_layouts.push(device.g_descriptor_set_layout);
+    }
+
+    let push_constant_info = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        .offset(0)
+        .size(reflection.push_constant_size)
+        .build();
+    let push_constant_ranges = if reflection.push_constant_size > 0 {
+        std::slice::from_ref(&push_constant_info)
+    } else {
+        &[]
+    };
+    let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(&set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+    let pipeline_layout = unsafe { device.device.create_pipeline_layout(&pipeline_layout_info, None) }?;
+
+    let module = unsafe {
+        device
+            .device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&code), None)
+    }?;
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage.build())
+        .layout(pipeline_layout);
+
+    let vk_pipeline = unsafe {
+        device
+            .device
+            .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info.build()], None)
+    };
+
+    unsafe {
+        device.device.destroy_shader_module(module, None);
+    }
+    let vk_pipeline = vk_pipeline.map_err(|(_, result)| result)?[0];
+
+    Ok(VkComputePipeline {
+        vk_pipeline,
+        pipeline_layout,
+        descriptor_set_layout,
+        descriptor_sets,
+        reflection,
+        uses_bindless,
+    })
+}
+
+pub struct ComputeDispatch {
+    pub push_constants: Vec<u8>,
+    /// Invocations in x, y and z, rounded up to whole workgroups
+    pub invocations: [u32; 3],
+}
+
+/// Dispatches of one pipeline sharing their bindings, each one sees the writes of the one before
+pub struct ComputePass {
+    pub pipeline: Handle<ComputePipeline>,
+    pub bindings: Vec<(u32, ComputeBinding)>,
+    pub dispatches: Vec<ComputeDispatch>,
+    /// Shown instead of the render target once the pass ran
+    pub output: Option<GraphImage>,
+}
+
+/// Compute passes queued up to `RenderSet::Render`, where they join the frame's render graph after
+/// the path tracer and before the render target is presented or read back.
+#[derive(Resource, Default)]
+pub struct ComputePasses(pub Vec<ComputePass>);
+
+impl ComputePasses {
+    /// Whether every queued pipeline is compiled
+    pub fn is_ready(&self, pipelines: &VulkanAssets<ComputePipeline>) -> bool {
+        self.0.iter().all(|pass| pipelines.get(&pass.pipeline).is_some())
+    }
+}
+
+/// Adds the queued passes to `graph` and clears the queue, passes whose pipeline is not compiled yet
+/// are dropped. Returns the output of the last pass that has one.
+pub fn add_compute_passes<'a>(
+    graph: &mut RenderGraph<'a>,
+    pipelines: &'a VulkanAssets<ComputePipeline>,
+    passes: &mut ComputePasses,
+    frame_idx: usize,
+) -> Option<GraphImage> {
+    let mut slots = HashMap::<HandleId, usize>::default();
+    let mut output = None;
+
+    for pass in std::mem::take(&mut passes.0) {
+        let Some(compiled) = pipelines.get(&pass.pipeline) else {
+            continue;
+        };
+        if let Err(e) = compiled.check_bindings(&pass.bindings) {
+            println!("Skipping a compute pass: {}", e);
+            continue;
+        }
+        let slot = slots.entry(pass.pipeline.id()).or_insert(0);
+        if *slot == SETS_PER_FRAME {
+            println!("Skipping a compute pass, its pipeline ran out of descriptor sets this frame");
+            continue;
+        }
+        let descriptor_set = compiled.descriptor_set(frame_idx, *slot);
+        *slot += 1;
+
+        // the reflection does not tell read only storage apart, so the pass is assumed to write them all
+        let stage = vk::PipelineStageFlags2::COMPUTE_SHADER;
+        let accesses = pass
+            .bindings
+            .iter()
+            .map(|(_, binding)| match binding {
+                ComputeBinding::StorageImage(image) => (graph.image(image.clone()), Access::StorageReadWrite(stage)),
+            })
+            .collect::<Vec<_>>();
+
+        output = pass.output.clone().or(output);
+        graph.add_pass("compute", &accesses, move |context| unsafe {
+            compiled.bind(context, descriptor_set, &pass.bindings);
+            for (i, dispatch) in pass.dispatches.iter().enumerate() {
+                // the graph only orders whole passes, the dispatches of one are ordered here
+                if i > 0 {
+                    vk_utils::memory_barrier(
+                        context.device,
+                        context.cmd_buffer,
+                        (stage, vk::AccessFlags2::SHADER_STORAGE_WRITE),
+                        (
+                            stage,
+                            vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                        ),
+                    );
+                }
+                compiled.dispatch(context, &dispatch.push_constants, dispatch.invocations);
+            }
+        });
+    }
+
+    output
+}
+
+pub struct ComputePipelinePlugin;
+
+impl Plugin for ComputePipelinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ComputePasses>();
+        app.add_composed_asset::<ComputePipeline>();
+        app.add_vulkan_asset::<ComputePipeline>();
+    }
+}
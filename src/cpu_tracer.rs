@@ -0,0 +1,2119 @@
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+
+use bevy::app::AppExit;
+use bevy::asset::LoadState;
+use bevy::math::{Affine3A, Vec3A, Vec3Swizzles};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::acceleration_structure::{
+    EmissiveTriangle, MaterialTexture, TriangleMaterial, Vertex, ALPHA_MODE_BLEND, ALPHA_MODE_MASK, ALPHA_MODE_OPAQUE,
+};
+use crate::accumulation::Accumulation;
+use crate::camera::{Camera3d, Camera3dPlugin, DEFAULT_FOCAL_DISTANCE};
+use crate::compute_pipeline::ComputePipeline;
+use crate::denoiser::{AccumulatedImages, ExternalDenoiserHook};
+use crate::environment::{image_texels, skybox_direction, skybox_uv, EnvironmentCdf, SkyConfig, SkyFallback};
+use crate::gltf_assets::{
+    emissive_triangles, extract_material, extract_mesh_data, extract_mesh_sizes, gltf_image_texels, gltf_sampler_info,
+    GltfImage, GltfLoader, GltfMesh, GltfScene, GltfScenePlugin, GltfSceneSpawned,
+};
+use crate::headless::{save_accumulated, HeadlessConfig};
+use crate::instance_material::{instance_appearance, GpuInstanceMaterial, InstanceAppearance, RayVisibility};
+use crate::lights::{
+    build_emissive_cdf, luminance, GpuEmissiveTriangle, GpuPunctualLight, PunctualLight, LIGHT_DIRECTIONAL, LIGHT_SPOT,
+};
+use crate::procedural::{GpuPrimitiveMaterial, PrimitiveInstance, PrimitiveKind};
+use crate::rasterization_pipeline::RasterizationPipeline;
+use crate::raytracing_pipeline::RaytracingPipeline;
+use crate::render_device::SamplerInfo;
+use crate::render_plugin::{RayFocalFocus, RenderConfig};
+use crate::sampler::{sobol_matrices, SamplerConfig, SamplerKind, SobolSequence, CAMERA_DIMENSIONS};
+use crate::shader::{Shader, ShaderLoader};
+use crate::texture::ColorSpace;
+
+// A pure Rust port of raygen.rgen, hit.rchit, hit.rahit, the .rint shaders, procedural.rchit, miss.rmiss and
+// brdf.glsl. The .sdf snippets are GLSL only, the CPU tracer doesn't see those primitives.
+// Keep the order in which random numbers are drawn identical to the shaders, so both backends
+// consume the same stream for a given pixel and frame.
+
+const NO_TEXTURE: u32 = 0xFFFFFFFF;
+const T_MIN: f32 = 0.0001;
+const T_MAX: f32 = 100.0;
+const MAX_BOUNCES: u32 = 256;
+const BVH_LEAF_SIZE: usize = 4;
+
+/// The per-pixel PCG generator from rand.glsl. With the Sobol sampler raygen.rgen reads `randf`
+/// from the scrambled Sobol points instead, `rand` stays PCG.
+struct ShaderRng<'a> {
+    state: u32,
+    sobol: Option<SobolSequence<'a>>,
+}
+
+impl<'a> ShaderRng<'a> {
+    fn new(pixel: UVec2, size: UVec2, frame: u32, sampler: &SamplerConfig, sobol_matrices: &'a [u32]) -> Self {
+        // raygen.rgen passes the launch id as the resolution to initRandom
+        let state = tea(
+            pixel.y.wrapping_mul(pixel.x).wrapping_add(pixel.x),
+            tea(frame, sampler.seed),
+        );
+        let sobol = (sampler.kind == SamplerKind::Sobol)
+            .then(|| SobolSequence::new(sobol_matrices, tea(pixel.y * size.x + pixel.x, sampler.seed)));
+        Self { state, sobol }
+    }
+
+    /// A PCG generator as the hit shaders seed it
+    fn from_state(state: u32) -> Self {
+        Self { state, sobol: None }
+    }
+
+    fn start_sample(&mut self, sample_index: u32, dimension: u32) {
+        if let Some(sobol) = &mut self.sobol {
+            sobol.start_sample(sample_index, dimension);
+        }
+    }
+
+    fn rand(&mut self) -> u32 {
+        let prev = self.state.wrapping_mul(747796405).wrapping_add(2891336453);
+        let word = ((prev >> ((prev >> 28) + 4)) ^ prev).wrapping_mul(277803737);
+        self.state = prev;
+        (word >> 22) ^ word
+    }
+
+    fn randf(&mut self) -> f32 {
+        if let Some(sobol) = &mut self.sobol {
+            return sobol.next();
+        }
+        f32::from_bits(0x3f800000 | (self.rand() >> 9)) - 1.0
+    }
+}
+
+fn tea(val0: u32, val1: u32) -> u32 {
+    let mut v0 = val0;
+    let mut v1 = val1;
+    let mut s0: u32 = 0;
+
+    for _ in 0..16 {
+        s0 = s0.wrapping_add(0x9e3779b9);
+        v0 = v0.wrapping_add(
+            (v1 << 4).wrapping_add(0xa341316c) ^ v1.wrapping_add(s0) ^ (v1 >> 5).wrapping_add(0xc8013ea4),
+        );
+        v1 = v1.wrapping_add(
+            (v0 << 4).wrapping_add(0xad90777d) ^ v0.wrapping_add(s0) ^ (v0 >> 5).wrapping_add(0x7e95761e),
+        );
+    }
+
+    v0
+}
+
+struct MipLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<Vec4>,
+}
+
+impl MipLevel {
+    fn texel(&self, x: i64, y: i64, sampler: &SamplerInfo) -> Vec4 {
+        let x = address(x, self.width, sampler.address_mode_u);
+        let y = address(y, self.height, sampler.address_mode_v);
+        self.texels[y * self.width as usize + x]
+    }
+
+    fn filter(&self, uv: Vec2, filter: vk::Filter, sampler: &SamplerInfo) -> Vec4 {
+        if filter == vk::Filter::NEAREST {
+            let x = (uv.x * self.width as f32).floor() as i64;
+            let y = (uv.y * self.height as f32).floor() as i64;
+            return self.texel(x, y, sampler);
+        }
+
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0, sampler).lerp(self.texel(x0 + 1, y0, sampler), fx);
+        let bottom = self
+            .texel(x0, y0 + 1, sampler)
+            .lerp(self.texel(x0 + 1, y0 + 1, sampler), fx);
+        top.lerp(bottom, fy)
+    }
+
+    /// The linear blit `load_texture` generates the next level with
+    fn downsample(&self) -> MipLevel {
+        let clamp = SamplerInfo {
+            address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            ..default()
+        };
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let texels = (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| (UVec2::new(x, y).as_vec2() + 0.5) / UVec2::new(width, height).as_vec2())
+            })
+            .map(|uv| self.filter(uv, vk::Filter::LINEAR, &clamp))
+            .collect();
+        MipLevel { width, height, texels }
+    }
+}
+
+/// An RGBA image sampled like the bindless textures, with the filter, mipmap and address modes of
+/// its sampler. Clones share the texels, so one image can be used with several samplers.
+#[derive(Clone)]
+pub struct CpuTexture {
+    levels: Arc<Vec<MipLevel>>,
+    sampler: SamplerInfo,
+}
+
+impl CpuTexture {
+    /// Builds the same mip chain the GPU generates for gltf images
+    pub fn from_texels(width: u32, height: u32, texels: Vec<Vec4>) -> Self {
+        let mut levels = vec![MipLevel { width, height, texels }];
+        while let Some(level) = levels.last().filter(|level| level.width > 1 || level.height > 1) {
+            let next = level.downsample();
+            levels.push(next);
+        }
+        Self {
+            levels: Arc::new(levels),
+            sampler: SamplerInfo::default(),
+        }
+    }
+
+    pub fn with_sampler(&self, sampler: SamplerInfo) -> Self {
+        Self {
+            sampler,
+            ..self.clone()
+        }
+    }
+
+    /// Expects the rgba32f layout produced by the EXR loader, which is also what the GPU uploads.
+    pub fn from_image(image: &bevy::prelude::Image) -> Self {
+        Self {
+            levels: Arc::new(vec![MipLevel {
+                width: image.texture_descriptor.size.width,
+                height: image.texture_descriptor.size.height,
+                texels: image_texels(image),
+            }]),
+            sampler: SamplerInfo::default(),
+        }
+    }
+
+    /// `textureSize(..., 0)`
+    fn size(&self) -> Vec2 {
+        UVec2::new(self.levels[0].width, self.levels[0].height).as_vec2()
+    }
+
+    pub fn sample(&self, uv: Vec2) -> Vec4 {
+        self.sample_lod(uv, 0.0)
+    }
+
+    /// `textureLod`: the magnification filter up to lod 0, past it the minification filter
+    /// within the levels picked by the mipmap mode, up to the `max_lod` of the sampler.
+    pub fn sample_lod(&self, uv: Vec2, lod: f32) -> Vec4 {
+        let lod = lod.clamp(0.0, ((self.levels.len() - 1) as f32).min(self.sampler.max_lod));
+        if lod <= 0.0 {
+            return self.levels[0].filter(uv, self.sampler.mag_filter, &self.sampler);
+        }
+
+        let filter = self.sampler.min_filter;
+        if self.sampler.mipmap_mode == vk::SamplerMipmapMode::NEAREST {
+            let level = ((lod + 0.5).ceil() - 1.0) as usize;
+            return self.levels[level].filter(uv, filter, &self.sampler);
+        }
+
+        let level = lod.floor() as usize;
+        let next = (level + 1).min(self.levels.len() - 1);
+        self.levels[level]
+            .filter(uv, filter, &self.sampler)
+            .lerp(self.levels[next].filter(uv, filter, &self.sampler), lod.fract())
+    }
+}
+
+fn address(coord: i64, size: u32, mode: vk::SamplerAddressMode) -> usize {
+    let size = size as i64;
+    let coord = match mode {
+        vk::SamplerAddressMode::CLAMP_TO_EDGE => coord.clamp(0, size - 1),
+        vk::SamplerAddressMode::MIRRORED_REPEAT => {
+            let coord = coord.rem_euclid(2 * size);
+            if coord >= size {
+                2 * size - 1 - coord
+            } else {
+                coord
+            }
+        }
+        _ => coord.rem_euclid(size),
+    };
+    coord as usize
+}
+
+struct BvhNode {
+    min: Vec3,
+    max: Vec3,
+    // interior nodes have a count of 0 and their children at `first` and `first + 1`
+    first: u32,
+    count: u32,
+}
+
+/// A median split bounding volume hierarchy over the triangles of a mesh, standing in for the BLAS.
+struct Bvh {
+    nodes: Vec<BvhNode>,
+    // (geometry index, primitive index within that geometry)
+    prims: Vec<(u32, u32)>,
+}
+
+impl Bvh {
+    fn build(prims: Vec<(u32, u32)>, corners: impl Fn(u32, u32) -> [Vec3; 3]) -> Self {
+        let bounds = prims
+            .iter()
+            .map(|&(geometry, prim)| {
+                let [a, b, c] = corners(geometry, prim);
+                (a.min(b).min(c), a.max(b).max(c))
+            })
+            .collect::<Vec<_>>();
+
+        let mut order = (0..prims.len() as u32).collect::<Vec<_>>();
+        let mut nodes = vec![BvhNode {
+            min: Vec3::ZERO,
+            max: Vec3::ZERO,
+            first: 0,
+            count: 0,
+        }];
+        if !prims.is_empty() {
+            Self::build_node(&mut nodes, 0, &mut order, 0, &bounds);
+        }
+
+        Self {
+            nodes,
+            prims: order.into_iter().map(|i| prims[i as usize]).collect(),
+        }
+    }
+
+    fn build_node(nodes: &mut Vec<BvhNode>, node_idx: usize, order: &mut [u32], first: usize, bounds: &[(Vec3, Vec3)]) {
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        let mut centroid_min = Vec3::splat(f32::INFINITY);
+        let mut centroid_max = Vec3::splat(f32::NEG_INFINITY);
+        for &i in order.iter() {
+            let (lo, hi) = bounds[i as usize];
+            min = min.min(lo);
+            max = max.max(hi);
+            centroid_min = centroid_min.min((lo + hi) * 0.5);
+            centroid_max = centroid_max.max((lo + hi) * 0.5);
+        }
+
+        nodes[node_idx].min = min;
+        nodes[node_idx].max = max;
+
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        if order.len() <= BVH_LEAF_SIZE || extent[axis] <= 0.0 {
+            nodes[node_idx].first = first as u32;
+            nodes[node_idx].count = order.len() as u32;
+            return;
+        }
+
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by(mid, |a, b| {
+            let a = bounds[*a as usize].0[axis] + bounds[*a as usize].1[axis];
+            let b = bounds[*b as usize].0[axis] + bounds[*b as usize].1[axis];
+            a.total_cmp(&b)
+        });
+
+        let left = nodes.len();
+        for _ in 0..2 {
+            nodes.push(BvhNode {
+                min: Vec3::ZERO,
+                max: Vec3::ZERO,
+                first: 0,
+                count: 0,
+            });
+        }
+        nodes[node_idx].first = left as u32;
+
+        let (left_order, right_order) = order.split_at_mut(mid);
+        Self::build_node(nodes, left, left_order, first, bounds);
+        Self::build_node(nodes, left + 1, right_order, first + mid, bounds);
+    }
+}
+
+fn intersect_aabb(min: Vec3, max: Vec3, origin: Vec3, inv_dir: Vec3, t_max: f32) -> bool {
+    let t0 = (min - origin) * inv_dir;
+    let t1 = (max - origin) * inv_dir;
+    let t_near = t0.min(t1).max_element();
+    let t_far = t0.max(t1).min_element();
+    t_near <= t_far && t_far >= T_MIN && t_near <= t_max
+}
+
+/// Möller-Trumbore without culling, returns t and the barycentrics of the second and third vertex.
+fn intersect_triangle(origin: Vec3, dir: Vec3, [a, b, c]: [Vec3; 3]) -> Option<(f32, f32, f32)> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = dir.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = origin - a;
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = dir.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    Some((edge2.dot(q) * inv_det, u, v))
+}
+
+/// The CPU counterpart of a `TriangleBLAS`: the same vertex, index and material arrays.
+pub struct CpuMesh {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    geometry_to_index_offset: Vec<u32>,
+    materials: Vec<TriangleMaterial>,
+    textures: Vec<CpuTexture>,
+    emissive_triangles: Vec<EmissiveTriangle>,
+    bvh: Bvh,
+}
+
+impl CpuMesh {
+    pub fn from_gltf(asset: &GltfMesh) -> Self {
+        let mesh = asset.mesh();
+        let (vertex_count, index_count) = extract_mesh_sizes(&mesh);
+        let mut vertices = vec![Vertex::default(); vertex_count];
+        let mut indices = vec![0; index_count];
+        let geometries = extract_mesh_data(asset, &mut vertices, &mut indices);
+
+        let mut textures = Vec::new();
+        let mut loaded_images: HashMap<(usize, ColorSpace), Option<CpuTexture>> = HashMap::new();
+        let mut loaded_textures: HashMap<(usize, ColorSpace, SamplerInfo), u32> = HashMap::new();
+        let mut load_cached_texture = |texture: &gltf::Texture, color_space: ColorSpace| {
+            let sampler = gltf_sampler_info(&texture.sampler());
+            let image_idx = asset.texture_images[texture.index()];
+            if let Some(index) = loaded_textures.get(&(image_idx, color_space, sampler)) {
+                return *index;
+            }
+
+            // texels are decoded up front, like sampling an _SRGB format on the GPU
+            let image =
+                loaded_images
+                    .entry((image_idx, color_space))
+                    .or_insert_with(|| match &asset.images[image_idx] {
+                        GltfImage::Pixels(image) => Some(CpuTexture::from_texels(
+                            image.width,
+                            image.height,
+                            gltf_image_texels(image)
+                                .into_iter()
+                                .map(|texel| color_space.decode(texel))
+                                .collect(),
+                        )),
+                        GltfImage::Ktx2(_) => {
+                            println!("WARNING: KTX2 textures are not supported by the CPU tracer, ignoring...");
+                            None
+                        }
+                    });
+            let Some(image) = image else {
+                return NO_TEXTURE;
+            };
+
+            textures.push(image.with_sampler(sampler));
+            loaded_textures.insert((image_idx, color_space, sampler), textures.len() as u32 - 1);
+            textures.len() as u32 - 1
+        };
+
+        let materials = mesh
+            .primitives()
+            .map(|primitive| extract_material(&primitive.material(), &mut load_cached_texture))
+            .collect::<Vec<_>>();
+        let emissive_triangles = emissive_triangles(&vertices, &indices, &geometries, &materials);
+
+        let geometry_to_index_offset = geometries.iter().map(|g| g.first_index as u32).collect::<Vec<_>>();
+        let prims = geometries
+            .iter()
+            .enumerate()
+            .flat_map(|(geometry_idx, g)| (0..g.index_count as u32 / 3).map(move |prim| (geometry_idx as u32, prim)))
+            .collect();
+
+        let bvh = Bvh::build(prims, |geometry, prim| {
+            let first = (geometry_to_index_offset[geometry as usize] + prim * 3) as usize;
+            [0, 1, 2].map(|i| Vec3::from(vertices[indices[first + i] as usize].pos))
+        });
+
+        println!(
+            "CPU tracer: built BVH over {} triangles with {} nodes",
+            bvh.prims.len(),
+            bvh.nodes.len()
+        );
+
+        Self {
+            vertices,
+            indices,
+            geometry_to_index_offset,
+            materials,
+            textures,
+            emissive_triangles,
+            bvh,
+        }
+    }
+
+    fn triangle(&self, geometry: u32, prim: u32) -> [&Vertex; 3] {
+        let first = (self.geometry_to_index_offset[geometry as usize] + prim * 3) as usize;
+        [0, 1, 2].map(|i| &self.vertices[self.indices[first + i] as usize])
+    }
+
+    /// hit.rahit, returns false when the intersection should be ignored
+    fn any_hit(&self, instance: u32, geometry: u32, prim: u32, attribs: Vec2, seed: u32) -> bool {
+        let material = &self.materials[geometry as usize];
+        if material.alpha_mode == ALPHA_MODE_OPAQUE {
+            return true;
+        }
+
+        let mut alpha = material.diffuse_factor[3];
+        if material.diffuse_texture.index != NO_TEXTURE {
+            let [v0, v1, v2] = self.triangle(geometry, prim);
+            let barycentrics = Vec3::new(1.0 - attribs.x - attribs.y, attribs.x, attribs.y);
+            let uv0 = Vec2::from(v0.uv) * barycentrics.x
+                + Vec2::from(v1.uv) * barycentrics.y
+                + Vec2::from(v2.uv) * barycentrics.z;
+            let uv1 = Vec2::from(v0.uv1) * barycentrics.x
+                + Vec2::from(v1.uv1) * barycentrics.y
+                + Vec2::from(v2.uv1) * barycentrics.z;
+            let uv = material.diffuse_texture.uv(uv0, uv1);
+            alpha *= self.textures[material.diffuse_texture.index as usize].sample(uv).w;
+        }
+
+        match material.alpha_mode {
+            ALPHA_MODE_MASK => alpha >= material.alpha_cutoff,
+            ALPHA_MODE_BLEND => {
+                let mut rng = ShaderRng::from_state(tea(
+                    seed,
+                    tea(instance, geometry.wrapping_mul(0x10000).wrapping_add(prim)),
+                ));
+                rng.randf() < alpha
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns (t, geometry, primitive, barycentrics) of the closest hit in object space.
+    fn intersect(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        mut t_max: f32,
+        instance: u32,
+        seed: u32,
+    ) -> Option<(f32, u32, u32, Vec2)> {
+        let inv_dir = dir.recip();
+        let mut closest = None;
+        let mut stack = vec![0usize];
+
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.bvh.nodes[node_idx];
+            if !intersect_aabb(node.min, node.max, origin, inv_dir, t_max) {
+                continue;
+            }
+
+            if node.count == 0 {
+                stack.push(node.first as usize);
+                stack.push(node.first as usize + 1);
+                continue;
+            }
+
+            for &(geometry, prim) in &self.bvh.prims[node.first as usize..(node.first + node.count) as usize] {
+                let corners = self.triangle(geometry, prim).map(|v| Vec3::from(v.pos));
+                if let Some((t, u, v)) = intersect_triangle(origin, dir, corners) {
+                    if (T_MIN..=t_max).contains(&t) && self.any_hit(instance, geometry, prim, Vec2::new(u, v), seed) {
+                        t_max = t;
+                        closest = Some((t, geometry, prim, Vec2::new(u, v)));
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+pub enum CpuGeometry {
+    Mesh(usize),
+    Procedural(PrimitiveKind, GpuPrimitiveMaterial),
+}
+
+pub struct CpuInstance {
+    object_to_world: Affine3A,
+    world_to_object: Affine3A,
+    geometry: CpuGeometry,
+    material: GpuInstanceMaterial,
+    /// The instance mask of the TLAS instance
+    mask: u8,
+}
+
+impl CpuInstance {
+    pub fn new(object_to_world: Affine3A, geometry: CpuGeometry, (material, mask): (GpuInstanceMaterial, u8)) -> Self {
+        Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            geometry,
+            material,
+            mask,
+        }
+    }
+}
+
+/// Mirrors the TLAS: instances in the order `update_scene` emits them (primitives first).
+pub struct CpuScene {
+    pub meshes: Vec<CpuMesh>,
+    pub instances: Vec<CpuInstance>,
+    pub lights: Vec<GpuPunctualLight>,
+    /// The light list of `update_emissive_triangles`, with the mesh whose textures `texture` indexes
+    pub emissive_triangles: Vec<(GpuEmissiveTriangle, usize)>,
+    pub emissive_power: f32,
+    pub skybox: CpuTexture,
+    pub environment_cdf: EnvironmentCdf,
+    pub sky: SkyConfig,
+    /// Set when the fallback replaces the skybox
+    pub sky_fallback: Option<SkyFallback>,
+    pub sampler: SamplerConfig,
+    pub sobol_matrices: Vec<u32>,
+}
+
+pub struct CpuCamera {
+    pub inverse_view: Mat4,
+    pub inverse_proj: Mat4,
+    pub focal_distance: f32,
+    pub aperture: f32,
+}
+
+impl CpuCamera {
+    pub fn new(camera: &Camera3d, transform: &GlobalTransform, aspect_ratio: f32) -> Self {
+        let (inverse_view, inverse_proj) = camera.inverse_view_proj(transform, aspect_ratio);
+        Self {
+            inverse_view,
+            inverse_proj,
+            focal_distance: DEFAULT_FOCAL_DISTANCE,
+            aperture: camera.aperture,
+        }
+    }
+
+    /// pinholeDirection in raygen.rgen
+    fn pinhole_direction(&self, pixel: Vec2, size: UVec2) -> Vec3 {
+        let d = pixel / size.as_vec2() * 2.0 - 1.0;
+        let target = (self.inverse_proj * Vec4::new(d.x, d.y, 1.0, 1.0)).truncate();
+        (self.inverse_view * target.normalize().extend(0.0)).truncate()
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct HitPayload {
+    t: f32,
+    inside: bool,
+    color: Vec4,
+    surface_normal: Vec3,
+    normal: Vec3,
+    emission: Vec3,
+    absorption: Vec3,
+    metallic: f32,
+    roughness: f32,
+    transmission: f32,
+    refract_index: f32,
+    seed: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    emissive_luminance: f32,
+    instance: u32,
+}
+
+enum Hit {
+    Triangle {
+        mesh: usize,
+        geometry: u32,
+        prim: u32,
+        attribs: Vec2,
+    },
+    Procedural {
+        object_normal: Vec3,
+        material: GpuPrimitiveMaterial,
+    },
+}
+
+/// What an intersection shader doesn't report
+const NO_INTERSECTION: (f32, Vec3) = (-1.0, Vec3::ZERO);
+
+/// The intersection shader of `kind`, the candidate distances with the object space normal at each
+fn procedural_intersections(kind: PrimitiveKind, orig: Vec3, dir: Vec3) -> [(f32, Vec3); 2] {
+    match kind {
+        PrimitiveKind::Sphere => {
+            let t = gems_intersections(orig, dir, Vec3::ZERO, 0.5);
+            [t.x, t.y].map(|t| (t, orig + t * dir))
+        }
+        PrimitiveKind::Cuboid => box_intersections(orig, dir),
+        PrimitiveKind::Cylinder => cylinder_intersections(orig, dir),
+        PrimitiveKind::Quad => [
+            plane_intersection(orig, dir, |p| p.abs().max_element() <= 0.5),
+            NO_INTERSECTION,
+        ],
+        PrimitiveKind::Disk => [plane_intersection(orig, dir, |p| p.dot(p) <= 0.25), NO_INTERSECTION],
+        // render_when_loaded leaves them out of the scene
+        PrimitiveKind::Sdf => [NO_INTERSECTION; 2],
+    }
+}
+
+/// sphere.rint, returns both candidate distances
+fn gems_intersections(orig: Vec3, dir: Vec3, center: Vec3, radius: f32) -> Vec2 {
+    let f = orig - center;
+    let a = dir.dot(dir);
+    let bi = (-f).dot(dir);
+    let c = f.dot(f) - radius * radius;
+    let s = f + (bi / a) * dir;
+    let discr = radius * radius - s.dot(s);
+
+    let mut t = Vec2::new(-1.0, -1.0);
+    if discr >= 0.0 {
+        let q = bi + bi.signum() * (a * discr).sqrt();
+        t = Vec2::new(c / q, q / a);
+    }
+    t
+}
+
+/// GLSL's step
+fn step(edge: Vec3, x: Vec3) -> Vec3 {
+    Vec3::select(x.cmplt(edge), Vec3::ZERO, Vec3::ONE)
+}
+
+/// box.rint
+fn box_intersections(orig: Vec3, dir: Vec3) -> [(f32, Vec3); 2] {
+    let t0 = (Vec3::splat(-0.5) - orig) / dir;
+    let t1 = (Vec3::splat(0.5) - orig) / dir;
+    let t_near = t0.min(t1);
+    let t_far = t0.max(t1);
+    let t_enter = t_near.max_element();
+    let t_exit = t_far.min_element();
+    if t_enter > t_exit {
+        return [NO_INTERSECTION; 2];
+    }
+
+    let sign = dir.signum();
+    [
+        (t_enter, -sign * step(t_near.yzx(), t_near) * step(t_near.zxy(), t_near)),
+        (t_exit, sign * step(t_far, t_far.yzx()) * step(t_far, t_far.zxy())),
+    ]
+}
+
+/// cylinder.rint
+fn cylinder_intersections(orig: Vec3, dir: Vec3) -> [(f32, Vec3); 2] {
+    const RADIUS: f32 = 0.5;
+    const HALF_HEIGHT: f32 = 0.5;
+
+    let (mut side_near, mut side_far) = (-1e30, 1e30);
+    let a = dir.xz().dot(dir.xz());
+    let b = orig.xz().dot(dir.xz());
+    let c = orig.xz().dot(orig.xz()) - RADIUS * RADIUS;
+    if a > 0.0 {
+        let discr = b * b - a * c;
+        if discr < 0.0 {
+            return [NO_INTERSECTION; 2];
+        }
+        side_near = (-b - discr.sqrt()) / a;
+        side_far = (-b + discr.sqrt()) / a;
+    } else if c > 0.0 {
+        return [NO_INTERSECTION; 2];
+    }
+
+    let (mut cap_near, mut cap_far) = (-1e30, 1e30);
+    if dir.y != 0.0 {
+        let t0 = (-HALF_HEIGHT - orig.y) / dir.y;
+        let t1 = (HALF_HEIGHT - orig.y) / dir.y;
+        cap_near = t0.min(t1);
+        cap_far = t0.max(t1);
+    } else if orig.y.abs() > HALF_HEIGHT {
+        return [NO_INTERSECTION; 2];
+    }
+
+    let t_enter = side_near.max(cap_near);
+    let t_exit = side_far.min(cap_far);
+    if t_enter > t_exit {
+        return [NO_INTERSECTION; 2];
+    }
+
+    let side_normal = |t: f32| {
+        let p = orig + t * dir;
+        Vec3::new(p.x, 0.0, p.z)
+    };
+    [
+        (
+            t_enter,
+            if side_near > cap_near {
+                side_normal(t_enter)
+            } else {
+                Vec3::new(0.0, -dir.y.signum(), 0.0)
+            },
+        ),
+        (
+            t_exit,
+            if side_far < cap_far {
+                side_normal(t_exit)
+            } else {
+                Vec3::new(0.0, dir.y.signum(), 0.0)
+            },
+        ),
+    ]
+}
+
+/// quad.rint and disk.rint, `inside` tells whether the point in the XZ plane is on the shape
+fn plane_intersection(orig: Vec3, dir: Vec3, inside: impl Fn(Vec2) -> bool) -> (f32, Vec3) {
+    if dir.y == 0.0 {
+        return NO_INTERSECTION;
+    }
+    let t = -orig.y / dir.y;
+    if !inside(orig.xz() + t * dir.xz()) {
+        return NO_INTERSECTION;
+    }
+    (t, Vec3::Y)
+}
+
+impl CpuScene {
+    fn trace(&self, origin: Vec3, direction: Vec3, ray_mask: u8, payload: &mut HitPayload) {
+        self.trace_segment(origin, direction, T_MAX, ray_mask, payload)
+    }
+
+    fn trace_segment(&self, origin: Vec3, direction: Vec3, mut t_max: f32, ray_mask: u8, payload: &mut HitPayload) {
+        let mut closest = None;
+
+        for (instance_idx, instance) in self.instances.iter().enumerate() {
+            if instance.mask & ray_mask == 0 {
+                continue;
+            }
+            let object_origin = instance.world_to_object.transform_point3(origin);
+            let object_dir = instance.world_to_object.transform_vector3(direction);
+
+            match instance.geometry {
+                CpuGeometry::Mesh(mesh) => {
+                    if let Some((t, geometry, prim, attribs)) =
+                        self.meshes[mesh].intersect(object_origin, object_dir, t_max, instance_idx as u32, payload.seed)
+                    {
+                        t_max = t;
+                        let hit = Hit::Triangle {
+                            mesh,
+                            geometry,
+                            prim,
+                            attribs,
+                        };
+                        closest = Some((instance_idx, t, object_dir, hit));
+                    }
+                }
+                CpuGeometry::Procedural(kind, material) => {
+                    // like reportIntersectionEXT, a later candidate only replaces a closer one
+                    for (t, object_normal) in procedural_intersections(kind, object_origin, object_dir) {
+                        if (T_MIN..=t_max).contains(&t) {
+                            t_max = t;
+                            let hit = Hit::Procedural {
+                                object_normal,
+                                material,
+                            };
+                            closest = Some((instance_idx, t, object_dir, hit));
+                        }
+                    }
+                }
+            }
+        }
+
+        match closest {
+            Some((
+                instance_idx,
+                t,
+                object_dir,
+                Hit::Triangle {
+                    mesh,
+                    geometry,
+                    prim,
+                    attribs,
+                },
+            )) => {
+                self.shade_triangle(
+                    &self.meshes[mesh],
+                    &self.instances[instance_idx],
+                    geometry,
+                    prim,
+                    attribs,
+                    t,
+                    object_dir,
+                    payload,
+                );
+                payload.instance = instance_idx as u32;
+            }
+            Some((
+                instance_idx,
+                t,
+                object_dir,
+                Hit::Procedural {
+                    object_normal,
+                    material,
+                },
+            )) => {
+                shade_procedural(
+                    &self.instances[instance_idx],
+                    &material,
+                    object_normal,
+                    t,
+                    object_dir,
+                    payload,
+                );
+                payload.instance = instance_idx as u32;
+            }
+            None => self.shade_miss(payload),
+        }
+    }
+
+    /// hit.rchit
+    #[allow(clippy::too_many_arguments)]
+    fn shade_triangle(
+        &self,
+        mesh: &CpuMesh,
+        instance: &CpuInstance,
+        geometry: u32,
+        prim: u32,
+        attribs: Vec2,
+        t: f32,
+        object_dir: Vec3,
+        payload: &mut HitPayload,
+    ) {
+        let barycentrics = Vec3::new(1.0 - attribs.x - attribs.y, attribs.x, attribs.y);
+        let material = &mesh.materials[geometry as usize];
+        let [v0, v1, v2] = mesh.triangle(geometry, prim);
+        let (p0, p1, p2) = (Vec3::from(v0.pos), Vec3::from(v1.pos), Vec3::from(v2.pos));
+
+        let mut surface_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let mut normal = (Vec3::from(v0.normal) * barycentrics.x
+            + Vec3::from(v1.normal) * barycentrics.y
+            + Vec3::from(v2.normal) * barycentrics.z)
+            .normalize();
+
+        payload.inside = surface_normal.dot(object_dir) > 0.0;
+        if payload.inside {
+            surface_normal = -surface_normal;
+            normal = -normal;
+        }
+
+        let uv0 = Vec2::from(v0.uv) * barycentrics.x
+            + Vec2::from(v1.uv) * barycentrics.y
+            + Vec2::from(v2.uv) * barycentrics.z;
+        let uv1 = Vec2::from(v0.uv1) * barycentrics.x
+            + Vec2::from(v1.uv1) * barycentrics.y
+            + Vec2::from(v2.uv1) * barycentrics.z;
+
+        // ray cone lod, see hit.rchit
+        let [w0, w1, w2] = [p0, p1, p2].map(|p| instance.object_to_world.transform_point3(p));
+        let world_cross = (w1 - w0).cross(w2 - w0);
+        let world_area = world_cross.length().max(1e-8);
+        let world_dir = instance.object_to_world.transform_vector3(object_dir);
+        let cos_theta = (world_cross / world_area).dot(world_dir).abs().max(1e-4);
+        let footprint = (payload.cone_width + t * payload.cone_spread) / cos_theta;
+        let lod_scale = footprint * footprint / world_area;
+
+        let sample = |texture: &MaterialTexture| {
+            let image = &mesh.textures[texture.index as usize];
+            let lod = cone_lod(image, texture, v0, v1, v2, lod_scale);
+            image.sample_lod(texture.uv(uv0, uv1), lod)
+        };
+        payload.t = t;
+
+        payload.color = Vec4::from(material.diffuse_factor);
+        if material.diffuse_texture.index != NO_TEXTURE {
+            payload.color *= sample(&material.diffuse_texture);
+        }
+
+        payload.metallic = material.metallic_factor;
+        payload.roughness = material.roughness_factor;
+        if material.metallic_roughness_texture.index != NO_TEXTURE {
+            let roughness_and_metallic = sample(&material.metallic_roughness_texture);
+            payload.roughness *= roughness_and_metallic.y;
+            payload.metallic *= roughness_and_metallic.z;
+        }
+
+        payload.emission = Vec3::from(material.emmisive_factor);
+        payload.emissive_luminance = luminance(Vec3::from(material.emmisive_factor));
+        if material.emmisive_texture.index != NO_TEXTURE {
+            payload.emission *= sample(&material.emmisive_texture).truncate();
+        }
+
+        if material.normal_texture.index != NO_TEXTURE {
+            let tangent = calc_tangent(v0, v1, v2, material.normal_texture.uv_set);
+            let bitangent = normal.cross(tangent).normalize();
+            let tbn = Mat3::from_cols(tangent, bitangent, normal);
+
+            let tex_normal = sample(&material.normal_texture).truncate() * 2.0 - 1.0;
+            payload.normal = (tbn * tex_normal).normalize();
+        } else {
+            payload.normal = normal;
+        }
+
+        payload.surface_normal = instance.object_to_world.transform_vector3(surface_normal).normalize();
+        payload.normal = instance.object_to_world.transform_vector3(payload.normal).normalize();
+
+        // transmission only applies to the dielectric part of the material
+        payload.transmission = material.transmission_factor * (1.0 - payload.metallic);
+        if material.transmission_texture.index != NO_TEXTURE {
+            payload.transmission *= sample(&material.transmission_texture).x;
+        }
+        payload.absorption = Vec3::from(material.absorption);
+        payload.refract_index = material.ior;
+    }
+
+    /// miss.rmiss
+    fn shade_miss(&self, payload: &mut HitPayload) {
+        payload.t = 0.0;
+        payload.emission = Vec3::ZERO;
+        payload.emissive_luminance = 0.0;
+    }
+
+    /// skyRadiance in raygen.rgen
+    fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        let radiance = match self.sky_fallback {
+            Some(fallback) => {
+                let (horizon, zenith) = fallback.colors();
+                horizon.lerp(zenith, dir.y.max(0.0))
+            }
+            None => self
+                .skybox
+                .sample(skybox_uv(dir, self.sky.rotation))
+                .truncate()
+                .min(Vec3::splat(100000.0)),
+        };
+        radiance * self.sky.intensity
+    }
+
+    /// environmentPdf in raygen.rgen
+    fn environment_pdf(&self, dir: Vec3) -> f32 {
+        if self.sky_fallback.is_some() {
+            return 1.0 / (4.0 * PI);
+        }
+        self.environment_cdf.solid_angle_pdf(skybox_uv(dir, self.sky.rotation))
+    }
+
+    /// environmentDirection in raygen.rgen
+    fn environment_direction(&self, rng: &mut ShaderRng) -> Vec3 {
+        let r1 = rng.randf();
+        let r2 = rng.randf();
+        if self.sky_fallback.is_some() {
+            return uniform_sample_sphere(r1, r2);
+        }
+        let (uv, _) = self.environment_cdf.sample(Vec2::new(r1, r2));
+        skybox_direction(uv, self.sky.rotation)
+    }
+
+    /// traceShadowRay in raygen.rgen, closest hit shading is skipped on the GPU so only the
+    /// visibility is of interest
+    fn trace_shadow_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        t_max: f32,
+        rng: &mut ShaderRng,
+        payload: &mut HitPayload,
+    ) -> bool {
+        payload.seed = rng.rand();
+        self.trace_segment(origin, direction, t_max, RayVisibility::SHADOW.mask(), payload);
+        payload.t == 0.0
+    }
+
+    /// samplePunctualLight in raygen.rgen
+    #[allow(clippy::too_many_arguments)]
+    fn sample_punctual_light(
+        &self,
+        position: Vec3,
+        v: Vec3,
+        n: Vec3,
+        surface_normal: Vec3,
+        mat: &Material,
+        rng: &mut ShaderRng,
+        payload: &mut HitPayload,
+    ) -> Vec3 {
+        let light_count = self.lights.len() as u32;
+        let light = &self.lights[((rng.randf() * light_count as f32) as u32).min(light_count - 1) as usize];
+
+        let mut radiance = Vec3::from(light.radiance);
+        let mut l = -Vec3::from(light.direction);
+        let mut dist = T_MAX;
+        if light.kind != LIGHT_DIRECTIONAL {
+            let to_light = Vec3::from(light.position) - position;
+            dist = to_light.length();
+            l = to_light / dist;
+            radiance /= dist * dist;
+
+            if light.range > 0.0 {
+                radiance *= (1.0 - (dist / light.range).powf(4.0)).clamp(0.0, 1.0);
+            }
+
+            if light.kind == LIGHT_SPOT {
+                let cd = Vec3::from(light.direction).dot(-l);
+                let t = ((cd - light.outer_cone_cos) / (light.inner_cone_cos - light.outer_cone_cos).max(0.0001))
+                    .clamp(0.0, 1.0);
+                radiance *= t * t;
+            }
+        }
+
+        if l.dot(surface_normal) <= 0.0 || radiance.max_element() == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        if !self.trace_shadow_ray(position, l, dist - T_MIN, rng, payload) {
+            return Vec3::ZERO;
+        }
+
+        eval_disney_brdf(v, n, l, mat) * radiance * light_count as f32
+    }
+
+    /// emissivePdf in raygen.rgen
+    fn emissive_pdf(&self, emissive_luminance: f32, t: f32, cos_light: f32) -> f32 {
+        emissive_luminance / self.emissive_power * t * t / cos_light.max(1e-6)
+    }
+
+    /// sampleEmissiveTriangle in raygen.rgen
+    #[allow(clippy::too_many_arguments)]
+    fn sample_emissive_triangle(
+        &self,
+        position: Vec3,
+        v: Vec3,
+        n: Vec3,
+        surface_normal: Vec3,
+        mat: &Material,
+        rng: &mut ShaderRng,
+        payload: &mut HitPayload,
+    ) -> Vec3 {
+        // first triangle whose cdf exceeds the random number
+        let r = rng.randf();
+        let light_idx = self
+            .emissive_triangles
+            .partition_point(|(triangle, _)| triangle.cdf <= r)
+            .min(self.emissive_triangles.len() - 1);
+        let (light, mesh) = &self.emissive_triangles[light_idx];
+
+        // uniform point on the triangle
+        let mut b1 = rng.randf();
+        let mut b2 = rng.randf();
+        if b1 + b2 > 1.0 {
+            b1 = 1.0 - b1;
+            b2 = 1.0 - b2;
+        }
+        let b0 = 1.0 - b1 - b2;
+
+        let [p0, p1, p2] = [light.p0, light.p1, light.p2].map(Vec3::from);
+        let to_light = p0 * b0 + p1 * b1 + p2 * b2 - position;
+        let dist = to_light.length();
+        let l = to_light / dist;
+        let cos_light = (p1 - p0).cross(p2 - p0).normalize().dot(l).abs();
+        if l.dot(surface_normal) <= 0.0 || cos_light <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let mut emission = Vec3::from(light.emission);
+        if light.texture != NO_TEXTURE {
+            let [uv0, uv1, uv2] = [light.uv0, light.uv1, light.uv2].map(Vec2::from);
+            let uv = uv0 * b0 + uv1 * b1 + uv2 * b2;
+            emission *= self.meshes[*mesh].textures[light.texture as usize]
+                .sample_lod(uv, 0.0)
+                .truncate();
+        }
+
+        let brdf = eval_disney_brdf(v, n, l, mat);
+        if (brdf * emission).max_element() == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        if !self.trace_shadow_ray(position, l, dist * 0.999, rng, payload) {
+            return Vec3::ZERO;
+        }
+
+        let light_pdf = self.emissive_pdf(luminance(Vec3::from(light.emission)), dist, cos_light);
+        brdf * emission / light_pdf * power_heuristic(light_pdf, pdf_disney_brdf(v, n, l, mat))
+    }
+
+    /// sampleEnvironment in raygen.rgen
+    #[allow(clippy::too_many_arguments)]
+    fn sample_environment(
+        &self,
+        position: Vec3,
+        v: Vec3,
+        n: Vec3,
+        surface_normal: Vec3,
+        mat: &Material,
+        rng: &mut ShaderRng,
+        payload: &mut HitPayload,
+    ) -> Vec3 {
+        let l = self.environment_direction(rng);
+        if l.dot(surface_normal) <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let env_pdf = self.environment_pdf(l);
+        if env_pdf <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let brdf = eval_disney_brdf(v, n, l, mat);
+        if brdf.max_element() == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        if !self.trace_shadow_ray(position, l, T_MAX, rng, payload) {
+            return Vec3::ZERO;
+        }
+
+        brdf * self.sky_radiance(l) / env_pdf * power_heuristic(env_pdf, pdf_disney_brdf(v, n, l, mat))
+    }
+
+    /// raygen.rgen for a single pixel, returns the radiance, the albedo and the normal/depth guides
+    /// summed over the samples of this frame
+    fn trace_pixel(
+        &self,
+        camera: &CpuCamera,
+        pixel: UVec2,
+        size: UVec2,
+        accumulation: &Accumulation,
+    ) -> (Vec3, Vec3, Vec4) {
+        let mut rng = ShaderRng::new(pixel, size, accumulation.frame(), &self.sampler, &self.sobol_matrices);
+        let aspect_ratio = size.x as f32 / size.y as f32;
+        let camera_origin = (camera.inverse_view * Vec4::new(0.0, 0.0, 0.0, 1.0)).truncate();
+
+        // angle covered by a single pixel, the spread of the ray cones used for texture lod
+        let pixel_spread = (2.0 * camera.inverse_proj.col(1).y / size.y as f32).atan();
+
+        let mut accum = Vec3::ZERO;
+        let mut albedo_accum = Vec3::ZERO;
+        let mut normal_depth_accum = Vec4::ZERO;
+        let mut payload = HitPayload::default();
+
+        for s in 0..accumulation.next_frame_samples() {
+            // every sample has its own pixel and lens position, its path starts after the camera dimensions
+            rng.start_sample(accumulation.samples() + s, 0);
+            let pixel_center = pixel.as_vec2() + Vec2::new(rng.randf(), rng.randf());
+
+            let focal_offset = camera.aperture * sample_hexagon(&mut rng) * Vec2::new(aspect_ratio, 1.0);
+
+            let focal_point = camera_origin + camera.focal_distance * camera.pinhole_direction(pixel_center, size);
+            let start_origin = (camera.inverse_view * Vec4::new(focal_offset.x, focal_offset.y, 0.0, 1.0)).truncate();
+            let start_direction = (focal_point - start_origin).normalize();
+
+            rng.start_sample(accumulation.samples() + s, CAMERA_DIMENSIONS);
+            let mut mask = Vec3::ONE;
+            let mut origin = start_origin;
+            let mut direction = start_direction;
+            let mut cone_width = 0.0;
+            let mut brdf_pdf = 0.0;
+            let mut guides_written = false;
+            let mut ray_mask = RayVisibility::CAMERA.mask();
+
+            for _ in 0..MAX_BOUNCES {
+                payload.seed = rng.rand();
+                payload.cone_width = cone_width;
+                payload.cone_spread = pixel_spread;
+                self.trace(origin, direction, ray_mask, &mut payload);
+
+                if payload.t == 0.0 {
+                    if !guides_written {
+                        albedo_accum += Vec3::ONE;
+                    }
+                    let sky_weight = if brdf_pdf > 0.0 {
+                        power_heuristic(brdf_pdf, self.environment_pdf(direction))
+                    } else {
+                        1.0
+                    };
+                    accum += mask * self.sky_radiance(direction) * sky_weight;
+                    break;
+                }
+                apply_instance_material(&self.instances[payload.instance as usize].material, &mut payload);
+
+                let mut emission_weight = 1.0;
+                if brdf_pdf > 0.0 && payload.emissive_luminance > 0.0 && !self.emissive_triangles.is_empty() {
+                    let cos_light = payload.surface_normal.dot(direction).abs();
+                    let light_pdf = self.emissive_pdf(payload.emissive_luminance, payload.t, cos_light);
+                    emission_weight = power_heuristic(brdf_pdf, light_pdf);
+                }
+                accum += mask * payload.emission * emission_weight;
+
+                origin += payload.t * direction;
+                cone_width += payload.t * pixel_spread;
+                if !guides_written && payload.color.truncate().max_element() > 0.0 {
+                    albedo_accum += payload.color.truncate();
+                    normal_depth_accum += payload.normal.extend(origin.distance(start_origin));
+                    guides_written = true;
+                }
+                if payload.color.truncate().max_element() == 0.0 {
+                    brdf_pdf = 0.0;
+                    continue;
+                }
+
+                // russian roullete
+                let p_russian = payload.color.truncate().max_element().clamp(0.1, 0.9);
+                if rng.randf() > p_russian {
+                    break;
+                }
+                mask /= p_russian;
+
+                if rng.randf() < payload.transmission {
+                    let n1 = if payload.inside { payload.refract_index } else { 1.0 };
+                    let n2 = if payload.inside { 1.0 } else { payload.refract_index };
+                    let eta = n1 / n2;
+
+                    let costi = payload.normal.dot(-direction);
+                    let k = 1.0 - (eta * eta) * (1.0 - costi * costi);
+
+                    let p_reflect = if k < 0.0 {
+                        // Total internal reflection
+                        1.0
+                    } else {
+                        // same expression as the shader, including its sin(theta_i)
+                        let sinti = (1.0 - costi - costi).max(0.0).sqrt();
+                        let costt = (1.0 - eta * eta * sinti * sinti).sqrt();
+                        let spol = (n1 * costi - n2 * costt) / (n1 * costi + n2 * costt);
+                        let ppol = (n1 * costt - n2 * costi) / (n1 * costt + n2 * costi);
+                        0.5 * (spol * spol + ppol * ppol)
+                    };
+
+                    let refract_dir = if rng.randf() < p_reflect {
+                        reflect(direction, payload.normal)
+                    } else {
+                        origin += 0.002 * direction;
+                        // transmitted light is tinted by the base color, reflections are not
+                        mask *= payload.color.truncate();
+                        (eta * direction + payload.normal * (eta * costi - k.sqrt())).normalize()
+                    };
+
+                    let r1 = rng.randf();
+                    let r2 = rng.randf();
+                    let sample_dir = align_to_normal_zup(cosine_sample_hemisphere(r1, r2), payload.normal);
+                    direction = refract_dir.lerp(sample_dir, payload.roughness);
+                    if payload.inside {
+                        mask *= (-payload.t * payload.absorption).exp();
+                    }
+                    brdf_pdf = 0.0;
+                    ray_mask = RayVisibility::REFLECTION.mask();
+                } else {
+                    let mat = Material {
+                        albedo: payload.color.truncate(),
+                        metallic: payload.metallic,
+                        roughness: payload.roughness,
+                    };
+
+                    // the shadow ray below overwrites the payload
+                    let normal = payload.normal;
+                    let surface_normal = payload.surface_normal;
+
+                    let (brdf, out_dir) = sample_disney_brdf(-direction, normal, &mat, &mut rng);
+
+                    if !self.lights.is_empty() {
+                        accum += mask
+                            * self.sample_punctual_light(
+                                origin,
+                                -direction,
+                                normal,
+                                surface_normal,
+                                &mat,
+                                &mut rng,
+                                &mut payload,
+                            );
+                    }
+                    if !self.emissive_triangles.is_empty() {
+                        accum += mask
+                            * self.sample_emissive_triangle(
+                                origin,
+                                -direction,
+                                normal,
+                                surface_normal,
+                                &mat,
+                                &mut rng,
+                                &mut payload,
+                            );
+                    }
+                    accum += mask
+                        * self.sample_environment(
+                            origin,
+                            -direction,
+                            normal,
+                            surface_normal,
+                            &mat,
+                            &mut rng,
+                            &mut payload,
+                        );
+
+                    if brdf.w > 0.0 {
+                        mask *= brdf.truncate() / brdf.w;
+                    }
+                    brdf_pdf = if brdf.w > 0.0 {
+                        pdf_disney_brdf(-direction, normal, out_dir, &mat)
+                    } else {
+                        0.0
+                    };
+
+                    direction = out_dir;
+                    ray_mask = RayVisibility::REFLECTION.mask();
+                    if direction.dot(surface_normal) < 0.0 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        (accum, albedo_accum, normal_depth_accum)
+    }
+
+    /// Traces frames until `accumulation` converges, which needs a target sample count. The result
+    /// has the layout of the GPU render target and guides.
+    pub fn render(
+        &self,
+        camera: &CpuCamera,
+        width: u32,
+        height: u32,
+        accumulation: &mut Accumulation,
+    ) -> AccumulatedImages {
+        let mut accumulated = AccumulatedImages::new(width, height);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let size = UVec2::new(width, height);
+
+        while !accumulation.is_converged() {
+            let samples = accumulation.next_frame_samples();
+            let should_clear = accumulation.samples() == 0;
+            let frame_accumulation = &*accumulation;
+            let row_len = width as usize * 4;
+            let rows = Mutex::new(
+                accumulated
+                    .radiance
+                    .chunks_mut(row_len)
+                    .zip(accumulated.albedo.chunks_mut(row_len))
+                    .zip(accumulated.normal_depth.chunks_mut(row_len))
+                    .enumerate(),
+            );
+
+            std::thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| loop {
+                        let Some((y, ((row, albedo_row), normal_depth_row))) = rows.lock().unwrap().next() else {
+                            break;
+                        };
+
+                        let pixels = row
+                            .chunks_exact_mut(4)
+                            .zip(albedo_row.chunks_exact_mut(4))
+                            .zip(normal_depth_row.chunks_exact_mut(4));
+                        for (x, ((pixel, albedo), normal_depth)) in pixels.enumerate() {
+                            let (radiance, albedo_sum, normal_depth_sum) =
+                                self.trace_pixel(camera, UVec2::new(x as u32, y as u32), size, frame_accumulation);
+                            // same as the shader, the first frame overwrites whatever is there
+                            for (value, sum) in [
+                                (pixel, radiance.extend(samples as f32)),
+                                (albedo, albedo_sum.extend(0.0)),
+                                (normal_depth, normal_depth_sum),
+                            ] {
+                                let old = if should_clear {
+                                    Vec4::ZERO
+                                } else {
+                                    Vec4::from_slice(value)
+                                };
+                                (old + sum).write_to_slice(value);
+                            }
+                        }
+                    });
+                }
+            });
+
+            accumulation.advance(samples);
+            println!(
+                "CPU tracer: {}/{} samples",
+                accumulation.samples(),
+                accumulation.target_samples.unwrap_or(0)
+            );
+        }
+
+        accumulated
+    }
+}
+
+/// applyInstanceMaterial in common.glsl
+fn apply_instance_material(material: &GpuInstanceMaterial, payload: &mut HitPayload) {
+    payload.color *= Vec3::from(material.tint).extend(1.0);
+    payload.emission += Vec3::from(material.emission);
+    if material.roughness >= 0.0 {
+        payload.roughness = material.roughness;
+    }
+    if material.metallic >= 0.0 {
+        payload.metallic = material.metallic;
+    }
+    if material.transmission >= 0.0 {
+        payload.transmission = material.transmission * (1.0 - payload.metallic);
+    }
+}
+
+/// procedural.rchit
+fn shade_procedural(
+    instance: &CpuInstance,
+    material: &GpuPrimitiveMaterial,
+    object_normal: Vec3,
+    t: f32,
+    object_dir: Vec3,
+    payload: &mut HitPayload,
+) {
+    let mut normal = object_normal.normalize();
+
+    payload.inside = normal.dot(object_dir) > 0.0;
+    if payload.inside {
+        normal = -normal;
+    }
+
+    let world_normal = (instance.world_to_object.matrix3.transpose() * Vec3A::from(normal))
+        .normalize()
+        .into();
+
+    payload.absorption = Vec3::from(material.absorption);
+    payload.color = Vec3::from(material.color).extend(1.0);
+    payload.t = t;
+    payload.surface_normal = world_normal;
+    payload.normal = world_normal;
+    payload.emission = Vec3::from(material.emission);
+    payload.emissive_luminance = material.emissive_luminance;
+    payload.refract_index = material.ior;
+    payload.roughness = material.roughness;
+    payload.metallic = material.metallic;
+    payload.transmission = material.transmission * (1.0 - material.metallic);
+}
+
+/// coneLod in hit.rchit
+fn cone_lod(
+    image: &CpuTexture,
+    texture: &MaterialTexture,
+    v0: &Vertex,
+    v1: &Vertex,
+    v2: &Vertex,
+    lod_scale: f32,
+) -> f32 {
+    let size = image.size();
+    let [t0, t1, t2] = [v0, v1, v2].map(|v| texture.uv(Vec2::from(v.uv), Vec2::from(v.uv1)) * size);
+    let texel_area = (t1 - t0).perp_dot(t2 - t0).abs();
+    0.5 * (texel_area * lod_scale).max(1e-12).log2()
+}
+
+fn calc_tangent(v0: &Vertex, v1: &Vertex, v2: &Vertex, uv_set: u32) -> Vec3 {
+    let edge1 = Vec3::from(v1.pos) - Vec3::from(v0.pos);
+    let edge2 = Vec3::from(v2.pos) - Vec3::from(v0.pos);
+    let uv = |v: &Vertex| Vec2::from(if uv_set == 0 { v.uv } else { v.uv1 });
+    let delta_uv1 = uv(v1) - uv(v0);
+    let delta_uv2 = uv(v2) - uv(v0);
+
+    let denom = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+    if denom.abs() < 0.0001 {
+        return Vec3::X;
+    }
+
+    let f = 1.0 / denom;
+    (f * (delta_uv2.y * edge1 - delta_uv1.y * edge2)).normalize()
+}
+
+fn is_inside_hexagon(pos: Vec2) -> bool {
+    let q2x = pos.x.abs();
+    let q2y = pos.y.abs();
+    if q2x > 0.5 || q2y > 1.0 {
+        return false;
+    }
+    1.0 - q2x - q2y >= 0.0
+}
+
+fn sample_hexagon(rng: &mut ShaderRng) -> Vec2 {
+    loop {
+        let x = rng.randf() * 2.0 - 1.0;
+        let y = rng.randf() * 2.0 - 1.0;
+        if is_inside_hexagon(Vec2::new(x, y)) {
+            return Vec2::new(x, y);
+        }
+    }
+}
+
+fn reflect(incident: Vec3, normal: Vec3) -> Vec3 {
+    incident - 2.0 * normal.dot(incident) * normal
+}
+
+fn uniform_sample_sphere(r1: f32, r2: f32) -> Vec3 {
+    let z = 1.0 - 2.0 * r1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * r2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// powerHeuristic in raygen.rgen
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let r = other_pdf / pdf;
+    1.0 / (1.0 + r * r)
+}
+
+fn cosine_sample_hemisphere(r1: f32, r2: f32) -> Vec3 {
+    let r = r1.sqrt();
+    let phi = 2.0 * PI * r2;
+    let x = r * phi.cos();
+    let y = r * phi.sin();
+    Vec3::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+fn from_axis_angle(axis: Vec3, angle: f32) -> Mat3 {
+    let axis = axis.normalize();
+    let (s, c) = angle.sin_cos();
+    let oc = 1.0 - c;
+    let (x, y, z) = (axis.x, axis.y, axis.z);
+
+    Mat3::from_cols(
+        Vec3::new(oc * x * x + c, oc * x * y - z * s, oc * z * x + y * s),
+        Vec3::new(oc * x * y + z * s, oc * y * y + c, oc * y * z - x * s),
+        Vec3::new(oc * z * x - y * s, oc * y * z + x * s, oc * z * z + c),
+    )
+}
+
+fn align_to_normal_zup(s: Vec3, normal: Vec3) -> Vec3 {
+    let up = Vec3::Z;
+    if up.dot(normal) > 0.999 {
+        return s;
+    }
+
+    if up.dot(normal) < -0.999 {
+        return -s;
+    }
+
+    let angle = up.dot(normal).acos();
+    let axis = up.cross(normal);
+    // glsl `s * m` multiplies with the row vector
+    from_axis_angle(axis, angle).transpose() * s
+}
+
+struct Material {
+    albedo: Vec3,
+    metallic: f32,
+    roughness: f32,
+}
+
+fn basis(n: Vec3) -> (Vec3, Vec3) {
+    if n.z < 0.0 {
+        let a = 1.0 / (1.0 - n.z);
+        let b = n.x * n.y * a;
+        (
+            Vec3::new(1.0 - n.x * n.x * a, -b, n.x),
+            Vec3::new(b, n.y * n.y * a - 1.0, -n.y),
+        )
+    } else {
+        let a = 1.0 / (1.0 + n.z);
+        let b = -n.x * n.y * a;
+        (
+            Vec3::new(1.0 - n.x * n.x * a, b, -n.x),
+            Vec3::new(b, 1.0 - n.y * n.y * a, -n.y),
+        )
+    }
+}
+
+fn luma(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.299, 0.587, 0.114))
+}
+
+fn f_schlick(f0: Vec3, theta: f32) -> Vec3 {
+    f0 + (1.0 - f0) * (1.0 - theta).powf(5.0)
+}
+
+fn f_schlick_scalar(f0: f32, f90: f32, theta: f32) -> f32 {
+    f0 + (f90 - f0) * (1.0 - theta).powf(5.0)
+}
+
+fn d_gtr(roughness: f32, no_h: f32, k: f32) -> f32 {
+    let a2 = roughness.powf(2.0);
+    a2 / (PI * ((no_h * no_h) * (a2 * a2 - 1.0) + 1.0).powf(k))
+}
+
+fn smith_g(n_dot_v: f32, alpha_g: f32) -> f32 {
+    let a = alpha_g * alpha_g;
+    let b = n_dot_v * n_dot_v;
+    (2.0 * n_dot_v) / (n_dot_v + (a + b - a * b).sqrt())
+}
+
+fn geometry_term(no_l: f32, no_v: f32, roughness: f32) -> f32 {
+    let a2 = roughness * roughness;
+    smith_g(no_v, a2) * smith_g(no_l, a2)
+}
+
+fn sample_ggx_vndf(v: Vec3, ax: f32, ay: f32, r1: f32, r2: f32) -> Vec3 {
+    let vh = Vec3::new(ax * v.x, ay * v.y, v.z).normalize();
+
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if lensq > 0.0 {
+        Vec3::new(-vh.y, vh.x, 0.0) / lensq.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = vh.cross(t1);
+
+    let r = r1.sqrt();
+    let phi = 2.0 * PI * r2;
+    let p1 = r * phi.cos();
+    let p2 = r * phi.sin();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * p2;
+
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+    Vec3::new(ax * nh.x, ay * nh.y, nh.z.max(0.0)).normalize()
+}
+
+fn ggx_vndf_pdf(no_h: f32, no_v: f32, roughness: f32) -> f32 {
+    let d = d_gtr(roughness, no_h, 2.0);
+    let g1 = smith_g(no_v, roughness * roughness);
+    (d * g1) / (4.0 * no_v).max(0.00001)
+}
+
+fn eval_disney_diffuse(mat: &Material, no_l: f32, no_v: f32, lo_h: f32, roughness: f32) -> Vec3 {
+    let fd90 = 0.5 + 2.0 * roughness * lo_h.powf(2.0);
+    let a = f_schlick_scalar(1.0, fd90, no_l);
+    let b = f_schlick_scalar(1.0, fd90, no_v);
+    mat.albedo * (a * b / PI)
+}
+
+fn eval_disney_specular(mat: &Material, f: Vec3, no_h: f32, no_v: f32, no_l: f32) -> Vec3 {
+    let roughness = mat.roughness.powf(2.0);
+    let d = d_gtr(roughness, no_h, 2.0);
+    let g = geometry_term(no_l, no_v, (0.5 + mat.roughness * 0.5).powf(2.0));
+    d * f * g / (4.0 * no_l * no_v)
+}
+
+/// evalDisneyBRDF, brdf * NoL for a known light direction
+fn eval_disney_brdf(v: Vec3, n: Vec3, l: Vec3, mat: &Material) -> Vec3 {
+    let no_l = n.dot(l);
+    let no_v = n.dot(v);
+    if no_l <= 0.0 || no_v <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let roughness = mat.roughness.powf(2.0);
+    let h = (l + v).normalize();
+    let no_h = n.dot(h).min(0.99);
+    let lo_h = l.dot(h);
+
+    let f0 = Vec3::splat(0.04).lerp(mat.albedo, mat.metallic);
+    let f = f_schlick(f0, v.dot(h));
+
+    let diff = eval_disney_diffuse(mat, no_l, no_v, lo_h, roughness) * (1.0 - f) * (1.0 - mat.metallic);
+    let spec = eval_disney_specular(mat, f, no_h, no_v, no_l);
+    (diff + spec) * no_l
+}
+
+/// pdfDisneyBRDF, the pdf with which sample_disney_brdf picks l
+fn pdf_disney_brdf(v: Vec3, n: Vec3, l: Vec3, mat: &Material) -> f32 {
+    let no_l = n.dot(l);
+    let no_v = n.dot(v);
+    if no_l <= 0.0 || no_v <= 0.0 {
+        return 0.0;
+    }
+
+    let roughness = mat.roughness.powf(2.0);
+    let h = (l + v).normalize();
+    let no_h = n.dot(h).min(0.99);
+
+    let f0 = Vec3::splat(0.04).lerp(mat.albedo, mat.metallic);
+    let f = f_schlick(f0, v.dot(h));
+
+    let diff_w = 1.0 - mat.metallic;
+    let spec_w = luma(f);
+    let inv_w = 1.0 / (diff_w + spec_w);
+
+    (diff_w * no_l / PI + spec_w * ggx_vndf_pdf(no_h, no_v, roughness)) * inv_w
+}
+
+/// Returns the weighted brdf in rgb and its pdf in alpha, together with the sampled direction.
+fn sample_disney_brdf(v: Vec3, n: Vec3, mat: &Material, rng: &mut ShaderRng) -> (Vec4, Vec3) {
+    let roughness = mat.roughness.powf(2.0);
+
+    // sample microfacet normal
+    let (t, b) = basis(n);
+    let local_v = Vec3::new(v.dot(t), v.dot(b), v.dot(n));
+    let r1 = rng.randf();
+    let r2 = rng.randf();
+    let mut h = sample_ggx_vndf(local_v, roughness, roughness, r1, r2);
+    if h.z < 0.0 {
+        h = -h;
+    }
+    h = h.x * t + h.y * b + h.z * n;
+
+    // fresnel
+    let f0 = Vec3::splat(0.04).lerp(mat.albedo, mat.metallic);
+    let f = f_schlick(f0, v.dot(h));
+
+    // lobe weight probability
+    let mut diff_w = 1.0 - mat.metallic;
+    let mut spec_w = luma(f);
+    let inv_w = 1.0 / (diff_w + spec_w);
+    diff_w *= inv_w;
+    spec_w *= inv_w;
+
+    if rng.randf() < diff_w {
+        let r1 = rng.randf();
+        let r2 = rng.randf();
+        let l = align_to_normal_zup(cosine_sample_hemisphere(r1, r2), n);
+        let h = (l + v).normalize();
+
+        let no_l = n.dot(l);
+        let no_v = n.dot(v);
+        if no_l <= 0.0 || no_v <= 0.0 {
+            return (Vec4::ZERO, l);
+        }
+        let lo_h = l.dot(h);
+        let pdf = no_l / PI;
+
+        let diff = eval_disney_diffuse(mat, no_l, no_v, lo_h, roughness) * (1.0 - f);
+        ((diff * no_l).extend(diff_w * pdf), l)
+    } else {
+        let l = reflect(-v, h);
+
+        let no_l = n.dot(l);
+        let no_v = n.dot(v);
+        if no_l <= 0.0 || no_v <= 0.0 {
+            return (Vec4::ZERO, l);
+        }
+        let no_h = n.dot(h).min(0.99);
+        let pdf = ggx_vndf_pdf(no_h, no_v, roughness);
+
+        let spec = eval_disney_specular(mat, f, no_h, no_v, no_l);
+        ((spec * no_l).extend(spec_w * pdf), l)
+    }
+}
+
+/// Replaces the `RenderPlugin` when rendering with `--cpu`, no Vulkan device is ever created.
+pub struct CpuRenderPlugin;
+
+impl Plugin for CpuRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RayFocalFocus>();
+        app.add_plugin(Camera3dPlugin);
+
+        // the pipelines are still declared by the scene setup, they are just never compiled
+        app.add_asset::<Shader>()
+            .init_asset_loader::<ShaderLoader>()
+            .add_asset::<RaytracingPipeline>()
+            .add_asset::<RasterizationPipeline>()
+            .add_asset::<ComputePipeline>()
+            .add_asset::<GltfMesh>()
+            .init_asset_loader::<GltfLoader>();
+        app.add_plugin(GltfScenePlugin);
+
+        app.add_system(render_when_loaded.in_base_set(CoreSet::Last));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render_when_loaded(
+    config: Res<HeadlessConfig>,
+    render_config: Option<Res<RenderConfig>>,
+    accumulation: Res<Accumulation>,
+    gltf_meshes: Res<Assets<GltfMesh>>,
+    images: Res<Assets<bevy::prelude::Image>>,
+    asset_server: Res<AssetServer>,
+    meshes: Query<(Entity, &Handle<GltfMesh>, &GlobalTransform)>,
+    unspawned_scenes: Query<(), (With<Handle<GltfScene>>, Without<GltfSceneSpawned>)>,
+    primitives: Query<(Entity, &PrimitiveInstance, &GlobalTransform)>,
+    appearance: InstanceAppearance,
+    lights: Query<(&PunctualLight, &GlobalTransform)>,
+    camera: Query<(&Camera3d, &GlobalTransform)>,
+    external_denoiser: Option<Res<ExternalDenoiserHook>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(render_config) = render_config else {
+        return;
+    };
+    // same choice as resolve_sky, a failed skybox is replaced by an all black 1x1 one under the fallback
+    let (skybox, environment_cdf, sky_fallback) = match images.get(&render_config.skybox) {
+        Some(skybox) => {
+            let size = skybox.texture_descriptor.size;
+            let texels = image_texels(skybox);
+            (
+                CpuTexture::from_image(skybox),
+                EnvironmentCdf::from_equirect(&texels, size.width, size.height),
+                None,
+            )
+        }
+        None => {
+            let Some(fallback) = render_config.sky.fallback else {
+                return;
+            };
+            if asset_server.get_load_state(&render_config.skybox) != LoadState::Failed {
+                return;
+            }
+            (
+                CpuTexture::from_texels(1, 1, vec![Vec4::ZERO]),
+                EnvironmentCdf::new(&[0.0], 1, 1),
+                Some(fallback),
+            )
+        }
+    };
+    if !unspawned_scenes.is_empty() || meshes.iter().any(|(_, mesh, _)| gltf_meshes.get(mesh).is_none()) {
+        return;
+    }
+
+    let mut scene = CpuScene {
+        meshes: Vec::new(),
+        instances: Vec::new(),
+        lights: lights
+            .iter()
+            .map(|(light, transform)| GpuPunctualLight::new(light, transform))
+            .collect(),
+        emissive_triangles: Vec::new(),
+        emissive_power: 0.0,
+        skybox,
+        environment_cdf,
+        sky: render_config.sky.clone(),
+        sky_fallback,
+        sampler: render_config.sampler,
+        sobol_matrices: sobol_matrices(),
+    };
+
+    // the distance functions only exist as GLSL, a CPU reference image can't contain them
+    let sdf_count = primitives
+        .iter()
+        .filter(|(_, primitive, _)| primitive.kind == PrimitiveKind::Sdf)
+        .count();
+    if sdf_count > 0 {
+        println!(
+            "CPU tracer: warning, leaving out {} sdf primitive(s), only the GPU can trace them",
+            sdf_count
+        );
+    }
+
+    for (primitive_e, primitive, transform) in primitives.iter() {
+        if primitive.kind == PrimitiveKind::Sdf {
+            continue;
+        }
+        let appearance = instance_appearance(primitive_e, &appearance);
+        scene.instances.push(CpuInstance::new(
+            transform.affine() * primitive.blas_transform,
+            CpuGeometry::Procedural(primitive.kind, primitive.gpu_material()),
+            appearance,
+        ));
+    }
+
+    let mut mesh_indices = HashMap::new();
+    let mut emissive_triangles = Vec::new();
+    for (mesh_e, mesh, transform) in meshes.iter() {
+        let mesh_idx = *mesh_indices.entry(mesh.id()).or_insert_with(|| {
+            scene.meshes.push(CpuMesh::from_gltf(gltf_meshes.get(mesh).unwrap()));
+            scene.meshes.len() - 1
+        });
+        let appearance = instance_appearance(mesh_e, &appearance);
+        scene.instances.push(CpuInstance::new(
+            transform.affine(),
+            CpuGeometry::Mesh(mesh_idx),
+            appearance,
+        ));
+        emissive_triangles.extend(
+            scene.meshes[mesh_idx]
+                .emissive_triangles
+                .iter()
+                .map(|triangle| (GpuEmissiveTriangle::new(triangle, transform), mesh_idx)),
+        );
+    }
+    // after the meshes like in update_emissive_triangles, they have no texture to look up a mesh for
+    for (_, primitive, transform) in primitives.iter() {
+        emissive_triangles.extend(primitive.emissive_triangles(transform).map(|triangle| (triangle, 0)));
+    }
+
+    // drop the triangles build_emissive_cdf would drop first, so the mesh indices stay aligned
+    emissive_triangles.retain(|(triangle, _)| triangle.power() > 0.0);
+    let mut triangles = emissive_triangles
+        .iter()
+        .map(|(triangle, _)| *triangle)
+        .collect::<Vec<_>>();
+    scene.emissive_power = build_emissive_cdf(&mut triangles);
+    scene.emissive_triangles = triangles
+        .into_iter()
+        .zip(emissive_triangles.into_iter().map(|(_, mesh)| mesh))
+        .collect();
+
+    let (camera, camera_transform) = camera.single();
+    let cpu_camera = CpuCamera::new(camera, camera_transform, config.width as f32 / config.height as f32);
+
+    let mut accumulation = accumulation.clone();
+    accumulation.reset();
+    let mut accumulated = scene.render(&cpu_camera, config.width, config.height, &mut accumulation);
+    if let Some(external_denoiser) = external_denoiser {
+        if let Err(e) = accumulated.denoise_with(external_denoiser.0.as_ref()) {
+            println!("CPU tracer: external denoiser failed, writing the noisy image: {}", e);
+        }
+    }
+    match save_accumulated(
+        &accumulated.radiance,
+        config.width,
+        config.height,
+        camera.exposure,
+        &config.out,
+    ) {
+        Ok(()) => println!("CPU tracer: written {}", config.out.display()),
+        Err(e) => println!("CPU tracer: failed to write {}: {}", config.out.display(), e),
+    }
+
+    exit.send(AppExit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::SkyConfig;
+    use crate::procedural::{PrimitiveMaterial, ProceduralPrimitive, Quad, Sphere};
+
+    const LIGHT_EMISSION: f32 = 5.0;
+
+    /// A black, two sided quad light of 1x1 at y = 2 above a diffuse sphere of radius 0.5 at the
+    /// origin, under a black sky
+    fn quad_light_scene() -> CpuScene {
+        let quad = Quad {
+            half_size: Vec2::splat(0.5),
+            material: PrimitiveMaterial {
+                color: Vec3::ZERO,
+                emission: Vec3::splat(LIGHT_EMISSION),
+                ..default()
+            },
+        };
+        let light = PrimitiveInstance {
+            kind: PrimitiveKind::Quad,
+            blas_transform: quad.blas_transform(),
+            material: quad.material,
+            light_triangles: Quad::light_triangles(),
+        };
+        let sphere = Sphere {
+            radius: 0.5,
+            material: PrimitiveMaterial {
+                color: Vec3::splat(0.8),
+                roughness: 1.0,
+                metallic: 0.0,
+                ..default()
+            },
+        };
+
+        let light_transform = GlobalTransform::from_translation(Vec3::new(0.0, 2.0, 0.0));
+        let mut emissive_triangles = light.emissive_triangles(&light_transform).collect::<Vec<_>>();
+        let emissive_power = build_emissive_cdf(&mut emissive_triangles);
+
+        let appearance = (GpuInstanceMaterial::default(), RayVisibility::ALL.mask());
+        CpuScene {
+            meshes: Vec::new(),
+            instances: vec![
+                CpuInstance::new(
+                    light_transform.affine() * light.blas_transform,
+                    CpuGeometry::Procedural(PrimitiveKind::Quad, light.gpu_material()),
+                    appearance,
+                ),
+                CpuInstance::new(
+                    sphere.blas_transform(),
+                    CpuGeometry::Procedural(
+                        PrimitiveKind::Sphere,
+                        GpuPrimitiveMaterial::new(&sphere.material, false),
+                    ),
+                    appearance,
+                ),
+            ],
+            lights: Vec::new(),
+            emissive_triangles: emissive_triangles.into_iter().map(|triangle| (triangle, 0)).collect(),
+            emissive_power,
+            skybox: CpuTexture::from_texels(1, 1, vec![Vec4::ZERO]),
+            environment_cdf: EnvironmentCdf::new(&[0.0], 1, 1),
+            sky: SkyConfig {
+                rotation: 0.0,
+                intensity: 1.0,
+                fallback: Some(SkyFallback::Solid([0.0; 3])),
+            },
+            sky_fallback: Some(SkyFallback::Solid([0.0; 3])),
+            sampler: SamplerConfig {
+                kind: SamplerKind::Pcg,
+                seed: 7,
+            },
+            sobol_matrices: sobol_matrices(),
+        }
+    }
+
+    /// A camera at `eye` with a field of view so narrow that a single pixel sees one point
+    fn point_camera(eye: Vec3, target: Vec3) -> CpuCamera {
+        CpuCamera {
+            inverse_view: Transform::from_translation(eye)
+                .looking_at(target, Vec3::Y)
+                .compute_matrix(),
+            inverse_proj: Mat4::perspective_rh(1e-4, 1.0, 0.1, T_MAX).inverse(),
+            focal_distance: DEFAULT_FOCAL_DISTANCE,
+            aperture: 0.0,
+        }
+    }
+
+    /// The mean radiance and albedo of a 1x1 render with `samples` samples
+    fn render_pixel(scene: &CpuScene, camera: &CpuCamera, samples: u32) -> (Vec3, Vec3) {
+        let mut accumulation = Accumulation::new(256, Some(samples));
+        let accumulated = scene.render(camera, 1, 1, &mut accumulation);
+        let radiance = Vec4::from_slice(&accumulated.radiance);
+        assert_eq!(radiance.w, samples as f32);
+        let albedo = Vec4::from_slice(&accumulated.albedo).truncate();
+        (radiance.truncate() / radiance.w, albedo / samples as f32)
+    }
+
+    #[test]
+    fn quad_light_seen_directly_has_its_emission() {
+        let scene = quad_light_scene();
+        let camera = point_camera(Vec3::new(0.2, 3.0, 0.6), Vec3::new(0.2, 2.0, 0.1));
+
+        let (radiance, albedo) = render_pixel(&scene, &camera, 16);
+        assert!(
+            radiance.abs_diff_eq(Vec3::splat(LIGHT_EMISSION), 1e-5),
+            "radiance {}",
+            radiance
+        );
+        // black surfaces leave the guides to the sky behind them
+        assert!(albedo.abs_diff_eq(Vec3::ONE, 1e-5), "albedo {}", albedo);
+    }
+
+    #[test]
+    fn diffuse_sphere_under_quad_light_matches_quadrature() {
+        let scene = quad_light_scene();
+        let normal = Vec3::new(0.0, 1.0, 1.0).normalize();
+        let point = 0.5 * normal;
+        let eye = point + 3.0 * normal;
+        let camera = point_camera(eye, point);
+
+        // the sphere is convex and the sky black, so all light reaching the point comes straight
+        // from the quad: integrate brdf * cos * emission over its area
+        let mat = Material {
+            albedo: Vec3::splat(0.8),
+            metallic: 0.0,
+            roughness: 1.0,
+        };
+        let v = (eye - point).normalize();
+        let steps = 200;
+        let cell = 1.0 / steps as f32;
+        let mut expected = Vec3::ZERO;
+        for i in 0..steps {
+            for j in 0..steps {
+                let x = -0.5 + (i as f32 + 0.5) * cell;
+                let z = -0.5 + (j as f32 + 0.5) * cell;
+                let to_light = Vec3::new(x, 2.0, z) - point;
+                let l = to_light.normalize();
+                let cos_light = l.y.abs();
+                expected += eval_disney_brdf(v, normal, l, &mat) * LIGHT_EMISSION * cos_light * cell * cell
+                    / to_light.length_squared();
+            }
+        }
+
+        let (radiance, albedo) = render_pixel(&scene, &camera, 4096);
+        let error = ((radiance - expected) / expected).abs().max_element();
+        assert!(error < 0.02, "radiance {}, expected {}", radiance, expected);
+        assert!(albedo.abs_diff_eq(mat.albedo, 1e-5), "albedo {}", albedo);
+    }
+}
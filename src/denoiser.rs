@@ -0,0 +1,389 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ash::vk;
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+
+use crate::accumulation::Accumulation;
+use crate::compute_pipeline::{ComputeBinding, ComputeDispatch, ComputePass, ComputePasses};
+use crate::headless::{HeadlessConfig, HeadlessTarget};
+use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::render_graph::{GraphImage, TransientImage};
+use crate::render_image::{vk_image_from_asset, Image, VkImage};
+use crate::render_plugin::{RenderConfig, RenderSchedule, RenderSet};
+use crate::swapchain::Swapchain;
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+/// The first hit guides raygen.rgen accumulates next to the radiance. Both are rgba32f and the size
+/// of the render target.
+pub struct AuxImages {
+    /// Base color summed over the samples like the radiance, the count is in the render target alpha
+    pub albedo: VkImage,
+    /// Shading normal summed over the samples in rgb, distance to the camera in alpha
+    pub normal_depth: VkImage,
+}
+
+impl AuxImages {
+    pub fn new(device: &RenderDevice, width: u32, height: u32) -> RenderResult<Self> {
+        let image = || {
+            vk_image_from_asset(
+                device,
+                &Image {
+                    width,
+                    height,
+                    format: vk::Format::R32G32B32A32_SFLOAT,
+                    usage: vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::TRANSFER_SRC,
+                    initial_layout: vk::ImageLayout::UNDEFINED,
+                },
+            )
+        };
+
+        Ok(Self {
+            albedo: image()?,
+            normal_depth: image()?,
+        })
+    }
+
+    pub fn null() -> Self {
+        Self {
+            albedo: VkImage::null(),
+            normal_depth: VkImage::null(),
+        }
+    }
+
+    pub fn images(&self) -> [&VkImage; 2] {
+        [&self.albedo, &self.normal_depth]
+    }
+
+    pub fn destroy(&self, cleanup: &VkCleanup) {
+        for image in self.images() {
+            cleanup.send(VkCleanupEvent::ImageView(image.view));
+            cleanup.send(VkCleanupEvent::Image(image.handle));
+        }
+    }
+}
+
+/// The two images the filter ping-pongs between
+const FILTER_IMAGES: [TransientImage; 2] = [
+    TransientImage {
+        name: "denoise_filter0",
+        format: vk::Format::R32G32B32A32_SFLOAT,
+    },
+    TransientImage {
+        name: "denoise_filter1",
+        format: vk::Format::R32G32B32A32_SFLOAT,
+    },
+];
+
+/// Settings of the à-trous filter in denoise.comp, toggled with N. The filter runs as a compute pass
+/// after the path tracer.
+#[derive(Resource, Debug, Clone)]
+pub struct Denoiser {
+    pub enabled: bool,
+    /// The filter footprint doubles every iteration, 5 of them cover 125 pixels
+    pub iterations: u32,
+    /// Tolerance for color differences, shrinks with every iteration
+    pub phi_color: f32,
+    /// Exponent on the cosine between normals, higher keeps edges sharper
+    pub phi_normal: f32,
+    /// Tolerated depth difference relative to the depth of the pixel
+    pub phi_depth: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            iterations: 5,
+            phi_color: 1.0,
+            phi_normal: 64.0,
+            phi_depth: 0.1,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct DenoiseRegisters {
+    pub iteration: u32,
+    pub last_iteration: u32,
+    pub phi_color: f32,
+    pub phi_normal: f32,
+    pub phi_depth: f32,
+}
+
+pub struct DenoiserPlugin;
+
+impl Plugin for DenoiserPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Denoiser>();
+        app.add_system(toggle_denoiser);
+        app.edit_schedule(RenderSchedule, |schedule| {
+            schedule.add_system(queue_denoise.in_set(RenderSet::Extract));
+        });
+    }
+}
+
+fn toggle_denoiser(input: Res<Input<KeyCode>>, mut denoiser: ResMut<Denoiser>) {
+    if input.just_pressed(KeyCode::N) {
+        denoiser.enabled = !denoiser.enabled;
+        println!("Denoiser {}", if denoiser.enabled { "enabled" } else { "disabled" });
+    }
+}
+
+/// Queues the filter iterations over the render target of the window or the headless target. Every
+/// frame for the window, so toggling the denoiser also shows on a converged image.
+#[allow(clippy::too_many_arguments)]
+fn queue_denoise(
+    denoiser: Res<Denoiser>,
+    render_config: Option<Res<RenderConfig>>,
+    accumulation: Res<Accumulation>,
+    swapchain: Query<&Swapchain>,
+    headless_config: Option<Res<HeadlessConfig>>,
+    headless_target: Option<Res<HeadlessTarget>>,
+    external_denoiser: Option<Res<ExternalDenoiserHook>>,
+    mut passes: ResMut<ComputePasses>,
+) {
+    let Some(render_config) = render_config else {
+        return;
+    };
+    if !denoiser.enabled || denoiser.iterations == 0 {
+        return;
+    }
+
+    let (radiance, aux, width, height) = if let Ok(swapchain) = swapchain.get_single() {
+        (
+            &swapchain.render_target,
+            &swapchain.aux,
+            swapchain.width,
+            swapchain.height,
+        )
+    } else if let (Some(config), Some(target)) = (&headless_config, &headless_target) {
+        // headless renders are only read back once, and the external denoiser wants the noisy image
+        let finishes = accumulation
+            .target_samples
+            .is_some_and(|target| accumulation.samples() + accumulation.next_frame_samples() >= target);
+        if !finishes || external_denoiser.is_some() {
+            return;
+        }
+        (&target.render_target, &target.aux, config.width, config.height)
+    } else {
+        return;
+    };
+
+    let filter = FILTER_IMAGES.map(GraphImage::Transient);
+    let bindings = [radiance.clone(), aux.albedo.clone(), aux.normal_depth.clone()]
+        .map(GraphImage::Persistent)
+        .into_iter()
+        .chain(filter.clone())
+        .enumerate()
+        .map(|(binding, image)| (binding as u32, ComputeBinding::StorageImage(image)))
+        .collect();
+
+    let dispatches = (0..denoiser.iterations)
+        .map(|iteration| ComputeDispatch {
+            push_constants: bytemuck::bytes_of(&DenoiseRegisters {
+                iteration,
+                last_iteration: (iteration + 1 == denoiser.iterations) as u32,
+                phi_color: denoiser.phi_color,
+                phi_normal: denoiser.phi_normal,
+                phi_depth: denoiser.phi_depth,
+            })
+            .to_vec(),
+            invocations: [width, height, 1],
+        })
+        .collect();
+
+    passes.0.push(ComputePass {
+        pipeline: render_config.denoise_pipeline.clone(),
+        bindings,
+        dispatches,
+        output: Some(filter[((denoiser.iterations - 1) % 2) as usize].clone()),
+    });
+}
+
+/// A denoiser running on the CPU after the image was read back, like Intel Open Image Denoise. All
+/// images are rgb float3 of `width` x `height`: the color is linear radiance, the albedo in [0, 1]
+/// and the normals in [-1, 1]. Returns the denoised color in the same layout.
+pub trait ExternalDenoiser: Send + Sync {
+    fn denoise(
+        &self,
+        width: u32,
+        height: u32,
+        color: &[f32],
+        albedo: &[f32],
+        normal: &[f32],
+    ) -> Result<Vec<f32>, String>;
+}
+
+/// Applied to headless renders before they are written, replaces the GPU filter
+#[derive(Resource)]
+pub struct ExternalDenoiserHook(pub Box<dyn ExternalDenoiser>);
+
+/// Runs OIDN's `oidnDenoise` example app, or anything taking the same arguments, on PFM files in
+/// the temp directory.
+pub struct OidnCommand {
+    pub program: PathBuf,
+}
+
+impl ExternalDenoiser for OidnCommand {
+    fn denoise(
+        &self,
+        width: u32,
+        height: u32,
+        color: &[f32],
+        albedo: &[f32],
+        normal: &[f32],
+    ) -> Result<Vec<f32>, String> {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("denoise_{}_{}.pfm", std::process::id(), name));
+        let (color_path, albedo_path, normal_path, output_path) =
+            (path("color"), path("albedo"), path("normal"), path("output"));
+
+        write_pfm(&color_path, width, height, color)?;
+        write_pfm(&albedo_path, width, height, albedo)?;
+        write_pfm(&normal_path, width, height, normal)?;
+
+        let status = Command::new(&self.program)
+            .arg("--hdr")
+            .arg(&color_path)
+            .arg("--alb")
+            .arg(&albedo_path)
+            .arg("--nrm")
+            .arg(&normal_path)
+            .arg("-o")
+            .arg(&output_path)
+            .status()
+            .map_err(|e| format!("failed to run {}: {}", self.program.display(), e))?;
+
+        let output = if status.success() {
+            read_pfm(&output_path, width, height)
+        } else {
+            Err(format!("{} exited with {}", self.program.display(), status))
+        };
+
+        for path in [color_path, albedo_path, normal_path, output_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        output
+    }
+}
+
+/// Little endian, rows bottom first like the render target
+fn write_pfm(path: &Path, width: u32, height: u32, rgb: &[f32]) -> Result<(), String> {
+    let mut data = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    data.extend(rgb.iter().flat_map(|v| v.to_le_bytes()));
+    std::fs::File::create(path)
+        .and_then(|mut file| file.write_all(&data))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+fn read_pfm(path: &Path, width: u32, height: u32) -> Result<Vec<f32>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+
+    // "PF", width, height and scale separated by whitespace, a single one before the data
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        let start = pos;
+        while bytes.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(format!("{} has a truncated header", path.display()));
+        }
+        tokens.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+    }
+    pos += 1;
+
+    if tokens[0] != "PF" || tokens[1] != width.to_string() || tokens[2] != height.to_string() {
+        return Err(format!("{} is not a {}x{} rgb PFM", path.display(), width, height));
+    }
+    let little_endian = tokens[3].parse::<f32>().map_err(|e| e.to_string())? < 0.0;
+
+    let data = &bytes[pos.min(bytes.len())..];
+    if data.len() != width as usize * height as usize * 12 {
+        return Err(format!("{} has {} bytes of pixel data", path.display(), data.len()));
+    }
+    Ok(data
+        .chunks_exact(4)
+        .map(|b| {
+            let b = b.try_into().unwrap();
+            if little_endian {
+                f32::from_le_bytes(b)
+            } else {
+                f32::from_be_bytes(b)
+            }
+        })
+        .collect())
+}
+
+/// The render target and the guides as read back from the GPU or produced by the CPU tracer. All
+/// rgba32f holding the sum over the samples, bottom row first, the count is the alpha of `radiance`.
+pub struct AccumulatedImages {
+    pub width: u32,
+    pub height: u32,
+    pub radiance: Vec<f32>,
+    pub albedo: Vec<f32>,
+    pub normal_depth: Vec<f32>,
+}
+
+impl AccumulatedImages {
+    pub fn new(width: u32, height: u32) -> Self {
+        let len = width as usize * height as usize * 4;
+        Self {
+            width,
+            height,
+            radiance: vec![0.0; len],
+            albedo: vec![0.0; len],
+            normal_depth: vec![0.0; len],
+        }
+    }
+
+    fn counts(&self) -> impl Iterator<Item = f32> + '_ {
+        self.radiance.chunks_exact(4).map(|pixel| pixel[3].max(1.0))
+    }
+
+    /// Replaces the radiance with the output of `denoiser`, as if it was a single sample
+    pub fn denoise_with(&mut self, denoiser: &dyn ExternalDenoiser) -> Result<(), String> {
+        let color = self
+            .radiance
+            .chunks_exact(4)
+            .flat_map(|pixel| [0, 1, 2].map(|c| pixel[c] / pixel[3].max(1.0)))
+            .collect::<Vec<_>>();
+        let albedo = self
+            .albedo
+            .chunks_exact(4)
+            .zip(self.counts())
+            .flat_map(|(pixel, count)| [0, 1, 2].map(|c| (pixel[c] / count).clamp(0.0, 1.0)))
+            .collect::<Vec<_>>();
+        let normal = self
+            .normal_depth
+            .chunks_exact(4)
+            .flat_map(|pixel| Vec3::from_slice(pixel).normalize_or_zero().to_array())
+            .collect::<Vec<_>>();
+
+        let denoised = denoiser.denoise(self.width, self.height, &color, &albedo, &normal)?;
+        if denoised.len() != color.len() {
+            return Err(format!(
+                "the denoiser returned {} values instead of {}",
+                denoised.len(),
+                color.len()
+            ));
+        }
+
+        self.radiance = denoised
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.0])
+            .collect();
+        Ok(())
+    }
+}
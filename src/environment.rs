@@ -0,0 +1,405 @@
+use std::f32::consts::{PI, TAU};
+
+use ash::vk;
+use bevy::asset::LoadState;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::lights::luminance;
+use crate::render_buffer::{Buffer, BufferProvider};
+use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::render_image::VkImage;
+use crate::render_plugin::RenderConfig;
+use crate::texture::load_texture_from_bytes;
+use crate::vulkan_assets::{VulkanAsset, VulkanAssets};
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+pub const SKY_ENVIRONMENT: u32 = 0;
+pub const SKY_FALLBACK: u32 = 1;
+
+/// A sky without an image, used when the skybox fails to load.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum SkyFallback {
+    Solid([f32; 3]),
+    /// Blends from the horizon up to the zenith, everything below the horizon has the horizon color
+    Gradient {
+        horizon: [f32; 3],
+        zenith: [f32; 3],
+    },
+}
+
+impl SkyFallback {
+    /// (horizon, zenith), a solid sky is a gradient between the same colors
+    pub fn colors(&self) -> (Vec3, Vec3) {
+        match *self {
+            SkyFallback::Solid(color) => (Vec3::from(color), Vec3::from(color)),
+            SkyFallback::Gradient { horizon, zenith } => (Vec3::from(horizon), Vec3::from(zenith)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkyConfig {
+    /// Radians around the world up axis
+    pub rotation: f32,
+    /// Scales the radiance of the skybox or the fallback
+    pub intensity: f32,
+    pub fallback: Option<SkyFallback>,
+}
+
+impl Default for SkyConfig {
+    fn default() -> Self {
+        Self {
+            rotation: 0.66 * TAU,
+            intensity: 0.3,
+            fallback: None,
+        }
+    }
+}
+
+/// skyboxUV in common.glsl, u wraps around while v goes from the zenith to the nadir
+pub fn skybox_uv(dir: Vec3, rotation: f32) -> Vec2 {
+    Vec2::new(
+        (dir.x.atan2(dir.z) + rotation) / TAU,
+        dir.y.clamp(-1.0, 1.0).acos() / PI,
+    )
+}
+
+/// skyboxDirection in common.glsl, the inverse of `skybox_uv`
+pub fn skybox_direction(uv: Vec2, rotation: f32) -> Vec3 {
+    let phi = uv.x * TAU - rotation;
+    let theta = uv.y * PI;
+    Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos())
+}
+
+/// A distribution over the texels of an equirectangular image: a marginal cdf picks the row, the
+/// conditional cdf of that row picks the texel.
+#[derive(Debug, Clone)]
+pub struct EnvironmentCdf {
+    pub width: u32,
+    pub height: u32,
+    /// Inclusive cdf over the rows, the last entry is 1
+    pub marginal: Vec<f32>,
+    /// Inclusive cdf over the texels of every row, row major
+    pub conditional: Vec<f32>,
+}
+
+impl EnvironmentCdf {
+    /// `weights` are row major and do not need to be normalized. Rows or images without any weight
+    /// are sampled uniformly.
+    pub fn new(weights: &[f32], width: u32, height: u32) -> Self {
+        assert_eq!(weights.len(), width as usize * height as usize);
+
+        let rows = weights.chunks_exact(width as usize);
+        let row_weights = rows
+            .clone()
+            .map(|row| row.iter().map(|w| w.max(0.0)).sum())
+            .collect::<Vec<f32>>();
+        Self {
+            width,
+            height,
+            marginal: inclusive_cdf(&row_weights),
+            conditional: rows.flat_map(inclusive_cdf).collect(),
+        }
+    }
+
+    /// Weights every texel by its luminance and by the solid angle it covers, the rows towards the
+    /// poles of an equirectangular image are stretched over less of the sphere.
+    pub fn from_equirect(texels: &[Vec4], width: u32, height: u32) -> Self {
+        let weights = texels
+            .iter()
+            .enumerate()
+            .map(|(i, texel)| {
+                let theta = ((i / width as usize) as f32 + 0.5) / height as f32 * PI;
+                luminance(texel.truncate().min(Vec3::splat(100000.0))) * theta.sin()
+            })
+            .collect::<Vec<_>>();
+        Self::new(&weights, width, height)
+    }
+
+    fn row(&self, y: usize) -> &[f32] {
+        &self.conditional[y * self.width as usize..(y + 1) * self.width as usize]
+    }
+
+    /// Probability with which `sample` lands in texel (x, y)
+    pub fn texel_probability(&self, x: u32, y: u32) -> f32 {
+        cdf_step(&self.marginal, y as usize) * cdf_step(self.row(y as usize), x as usize)
+    }
+
+    /// Maps two uniform random numbers to a uv in [0, 1)², returned with its density over uv.
+    /// `r.x` picks the column and `r.y` the row, like environmentDirection in raygen.rgen.
+    pub fn sample(&self, r: Vec2) -> (Vec2, f32) {
+        let y = search_cdf(&self.marginal, r.y);
+        let row = self.row(y);
+        let x = search_cdf(row, r.x);
+
+        // where the random number falls within its step becomes the position inside the texel
+        let uv = Vec2::new(
+            (x as f32 + step_fraction(row, x, r.x)) / self.width as f32,
+            (y as f32 + step_fraction(&self.marginal, y, r.y)) / self.height as f32,
+        );
+        (uv, self.pdf(uv))
+    }
+
+    /// Density over uv with which `sample` returns `uv`
+    pub fn pdf(&self, uv: Vec2) -> f32 {
+        let x = ((uv.x.rem_euclid(1.0) * self.width as f32) as u32).min(self.width - 1);
+        let y = ((uv.y.clamp(0.0, 1.0) * self.height as f32) as u32).min(self.height - 1);
+        self.texel_probability(x, y) * (self.width * self.height) as f32
+    }
+
+    /// The density over solid angle of the direction `skybox_direction` makes of `uv`
+    pub fn solid_angle_pdf(&self, uv: Vec2) -> f32 {
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// The marginal cdf followed by the conditional ones, the layout raygen.rgen reads
+    pub fn gpu_data(&self) -> Vec<f32> {
+        self.marginal.iter().chain(&self.conditional).copied().collect()
+    }
+}
+
+fn inclusive_cdf(weights: &[f32]) -> Vec<f32> {
+    let total = weights.iter().map(|w| w.max(0.0)).sum::<f32>();
+    if total <= 0.0 {
+        return (1..=weights.len()).map(|i| i as f32 / weights.len() as f32).collect();
+    }
+
+    let mut sum = 0.0;
+    let mut cdf = weights
+        .iter()
+        .map(|w| {
+            sum += w.max(0.0);
+            sum / total
+        })
+        .collect::<Vec<_>>();
+    // guard against rounding, every random number below 1 has to find an entry
+    *cdf.last_mut().unwrap() = 1.0;
+    cdf
+}
+
+fn cdf_step(cdf: &[f32], i: usize) -> f32 {
+    cdf[i] - if i > 0 { cdf[i - 1] } else { 0.0 }
+}
+
+/// The first entry that exceeds `r`, entries without weight are skipped
+fn search_cdf(cdf: &[f32], r: f32) -> usize {
+    cdf.partition_point(|&c| c <= r).min(cdf.len() - 1)
+}
+
+fn step_fraction(cdf: &[f32], i: usize, r: f32) -> f32 {
+    let low = if i > 0 { cdf[i - 1] } else { 0.0 };
+    let step = cdf[i] - low;
+    if step > 0.0 {
+        ((r - low) / step).clamp(0.0, 1.0)
+    } else {
+        0.5
+    }
+}
+
+/// Expects the rgba32f layout produced by the EXR loader
+pub fn image_texels(image: &Image) -> Vec<Vec4> {
+    image
+        .data
+        .chunks_exact(16)
+        .map(|bytes| Vec4::from_array([0, 4, 8, 12].map(|i| f32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()))))
+        .collect()
+}
+
+/// A skybox prepared for the path tracer, together with the cdf it is importance sampled with.
+pub struct EnvironmentMap {
+    pub image: VkImage,
+    pub cdf: Buffer<f32>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl EnvironmentMap {
+    pub fn new(device: &RenderDevice, texels: &[Vec4], width: u32, height: u32) -> RenderResult<Self> {
+        let image = load_texture_from_bytes(
+            device,
+            vk::Format::R32G32B32A32_SFLOAT,
+            bytemuck::cast_slice(texels),
+            width,
+            height,
+        )?;
+
+        let cdf = EnvironmentCdf::from_equirect(texels, width, height).gpu_data();
+        let mut cdf_host = device.create_host_buffer::<f32>(cdf.len() as u64, vk::BufferUsageFlags::TRANSFER_SRC)?;
+        device.map_buffer(&mut cdf_host).as_slice_mut().copy_from_slice(&cdf);
+
+        let cdf_device = device.create_device_buffer::<f32>(
+            cdf.len() as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+        let uploaded = device.run_asset_commands(|cmd_buffer| {
+            device.upload_buffer(cmd_buffer, &cdf_host, &cdf_device);
+        });
+        device.destroy_buffer(cdf_host);
+        uploaded?;
+
+        Ok(Self {
+            image,
+            cdf: cdf_device,
+            width,
+            height,
+        })
+    }
+
+    fn destroy(&self, cleanup: &VkCleanup) {
+        cleanup.send(VkCleanupEvent::ImageView(self.image.view));
+        cleanup.send(VkCleanupEvent::Image(self.image.handle));
+        cleanup.send(VkCleanupEvent::Buffer(self.cdf.handle));
+    }
+}
+
+impl VulkanAsset for Image {
+    type ExtractedAsset = Image;
+    type PreparedAsset = EnvironmentMap;
+    type ExtractParam = ();
+
+    fn extract_asset(
+        &self,
+        _param: &mut bevy::ecs::system::SystemParamItem<Self::ExtractParam>,
+    ) -> Option<Self::ExtractedAsset> {
+        Some(self.clone())
+    }
+
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
+        let size = asset.texture_descriptor.size;
+        println!("Building environment cdf for a {}x{} skybox", size.width, size.height);
+        EnvironmentMap::new(device, &image_texels(&asset), size.width, size.height)
+    }
+
+    fn destroy_asset(asset: Self::PreparedAsset, cleanup: &VkCleanup) {
+        asset.destroy(cleanup);
+    }
+}
+
+/// Bound in place of the skybox while a fallback sky is shown, the shaders never read it.
+#[derive(Resource)]
+pub struct PlaceholderEnvironment(pub EnvironmentMap);
+
+impl PlaceholderEnvironment {
+    pub fn new(device: &RenderDevice) -> Self {
+        Self(EnvironmentMap::new(device, &[Vec4::ZERO], 1, 1).unwrap())
+    }
+}
+
+pub fn cleanup_placeholder_environment(placeholder: Res<PlaceholderEnvironment>, cleanup: Res<VkCleanup>) {
+    placeholder.0.destroy(&cleanup);
+}
+
+/// The sky a frame is traced with
+pub struct Sky<'a> {
+    pub environment: &'a EnvironmentMap,
+    pub config: &'a SkyConfig,
+    /// Set when the fallback replaces the environment map
+    pub fallback: Option<SkyFallback>,
+}
+
+/// The skybox once it is prepared. When it failed to load and a fallback is configured, the
+/// fallback over the placeholder. None while the skybox is still loading.
+pub fn resolve_sky<'a>(
+    config: &'a RenderConfig,
+    environments: &'a VulkanAssets<Image>,
+    placeholder: &'a PlaceholderEnvironment,
+    asset_server: &AssetServer,
+) -> Option<Sky<'a>> {
+    if let Some(environment) = environments.get(&config.skybox) {
+        return Some(Sky {
+            environment,
+            config: &config.sky,
+            fallback: None,
+        });
+    }
+
+    let fallback = config.sky.fallback?;
+    if asset_server.get_load_state(&config.skybox) != LoadState::Failed {
+        return None;
+    }
+
+    Some(Sky {
+        environment: &placeholder.0,
+        config: &config.sky,
+        fallback: Some(fallback),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A grid of random numbers in [0, 1)², offset from the texel edges
+    fn random_numbers(n: u32) -> impl Iterator<Item = Vec2> {
+        (0..n).flat_map(move |y| (0..n).map(move |x| (Vec2::new(x as f32, y as f32) + 0.37) / n as f32))
+    }
+
+    #[test]
+    fn uniform_map_has_uniform_pdf() {
+        let cdf = EnvironmentCdf::new(&[2.0; 8 * 4], 8, 4);
+        for r in random_numbers(16) {
+            let (uv, pdf) = cdf.sample(r);
+            assert!((pdf - 1.0).abs() < 1e-5, "pdf {} at {}", pdf, uv);
+            assert!((uv - r).abs().max_element() < 1e-5, "{} maps to {}", r, uv);
+        }
+    }
+
+    #[test]
+    fn empty_map_is_sampled_uniformly() {
+        let cdf = EnvironmentCdf::new(&[0.0; 4 * 4], 4, 4);
+        for r in random_numbers(8) {
+            assert!((cdf.sample(r).1 - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn single_bright_texel_is_always_picked() {
+        let (width, height) = (8, 4);
+        let mut weights = vec![0.0; width * height];
+        weights[2 * width + 5] = 10.0;
+        let cdf = EnvironmentCdf::new(&weights, width as u32, height as u32);
+
+        for r in random_numbers(16) {
+            let (uv, pdf) = cdf.sample(r);
+            let texel = (uv * Vec2::new(width as f32, height as f32)).floor();
+            assert_eq!(texel, Vec2::new(5.0, 2.0), "{} maps to {}", r, uv);
+            assert!((pdf - (width * height) as f32).abs() < 1e-3);
+        }
+        assert_eq!(cdf.pdf(Vec2::new(0.1, 0.1)), 0.0);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let (width, height) = (16, 8);
+        let weights = (0..width * height)
+            .map(|i| ((i * 7919) % 13) as f32 * 0.3)
+            .collect::<Vec<_>>();
+        let cdf = EnvironmentCdf::new(&weights, width, height);
+
+        let integral = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let uv = (Vec2::new(x as f32, y as f32) + 0.5) / Vec2::new(width as f32, height as f32);
+                cdf.pdf(uv) / (width * height) as f32
+            })
+            .sum::<f32>();
+        assert!((integral - 1.0).abs() < 1e-4, "integral {}", integral);
+    }
+
+    #[test]
+    fn sample_returns_its_pdf() {
+        let weights = (0..8 * 8).map(|i| (i % 5) as f32).collect::<Vec<_>>();
+        let cdf = EnvironmentCdf::new(&weights, 8, 8);
+        for r in random_numbers(16) {
+            let (uv, pdf) = cdf.sample(r);
+            assert!(pdf > 0.0, "{} lands on a texel without weight at {}", r, uv);
+            assert_eq!(pdf, cdf.pdf(uv));
+        }
+    }
+}
@@ -1,42 +1,316 @@
+use std::sync::{Arc, Mutex};
+
 use ash::vk;
 use bevy::{
-    asset::{AssetLoader, LoadedAsset},
+    asset::{AssetLoader, HandleId, LoadedAsset},
+    prelude::*,
     reflect::TypeUuid,
     utils::{HashMap, HashSet},
 };
 
 use crate::{
-    acceleration_structure::{allocate_acceleration_structure, TriangleBLAS, TriangleMaterial, Vertex},
+    acceleration_structure::{
+        allocate_acceleration_structure, EmissiveTriangle, MaterialTexture, TriangleBLAS, TriangleMaterial, Vertex,
+        ALPHA_MODE_BLEND, ALPHA_MODE_MASK, ALPHA_MODE_OPAQUE,
+    },
+    camera::GltfCamera,
+    lights::PunctualLight,
     render_buffer::{Buffer, BufferProvider},
-    render_device::RenderDevice,
+    render_device::{RenderDevice, SamplerInfo},
+    render_error::RenderResult,
     render_image::VkImage,
-    texture::{load_texture_from_bytes, padd_pixel_bytes_rgba_unorm},
+    texture::{format_features, ktx2_texture_data, load_texture, padd_pixel_bytes_rgba, ColorSpace, TextureData},
     vk_utils,
-    vulkan_assets::VulkanAsset,
+    vulkan_assets::{VulkanAsset, VulkanAssets},
     vulkan_cleanup::{VkCleanup, VkCleanupEvent},
 };
 
+/// A single mesh of a gltf file, loaded as the `Mesh{index}` sub-asset. Every entity holding
+/// the same handle shares one BLAS.
 #[derive(TypeUuid, Default, Clone)]
 #[uuid = "ddd211b2-ba53-47d2-a40b-15fd29d757c6"]
 pub struct GltfMesh {
-    pub document: Option<gltf::Document>,
-    pub buffers: Vec<gltf::buffer::Data>,
-    pub images: Vec<gltf::image::Data>,
+    pub document: Option<Arc<gltf::Document>>,
+    pub buffers: Arc<Vec<gltf::buffer::Data>>,
+    pub images: Arc<Vec<GltfImage>>,
+    /// Image index of every texture, the `KHR_texture_basisu` source takes precedence
+    pub texture_images: Arc<Vec<usize>>,
+    /// Shared by every mesh of the file
+    pub textures: Arc<GltfTextures>,
+    pub mesh_index: usize,
+}
+
+/// The uploaded images of one gltf file by image index and color space, so a texture used by
+/// several meshes takes one image and one bindless slot per sampler. An image referenced as both
+/// color and data is uploaded once for every color space.
+#[derive(Default)]
+pub struct GltfTextures(Mutex<HashMap<(usize, ColorSpace), VkImage>>);
+
+impl GltfTextures {
+    fn destroy(&self, cleanup: &VkCleanup) {
+        for (_, texture) in self.0.lock().unwrap().drain() {
+            cleanup.send(VkCleanupEvent::ImageView(texture.view));
+            cleanup.send(VkCleanupEvent::Image(texture.handle));
+        }
+    }
+}
+
+/// Owns the uploaded textures of every gltf file. The textures a mesh stops using, because it was
+/// hot reloaded or removed, are freed once no BLAS renders with them anymore.
+#[derive(Resource, Default)]
+pub struct GltfTextureOwner {
+    by_mesh: HashMap<HandleId, Arc<GltfTextures>>,
+    retired: Vec<Arc<GltfTextures>>,
+}
+
+pub fn free_unused_gltf_textures(
+    mut events: EventReader<AssetEvent<GltfMesh>>,
+    meshes: Res<Assets<GltfMesh>>,
+    blases: Res<VulkanAssets<GltfMesh>>,
+    mut owner: ResMut<GltfTextureOwner>,
+    cleanup: Res<VkCleanup>,
+) {
+    let owner = &mut *owner;
+    for event in events.iter() {
+        let previous = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                let Some(mesh) = meshes.get(handle) else {
+                    continue;
+                };
+                let previous = owner.by_mesh.insert(handle.id(), mesh.textures.clone());
+                previous.filter(|previous| !Arc::ptr_eq(previous, &mesh.textures))
+            }
+            AssetEvent::Removed { handle } => owner.by_mesh.remove(&handle.id()),
+        };
+        owner.retired.extend(previous);
+    }
+
+    let by_mesh = &owner.by_mesh;
+    owner.retired.retain(|textures| {
+        let in_use = by_mesh.values().any(|other| Arc::ptr_eq(other, textures))
+            || blases.items().any(|(_, blas)| Arc::ptr_eq(&blas.textures, textures));
+        if !in_use {
+            textures.destroy(&cleanup);
+        }
+        in_use
+    });
+}
+
+/// The textures of the files still loaded go at exit
+pub fn cleanup_gltf_textures(
+    meshes: Res<Assets<GltfMesh>>,
+    owner: Option<Res<GltfTextureOwner>>,
+    cleanup: Res<VkCleanup>,
+) {
+    for (_, mesh) in meshes.iter() {
+        mesh.textures.destroy(&cleanup);
+    }
+    if let Some(owner) = owner {
+        for textures in owner.by_mesh.values().chain(&owner.retired) {
+            textures.destroy(&cleanup);
+        }
+    }
+}
+
+/// Image data of a gltf file. KTX2 images stay encoded until they are uploaded.
+pub enum GltfImage {
+    Pixels(gltf::image::Data),
+    Ktx2(Vec<u8>),
 }
 
 impl GltfMesh {
-    pub fn single_mesh(&self) -> gltf::Mesh {
+    pub fn mesh(&self) -> gltf::Mesh {
         let document = self.document.as_ref().unwrap();
-        let scene = document.default_scene().unwrap();
-        let mut node = scene.nodes().next().unwrap();
-        while node.mesh().is_none() {
-            node = node.children().next().unwrap();
+        document.meshes().nth(self.mesh_index).unwrap()
+    }
+}
+
+/// The node hierarchy of the default scene of a gltf file, this is the asset a `.glb` path
+/// loads as. Put the handle on an entity to spawn the nodes as its children.
+#[derive(TypeUuid, Default, Clone)]
+#[uuid = "5d1f0a3c-7e42-4b8a-a6c9-2f91d3e8b047"]
+pub struct GltfScene {
+    pub nodes: Vec<GltfNode>,
+}
+
+#[derive(Clone)]
+pub struct GltfNode {
+    pub name: Option<String>,
+    pub transform: Transform,
+    pub mesh: Option<Handle<GltfMesh>>,
+    pub camera: Option<GltfCamera>,
+    pub light: Option<PunctualLight>,
+    pub children: Vec<GltfNode>,
+}
+
+impl GltfNode {
+    fn from_gltf(node: &gltf::Node, meshes: &[Handle<GltfMesh>]) -> Self {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        Self {
+            name: node.name().map(|name| name.to_string()),
+            transform: Transform {
+                translation: Vec3::from(translation),
+                rotation: Quat::from_array(rotation),
+                scale: Vec3::from(scale),
+            },
+            mesh: node.mesh().map(|mesh| meshes[mesh.index()].clone()),
+            camera: node.camera().and_then(|camera| GltfCamera::from_gltf(&camera)),
+            light: node.light().map(|light| PunctualLight::from_gltf(&light)),
+            children: node.children().map(|child| Self::from_gltf(&child, meshes)).collect(),
         }
+    }
+}
+
+/// Added once the nodes of an entity's `Handle<GltfScene>` have been spawned as its children.
+#[derive(Component)]
+pub struct GltfSceneSpawned;
+
+pub struct GltfScenePlugin;
+
+impl Plugin for GltfScenePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<GltfScene>();
+        app.add_system(spawn_gltf_scenes);
+    }
+}
+
+fn spawn_gltf_scenes(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<GltfScene>>,
+    scenes: Res<Assets<GltfScene>>,
+    pending: Query<(Entity, &Handle<GltfScene>), Without<GltfSceneSpawned>>,
+    spawned: Query<(Entity, &Handle<GltfScene>), With<GltfSceneSpawned>>,
+) {
+    // respawn the hierarchy when the file is hot reloaded
+    for event in events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            for (entity, scene) in spawned.iter() {
+                if scene == handle {
+                    commands.entity(entity).remove::<GltfSceneSpawned>();
+                    commands.entity(entity).despawn_descendants();
+                }
+            }
+        }
+    }
+
+    for (entity, handle) in pending.iter() {
+        let Some(scene) = scenes.get(handle) else {
+            continue;
+        };
+
+        commands
+            .entity(entity)
+            .insert(GltfSceneSpawned)
+            .with_children(|parent| {
+                for node in &scene.nodes {
+                    spawn_gltf_node(parent, node);
+                }
+            });
+    }
+}
+
+fn spawn_gltf_node(parent: &mut ChildBuilder, node: &GltfNode) {
+    let mut entity = parent.spawn(TransformBundle::from_transform(node.transform));
+    if let Some(name) = &node.name {
+        entity.insert(Name::new(name.clone()));
+    }
+    if let Some(mesh) = &node.mesh {
+        entity.insert(mesh.clone());
+    }
+    if let Some(camera) = &node.camera {
+        entity.insert(camera.clone());
+    }
+    if let Some(light) = &node.light {
+        entity.insert(light.clone());
+    }
+
+    entity.with_children(|children| {
+        for child in &node.children {
+            spawn_gltf_node(children, child);
+        }
+    });
+}
+
+/// `gltf::import_slice` only decodes PNG and JPEG, so files with KTX2 images are imported here
+/// instead. Only self contained `.glb` files are supported this way.
+#[allow(clippy::type_complexity)]
+fn import_slice_with_ktx2(
+    bytes: &[u8],
+) -> Result<(gltf::Document, Vec<gltf::buffer::Data>, Vec<GltfImage>), bevy::asset::Error> {
+    let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(bytes)?;
+    let external = || bevy::asset::Error::msg("KTX2 images are only supported in self contained .glb files");
+
+    let buffers = document
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Bin => blob.take().map(gltf::buffer::Data).ok_or_else(external),
+            gltf::buffer::Source::Uri(_) => Err(external()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let images = document
+        .images()
+        .map(|gltf_image| {
+            let gltf::image::Source::View { view, mime_type } = gltf_image.source() else {
+                return Err(external());
+            };
+            let encoded = &buffers[view.buffer().index()][view.offset()..view.offset() + view.length()];
+            if mime_type == "image/ktx2" {
+                return Ok(GltfImage::Ktx2(encoded.to_vec()));
+            }
+            Ok(GltfImage::Pixels(gltf_image_data(image::load_from_memory(encoded)?)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((document, buffers, images))
+}
 
-        return node.mesh().unwrap();
+/// Mirrors what `gltf::import_slice` does with a decoded image.
+fn gltf_image_data(image: image::DynamicImage) -> gltf::image::Data {
+    use gltf::image::Format;
+    use image::DynamicImage;
+
+    let format = match &image {
+        DynamicImage::ImageLuma8(_) => Format::R8,
+        DynamicImage::ImageLumaA8(_) => Format::R8G8,
+        DynamicImage::ImageRgb8(_) => Format::R8G8B8,
+        DynamicImage::ImageRgba8(_) => Format::R8G8B8A8,
+        DynamicImage::ImageLuma16(_) => Format::R16,
+        DynamicImage::ImageLumaA16(_) => Format::R16G16,
+        DynamicImage::ImageRgb16(_) => Format::R16G16B16,
+        DynamicImage::ImageRgba16(_) => Format::R16G16B16A16,
+        DynamicImage::ImageRgb32F(_) => Format::R32G32B32FLOAT,
+        DynamicImage::ImageRgba32F(_) => Format::R32G32B32A32FLOAT,
+        _ => return gltf_image_data(DynamicImage::ImageRgba8(image.into_rgba8())),
+    };
+
+    gltf::image::Data {
+        format,
+        width: image.width(),
+        height: image.height(),
+        pixels: image.into_bytes(),
     }
 }
 
+/// The gltf crate does not know `KHR_texture_basisu`, so its source is read from the raw json.
+/// The core `source` of a texture is the fallback for viewers without KTX2 support.
+fn texture_images(bytes: &[u8], document: &gltf::Document) -> Vec<usize> {
+    let json = match gltf::Glb::from_slice(bytes) {
+        Ok(glb) => serde_json::from_slice::<serde_json::Value>(&glb.json),
+        Err(_) => serde_json::from_slice::<serde_json::Value>(bytes),
+    }
+    .unwrap_or_default();
+
+    document
+        .textures()
+        .map(|texture| {
+            json["textures"][texture.index()]["extensions"]["KHR_texture_basisu"]["source"]
+                .as_u64()
+                .map_or(texture.source().index(), |source| source as usize)
+        })
+        .collect()
+}
+
 #[derive(Default)]
 pub struct GltfLoader;
 
@@ -47,26 +321,67 @@ impl AssetLoader for GltfLoader {
         load_context: &'a mut bevy::asset::LoadContext,
     ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
         Box::pin(async move {
-            let (document, buffers, images) = gltf::import_slice(bytes)?;
-
-            let asset = GltfMesh {
-                document: Some(document),
-                buffers,
-                images,
+            let (document, buffers, images) = match gltf::import_slice(bytes) {
+                Ok((document, buffers, images)) => {
+                    (document, buffers, images.into_iter().map(GltfImage::Pixels).collect())
+                }
+                Err(gltf::Error::UnsupportedImageEncoding) => import_slice_with_ktx2(bytes)?,
+                Err(e) => return Err(e.into()),
             };
+            let texture_images = Arc::new(texture_images(bytes, &document));
+            let textures = Arc::new(GltfTextures::default());
+            let buffers = Arc::new(buffers);
+            let images = Arc::new(images);
 
             println!(
                 "GLTF {} has {} chunks of buffer data",
                 load_context.path().display(),
-                asset.buffers.len()
+                buffers.len()
             );
             println!(
                 "GLTF {} has {} chunks of image data",
                 load_context.path().display(),
-                asset.images.len()
+                images.len()
             );
 
-            load_context.set_default_asset(LoadedAsset::new(asset));
+            let document = Arc::new(document);
+            let meshes = document
+                .meshes()
+                .map(|mesh| {
+                    load_context.set_labeled_asset(
+                        &format!("Mesh{}", mesh.index()),
+                        LoadedAsset::new(GltfMesh {
+                            document: Some(document.clone()),
+                            buffers: buffers.clone(),
+                            images: images.clone(),
+                            texture_images: texture_images.clone(),
+                            textures: textures.clone(),
+                            mesh_index: mesh.index(),
+                        }),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let Some(scene) = document.default_scene().or_else(|| document.scenes().next()) else {
+                return Err(bevy::asset::Error::msg(format!(
+                    "GLTF {} does not contain a scene",
+                    load_context.path().display()
+                )));
+            };
+
+            let nodes = scene
+                .nodes()
+                .map(|node| GltfNode::from_gltf(&node, &meshes))
+                .collect::<Vec<_>>();
+
+            println!(
+                "GLTF {} has {} meshes and {} root nodes",
+                load_context.path().display(),
+                meshes.len(),
+                nodes.len()
+            );
+
+            load_context.set_default_asset(LoadedAsset::new(GltfScene { nodes }));
             Ok(())
         })
     }
@@ -76,11 +391,11 @@ impl AssetLoader for GltfLoader {
     }
 }
 
-struct GeometryDescr {
-    first_vertex: usize,
-    vertex_count: usize,
-    first_index: usize,
-    index_count: usize,
+pub struct GeometryDescr {
+    pub first_vertex: usize,
+    pub vertex_count: usize,
+    pub first_index: usize,
+    pub index_count: usize,
 }
 
 impl VulkanAsset for GltfMesh {
@@ -95,19 +410,56 @@ impl VulkanAsset for GltfMesh {
         Some(self.clone())
     }
 
-    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset {
-        let mesh = asset.single_mesh();
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
+        let mesh = asset.mesh();
         let (vertex_count, index_count) = extract_mesh_sizes(&mesh);
         let as_propeties = vk_utils::get_acceleration_structure_properties(device);
 
+        let mut texture_error = None;
+
+        let mut load_cached_texture = |texture: &gltf::Texture, color_space: ColorSpace| {
+            let sampler = match device.get_sampler(gltf_sampler_info(&texture.sampler())) {
+                Ok(sampler) => sampler,
+                Err(e) => {
+                    texture_error = Some(e);
+                    return 0xFFFFFFFF;
+                }
+            };
+            let key = (asset.texture_images[texture.index()], color_space);
+            let mut loaded_textures = asset.textures.0.lock().unwrap();
+            if let Some(res) = loaded_textures.get(&key) {
+                return device.get_texture_descriptor_index(res.view, sampler);
+            }
+
+            let image = match load_gltf_texture(&device, &asset, key.0, color_space) {
+                Ok(Some(image)) => image,
+                Ok(None) => return 0xFFFFFFFF,
+                Err(e) => {
+                    texture_error = Some(e);
+                    return 0xFFFFFFFF;
+                }
+            };
+
+            loaded_textures.insert(key, image);
+            return device.get_texture_descriptor_index(loaded_textures.get(&key).unwrap().view, sampler);
+        };
+
+        let materials = mesh
+            .primitives()
+            .map(|primitive| extract_material(&primitive.material(), &mut load_cached_texture))
+            .collect::<Vec<_>>();
+        if let Some(e) = texture_error {
+            return Err(e);
+        }
+
         let mut vertex_buffer_host: Buffer<Vertex> = device.create_host_buffer(
             vertex_count as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
-        );
+        )?;
         let mut index_buffer_host: Buffer<u32> = device.create_host_buffer(
             index_count as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
-        );
+        )?;
 
         let mut vertex_buffer_view = device.map_buffer(&mut vertex_buffer_host);
         let mut index_buffer_view = device.map_buffer(&mut index_buffer_host);
@@ -115,7 +467,7 @@ impl VulkanAsset for GltfMesh {
         let mut geometry_to_index_offset_host: Buffer<u32> = device.create_host_buffer(
             mesh.primitives().len() as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
-        );
+        )?;
 
         let geometries_descrs = extract_mesh_data(
             &asset,
@@ -130,14 +482,22 @@ impl VulkanAsset for GltfMesh {
             geometry_to_index_offset_view[i] = g.first_index as u32;
         }
 
+        let emissive_triangles = emissive_triangles(
+            vertex_buffer_view.as_slice_mut(),
+            index_buffer_view.as_slice_mut(),
+            &geometries_descrs,
+            &materials,
+        );
+
         drop(index_buffer_view);
         drop(vertex_buffer_view);
 
         println!(
-            "Building BLAS with {} vertices and {} indices, divided over {} geometries",
+            "Building BLAS with {} vertices and {} indices, divided over {} geometries, {} emissive triangles",
             vertex_count,
             index_count,
-            geometries_descrs.len()
+            geometries_descrs.len(),
+            emissive_triangles.len()
         );
         println!("Uploading data to GPU");
 
@@ -146,21 +506,21 @@ impl VulkanAsset for GltfMesh {
             vk::BufferUsageFlags::STORAGE_BUFFER
                 | vk::BufferUsageFlags::TRANSFER_DST
                 | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
-        );
+        )?;
 
         let index_buffer_device: Buffer<u32> = device.create_device_buffer(
             index_count as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER
                 | vk::BufferUsageFlags::TRANSFER_DST
                 | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
-        );
+        )?;
 
         let geometry_to_index_offset_device: Buffer<u32> = device.create_device_buffer(
             mesh.primitives().len() as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
-        );
+        )?;
 
-        device.run_asset_commands(|cmd_buffer| {
+        let uploaded = device.run_asset_commands(|cmd_buffer| {
             device.upload_buffer(cmd_buffer, &mut vertex_buffer_host, &vertex_buffer_device);
             device.upload_buffer(cmd_buffer, &mut index_buffer_host, &index_buffer_device);
             device.upload_buffer(
@@ -173,12 +533,14 @@ impl VulkanAsset for GltfMesh {
         device.destroy_buffer(vertex_buffer_host);
         device.destroy_buffer(index_buffer_host);
         device.destroy_buffer(geometry_to_index_offset_host);
+        uploaded?;
 
         let geometry_infos = geometries_descrs
             .iter()
-            .map(|geometry| {
+            .zip(mesh.primitives())
+            .map(|(geometry, primitive)| {
                 vk::AccelerationStructureGeometryKHR::builder()
-                    .flags(vk::GeometryFlagsKHR::OPAQUE)
+                    .flags(geometry_flags(&primitive.material()))
                     .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
                     .geometry(vk::AccelerationStructureGeometryDataKHR {
                         triangles: vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
@@ -221,13 +583,13 @@ impl VulkanAsset for GltfMesh {
         };
 
         let mut acceleration_structure =
-            allocate_acceleration_structure(&device, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL, &geometry_sizes);
+            allocate_acceleration_structure(&device, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL, &geometry_sizes)?;
 
         let scratch_alignment = as_propeties.min_acceleration_structure_scratch_offset_alignment as u64;
         let scratch_buffer: Buffer<u8> = device.create_device_buffer(
             geometry_sizes.build_scratch_size + scratch_alignment,
             vk::BufferUsageFlags::STORAGE_BUFFER,
-        );
+        )?;
 
         let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
             .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
@@ -258,47 +620,45 @@ impl VulkanAsset for GltfMesh {
 
         let singleton_build_ranges = &[build_ranges.as_slice()];
 
-        unsafe {
-            device.run_asset_commands(&|cmd_buffer| {
-                device.exts.rt_acc_struct.cmd_build_acceleration_structures(
-                    cmd_buffer,
-                    std::slice::from_ref(&build_geometry_info),
-                    singleton_build_ranges,
-                );
-            })
-        }
+        let built = device.run_asset_commands(&|cmd_buffer| unsafe {
+            device.exts.rt_acc_struct.cmd_build_acceleration_structures(
+                cmd_buffer,
+                std::slice::from_ref(&build_geometry_info),
+                singleton_build_ranges,
+            );
+        });
 
         device.destroy_buffer(scratch_buffer);
+        built?;
 
         let query_pool_info = vk::QueryPoolCreateInfo::builder()
             .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
             .query_count(1);
 
-        let query_pool = unsafe { device.device.create_query_pool(&query_pool_info, None) }.unwrap();
-        unsafe {
-            device.run_asset_commands(&|cmd_buffer| {
-                device.device.cmd_reset_query_pool(cmd_buffer, query_pool, 0, 1);
-            })
-        }
+        let query_pool = unsafe { device.device.create_query_pool(&query_pool_info, None) }?;
+        device.run_asset_commands(&|cmd_buffer| unsafe {
+            device.device.cmd_reset_query_pool(cmd_buffer, query_pool, 0, 1);
+        })?;
 
-        unsafe {
-            device.run_asset_commands(&|cmd_buffer| {
-                device.exts.rt_acc_struct.cmd_write_acceleration_structures_properties(
-                    cmd_buffer,
-                    std::slice::from_ref(&acceleration_structure.handle),
-                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
-                    query_pool,
-                    0,
-                );
-            })
-        }
+        device.run_asset_commands(&|cmd_buffer| unsafe {
+            device.exts.rt_acc_struct.cmd_write_acceleration_structures_properties(
+                cmd_buffer,
+                std::slice::from_ref(&acceleration_structure.handle),
+                vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                query_pool,
+                0,
+            );
+        })?;
 
         let mut compacted_sizes = [0];
         unsafe {
-            device
-                .device
-                .get_query_pool_results::<u64>(query_pool, 0, 1, &mut compacted_sizes, vk::QueryResultFlags::WAIT)
-                .unwrap();
+            device.device.get_query_pool_results::<u64>(
+                query_pool,
+                0,
+                1,
+                &mut compacted_sizes,
+                vk::QueryResultFlags::WAIT,
+            )?;
         };
 
         println!(
@@ -311,7 +671,7 @@ impl VulkanAsset for GltfMesh {
         let compacted_buffer = device.create_device_buffer::<u8>(
             compacted_sizes[0],
             vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
-        );
+        )?;
 
         let compacted_as_info = vk::AccelerationStructureCreateInfoKHR::builder()
             .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
@@ -324,22 +684,19 @@ impl VulkanAsset for GltfMesh {
                 .exts
                 .rt_acc_struct
                 .create_acceleration_structure(&compacted_as_info, None)
-        }
-        .unwrap();
-
-        unsafe {
-            device.run_asset_commands(&|cmd_buffer| {
-                let copy_info = vk::CopyAccelerationStructureInfoKHR::builder()
-                    .src(acceleration_structure.handle)
-                    .dst(compacted_as)
-                    .mode(vk::CopyAccelerationStructureModeKHR::COMPACT)
-                    .build();
-                device
-                    .exts
-                    .rt_acc_struct
-                    .cmd_copy_acceleration_structure(cmd_buffer, &copy_info);
-            })
-        }
+        }?;
+
+        device.run_asset_commands(&|cmd_buffer| unsafe {
+            let copy_info = vk::CopyAccelerationStructureInfoKHR::builder()
+                .src(acceleration_structure.handle)
+                .dst(compacted_as)
+                .mode(vk::CopyAccelerationStructureModeKHR::COMPACT)
+                .build();
+            device
+                .exts
+                .rt_acc_struct
+                .cmd_copy_acceleration_structure(cmd_buffer, &copy_info);
+        })?;
 
         unsafe {
             device
@@ -359,70 +716,25 @@ impl VulkanAsset for GltfMesh {
             )
         };
 
-        let mut geometry_to_material_host = device
-            .create_host_buffer::<TriangleMaterial>(geometries_descrs.len() as u64, vk::BufferUsageFlags::TRANSFER_SRC);
-        let mut geometry_to_material_host_view = device.map_buffer(&mut geometry_to_material_host);
-        let mut loaded_textures: HashMap<usize, VkImage> = HashMap::new();
-
-        let mut load_cached_texture = |image_idx: usize| {
-            if let Some(res) = loaded_textures.get(&image_idx) {
-                return device.get_texture_descriptor_index(res.view);
-            }
-
-            let Some(image) = load_gltf_texture(&device, &asset, image_idx) else {
-                return 0xFFFFFFFF;
-            };
-
-            loaded_textures.insert(image_idx, image);
-            return device.get_texture_descriptor_index(loaded_textures.get(&image_idx).unwrap().view);
-        };
-
-        for (geometry_id, primitive) in mesh.primitives().enumerate() {
-            geometry_to_material_host_view[geometry_id] = TriangleMaterial {
-                diffuse_factor: primitive.material().pbr_metallic_roughness().base_color_factor(),
-                diffuse_texture: 0xFFFFFFFF,
-                normal_texture: 0xFFFFFFFF,
-                metallic_factor: primitive.material().pbr_metallic_roughness().metallic_factor(),
-                roughness_factor: primitive.material().pbr_metallic_roughness().roughness_factor(),
-                metallic_roughness_texture: 0xFFFFFFFF,
-                emmisive_factor: primitive.material().emissive_factor(),
-                emmisive_texture: 0xFFFFFFFF,
-            };
-
-            if let Some(diffuse_texture) = primitive.material().pbr_metallic_roughness().base_color_texture() {
-                geometry_to_material_host_view[geometry_id].diffuse_texture =
-                    load_cached_texture(diffuse_texture.texture().source().index());
-            }
-
-            if let Some(emmisive_texture) = primitive.material().emissive_texture() {
-                geometry_to_material_host_view[geometry_id].emmisive_texture =
-                    load_cached_texture(emmisive_texture.texture().source().index());
-            }
-
-            if let Some(normal_texture) = primitive.material().normal_texture() {
-                geometry_to_material_host_view[geometry_id].normal_texture =
-                    load_cached_texture(normal_texture.texture().source().index());
-            }
-
-            if let Some(metallic_rougness_texture) = primitive
-                .material()
-                .pbr_metallic_roughness()
-                .metallic_roughness_texture()
-            {
-                geometry_to_material_host_view[geometry_id].metallic_roughness_texture =
-                    load_cached_texture(metallic_rougness_texture.texture().source().index());
-            }
-        }
+        let mut geometry_to_material_host = device.create_host_buffer::<TriangleMaterial>(
+            geometries_descrs.len() as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        )?;
+        device
+            .map_buffer(&mut geometry_to_material_host)
+            .as_slice_mut()
+            .copy_from_slice(&materials);
 
         let geometry_to_material_device = device.create_device_buffer::<TriangleMaterial>(
             geometry_to_material_host.nr_elements,
             vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER,
-        );
+        )?;
 
-        device.run_asset_commands(|cmd_buffer| {
+        let uploaded = device.run_asset_commands(|cmd_buffer| {
             device.upload_buffer(cmd_buffer, &geometry_to_material_host, &geometry_to_material_device);
         });
         device.destroy_buffer(geometry_to_material_host);
+        uploaded?;
 
         let blas = TriangleBLAS {
             vertex_buffer: vertex_buffer_device,
@@ -430,17 +742,15 @@ impl VulkanAsset for GltfMesh {
             geometry_to_index_offset: geometry_to_index_offset_device,
             geometry_to_material: geometry_to_material_device,
             acceleration_structure,
-            textures: loaded_textures.drain().map(|(_, v)| v).collect(),
+            textures: asset.textures.clone(),
+            emissive_triangles,
         };
 
-        blas
+        Ok(blas)
     }
 
     fn destroy_asset(asset: Self::PreparedAsset, cleanup: &VkCleanup) {
-        for texture in asset.textures {
-            cleanup.send(VkCleanupEvent::ImageView(texture.view));
-            cleanup.send(VkCleanupEvent::Image(texture.handle));
-        }
+        // the textures are shared with the other meshes of the file, GltfTextureOwner frees them
         cleanup.send(VkCleanupEvent::Buffer(asset.vertex_buffer.handle));
         cleanup.send(VkCleanupEvent::Buffer(asset.index_buffer.handle));
         cleanup.send(VkCleanupEvent::Buffer(asset.geometry_to_index_offset.handle));
@@ -452,7 +762,7 @@ impl VulkanAsset for GltfMesh {
     }
 }
 
-fn extract_mesh_sizes(mesh: &gltf::Mesh) -> (usize, usize) {
+pub fn extract_mesh_sizes(mesh: &gltf::Mesh) -> (usize, usize) {
     let mut vertex_count = 0;
     let mut index_count = 0;
     for primitive in mesh.primitives() {
@@ -467,8 +777,12 @@ fn extract_mesh_sizes(mesh: &gltf::Mesh) -> (usize, usize) {
     (vertex_count, index_count)
 }
 
-fn extract_mesh_data(gltf: &GltfMesh, vertex_buffer: &mut [Vertex], index_buffer: &mut [u32]) -> Vec<GeometryDescr> {
-    let mesh = gltf.single_mesh();
+pub fn extract_mesh_data(
+    gltf: &GltfMesh,
+    vertex_buffer: &mut [Vertex],
+    index_buffer: &mut [u32],
+) -> Vec<GeometryDescr> {
+    let mesh = gltf.mesh();
     let mut geometries = Vec::new();
     let mut vertex_buffer_head = 0;
     let mut index_buffer_head = 0;
@@ -527,6 +841,12 @@ fn extract_mesh_data(gltf: &GltfMesh, vertex_buffer: &mut [Vertex], index_buffer
             }
         }
 
+        if let Some(uv_reader) = reader.read_tex_coords(1).map(|r| r.into_f32()) {
+            for (i, uv) in uv_reader.enumerate() {
+                vertex_buffer[geometry.first_vertex + i].uv1 = uv;
+            }
+        }
+
         let index_reader = reader.read_indices().unwrap().into_u32();
         assert!(index_reader.len() == geometry.index_count);
         assert!(geometry.index_count % 3 == 0);
@@ -543,25 +863,362 @@ fn extract_mesh_data(gltf: &GltfMesh, vertex_buffer: &mut [Vertex], index_buffer
     geometries
 }
 
-fn load_gltf_texture(device: &RenderDevice, asset: &GltfMesh, image_idx: usize) -> Option<VkImage> {
-    let image = &asset.images[image_idx];
-    let (bytes, format) = match image.format {
-        gltf::image::Format::R8G8B8A8 => (image.pixels.clone(), vk::Format::R8G8B8A8_UNORM),
-        gltf::image::Format::R8G8B8 => (
-            padd_pixel_bytes_rgba_unorm(&image.pixels, 3, image.width as usize, image.height as usize),
-            vk::Format::R8G8B8A8_UNORM,
-        ),
-        _ => {
-            println!("WARNING: Unsupported texture format {:?}, ignoring...", image.format);
-            return None;
+/// Collects the triangles of every geometry whose material emits light, these become the light
+/// list for next event estimation.
+pub fn emissive_triangles(
+    vertices: &[Vertex],
+    indices: &[u32],
+    geometries: &[GeometryDescr],
+    materials: &[TriangleMaterial],
+) -> Vec<EmissiveTriangle> {
+    let mut triangles = Vec::new();
+    for (geometry, material) in geometries.iter().zip(materials) {
+        if material.emmisive_factor.iter().all(|&c| c <= 0.0) {
+            continue;
+        }
+
+        let texture = &material.emmisive_texture;
+        for triangle in indices[geometry.first_index..geometry.first_index + geometry.index_count].chunks_exact(3) {
+            let [v0, v1, v2] = [0, 1, 2].map(|i| &vertices[triangle[i] as usize]);
+            triangles.push(EmissiveTriangle {
+                positions: [v0.pos, v1.pos, v2.pos],
+                uvs: [v0, v1, v2].map(|v| texture.uv(Vec2::from(v.uv), Vec2::from(v.uv1)).to_array()),
+                emission: material.emmisive_factor,
+                texture: texture.index,
+            });
+        }
+    }
+    triangles
+}
+
+/// Builds the material of a primitive, `load_texture` maps a gltf texture (image and sampler) to
+/// the index the hit shader uses to look it up (0xFFFFFFFF when it could not be loaded). Occlusion
+/// textures are left out, baked ambient occlusion would darken what the paths already occlude.
+pub fn extract_material(
+    material: &gltf::Material,
+    mut load_texture: impl FnMut(&gltf::Texture, ColorSpace) -> u32,
+) -> TriangleMaterial {
+    let pbr = material.pbr_metallic_roughness();
+    let mut ret = TriangleMaterial {
+        diffuse_factor: pbr.base_color_factor(),
+        diffuse_texture: MaterialTexture::NONE,
+        normal_texture: MaterialTexture::NONE,
+        metallic_factor: pbr.metallic_factor(),
+        roughness_factor: pbr.roughness_factor(),
+        metallic_roughness_texture: MaterialTexture::NONE,
+        emmisive_factor: material.emissive_factor(),
+        emmisive_texture: MaterialTexture::NONE,
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => ALPHA_MODE_OPAQUE,
+            gltf::material::AlphaMode::Mask => ALPHA_MODE_MASK,
+            gltf::material::AlphaMode::Blend => ALPHA_MODE_BLEND,
+        },
+        alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+        transmission_factor: 0.0,
+        transmission_texture: MaterialTexture::NONE,
+        ior: material.ior().unwrap_or(1.5),
+        absorption: [0.0; 3],
+    };
+
+    if let Some(diffuse_texture) = pbr.base_color_texture() {
+        ret.diffuse_texture = material_texture(&diffuse_texture, ColorSpace::Srgb, &mut load_texture);
+    }
+
+    if let Some(emmisive_texture) = material.emissive_texture() {
+        ret.emmisive_texture = material_texture(&emmisive_texture, ColorSpace::Srgb, &mut load_texture);
+    }
+
+    // gltf only exposes KHR_texture_transform on regular texture infos, not on normal textures
+    if let Some(normal_texture) = material.normal_texture() {
+        ret.normal_texture = MaterialTexture {
+            index: load_texture(&normal_texture.texture(), ColorSpace::Linear),
+            uv_set: uv_set(normal_texture.tex_coord()),
+            ..MaterialTexture::NONE
+        };
+    }
+
+    if let Some(metallic_rougness_texture) = pbr.metallic_roughness_texture() {
+        ret.metallic_roughness_texture =
+            material_texture(&metallic_rougness_texture, ColorSpace::Linear, &mut load_texture);
+    }
+
+    if let Some(transmission) = material.transmission() {
+        ret.transmission_factor = transmission.transmission_factor();
+        if let Some(transmission_texture) = transmission.transmission_texture() {
+            ret.transmission_texture = material_texture(&transmission_texture, ColorSpace::Linear, &mut load_texture);
+        }
+    }
+
+    // thin walled materials (zero thickness) have no interior, so there is nothing to absorb light.
+    // The thickness itself is not needed, the path tracer measures the distance travelled inside.
+    if let Some(volume) = material.volume() {
+        if volume.thickness_factor() > 0.0 && volume.attenuation_distance().is_finite() {
+            let distance = volume.attenuation_distance();
+            ret.absorption = volume.attenuation_color().map(|c| -c.max(0.0001).ln() / distance);
+        }
+    }
+
+    ret
+}
+
+fn material_texture(
+    info: &gltf::texture::Info,
+    color_space: ColorSpace,
+    load_texture: &mut impl FnMut(&gltf::Texture, ColorSpace) -> u32,
+) -> MaterialTexture {
+    let mut ret = MaterialTexture {
+        index: load_texture(&info.texture(), color_space),
+        uv_set: uv_set(info.tex_coord()),
+        ..MaterialTexture::NONE
+    };
+
+    // uv' = translation * rotation * scale * uv, as specified by KHR_texture_transform
+    if let Some(transform) = info.texture_transform() {
+        let [offset_x, offset_y] = transform.offset();
+        let [scale_x, scale_y] = transform.scale();
+        let (sin, cos) = transform.rotation().sin_cos();
+        ret.transform = [
+            [scale_x * cos, -scale_x * sin],
+            [scale_y * sin, scale_y * cos],
+            [offset_x, offset_y],
+        ];
+
+        if let Some(tex_coord) = transform.tex_coord() {
+            ret.uv_set = uv_set(tex_coord);
         }
+    }
+
+    ret
+}
+
+fn uv_set(tex_coord: u32) -> u32 {
+    if tex_coord > 1 {
+        println!("WARNING: TEXCOORD_{} is not supported, using TEXCOORD_1", tex_coord);
+    }
+    tex_coord.min(1)
+}
+
+/// Maps a gltf sampler to its Vulkan counterpart, unspecified filters default to linear.
+pub fn gltf_sampler_info(sampler: &gltf::texture::Sampler) -> SamplerInfo {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    };
+
+    // the filters without mipmaps become nearest mipmapping clamped to the first level
+    let (min_filter, mipmap_mode, max_lod) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST, 0.25),
+        Some(MinFilter::Linear) => (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST, 0.25),
+        Some(MinFilter::NearestMipmapNearest) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST, vk::LOD_CLAMP_NONE)
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::LINEAR, vk::LOD_CLAMP_NONE)
+        }
+        Some(MinFilter::LinearMipmapNearest) => {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST, vk::LOD_CLAMP_NONE)
+        }
+        Some(MinFilter::LinearMipmapLinear) | None => {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR, vk::LOD_CLAMP_NONE)
+        }
+    };
+
+    SamplerInfo {
+        mag_filter: match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+            Some(MagFilter::Linear) | None => vk::Filter::LINEAR,
+        },
+        min_filter,
+        mipmap_mode,
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        max_lod,
+    }
+}
+
+/// Opaque geometry skips the any-hit shader entirely, alpha tested and blended geometry needs it
+/// to run exactly once per primitive so stochastic transparency is not applied twice.
+fn geometry_flags(material: &gltf::Material) -> vk::GeometryFlagsKHR {
+    match material.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => vk::GeometryFlagsKHR::OPAQUE,
+        gltf::material::AlphaMode::Mask | gltf::material::AlphaMode::Blend => {
+            vk::GeometryFlagsKHR::NO_DUPLICATE_ANY_HIT_INVOCATION
+        }
+    }
+}
+
+/// Returns the texels of a gltf image as normalized RGBA, single and dual channel images are
+/// expanded the same way the swizzle in `gltf_texture_data` does on the GPU. sRGB data is returned
+/// still encoded.
+pub fn gltf_image_texels(image: &gltf::image::Data) -> Vec<Vec4> {
+    use gltf::image::Format;
+
+    let (channels, channel_bytes) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
     };
 
-    Some(load_texture_from_bytes(
-        device,
+    image
+        .pixels
+        .chunks_exact(channels * channel_bytes)
+        .map(|pixel| {
+            let c = pixel
+                .chunks_exact(channel_bytes)
+                .map(|bytes| match bytes {
+                    [x] => *x as f32 / 255.0,
+                    [a, b] => u16::from_ne_bytes([*a, *b]) as f32 / 65535.0,
+                    _ => f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                })
+                .collect::<Vec<_>>();
+            match channels {
+                1 => Vec4::new(c[0], c[0], c[0], 1.0),
+                2 => Vec4::new(c[0], c[0], c[0], c[1]),
+                3 => Vec4::new(c[0], c[1], c[2], 1.0),
+                _ => Vec4::from_slice(&c),
+            }
+        })
+        .collect()
+}
+
+fn rgba8_bytes(texels: &[Vec4]) -> Vec<u8> {
+    texels
+        .iter()
+        .flat_map(|texel| texel.to_array().map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+        .collect()
+}
+
+/// Picks the format a gltf image is uploaded in. RGB images get an alpha channel since three
+/// channel formats are rarely sampleable, single and dual channel images read as grayscale.
+/// sRGB data uses the `_SRGB` formats, which only exist for 8 bit channels, so deeper images are
+/// decoded here instead.
+fn gltf_texture_data(image: &gltf::image::Data, color_space: ColorSpace) -> TextureData {
+    use gltf::image::Format;
+    use vk::ComponentSwizzle as Swizzle;
+
+    let gray = |a| vk::ComponentMapping {
+        r: Swizzle::R,
+        g: Swizzle::R,
+        b: Swizzle::R,
+        a,
+    };
+    let srgb = color_space == ColorSpace::Srgb;
+    let rgba8 = if srgb {
+        vk::Format::R8G8B8A8_SRGB
+    } else {
+        vk::Format::R8G8B8A8_UNORM
+    };
+
+    let (format, components, pixels) = match image.format {
+        Format::R8 if srgb => (vk::Format::R8_SRGB, gray(Swizzle::ONE), image.pixels.clone()),
+        Format::R8 => (vk::Format::R8_UNORM, gray(Swizzle::ONE), image.pixels.clone()),
+        // R8G8_SRGB would decode the alpha channel as well
+        Format::R8G8 if srgb => (rgba8, Default::default(), rgba8_bytes(&gltf_image_texels(image))),
+        Format::R8G8 => (vk::Format::R8G8_UNORM, gray(Swizzle::G), image.pixels.clone()),
+        Format::R8G8B8 => (rgba8, Default::default(), padd_pixel_bytes_rgba(&image.pixels, &[0xFF])),
+        Format::R8G8B8A8 => (rgba8, Default::default(), image.pixels.clone()),
+        _ if srgb => (
+            vk::Format::R16G16B16A16_UNORM,
+            Default::default(),
+            gltf_image_texels(image)
+                .into_iter()
+                .flat_map(|texel| {
+                    color_space
+                        .decode(texel)
+                        .to_array()
+                        .map(|c| (c.clamp(0.0, 1.0) * 65535.0).round() as u16)
+                })
+                .flat_map(u16::to_ne_bytes)
+                .collect(),
+        ),
+        Format::R16 => (vk::Format::R16_UNORM, gray(Swizzle::ONE), image.pixels.clone()),
+        Format::R16G16 => (vk::Format::R16G16_UNORM, gray(Swizzle::G), image.pixels.clone()),
+        Format::R16G16B16 => (
+            vk::Format::R16G16B16A16_UNORM,
+            Default::default(),
+            padd_pixel_bytes_rgba(&image.pixels, &[0xFF, 0xFF]),
+        ),
+        Format::R16G16B16A16 => (vk::Format::R16G16B16A16_UNORM, Default::default(), image.pixels.clone()),
+        Format::R32G32B32FLOAT => (
+            vk::Format::R32G32B32A32_SFLOAT,
+            Default::default(),
+            padd_pixel_bytes_rgba(&image.pixels, &1.0f32.to_ne_bytes()),
+        ),
+        Format::R32G32B32A32FLOAT => (
+            vk::Format::R32G32B32A32_SFLOAT,
+            Default::default(),
+            image.pixels.clone(),
+        ),
+    };
+
+    TextureData {
         format,
-        &bytes,
-        image.width,
-        image.height,
-    ))
+        width: image.width,
+        height: image.height,
+        levels: vec![pixels],
+        components,
+        generate_mips: true,
+    }
+}
+
+/// KTX2 images carry their color space in their format, so `color_space` only applies to
+/// regular images.
+fn load_gltf_texture(
+    device: &RenderDevice,
+    asset: &GltfMesh,
+    image_idx: usize,
+    color_space: ColorSpace,
+) -> RenderResult<Option<VkImage>> {
+    let sampleable = |format| {
+        format_features(device, format)
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    };
+    let data = match &asset.images[image_idx] {
+        GltfImage::Pixels(image) => {
+            let data = gltf_texture_data(image, color_space);
+            if sampleable(data.format) {
+                data
+            } else {
+                println!(
+                    "WARNING: {:?} textures are not supported, converting to rgba8",
+                    data.format
+                );
+                TextureData {
+                    format: match color_space {
+                        ColorSpace::Srgb => vk::Format::R8G8B8A8_SRGB,
+                        ColorSpace::Linear => vk::Format::R8G8B8A8_UNORM,
+                    },
+                    levels: vec![rgba8_bytes(&gltf_image_texels(image))],
+                    components: Default::default(),
+                    ..data
+                }
+            }
+        }
+        GltfImage::Ktx2(bytes) => match ktx2_texture_data(bytes) {
+            Ok(data) if sampleable(data.format) => data,
+            Ok(data) => {
+                println!(
+                    "WARNING: KTX2 format {:?} is not supported by the device, ignoring...",
+                    data.format
+                );
+                return Ok(None);
+            }
+            Err(e) => {
+                println!("WARNING: Failed to read KTX2 texture: {}, ignoring...", e);
+                return Ok(None);
+            }
+        },
+    };
+
+    load_texture(device, &data).map(Some)
 }
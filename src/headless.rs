@@ -0,0 +1,355 @@
+use std::path::{Path, PathBuf};
+
+use ash::vk;
+use bevy::app::AppExit;
+use bevy::prelude::*;
+
+use crate::accumulation::Accumulation;
+use crate::camera::Camera3d;
+use crate::compute_pipeline::{add_compute_passes, ComputePasses, ComputePipeline};
+use crate::denoiser::{AccumulatedImages, AuxImages, ExternalDenoiserHook};
+use crate::environment::{resolve_sky, PlaceholderEnvironment};
+use crate::initializers;
+use crate::raytracing_pipeline::RaytracingPipeline;
+use crate::render_buffer::{Buffer, BufferProvider};
+use crate::render_device::RenderDevice;
+use crate::render_error::{report_render_error, RenderResult};
+use crate::render_graph::{Access, GraphImage, RenderGraph, TransientImages};
+use crate::render_image::{vk_image_from_asset, Image, VkImage};
+use crate::render_plugin::{
+    ready_to_trace, record_trace, wait_for_frame_finish, FrameResources, RenderConfig, RenderSchedule, RenderSet,
+    UniformData,
+};
+use crate::sampler::SobolMatrices;
+use crate::scene::Scene;
+use crate::shader_binding_table::SBT;
+use crate::vulkan_assets::{VkAssetCleanupPlaybook, VulkanAssets};
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+/// Inserted before the `RenderPlugin` to render offline instead of presenting to a window. The
+/// image is written to `out` once the `Accumulation` reaches its target sample count, filtered by
+/// the `ExternalDenoiserHook` when there is one and otherwise by the queued `ComputePasses`.
+#[derive(Resource, Clone)]
+pub struct HeadlessConfig {
+    pub width: u32,
+    pub height: u32,
+    pub out: PathBuf,
+}
+
+#[derive(Resource)]
+pub struct HeadlessTarget {
+    pub render_target: VkImage,
+    pub aux: AuxImages,
+    /// The radiance, or the output of the compute passes, followed by the two guides
+    readback_buffers: [Buffer<f32>; 3],
+    readback_pending: bool,
+}
+
+pub struct HeadlessPlugin;
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        let Some(config) = app.world.get_resource::<HeadlessConfig>().cloned() else {
+            return;
+        };
+        let device = app.world.get_resource::<RenderDevice>().unwrap().clone();
+
+        let render_target = vk_image_from_asset(
+            &device,
+            &Image {
+                width: config.width,
+                height: config.height,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                usage: vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+            },
+        )
+        .unwrap();
+
+        let readback_buffer = || {
+            device
+                .create_host_buffer::<f32>(
+                    config.width as u64 * config.height as u64 * 4,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                )
+                .unwrap()
+        };
+
+        app.insert_resource(HeadlessTarget {
+            render_target,
+            aux: AuxImages::new(&device, config.width, config.height).unwrap(),
+            readback_buffers: [readback_buffer(), readback_buffer(), readback_buffer()],
+            readback_pending: false,
+        });
+
+        app.edit_schedule(RenderSchedule, |schedule| {
+            schedule.add_system(
+                write_headless_output
+                    .in_set(RenderSet::Prepare)
+                    .after(wait_for_frame_finish),
+            );
+            schedule.add_system(render_headless.pipe(report_render_error).in_set(RenderSet::Render));
+        });
+
+        app.world
+            .get_resource_mut::<VkAssetCleanupPlaybook>()
+            .unwrap()
+            .add_system(cleanup_headless_target);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render_headless(
+    device: Res<RenderDevice>,
+    mut scene: ResMut<Scene>,
+    textures: Res<VulkanAssets<bevy::prelude::Image>>,
+    gtransforms: Query<&GlobalTransform>,
+    render_config: Option<Res<RenderConfig>>,
+    mut render_resources: ResMut<FrameResources>,
+    rt_pipelines: Res<VulkanAssets<RaytracingPipeline>>,
+    sbt: Res<SBT>,
+    camera: Query<(Entity, &Camera3d)>,
+    config: Res<HeadlessConfig>,
+    mut target: ResMut<HeadlessTarget>,
+    placeholder_environment: Res<PlaceholderEnvironment>,
+    asset_server: Res<AssetServer>,
+    // grouped, systems take at most 16 parameters
+    (mut accumulation, sobol_matrices): (ResMut<Accumulation>, Res<SobolMatrices>),
+    (compute_pipelines, mut compute_passes, mut transient_images, cleanup): (
+        Res<VulkanAssets<ComputePipeline>>,
+        ResMut<ComputePasses>,
+        ResMut<TransientImages>,
+        Res<VkCleanup>,
+    ),
+) -> RenderResult<()> {
+    let (camera_e, camera) = camera.single();
+
+    unsafe {
+        let cmd_buffer = render_resources.get().cmd_buffer;
+        device
+            .device
+            .reset_command_buffer(cmd_buffer, vk::CommandBufferResetFlags::empty())?;
+
+        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        device.device.begin_command_buffer(cmd_buffer, &begin_info)?;
+
+        let frame_idx = render_resources.current_idx();
+        let mut graph = RenderGraph::new(config.width, config.height);
+        let mut traced = false;
+
+        // only start counting once every mesh is in the TLAS, otherwise the first
+        // samples would be missing geometry that is still being uploaded
+        if !accumulation.is_converged() && scene.is_complete(frame_idx) && ready_to_trace(&scene, &sbt, frame_idx) {
+            // the compute passes only get one chance to process the image, wait until they are compiled
+            let ready = compute_passes.is_ready(&compute_pipelines);
+            if let Some((render_config, compiled, sky)) = render_config.as_ref().filter(|_| ready).and_then(|config| {
+                Some((
+                    config,
+                    rt_pipelines.get(&config.rt_pipeline)?,
+                    resolve_sky(config, &textures, &placeholder_environment, &asset_server)?,
+                ))
+            }) {
+                let uniforms = UniformData::new(
+                    camera,
+                    gtransforms.get(camera_e).unwrap(),
+                    config.width as f32 / config.height as f32,
+                    &accumulation,
+                    &render_config.sampler,
+                    &sobol_matrices,
+                    None,
+                );
+                let samples = accumulation.next_frame_samples();
+                accumulation.advance(samples);
+                println!(
+                    "Headless: {}/{} samples ({:.0}%)",
+                    accumulation.samples(),
+                    accumulation.target_samples.unwrap_or(0),
+                    accumulation.progress().unwrap_or(0.0) * 100.0
+                );
+                let converged = accumulation.is_converged();
+                target.readback_pending = converged;
+
+                let target = &*target;
+                let (scene, sbt, frame_resources) = (&*scene, &*sbt, &mut *render_resources);
+                let (width, height) = (config.width, config.height);
+                let tlas = scene.add_tlas_pass(&mut graph, frame_idx);
+                traced = true;
+                let traced = Access::StorageReadWrite(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR);
+                let mut accesses = [&target.render_target, &target.aux.albedo, &target.aux.normal_depth]
+                    .map(|image| (graph.image(GraphImage::Persistent(image.clone())), traced))
+                    .to_vec();
+                accesses.push((
+                    tlas,
+                    Access::AccelerationStructureRead(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR),
+                ));
+                graph.add_pass("trace", &accesses, move |context| {
+                    record_trace(
+                        context.device,
+                        context.cmd_buffer,
+                        frame_resources,
+                        compiled,
+                        &sky,
+                        scene,
+                        sbt,
+                        &target.render_target,
+                        &target.aux,
+                        width,
+                        height,
+                        uniforms,
+                    );
+                });
+
+                if converged {
+                    let output = add_compute_passes(&mut graph, &compute_pipelines, &mut compute_passes, frame_idx)
+                        .unwrap_or(GraphImage::Persistent(target.render_target.clone()));
+                    let images = [
+                        output,
+                        GraphImage::Persistent(target.aux.albedo.clone()),
+                        GraphImage::Persistent(target.aux.normal_depth.clone()),
+                    ];
+                    let mut accesses = images
+                        .iter()
+                        .map(|image| (graph.image(image.clone()), Access::TransferSrc))
+                        .collect::<Vec<_>>();
+                    for buffer in &target.readback_buffers {
+                        accesses.push((graph.buffer(buffer.handle), Access::TransferDst));
+                    }
+                    graph.add_pass("readback", &accesses, move |context| {
+                        let copy_region = initializers::buffer_image_copy(width, height);
+                        for (image, buffer) in images.iter().zip(&target.readback_buffers) {
+                            context.device.device.cmd_copy_image_to_buffer(
+                                context.cmd_buffer,
+                                context.image(image).handle,
+                                Access::TransferSrc.layout(),
+                                buffer.handle,
+                                std::slice::from_ref(&copy_region),
+                            );
+                        }
+                    });
+                }
+            }
+        }
+
+        // passes queued for a frame that did not read back are queued again for the next one
+        compute_passes.0.clear();
+
+        // without the readback there is nothing to write
+        let recorded = graph
+            .execute(&device, cmd_buffer, &mut transient_images, &cleanup)
+            .and_then(|()| Ok(device.device.end_command_buffer(cmd_buffer)?));
+        if let Err(e) = recorded {
+            target.readback_pending = false;
+            return Err(e);
+        }
+
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(std::slice::from_ref(&cmd_buffer))
+            .build();
+
+        let fence = render_resources.get().fence;
+        device.device.reset_fences(std::slice::from_ref(&fence))?;
+        let queue = device.queue.lock().unwrap();
+        device
+            .device
+            .queue_submit(*queue, std::slice::from_ref(&submit_info), fence)?;
+        if traced {
+            scene.tlas_submitted(frame_idx);
+        }
+    }
+    Ok(())
+}
+
+fn write_headless_output(
+    device: Res<RenderDevice>,
+    config: Res<HeadlessConfig>,
+    mut target: ResMut<HeadlessTarget>,
+    camera: Query<&Camera3d>,
+    external_denoiser: Option<Res<ExternalDenoiserHook>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !target.readback_pending {
+        return;
+    }
+    target.readback_pending = false;
+
+    // the copy might have been submitted with another frame's fence
+    device.wait_idle();
+
+    let [radiance, albedo, normal_depth] = &mut target.readback_buffers;
+    let mut images = AccumulatedImages {
+        width: config.width,
+        height: config.height,
+        radiance: device.map_buffer(radiance).as_slice_mut().to_vec(),
+        albedo: device.map_buffer(albedo).as_slice_mut().to_vec(),
+        normal_depth: device.map_buffer(normal_depth).as_slice_mut().to_vec(),
+    };
+    if let Some(external_denoiser) = external_denoiser {
+        if let Err(e) = images.denoise_with(external_denoiser.0.as_ref()) {
+            println!("Headless: external denoiser failed, writing the noisy image: {}", e);
+        }
+    }
+
+    match save_accumulated(
+        &images.radiance,
+        config.width,
+        config.height,
+        camera.single().exposure,
+        &config.out,
+    ) {
+        Ok(()) => println!("Headless: written {}", config.out.display()),
+        Err(e) => println!("Headless: failed to write {}: {}", config.out.display(), e),
+    }
+
+    exit.send(AppExit);
+}
+
+fn cleanup_headless_target(target: Res<HeadlessTarget>, cleanup: Res<VkCleanup>) {
+    cleanup.send(VkCleanupEvent::ImageView(target.render_target.view));
+    cleanup.send(VkCleanupEvent::Image(target.render_target.handle));
+    target.aux.destroy(&cleanup);
+    for buffer in &target.readback_buffers {
+        cleanup.send(VkCleanupEvent::Buffer(buffer.handle));
+    }
+}
+
+/// Resolves the accumulated rgba32f data (sum of the samples in rgb, their count in alpha) and writes it
+/// to disk. EXR files receive the linear radiance, PNG files the same tonemapping as `quad.frag`.
+pub fn save_accumulated(
+    pixels: &[f32],
+    width: u32,
+    height: u32,
+    exposure: f32,
+    path: &Path,
+) -> Result<(), image::ImageError> {
+    let mut radiance = Vec::with_capacity(pixels.len());
+    // the render target is stored bottom row first
+    for row in pixels.chunks_exact(width as usize * 4).rev() {
+        for pixel in row.chunks_exact(4) {
+            let count = pixel[3].max(1.0);
+            radiance.extend_from_slice(&[pixel[0] / count, pixel[1] / count, pixel[2] / count, 1.0]);
+        }
+    }
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => {
+            let exposure = exposure * exposure;
+            let ldr = radiance
+                .chunks_exact(4)
+                .flat_map(|pixel| {
+                    let [r, g, b] =
+                        [0, 1, 2].map(|c| (tonemap_filmic(1.0 - (-pixel[c] * exposure).exp()) * 255.0) as u8);
+                    [r, g, b, 255]
+                })
+                .collect();
+            image::RgbaImage::from_raw(width, height, ldr).unwrap().save(path)
+        }
+        _ => image::Rgba32FImage::from_raw(width, height, radiance)
+            .unwrap()
+            .save(path),
+    }
+}
+
+fn tonemap_filmic(color: f32) -> f32 {
+    let x = (color - 0.004).max(0.0);
+    ((x * (6.2 * x + 0.5)) / (x * (6.2 * x + 1.7) + 0.06)).clamp(0.0, 1.0)
+}
@@ -0,0 +1,121 @@
+use bevy::prelude::*;
+
+/// Changes the material of every geometry of an instance, without touching the asset it comes
+/// from. On a parent, like the root of a gltf scene, it applies to every instance below that
+/// doesn't have its own.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct InstanceMaterialOverride {
+    /// Multiplies the base color
+    pub tint: Vec3,
+    pub roughness: Option<f32>,
+    pub metallic: Option<f32>,
+    /// Added to the emission of the material. Only paths that hit the instance find it, it isn't
+    /// sampled like the emissive triangles of the meshes.
+    pub emission: Vec3,
+    /// Like the material's own, it only applies to the dielectric part
+    pub transmission: Option<f32>,
+}
+
+impl Default for InstanceMaterialOverride {
+    fn default() -> Self {
+        Self {
+            tint: Vec3::ONE,
+            roughness: None,
+            metallic: None,
+            emission: Vec3::ZERO,
+            transmission: None,
+        }
+    }
+}
+
+/// Which rays see an instance, like bevy's `RenderLayers` but for the kinds of rays raygen.rgen
+/// traces. It becomes the 8 bit mask of the TLAS instance and is inherited like
+/// `InstanceMaterialOverride`, instances without one are seen by all rays.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RayVisibility(u8);
+
+impl RayVisibility {
+    /// Rays from the camera, including the one finding the focal distance
+    pub const CAMERA: Self = Self(1 << 0);
+    /// Rays towards the lights and the sky
+    pub const SHADOW: Self = Self(1 << 1);
+    /// Rays after the first bounce, reflections, refractions and diffuse bounces alike
+    pub const REFLECTION: Self = Self(1 << 2);
+    pub const ALL: Self = Self(0xFF);
+
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn mask(self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for RayVisibility {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// Matches `InstanceMaterial` in common.glsl, indexed by the custom index of the instance. Negative
+/// factors keep the ones of the material.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct GpuInstanceMaterial {
+    pub tint: [f32; 3],
+    pub roughness: f32,
+    pub emission: [f32; 3],
+    pub metallic: f32,
+    pub transmission: f32,
+}
+
+impl GpuInstanceMaterial {
+    pub fn new(material: Option<&InstanceMaterialOverride>) -> Self {
+        let material = material.copied().unwrap_or_default();
+        Self {
+            tint: material.tint.to_array(),
+            roughness: material.roughness.unwrap_or(-1.0),
+            emission: material.emission.to_array(),
+            metallic: material.metallic.unwrap_or(-1.0),
+            transmission: material.transmission.unwrap_or(-1.0),
+        }
+    }
+}
+
+impl Default for GpuInstanceMaterial {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Looks up `InstanceMaterialOverride` and `RayVisibility` of an instance and its ancestors
+pub type InstanceAppearance<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Option<&'static InstanceMaterialOverride>,
+        Option<&'static RayVisibility>,
+        Option<&'static Parent>,
+    ),
+>;
+
+/// The material and the instance mask of `entity`, each from the nearest of it and its ancestors
+/// that has one
+pub fn instance_appearance(entity: Entity, appearance: &InstanceAppearance) -> (GpuInstanceMaterial, u8) {
+    let mut material = None;
+    let mut visibility = None;
+    let mut current = Some(entity);
+    while let Some(entity) = current {
+        let Ok((entity_material, entity_visibility, parent)) = appearance.get(entity) else {
+            break;
+        };
+        material = material.or(entity_material);
+        visibility = visibility.or(entity_visibility);
+        current = parent.map(|parent| parent.get());
+    }
+    (
+        GpuInstanceMaterial::new(material),
+        visibility.copied().unwrap_or_default().mask(),
+    )
+}
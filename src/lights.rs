@@ -0,0 +1,156 @@
+use bevy::prelude::*;
+
+use crate::acceleration_structure::EmissiveTriangle;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PunctualLightKind {
+    Point,
+    /// Cone angles in radians, measured from the light direction
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+    Directional,
+}
+
+/// A `KHR_lights_punctual` light, it shines along the local -Z axis of its entity. These lights
+/// have no area, so they are only ever reached through the explicit light samples in raygen.rgen.
+#[derive(Component, Debug, Clone)]
+pub struct PunctualLight {
+    pub kind: PunctualLightKind,
+    pub color: Vec3,
+    /// Candela for point and spot lights, lux for directional lights
+    pub intensity: f32,
+    /// Distance at which the light reaches zero, None means infinite
+    pub range: Option<f32>,
+}
+
+impl PunctualLight {
+    pub fn from_gltf(light: &gltf::khr_lights_punctual::Light) -> Self {
+        Self {
+            kind: match light.kind() {
+                gltf::khr_lights_punctual::Kind::Point => PunctualLightKind::Point,
+                gltf::khr_lights_punctual::Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => PunctualLightKind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                },
+                gltf::khr_lights_punctual::Kind::Directional => PunctualLightKind::Directional,
+            },
+            color: Vec3::from(light.color()),
+            intensity: light.intensity(),
+            range: light.range(),
+        }
+    }
+}
+
+pub const LIGHT_POINT: u32 = 0;
+pub const LIGHT_SPOT: u32 = 1;
+pub const LIGHT_DIRECTIONAL: u32 = 2;
+
+/// Matches `PunctualLight` in common.glsl
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GpuPunctualLight {
+    pub position: [f32; 3],
+    pub kind: u32,
+    pub direction: [f32; 3],
+    pub range: f32,
+    pub radiance: [f32; 3],
+    pub inner_cone_cos: f32,
+    pub outer_cone_cos: f32,
+}
+
+impl GpuPunctualLight {
+    pub fn new(light: &PunctualLight, transform: &GlobalTransform) -> Self {
+        let (kind, inner_cone_cos, outer_cone_cos) = match light.kind {
+            PunctualLightKind::Point => (LIGHT_POINT, 1.0, 1.0),
+            PunctualLightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (LIGHT_SPOT, inner_cone_angle.cos(), outer_cone_angle.cos()),
+            PunctualLightKind::Directional => (LIGHT_DIRECTIONAL, 1.0, 1.0),
+        };
+
+        Self {
+            position: transform.translation().to_array(),
+            kind,
+            direction: transform.affine().transform_vector3(-Vec3::Z).normalize().to_array(),
+            range: light.range.unwrap_or(0.0),
+            radiance: (light.color * light.intensity).to_array(),
+            inner_cone_cos,
+            outer_cone_cos,
+        }
+    }
+}
+
+/// Matches `luminance` in common.glsl
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Matches `EmissiveTriangle` in common.glsl
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GpuEmissiveTriangle {
+    pub p0: [f32; 3],
+    pub p1: [f32; 3],
+    pub p2: [f32; 3],
+    pub emission: [f32; 3],
+    pub uv0: [f32; 2],
+    pub uv1: [f32; 2],
+    pub uv2: [f32; 2],
+    pub texture: u32,
+    /// Summed power of this and all preceding triangles, relative to the total power
+    pub cdf: f32,
+}
+
+impl GpuEmissiveTriangle {
+    pub fn new(triangle: &EmissiveTriangle, transform: &GlobalTransform) -> Self {
+        let [p0, p1, p2] = triangle
+            .positions
+            .map(|p| transform.affine().transform_point3(Vec3::from(p)).to_array());
+        let [uv0, uv1, uv2] = triangle.uvs;
+        Self {
+            p0,
+            p1,
+            p2,
+            emission: triangle.emission,
+            uv0,
+            uv1,
+            uv2,
+            texture: triangle.texture,
+            cdf: 0.0,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        let [p0, p1, p2] = [self.p0, self.p1, self.p2].map(Vec3::from);
+        (p1 - p0).cross(p2 - p0).length() * 0.5
+    }
+
+    /// The emissive texture is left out, so this is the power the triangle is picked by rather than
+    /// the exact power it emits.
+    pub fn power(&self) -> f32 {
+        self.area() * luminance(Vec3::from(self.emission))
+    }
+}
+
+/// Drops the triangles that emit no power, fills in the `cdf` of the others and returns the total
+/// power.
+pub fn build_emissive_cdf(triangles: &mut Vec<GpuEmissiveTriangle>) -> f32 {
+    triangles.retain(|triangle| triangle.power() > 0.0);
+    let total = triangles.iter().map(GpuEmissiveTriangle::power).sum::<f32>();
+    let mut sum = 0.0;
+    for triangle in triangles.iter_mut() {
+        sum += triangle.power();
+        triangle.cdf = sum / total;
+    }
+    // guard against rounding, the shader relies on the last entry covering every random number
+    if let Some(last) = triangles.last_mut() {
+        last.cdf = 1.0;
+    }
+    total
+}
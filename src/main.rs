@@ -1,18 +1,32 @@
 mod acceleration_structure;
+mod accumulation;
 mod camera;
 mod composed_asset;
+mod compute_pipeline;
+mod cpu_tracer;
+mod denoiser;
+mod environment;
 mod gltf_assets;
+mod headless;
 mod initializers;
+mod instance_material;
+mod lights;
+mod physical_device;
+mod procedural;
 mod rasterization_pipeline;
 mod raytracing_pipeline;
 mod render_buffer;
 mod render_device;
+mod render_error;
+mod render_graph;
 mod render_image;
 mod render_plugin;
+mod sampler;
 mod scene;
+mod scene_description;
 mod shader;
 mod shader_binding_table;
-mod sphere_blas;
+mod shader_reflection;
 mod swapchain;
 mod texture;
 mod vk_utils;
@@ -20,46 +34,112 @@ mod vulkan_assets;
 mod vulkan_cleanup;
 
 use std::f32::consts::PI;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use accumulation::Accumulation;
 use bevy::asset::HandleId;
 use bevy::input::mouse::MouseWheel;
 use bevy::prelude::*;
 use bevy::time::common_conditions::on_timer;
 use bevy::window::PrimaryWindow;
 use bevy_rapier3d::prelude::*;
-use camera::{Camera3d, Camera3dBundle, PitchYaw};
-use clap::Parser;
-use gltf_assets::GltfMesh;
-use rasterization_pipeline::RasterizationPipeline;
-use render_plugin::{RayFocalFocus, RenderConfig};
-use sphere_blas::Sphere;
-
-use crate::raytracing_pipeline::RaytracingPipeline;
+use camera::{Camera3d, Camera3dBundle, GltfCameraSelection, PitchYaw};
+use clap::{CommandFactory, Parser};
+use cpu_tracer::CpuRenderPlugin;
+use denoiser::{Denoiser, ExternalDenoiserHook, OidnCommand};
+use gltf_assets::GltfScene;
+use headless::HeadlessConfig;
+use physical_device::GpuSelection;
+use procedural::Sphere;
+use render_plugin::RayFocalFocus;
+use sampler::{SamplerConfig, SamplerKind};
+use scene_description::SceneDescriptionPlugin;
+
 use crate::render_plugin::RenderPlugin;
 
 #[derive(Parser)]
 struct Cli {
     #[arg(long, default_value_t = false)]
     dump_schedule: bool,
+    /// Render without a window and write the accumulated image to --out
+    #[arg(long, default_value_t = false)]
+    headless: bool,
+    /// Render headless with the CPU reference path tracer instead of Vulkan
+    #[arg(long, default_value_t = false)]
+    cpu: bool,
+    /// Stop accumulating once the image has this many samples per pixel, headless renders
+    /// default to 64 and write the image when they get there
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    spp: Option<u32>,
+    /// Samples per pixel traced every frame
+    #[arg(long, default_value_t = 4)]
+    samples_per_frame: u32,
+    /// Seeds every random number, renders with the same seed are identical
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+    #[arg(long, value_enum, default_value_t = SamplerKind::Pcg)]
+    sampler: SamplerKind,
+    /// Filter the image with the à-trous denoiser, toggle it with N
+    #[arg(long, default_value_t = false)]
+    denoise: bool,
+    /// Denoise headless renders with OIDN's oidnDenoise at this path instead, before they are written
+    #[arg(long)]
+    oidn: Option<PathBuf>,
+    /// Output file in headless mode, either .exr (linear) or .png (tonemapped)
+    #[arg(long, default_value = "frame.exr")]
+    out: PathBuf,
+    #[arg(long, default_value_t = 1280)]
+    width: u32,
+    #[arg(long, default_value_t = 720)]
+    height: u32,
+    /// Scene description to load, relative to the assets folder
+    #[arg(long, default_value = "scenes/default.scene.ron")]
+    scene: PathBuf,
+    /// Look through the camera with this index from the loaded gltf files, cycle with C
+    #[arg(long)]
+    gltf_camera: Option<usize>,
+    /// Render on this GPU, by its index in the printed device list or part of its name
+    #[arg(long)]
+    gpu: Option<GpuSelection>,
 }
 
 #[derive(Resource, Default)]
 struct GameAssets {
-    box_mesh: Handle<GltfMesh>,
-    sponza: Handle<GltfMesh>,
-    bistro_interior: Handle<GltfMesh>,
-    bistro_exterior: Handle<GltfMesh>,
-    fireplace_room: Handle<GltfMesh>,
-    rungholt: Handle<GltfMesh>,
+    box_mesh: Handle<GltfScene>,
 }
 
 #[derive(Component)]
 struct MainBlock;
 
 fn main() {
-    App::new()
-        .add_plugin(bevy::log::LogPlugin::default())
+    let cli = Cli::parse();
+    let headless = cli.headless || cli.cpu;
+    if headless && !matches!(cli.out.extension().and_then(|e| e.to_str()), Some("exr" | "png")) {
+        Cli::command()
+            .error(clap::error::ErrorKind::InvalidValue, "--out must end in .exr or .png")
+            .exit();
+    }
+
+    let mut app = App::new();
+    app.insert_resource(GltfCameraSelection(cli.gltf_camera));
+    if let Some(gpu) = cli.gpu {
+        app.insert_resource(gpu);
+    }
+    let target_samples = if headless { Some(cli.spp.unwrap_or(64)) } else { cli.spp };
+    app.insert_resource(Accumulation::new(cli.samples_per_frame, target_samples));
+    app.insert_resource(SamplerConfig {
+        kind: cli.sampler,
+        seed: cli.seed,
+    });
+    app.insert_resource(Denoiser {
+        enabled: cli.denoise,
+        ..default()
+    });
+    if let Some(program) = cli.oidn {
+        app.insert_resource(ExternalDenoiserHook(Box::new(OidnCommand { program })));
+    }
+    app.add_plugin(bevy::log::LogPlugin::default())
         .add_plugin(bevy::core::TaskPoolPlugin::default())
         .add_plugin(bevy::core::TypeRegistrationPlugin::default())
         .add_plugin(bevy::core::FrameCountPlugin::default())
@@ -67,19 +147,46 @@ fn main() {
         .add_plugin(bevy::transform::TransformPlugin::default())
         .add_plugin(bevy::hierarchy::HierarchyPlugin::default())
         .add_plugin(bevy::diagnostic::DiagnosticsPlugin::default())
-        .add_plugin(bevy::input::InputPlugin::default())
-        .add_plugin(bevy::window::WindowPlugin::default())
-        .add_plugin(bevy::a11y::AccessibilityPlugin)
+        .add_plugin(bevy::input::InputPlugin::default());
+
+    if headless {
+        app.add_plugin(bevy::window::WindowPlugin {
+            primary_window: None,
+            exit_condition: bevy::window::ExitCondition::DontExit,
+            ..default()
+        })
+        .add_plugin(bevy::app::ScheduleRunnerPlugin)
+        .insert_resource(HeadlessConfig {
+            width: cli.width,
+            height: cli.height,
+            out: cli.out,
+        });
+    } else {
+        app.add_plugin(bevy::window::WindowPlugin::default());
+    }
+
+    app.add_plugin(bevy::a11y::AccessibilityPlugin)
         .add_plugin(bevy::asset::AssetPlugin {
             watch_for_changes: true,
             ..default()
         })
-        .add_plugin(bevy::asset::debug_asset_server::DebugAssetServerPlugin::default())
-        .add_plugin(bevy::winit::WinitPlugin::default())
-        .add_plugin(bevy::scene::ScenePlugin::default())
+        .add_plugin(bevy::asset::debug_asset_server::DebugAssetServerPlugin::default());
+
+    if !headless {
+        app.add_plugin(bevy::winit::WinitPlugin::default());
+    }
+
+    app.add_plugin(bevy::scene::ScenePlugin::default())
         .add_asset::<bevy::render::mesh::Mesh>()
-        .add_asset_loader(bevy::render::texture::ExrTextureLoader)
-        .add_plugin(RenderPlugin)
+        .add_asset_loader(bevy::render::texture::ExrTextureLoader);
+
+    if cli.cpu {
+        app.add_plugin(CpuRenderPlugin);
+    } else {
+        app.add_plugin(RenderPlugin);
+    }
+
+    app.add_plugin(SceneDescriptionPlugin { path: cli.scene })
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .insert_resource(RapierConfiguration {
             gravity: Vec3::new(0.0, -9.81, 0.0),
@@ -97,72 +204,10 @@ fn main() {
     println!("Goodbye!");
 }
 
-fn startup(
-    mut commands: Commands,
-    assets: Res<AssetServer>,
-    mut rt_pipelines: ResMut<Assets<RaytracingPipeline>>,
-    mut rast_pipelines: ResMut<Assets<RasterizationPipeline>>,
-) {
-    for i in 0..10 {
-        commands.spawn((
-            Sphere,
-            TransformBundle::from_transform(
-                Transform::from_translation(Vec3::new(-5.0 + i as f32, 0.5, -1.25 + (i as f32) / 4.0))
-                    .with_scale(Vec3::splat(0.9)),
-            ),
-            RigidBody::Fixed,
-            Collider::ball(0.5),
-        ));
-    }
-
-    commands.spawn(Camera3dBundle {
-        transform: Transform::from_xyz(0.0, 0.0, -3.0),
-        ..default()
-    });
-
-    let game_assets = GameAssets {
-        rungholt: assets.load("models/rungholt.glb"),
-        ..default()
-    };
-
-    // floor
-    //commands.spawn((
-    //    game_assets.box_mesh.clone(),
-    //    TransformBundle::from_transform(Transform::from_xyz(0.0, -1.0, 0.0).with_scale(Vec3::new(100.0, 0.2, 100.0))),
-    //    RigidBody::Fixed,
-    //    Collider::cuboid(0.5, 0.5, 0.5),
-    //));
-    //
-    //commands.spawn((
-    //    game_assets.bistro_interior.clone(),
-    //    TransformBundle::from_transform(
-    //        Transform::from_scale(Vec3::splat(0.01)).with_rotation(Quat::from_rotation_x(0.0)),
-    //    ),
-    //));
-    //
-    commands.spawn((
-        game_assets.rungholt.clone(),
-        TransformBundle::from_transform(
-            Transform::from_scale(Vec3::splat(0.1)).with_rotation(Quat::from_rotation_x(PI/2.0)),
-        ),
-    ));
-
-    commands.insert_resource(game_assets);
-
-    commands.insert_resource(RenderConfig {
-        rt_pipeline: rt_pipelines.add(RaytracingPipeline {
-            raygen_shader: assets.load("shaders/raygen.rgen"),
-            triangle_hit_shader: assets.load("shaders/hit.rchit"),
-            miss_shader: assets.load("shaders/miss.rmiss"),
-            sphere_int_shader: assets.load("shaders/sphere.rint"),
-            sphere_hit_shader: assets.load("shaders/sphere.rchit"),
-        }),
-        quad_pipeline: rast_pipelines.add(RasterizationPipeline {
-            vs_shader: assets.load("shaders/quad.vert"),
-            fs_shader: assets.load("shaders/quad.frag"),
-        }),
-        skybox: assets.load("textures/sky.exr"),
-    });
+fn startup(mut commands: Commands) {
+    // the scene description moves the camera into place once it has loaded
+    commands.spawn(Camera3dBundle::default());
+    commands.insert_resource(GameAssets::default());
 }
 
 fn report_fps(time: Res<Time>, input: Res<Input<KeyCode>>, mut ravg: Local<f32>) {
@@ -0,0 +1,395 @@
+use std::convert::Infallible;
+use std::ffi::CStr;
+use std::str::FromStr;
+
+use ash::extensions::khr;
+use ash::prelude::VkResult;
+use ash::{vk, Instance};
+use bevy::prelude::*;
+
+const REQUIRED_API_VERSION: u32 = vk::make_api_version(0, 1, 3, 0);
+
+/// The GPU to render on instead of the best scoring one, from `--gpu`
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub enum GpuSelection {
+    /// Position in the list of devices printed at startup. A number past the end of the list is
+    /// looked for in the names instead, so `--gpu 3080` finds an RTX 3080.
+    Index(usize),
+    /// Any part of the device name, ignoring case
+    Name(String),
+}
+
+impl FromStr for GpuSelection {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => GpuSelection::Index(index),
+            Err(_) => GpuSelection::Name(s.to_string()),
+        })
+    }
+}
+
+/// The features the renderer can't do without
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceFeatures {
+    pub acceleration_structure: bool,
+    pub ray_tracing_pipeline: bool,
+    /// Partially bound, update after bind and variable count runtime arrays, for the bindless set
+    pub descriptor_indexing: bool,
+    pub buffer_device_address: bool,
+    pub dynamic_rendering: bool,
+    pub synchronization2: bool,
+    pub maintenance4: bool,
+}
+
+/// Everything selection looks at, so it works the same on made up devices
+#[derive(Debug, Clone, Default)]
+pub struct DeviceDescription {
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub extensions: Vec<String>,
+    pub features: DeviceFeatures,
+    /// A family with graphics that can present to the window, if the device has one
+    pub queue_family: Option<u32>,
+    pub device_local_memory: u64,
+}
+
+/// The device extensions the renderer enables, the swapchain only with a window
+pub fn required_extensions(windowed: bool) -> Vec<&'static CStr> {
+    let mut extensions = vec![
+        khr::Synchronization2::name(),
+        khr::Maintenance4::name(),
+        khr::AccelerationStructure::name(),
+        khr::RayTracingPipeline::name(),
+        khr::DeferredHostOperations::name(),
+        vk::KhrSpirv14Fn::name(),
+        vk::ExtDescriptorIndexingFn::name(),
+    ];
+    if windowed {
+        extensions.push(khr::Swapchain::name());
+    }
+    extensions
+}
+
+/// Every reason `device` can't run the renderer, empty when it can
+pub fn unmet_requirements(device: &DeviceDescription, extensions: &[&CStr]) -> Vec<String> {
+    let mut unmet = Vec::new();
+    if device.api_version < REQUIRED_API_VERSION {
+        unmet.push(format!(
+            "supports Vulkan {}.{}, needs 1.3",
+            vk::api_version_major(device.api_version),
+            vk::api_version_minor(device.api_version)
+        ));
+    }
+
+    for extension in extensions {
+        let extension = extension.to_str().unwrap();
+        if !device.extensions.iter().any(|supported| supported == extension) {
+            unmet.push(format!("missing extension {}", extension));
+        }
+    }
+
+    let features = device.features;
+    let required_features = [
+        (features.acceleration_structure, "accelerationStructure"),
+        (features.ray_tracing_pipeline, "rayTracingPipeline"),
+        (
+            features.descriptor_indexing,
+            "descriptor indexing for bindless textures",
+        ),
+        (features.buffer_device_address, "bufferDeviceAddress"),
+        (features.dynamic_rendering, "dynamicRendering"),
+        (features.synchronization2, "synchronization2"),
+        (features.maintenance4, "maintenance4"),
+    ];
+    for (supported, feature) in required_features {
+        if !supported {
+            unmet.push(format!("missing feature {}", feature));
+        }
+    }
+
+    if device.queue_family.is_none() {
+        unmet.push("no graphics queue that can present to the window".to_string());
+    }
+    unmet
+}
+
+/// Higher is better, discrete GPUs before integrated ones and more memory after that
+pub fn score(device: &DeviceDescription) -> u64 {
+    let kind = match device.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 3,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 2,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+        _ => 0,
+    };
+    (kind << 48) | (device.device_local_memory >> 20)
+}
+
+fn position_by_name(devices: &[DeviceDescription], name: &str) -> Option<usize> {
+    devices
+        .iter()
+        .position(|device| device.name.to_lowercase().contains(&name.to_lowercase()))
+}
+
+/// The index of the device to render on, or why none of them will do
+pub fn select_device(
+    devices: &[DeviceDescription],
+    extensions: &[&CStr],
+    selection: Option<&GpuSelection>,
+) -> Result<usize, String> {
+    if let Some(selection) = selection {
+        let idx = match selection {
+            GpuSelection::Index(idx) if *idx < devices.len() => Some(*idx),
+            GpuSelection::Index(idx) => position_by_name(devices, &idx.to_string()),
+            GpuSelection::Name(name) => position_by_name(devices, name),
+        }
+        .ok_or_else(|| format!("no device matches --gpu {:?}", selection))?;
+
+        let unmet = unmet_requirements(&devices[idx], extensions);
+        if !unmet.is_empty() {
+            return Err(format!("{} can't be used: {}", devices[idx].name, unmet.join(", ")));
+        }
+        return Ok(idx);
+    }
+
+    let usable = devices
+        .iter()
+        .enumerate()
+        .filter(|(_, device)| unmet_requirements(device, extensions).is_empty())
+        .max_by_key(|(idx, device)| (score(device), std::cmp::Reverse(*idx)));
+    match usable {
+        Some((idx, _)) => Ok(idx),
+        None if devices.is_empty() => Err("no Vulkan devices found".to_string()),
+        None => {
+            let reasons = devices
+                .iter()
+                .map(|device| {
+                    format!(
+                        "  - {}: {}",
+                        device.name,
+                        unmet_requirements(device, extensions).join(", ")
+                    )
+                })
+                .collect::<Vec<_>>();
+            Err(format!("no device can run the renderer:\n{}", reasons.join("\n")))
+        }
+    }
+}
+
+/// Queries what selection needs from a physical device, `supports_present` decides for the window
+pub unsafe fn describe_device(
+    instance: &Instance,
+    device: vk::PhysicalDevice,
+    supports_present: impl Fn(u32) -> bool,
+) -> VkResult<DeviceDescription> {
+    let properties = instance.get_physical_device_properties(device);
+
+    let extensions = instance
+        .enumerate_device_extension_properties(device)?
+        .iter()
+        .map(|extension| {
+            CStr::from_ptr(extension.extension_name.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+
+    let mut acceleration_structure = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+    let mut ray_tracing_pipeline = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+    let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::default();
+    let mut vulkan13 = vk::PhysicalDeviceVulkan13Features::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder()
+        .push_next(&mut acceleration_structure)
+        .push_next(&mut ray_tracing_pipeline)
+        .push_next(&mut vulkan12);
+    // older devices don't know the 1.3 struct
+    if properties.api_version >= REQUIRED_API_VERSION {
+        features = features.push_next(&mut vulkan13);
+    }
+    instance.get_physical_device_features2(device, &mut features);
+
+    let queue_family = instance
+        .get_physical_device_queue_family_properties(device)
+        .iter()
+        .enumerate()
+        .position(|(i, family)| family.queue_flags.contains(vk::QueueFlags::GRAPHICS) && supports_present(i as u32))
+        .map(|i| i as u32);
+
+    let memory = instance.get_physical_device_memory_properties(device);
+    let device_local_memory = memory.memory_heaps[..memory.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum();
+
+    let enabled = |feature: vk::Bool32| feature == vk::TRUE;
+    Ok(DeviceDescription {
+        name: CStr::from_ptr(properties.device_name.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+        device_type: properties.device_type,
+        api_version: properties.api_version,
+        extensions,
+        features: DeviceFeatures {
+            acceleration_structure: enabled(acceleration_structure.acceleration_structure),
+            ray_tracing_pipeline: enabled(ray_tracing_pipeline.ray_tracing_pipeline),
+            descriptor_indexing: enabled(vulkan12.descriptor_binding_partially_bound)
+                && enabled(vulkan12.runtime_descriptor_array)
+                && enabled(vulkan12.descriptor_binding_sampled_image_update_after_bind)
+                && enabled(vulkan12.descriptor_binding_storage_image_update_after_bind)
+                && enabled(vulkan12.descriptor_binding_variable_descriptor_count),
+            buffer_device_address: enabled(vulkan12.buffer_device_address),
+            dynamic_rendering: enabled(vulkan13.dynamic_rendering),
+            synchronization2: enabled(vulkan13.synchronization2),
+            maintenance4: enabled(vulkan13.maintenance4),
+        },
+        queue_family,
+        device_local_memory,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device with everything the renderer needs
+    fn usable(name: &str, device_type: vk::PhysicalDeviceType, memory_mb: u64) -> DeviceDescription {
+        DeviceDescription {
+            name: name.to_string(),
+            device_type,
+            api_version: REQUIRED_API_VERSION,
+            extensions: required_extensions(true)
+                .iter()
+                .map(|extension| extension.to_str().unwrap().to_string())
+                .collect(),
+            features: DeviceFeatures {
+                acceleration_structure: true,
+                ray_tracing_pipeline: true,
+                descriptor_indexing: true,
+                buffer_device_address: true,
+                dynamic_rendering: true,
+                synchronization2: true,
+                maintenance4: true,
+            },
+            queue_family: Some(0),
+            device_local_memory: memory_mb << 20,
+        }
+    }
+
+    fn devices() -> Vec<DeviceDescription> {
+        vec![
+            usable(
+                "Intel(R) UHD Graphics 770",
+                vk::PhysicalDeviceType::INTEGRATED_GPU,
+                16384,
+            ),
+            usable("NVIDIA GeForce RTX 3080", vk::PhysicalDeviceType::DISCRETE_GPU, 10240),
+            usable("llvmpipe (LLVM 15.0.7, 256 bits)", vk::PhysicalDeviceType::CPU, 32768),
+        ]
+    }
+
+    #[test]
+    fn usable_device_has_no_unmet_requirements() {
+        for device in devices() {
+            assert_eq!(
+                unmet_requirements(&device, &required_extensions(true)),
+                Vec::<String>::new()
+            );
+        }
+    }
+
+    #[test]
+    fn missing_extension_is_listed() {
+        let mut device = usable("Old GPU", vk::PhysicalDeviceType::DISCRETE_GPU, 4096);
+        let ray_tracing = khr::RayTracingPipeline::name().to_str().unwrap();
+        device.extensions.retain(|extension| extension != ray_tracing);
+        device.features.ray_tracing_pipeline = false;
+
+        let unmet = unmet_requirements(&device, &required_extensions(true));
+        assert_eq!(
+            unmet,
+            vec![
+                format!("missing extension {}", ray_tracing),
+                "missing feature rayTracingPipeline".to_string(),
+            ]
+        );
+        let error = select_device(&[device], &required_extensions(true), None).unwrap_err();
+        assert!(error.contains("Old GPU") && error.contains(ray_tracing), "{}", error);
+    }
+
+    #[test]
+    fn swapchain_is_only_required_with_a_window() {
+        let mut device = usable("Headless GPU", vk::PhysicalDeviceType::DISCRETE_GPU, 4096);
+        device.extensions.retain(|extension| extension != "VK_KHR_swapchain");
+        assert!(unmet_requirements(&device, &required_extensions(false)).is_empty());
+        assert_eq!(unmet_requirements(&device, &required_extensions(true)).len(), 1);
+    }
+
+    #[test]
+    fn discrete_gpu_beats_integrated() {
+        // the integrated GPU shares more memory, the device type decides first
+        assert_eq!(select_device(&devices(), &required_extensions(true), None), Ok(1));
+    }
+
+    #[test]
+    fn more_memory_breaks_ties() {
+        let devices = [
+            usable("Small", vk::PhysicalDeviceType::DISCRETE_GPU, 8192),
+            usable("Large", vk::PhysicalDeviceType::DISCRETE_GPU, 24576),
+        ];
+        assert_eq!(select_device(&devices, &required_extensions(true), None), Ok(1));
+    }
+
+    #[test]
+    fn gpu_by_index() {
+        let selection = "2".parse::<GpuSelection>().unwrap();
+        assert_eq!(selection, GpuSelection::Index(2));
+        assert_eq!(
+            select_device(&devices(), &required_extensions(true), Some(&selection)),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn gpu_by_name() {
+        let selection = "intel".parse::<GpuSelection>().unwrap();
+        assert_eq!(
+            select_device(&devices(), &required_extensions(true), Some(&selection)),
+            Ok(0)
+        );
+
+        // past the end of the list a number is part of a name
+        let selection = "3080".parse::<GpuSelection>().unwrap();
+        assert_eq!(
+            select_device(&devices(), &required_extensions(true), Some(&selection)),
+            Ok(1)
+        );
+
+        let selection = "radeon".parse::<GpuSelection>().unwrap();
+        assert!(select_device(&devices(), &required_extensions(true), Some(&selection)).is_err());
+    }
+
+    #[test]
+    fn selected_device_must_be_usable() {
+        let mut devices = devices();
+        devices[0].api_version = vk::make_api_version(0, 1, 2, 0);
+        let error = select_device(&devices, &required_extensions(true), Some(&GpuSelection::Index(0))).unwrap_err();
+        assert!(error.contains("needs 1.3"), "{}", error);
+    }
+
+    #[test]
+    fn no_device_fits() {
+        assert!(select_device(&[], &required_extensions(true), None).is_err());
+
+        let mut devices = devices();
+        for device in &mut devices {
+            device.queue_family = None;
+        }
+        let error = select_device(&devices, &required_extensions(true), None).unwrap_err();
+        for device in &devices {
+            assert!(error.contains(&device.name), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use ash::vk;
+use bevy::math::Affine3A;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::{
+    acceleration_structure::{allocate_acceleration_structure, AccelerationStructure, EmissiveTriangle},
+    lights::{luminance, GpuEmissiveTriangle},
+    render_buffer::{Buffer, BufferProvider},
+    render_device::RenderDevice,
+    render_error::RenderResult,
+    vulkan_assets::VkAssetCleanupPlaybook,
+    vulkan_cleanup::{VkCleanup, VkCleanupEvent},
+};
+
+/// The shapes traced with their own intersection shader, in the order of their hit groups in the
+/// pipeline and of their entries at the start of the hit region of the SBT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PrimitiveKind {
+    Sphere,
+    Cuboid,
+    Cylinder,
+    Quad,
+    Disk,
+    Sdf,
+}
+
+impl PrimitiveKind {
+    pub const ALL: [Self; 6] = [
+        Self::Sphere,
+        Self::Cuboid,
+        Self::Cylinder,
+        Self::Quad,
+        Self::Disk,
+        Self::Sdf,
+    ];
+
+    pub fn sbt_offset(self) -> u32 {
+        self as u32
+    }
+}
+
+/// A shape intersected in object space by the intersection shader of its `KIND`, shaded by
+/// procedural.rchit with its `PrimitiveMaterial`. Registered with `add_procedural_primitive`.
+pub trait ProceduralPrimitive: Component {
+    const KIND: PrimitiveKind;
+
+    /// Bounds of the shape in the object space of its intersection shader. Every primitive of the
+    /// kind shares one BLAS built from them, `blas_transform` fits it to each one.
+    fn aabb() -> AABB {
+        AABB::default()
+    }
+
+    /// Triangles covering the shape in the same space. raygen.rgen samples them as area lights when
+    /// the primitive is emissive, shapes without them are only found by the paths that hit them.
+    fn light_triangles() -> &'static [[Vec3; 3]] {
+        &[]
+    }
+
+    /// Goes between the transform of the entity and the BLAS
+    fn blas_transform(&self) -> Affine3A;
+
+    fn material(&self) -> &PrimitiveMaterial;
+}
+
+/// What the renderer sees of a `ProceduralPrimitive`, kept up to date by `sync_primitive`
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct PrimitiveInstance {
+    pub kind: PrimitiveKind,
+    pub blas_transform: Affine3A,
+    pub material: PrimitiveMaterial,
+    pub light_triangles: &'static [[Vec3; 3]],
+}
+
+impl PrimitiveInstance {
+    fn new<T: ProceduralPrimitive>(primitive: &T) -> Self {
+        Self {
+            kind: T::KIND,
+            blas_transform: primitive.blas_transform(),
+            material: *primitive.material(),
+            light_triangles: T::light_triangles(),
+        }
+    }
+
+    pub fn gpu_material(&self) -> GpuPrimitiveMaterial {
+        GpuPrimitiveMaterial::new(&self.material, !self.light_triangles.is_empty())
+    }
+
+    /// The light triangles in world space, they join the emissive triangles of the meshes
+    pub fn emissive_triangles<'a>(
+        &'a self,
+        transform: &GlobalTransform,
+    ) -> impl Iterator<Item = GpuEmissiveTriangle> + 'a {
+        let transform = GlobalTransform::from(transform.affine() * self.blas_transform);
+        self.light_triangles.iter().map(move |positions| {
+            let triangle = EmissiveTriangle {
+                positions: positions.map(|position| position.to_array()),
+                uvs: [[0.0; 2]; 3],
+                emission: self.material.emission.to_array(),
+                texture: 0xFFFFFFFF,
+            };
+            GpuEmissiveTriangle::new(&triangle, &transform)
+        })
+    }
+}
+
+/// Traced by sphere.rint
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub radius: f32,
+    pub material: PrimitiveMaterial,
+}
+
+impl Default for Sphere {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            material: PrimitiveMaterial::default(),
+        }
+    }
+}
+
+impl ProceduralPrimitive for Sphere {
+    const KIND: PrimitiveKind = PrimitiveKind::Sphere;
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(Vec3::splat(self.radius * 2.0))
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// A box, traced by box.rint
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Cuboid {
+    pub half_extents: Vec3,
+    pub material: PrimitiveMaterial,
+}
+
+impl ProceduralPrimitive for Cuboid {
+    const KIND: PrimitiveKind = PrimitiveKind::Cuboid;
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(self.half_extents * 2.0)
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// A capped cylinder along the Y axis, traced by cylinder.rint
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Cylinder {
+    pub radius: f32,
+    pub half_height: f32,
+    pub material: PrimitiveMaterial,
+}
+
+impl ProceduralPrimitive for Cylinder {
+    const KIND: PrimitiveKind = PrimitiveKind::Cylinder;
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(Vec3::new(self.radius * 2.0, self.half_height * 2.0, self.radius * 2.0))
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// Flat shapes lie in the XZ plane and are seen from both sides, the box around them has to have
+/// some thickness
+const FLAT_AABB: AABB = AABB {
+    min_x: -0.5,
+    min_y: -1e-4,
+    min_z: -0.5,
+    max_x: 0.5,
+    max_y: 1e-4,
+    max_z: 0.5,
+};
+
+/// A rectangle facing +Y, traced by quad.rint. An emissive quad is an area light.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Quad {
+    pub half_size: Vec2,
+    pub material: PrimitiveMaterial,
+}
+
+impl ProceduralPrimitive for Quad {
+    const KIND: PrimitiveKind = PrimitiveKind::Quad;
+
+    fn aabb() -> AABB {
+        FLAT_AABB
+    }
+
+    fn light_triangles() -> &'static [[Vec3; 3]] {
+        const CORNERS: [Vec3; 4] = [
+            Vec3::new(-0.5, 0.0, -0.5),
+            Vec3::new(0.5, 0.0, -0.5),
+            Vec3::new(0.5, 0.0, 0.5),
+            Vec3::new(-0.5, 0.0, 0.5),
+        ];
+        const TRIANGLES: [[Vec3; 3]; 2] = [
+            [CORNERS[0], CORNERS[1], CORNERS[2]],
+            [CORNERS[0], CORNERS[2], CORNERS[3]],
+        ];
+        &TRIANGLES
+    }
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(Vec3::new(self.half_size.x * 2.0, 1.0, self.half_size.y * 2.0))
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// A circle facing +Y, traced by disk.rint. Emissive disks light the scene only where paths hit
+/// them, use a `Quad` for an area light.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Disk {
+    pub radius: f32,
+    pub material: PrimitiveMaterial,
+}
+
+impl ProceduralPrimitive for Disk {
+    const KIND: PrimitiveKind = PrimitiveKind::Disk;
+
+    fn aabb() -> AABB {
+        FLAT_AABB
+    }
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(Vec3::new(self.radius * 2.0, 1.0, self.radius * 2.0))
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// The signed distance field of the `.sdf` snippet in the scene's pipelines, sphere traced by the
+/// intersection shader compiled from it. The field is evaluated in the unit box around the origin,
+/// which is scaled uniformly so distances stay distances.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Sdf {
+    pub size: f32,
+    pub material: PrimitiveMaterial,
+}
+
+impl ProceduralPrimitive for Sdf {
+    const KIND: PrimitiveKind = PrimitiveKind::Sdf;
+
+    fn blas_transform(&self) -> Affine3A {
+        Affine3A::from_scale(Vec3::splat(self.size))
+    }
+
+    fn material(&self) -> &PrimitiveMaterial {
+        &self.material
+    }
+}
+
+/// Defaults to a mirror
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrimitiveMaterial {
+    pub color: Vec3,
+    pub roughness: f32,
+    pub metallic: f32,
+    pub emission: Vec3,
+    pub transmission: f32,
+    pub ior: f32,
+    /// Per unit of distance a ray travels inside
+    pub absorption: Vec3,
+}
+
+impl Default for PrimitiveMaterial {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ONE,
+            roughness: 0.0,
+            metallic: 1.0,
+            emission: Vec3::ZERO,
+            transmission: 0.0,
+            ior: 1.33,
+            absorption: Vec3::splat(1.5),
+        }
+    }
+}
+
+/// Matches `PrimitiveMaterial` in common.glsl, indexed by the custom index of the instance like
+/// `GpuInstanceMaterial`. Instances that aren't primitives have the default one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct GpuPrimitiveMaterial {
+    pub color: [f32; 3],
+    pub roughness: f32,
+    pub emission: [f32; 3],
+    pub metallic: f32,
+    pub absorption: [f32; 3],
+    pub transmission: f32,
+    pub ior: f32,
+    /// Like the one of the emissive triangles, 0 unless the light triangles of the primitive are
+    /// sampled
+    pub emissive_luminance: f32,
+}
+
+impl GpuPrimitiveMaterial {
+    pub fn new(material: &PrimitiveMaterial, sampled: bool) -> Self {
+        Self {
+            color: material.color.to_array(),
+            roughness: material.roughness,
+            emission: material.emission.to_array(),
+            metallic: material.metallic,
+            absorption: material.absorption.to_array(),
+            transmission: material.transmission,
+            ior: material.ior,
+            emissive_luminance: if sampled { luminance(material.emission) } else { 0.0 },
+        }
+    }
+}
+
+impl Default for GpuPrimitiveMaterial {
+    fn default() -> Self {
+        Self::new(&PrimitiveMaterial::default(), false)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct AABB {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub min_z: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+    pub max_z: f32,
+}
+
+impl Default for AABB {
+    fn default() -> Self {
+        Self {
+            min_x: -0.5,
+            min_y: -0.5,
+            min_z: -0.5,
+            max_x: 0.5,
+            max_y: 0.5,
+            max_z: 0.5,
+        }
+    }
+}
+
+impl AABB {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self {
+            min_x: min.x,
+            min_y: min.y,
+            min_z: min.z,
+            max_x: max.x,
+            max_y: max.y,
+            max_z: max.z,
+        }
+    }
+}
+
+/// A BLAS of a single AABB
+pub struct ProceduralBLAS {
+    pub aabb_buffer: Buffer<AABB>,
+    pub acceleration_structure: AccelerationStructure,
+}
+
+impl ProceduralBLAS {
+    pub fn get_reference(&self) -> vk::AccelerationStructureReferenceKHR {
+        self.acceleration_structure.get_reference()
+    }
+
+    pub fn make_one(aabb: &AABB, device: &RenderDevice) -> RenderResult<Self> {
+        let mut aabb_buffer_host: Buffer<AABB> = device.create_host_buffer(
+            1,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+        )?;
+
+        {
+            let mut aabb_buffer = device.map_buffer(&mut aabb_buffer_host);
+            aabb_buffer[0] = aabb.clone();
+        }
+
+        let aabb_buffer_device: Buffer<AABB> = device.create_device_buffer(
+            1,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST
+                | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+        )?;
+        let uploaded = device.run_asset_commands(|cmd_buffer| {
+            device.upload_buffer(cmd_buffer, &mut aabb_buffer_host, &aabb_buffer_device);
+        });
+
+        device.destroy_buffer(aabb_buffer_host);
+        uploaded?;
+
+        let geometry_info = vk::AccelerationStructureGeometryKHR::builder()
+            .flags(vk::GeometryFlagsKHR::OPAQUE)
+            .geometry_type(vk::GeometryTypeKHR::AABBS)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                aabbs: vk::AccelerationStructureGeometryAabbsDataKHR::builder()
+                    .stride(std::mem::size_of::<AABB>() as u64)
+                    .data(vk::DeviceOrHostAddressConstKHR {
+                        device_address: aabb_buffer_device.address,
+                    })
+                    .build(),
+            });
+
+        let combined_build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .geometries(std::slice::from_ref(&geometry_info));
+
+        let primitive_counts = [1];
+
+        let geometry_sizes = unsafe {
+            device.exts.rt_acc_struct.get_acceleration_structure_build_sizes(
+                vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                &combined_build_info,
+                &primitive_counts,
+            )
+        };
+
+        let mut acceleration_structure =
+            allocate_acceleration_structure(device, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL, &geometry_sizes)?;
+
+        let scratch_buffer: Buffer<u8> =
+            device.create_device_buffer(geometry_sizes.build_scratch_size, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+
+        let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .dst_acceleration_structure(acceleration_structure.handle)
+            .geometries(std::slice::from_ref(&geometry_info))
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_buffer.address,
+            })
+            .build();
+
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::builder()
+            .primitive_count(1)
+            // offset in bytes where the primitive data is defined
+            .primitive_offset(0)
+            .first_vertex(0)
+            .transform_offset(0)
+            .build();
+
+        let build_range_infos = std::slice::from_ref(&build_range_info);
+
+        let built = device.run_asset_commands(&|cmd_buffer| unsafe {
+            device.exts.rt_acc_struct.cmd_build_acceleration_structures(
+                cmd_buffer,
+                std::slice::from_ref(&build_geometry_info),
+                std::slice::from_ref(&build_range_infos),
+            );
+        });
+
+        device.destroy_buffer(scratch_buffer);
+        built?;
+
+        acceleration_structure.address = unsafe {
+            device.exts.rt_acc_struct.get_acceleration_structure_device_address(
+                &vk::AccelerationStructureDeviceAddressInfoKHR::builder()
+                    .acceleration_structure(acceleration_structure.handle)
+                    .build(),
+            )
+        };
+
+        Ok(Self {
+            aabb_buffer: aabb_buffer_device,
+            acceleration_structure,
+        })
+    }
+}
+
+/// The BLAS of every registered `PrimitiveKind`
+#[derive(Resource, Default)]
+pub struct ProceduralBlasses(HashMap<PrimitiveKind, ProceduralBLAS>);
+
+impl ProceduralBlasses {
+    pub fn get(&self, kind: PrimitiveKind) -> Option<&ProceduralBLAS> {
+        self.0.get(&kind)
+    }
+}
+
+pub trait ProceduralPrimitiveAppExtension {
+    fn add_procedural_primitive<T: ProceduralPrimitive>(&mut self) -> &mut Self;
+}
+
+impl ProceduralPrimitiveAppExtension for App {
+    fn add_procedural_primitive<T: ProceduralPrimitive>(&mut self) -> &mut Self {
+        let device = self.world.get_resource::<RenderDevice>().unwrap().clone();
+        let blas = ProceduralBLAS::make_one(&T::aabb(), &device).unwrap();
+        self.world
+            .get_resource_or_insert_with(ProceduralBlasses::default)
+            .0
+            .insert(T::KIND, blas);
+
+        // the instances are read by the render schedule in the next frame's update
+        self.add_system(sync_primitive::<T>.in_base_set(CoreSet::PostUpdate));
+        self
+    }
+}
+
+fn sync_primitive<T: ProceduralPrimitive>(
+    mut commands: Commands,
+    changed: Query<(Entity, &T), Changed<T>>,
+    mut removed: RemovedComponents<T>,
+) {
+    for (entity, primitive) in changed.iter() {
+        commands.entity(entity).insert(PrimitiveInstance::new(primitive));
+    }
+    for entity in removed.iter() {
+        if let Some(mut entity) = commands.get_entity(entity) {
+            entity.remove::<PrimitiveInstance>();
+        }
+    }
+}
+
+pub struct ProceduralPlugin;
+
+impl Plugin for ProceduralPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_procedural_primitive::<Sphere>()
+            .add_procedural_primitive::<Cuboid>()
+            .add_procedural_primitive::<Cylinder>()
+            .add_procedural_primitive::<Quad>()
+            .add_procedural_primitive::<Disk>()
+            .add_procedural_primitive::<Sdf>();
+
+        app.world
+            .get_resource_mut::<VkAssetCleanupPlaybook>()
+            .unwrap()
+            .add_system(cleanup_procedural_blasses);
+    }
+}
+
+fn cleanup_procedural_blasses(blasses: Res<ProceduralBlasses>, cleanup: Res<VkCleanup>) {
+    for blas in blasses.0.values() {
+        cleanup.send(VkCleanupEvent::AccelerationStructure(
+            blas.acceleration_structure.handle,
+        ));
+        cleanup.send(VkCleanupEvent::Buffer(blas.acceleration_structure.buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(blas.aabb_buffer.handle));
+    }
+}
@@ -6,6 +6,8 @@ use bytemuck::{Pod, Zeroable};
 
 use crate::composed_asset::{ComposedAsset, ComposedAssetAppExtension};
 use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::render_plugin::FRAMES_IN_FLIGHT;
 use crate::shader::{Shader, ShaderProvider};
 use crate::vulkan_assets::{AddVulkanAsset, VulkanAsset};
 use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
@@ -21,6 +23,9 @@ pub struct RasterizationPipeline {
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct RasterizationRegisters {
     pub uniforms: u64,
+    /// Non zero when the target is sRGB encoded on write, `quad.frag` then outputs linear values
+    pub srgb_target: u32,
+    pub _padding: u32,
 }
 
 impl ComposedAsset for RasterizationPipeline {
@@ -45,7 +50,7 @@ impl VulkanAsset for RasterizationPipeline {
         Some((vs_shader.clone(), fs_shader.clone()))
     }
 
-    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset {
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
         let (vs_shader, fs_shader) = asset;
         println!("creating rasterization pipeline");
         create_rast_pipeline(&device, &vs_shader, &fs_shader)
@@ -74,10 +79,10 @@ impl Plugin for RasterizationPipelinePlugin {
     }
 }
 
-fn create_rast_pipeline(device: &RenderDevice, vs: &Shader, fs: &Shader) -> VkRasterizationPipeline {
+fn create_rast_pipeline(device: &RenderDevice, vs: &Shader, fs: &Shader) -> RenderResult<VkRasterizationPipeline> {
     let shader_stages = [
-        device.load_shader(&vs, vk::ShaderStageFlags::VERTEX),
-        device.load_shader(&fs, vk::ShaderStageFlags::FRAGMENT),
+        device.load_shader(&vs, vk::ShaderStageFlags::VERTEX)?,
+        device.load_shader(&fs, vk::ShaderStageFlags::FRAGMENT)?,
     ];
 
     let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default();
@@ -111,7 +116,7 @@ fn create_rast_pipeline(device: &RenderDevice, vs: &Shader, fs: &Shader) -> VkRa
     let color_blending =
         vk::PipelineColorBlendStateCreateInfo::builder().attachments(std::slice::from_ref(&color_blend_attachment));
 
-    let (descriptor_set_layout, descriptor_sets) = create_rast_descriptor_data(device);
+    let (descriptor_set_layout, descriptor_sets) = create_rast_descriptor_data(device)?;
 
     let push_constant_info = vk::PushConstantRange::builder()
         .stage_flags(vk::ShaderStageFlags::FRAGMENT)
@@ -121,7 +126,7 @@ fn create_rast_pipeline(device: &RenderDevice, vs: &Shader, fs: &Shader) -> VkRa
     let layout_info = vk::PipelineLayoutCreateInfo::builder()
         .set_layouts(std::slice::from_ref(&descriptor_set_layout))
         .push_constant_ranges(std::slice::from_ref(&push_constant_info));
-    let pipeline_layout = unsafe { device.device.create_pipeline_layout(&layout_info, None) }.unwrap();
+    let pipeline_layout = unsafe { device.device.create_pipeline_layout(&layout_info, None) }?;
 
     let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
         .stages(&shader_stages)
@@ -138,23 +143,25 @@ fn create_rast_pipeline(device: &RenderDevice, vs: &Shader, fs: &Shader) -> VkRa
         device
             .device
             .create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info.build()], None)
-    }
-    .unwrap()[0];
+    };
 
     unsafe {
         device.device.destroy_shader_module(shader_stages[0].module, None);
         device.device.destroy_shader_module(shader_stages[1].module, None);
     }
+    let pipeline = pipeline.map_err(|(_, result)| result)?[0];
 
-    VkRasterizationPipeline {
+    Ok(VkRasterizationPipeline {
         vk_pipeline: pipeline,
         pipeline_layout,
         descriptor_set_layout,
         descriptor_sets,
-    }
+    })
 }
 
-fn create_rast_descriptor_data(device: &RenderDevice) -> (vk::DescriptorSetLayout, Vec<vk::DescriptorSet>) {
+fn create_rast_descriptor_data(
+    device: &RenderDevice,
+) -> RenderResult<(vk::DescriptorSetLayout, Vec<vk::DescriptorSet>)> {
     let sampler_layout_binding = vk::DescriptorSetLayoutBinding::builder()
         .binding(0)
         .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
@@ -165,19 +172,16 @@ fn create_rast_descriptor_data(device: &RenderDevice) -> (vk::DescriptorSetLayou
     let layout_info =
         vk::DescriptorSetLayoutCreateInfo::builder().bindings(std::slice::from_ref(&sampler_layout_binding));
 
-    let layout = unsafe { device.device.create_descriptor_set_layout(&layout_info, None).unwrap() };
+    let layout = unsafe { device.device.create_descriptor_set_layout(&layout_info, None) }?;
 
-    let layouts = [layout, layout];
+    let layouts = [layout; FRAMES_IN_FLIGHT];
     let sets = unsafe {
-        device
-            .device
-            .allocate_descriptor_sets(
-                &vk::DescriptorSetAllocateInfo::builder()
-                    .descriptor_pool(device.descriptor_pool)
-                    .set_layouts(&layouts),
-            )
-            .unwrap()
-    };
-
-    (layout, sets)
+        device.device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(device.descriptor_pool)
+                .set_layouts(&layouts),
+        )
+    }?;
+
+    Ok((layout, sets))
 }
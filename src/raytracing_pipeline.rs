@@ -6,6 +6,8 @@ use bytemuck_derive::{Pod, Zeroable};
 
 use crate::composed_asset::{ComposedAsset, ComposedAssetAppExtension};
 use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::render_plugin::FRAMES_IN_FLIGHT;
 use crate::shader::{Shader, ShaderProvider};
 use crate::shader_binding_table::RTGroupHandle;
 use crate::vk_utils;
@@ -19,31 +21,40 @@ pub struct RaytracerRegisters {
     pub query_buffer_address: u64,
 }
 
+/// procedural.rchit reads the uniforms too, for its `PrimitiveMaterial`
+pub const RAYTRACER_REGISTER_STAGES: vk::ShaderStageFlags = vk::ShaderStageFlags::from_raw(
+    vk::ShaderStageFlags::RAYGEN_KHR.as_raw() | vk::ShaderStageFlags::CLOSEST_HIT_KHR.as_raw(),
+);
+
 #[derive(TypeUuid)]
 #[uuid = "a0b0c0d0-e0f0-11ea-87d0-0242ac130003"]
 pub struct RaytracingPipeline {
     pub raygen_shader: Handle<Shader>,
     pub miss_shader: Handle<Shader>,
     pub triangle_hit_shader: Handle<Shader>,
-    pub sphere_int_shader: Handle<Shader>,
-    pub sphere_hit_shader: Handle<Shader>,
+    pub triangle_any_hit_shader: Handle<Shader>,
+    pub procedural_hit_shader: Handle<Shader>,
+    /// One for every `PrimitiveKind`, in the order of `PrimitiveKind::ALL`
+    pub procedural_int_shaders: Vec<Handle<Shader>>,
 }
 
 impl ComposedAsset for RaytracingPipeline {
     type DepType = Shader;
     fn get_deps(&self) -> Vec<&Handle<Self::DepType>> {
-        vec![
+        let mut deps = vec![
             &self.raygen_shader,
             &self.triangle_hit_shader,
+            &self.triangle_any_hit_shader,
             &self.miss_shader,
-            &self.sphere_int_shader,
-            &self.sphere_hit_shader,
-        ]
+            &self.procedural_hit_shader,
+        ];
+        deps.extend(&self.procedural_int_shaders);
+        deps
     }
 }
 
 impl VulkanAsset for RaytracingPipeline {
-    type ExtractedAsset = (Shader, Shader, Shader, Shader, Shader);
+    type ExtractedAsset = (Shader, Shader, Shader, Shader, Shader, Vec<Shader>);
     type PreparedAsset = VkRaytracingPipeline;
     type ExtractParam = SRes<Assets<Shader>>;
 
@@ -54,28 +65,42 @@ impl VulkanAsset for RaytracingPipeline {
         let raygen_shader = shaders.get(&self.raygen_shader)?;
         let miss_shader = shaders.get(&self.miss_shader)?;
         let triangle_hit_shader = shaders.get(&self.triangle_hit_shader)?;
-        let sphere_int_shader = shaders.get(&self.sphere_int_shader)?;
-        let sphere_hit_shader = shaders.get(&self.sphere_hit_shader)?;
+        let triangle_any_hit_shader = shaders.get(&self.triangle_any_hit_shader)?;
+        let procedural_hit_shader = shaders.get(&self.procedural_hit_shader)?;
+        let procedural_int_shaders = self
+            .procedural_int_shaders
+            .iter()
+            .map(|shader| shaders.get(shader).cloned())
+            .collect::<Option<Vec<_>>>()?;
         Some((
             raygen_shader.clone(),
             triangle_hit_shader.clone(),
+            triangle_any_hit_shader.clone(),
             miss_shader.clone(),
-            sphere_int_shader.clone(),
-            sphere_hit_shader.clone(),
+            procedural_hit_shader.clone(),
+            procedural_int_shaders,
         ))
     }
 
-    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset {
-        let (raygen_shader, triangle_hit_shader, miss_shader, sphere_int_shader, sphere_hit_shader) = asset;
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
+        let (
+            raygen_shader,
+            triangle_hit_shader,
+            triangle_any_hit_shader,
+            miss_shader,
+            procedural_hit_shader,
+            procedural_int_shaders,
+        ) = asset;
         println!("creating RT pipeline");
         let (descriptor_set_layout, pipeline_layout, vk_pipeline) = create_raytracing_pipeline(
             &device,
             &raygen_shader,
             &triangle_hit_shader,
+            &triangle_any_hit_shader,
             &miss_shader,
-            &sphere_int_shader,
-            &sphere_hit_shader,
-        );
+            &procedural_hit_shader,
+            &procedural_int_shaders,
+        )?;
 
         let rtprops = vk_utils::get_raytracing_properties(&device);
         let handle_size = rtprops.shader_group_handle_size;
@@ -84,14 +109,13 @@ impl VulkanAsset for RaytracingPipeline {
             "at the time we only support 128-bit handles (at time of writing all devices have this)"
         );
 
-        let handle_count = 4;
+        let handle_count = 3 + procedural_int_shaders.len() as u32;
         let handle_data_size = handle_count * handle_size;
         let handles: Vec<RTGroupHandle> = unsafe {
             device
                 .exts
                 .rt_pipeline
-                .get_ray_tracing_shader_group_handles(vk_pipeline, 0, handle_count, handle_data_size as usize)
-                .unwrap()
+                .get_ray_tracing_shader_group_handles(vk_pipeline, 0, handle_count, handle_data_size as usize)?
                 .chunks(handle_size as usize)
                 .map(|chunk| {
                     let mut handle = RTGroupHandle::default();
@@ -101,20 +125,15 @@ impl VulkanAsset for RaytracingPipeline {
                 .collect()
         };
 
-        let layouts = [descriptor_set_layout, descriptor_set_layout];
+        let layouts = [descriptor_set_layout; FRAMES_IN_FLIGHT];
         let descriptor_set_alloc_info = vk::DescriptorSetAllocateInfo::builder()
             .descriptor_pool(device.descriptor_pool)
             .set_layouts(&layouts)
             .build();
 
-        let descriptor_sets = unsafe {
-            device
-                .device
-                .allocate_descriptor_sets(&descriptor_set_alloc_info)
-                .unwrap()
-        };
+        let descriptor_sets = unsafe { device.device.allocate_descriptor_sets(&descriptor_set_alloc_info)? };
 
-        VkRaytracingPipeline {
+        Ok(VkRaytracingPipeline {
             vk_pipeline,
             pipeline_layout,
             descriptor_set_layout,
@@ -122,8 +141,8 @@ impl VulkanAsset for RaytracingPipeline {
             raygen_handle: handles[0],
             miss_handle: handles[1],
             triangle_hit_handle: handles[2],
-            sphere_hit_handle: handles[3],
-        }
+            procedural_hit_handles: handles[3..].to_vec(),
+        })
     }
 
     fn destroy_asset(asset: Self::PreparedAsset, cleanup: &VkCleanup) {
@@ -141,7 +160,8 @@ pub struct VkRaytracingPipeline {
     pub raygen_handle: RTGroupHandle,
     pub miss_handle: RTGroupHandle,
     pub triangle_hit_handle: RTGroupHandle,
-    pub sphere_hit_handle: RTGroupHandle,
+    /// Indexed like `RaytracingPipeline::procedural_int_shaders`
+    pub procedural_hit_handles: Vec<RTGroupHandle>,
 }
 
 pub struct RaytracingPlugin;
@@ -157,10 +177,11 @@ fn create_raytracing_pipeline(
     device: &RenderDevice,
     raygen_shader: &Shader,
     triangle_hit_shader: &Shader,
+    triangle_any_hit_shader: &Shader,
     miss_shader: &Shader,
-    sphere_int_shader: &Shader,
-    sphere_hit_shader: &Shader,
-) -> (vk::DescriptorSetLayout, vk::PipelineLayout, vk::Pipeline) {
+    procedural_hit_shader: &Shader,
+    procedural_int_shaders: &[Shader],
+) -> RenderResult<(vk::DescriptorSetLayout, vk::PipelineLayout, vk::Pipeline)> {
     let bindings = [
         vk::DescriptorSetLayoutBinding::builder()
             .binding(0)
@@ -178,7 +199,20 @@ fn create_raytracing_pipeline(
             .binding(2)
             .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
             .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::MISS_KHR)
+            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+            .build(),
+        // first hit albedo and normal/depth for the denoiser
+        vk::DescriptorSetLayoutBinding::builder()
+            .binding(3)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+            .build(),
+        vk::DescriptorSetLayoutBinding::builder()
+            .binding(4)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
             .build(),
     ];
 
@@ -187,13 +221,12 @@ fn create_raytracing_pipeline(
     let descriptor_set_layout = unsafe {
         device
             .device
-            .create_descriptor_set_layout(&descriptor_set_layout_info, None)
-            .unwrap()
+            .create_descriptor_set_layout(&descriptor_set_layout_info, None)?
     };
 
     let all_descriptor_set_layouts = [descriptor_set_layout, device.g_descriptor_set_layout];
     let push_constant_info = vk::PushConstantRange::builder()
-        .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+        .stage_flags(RAYTRACER_REGISTER_STAGES)
         .offset(0)
         .size(std::mem::size_of::<RaytracerRegisters>() as u32)
         .build();
@@ -202,18 +235,13 @@ fn create_raytracing_pipeline(
         .set_layouts(&all_descriptor_set_layouts)
         .push_constant_ranges(std::slice::from_ref(&push_constant_info));
 
-    let pipeline_layout = unsafe {
-        device
-            .device
-            .create_pipeline_layout(&pipeline_layout_info, None)
-            .unwrap()
-    };
+    let pipeline_layout = unsafe { device.device.create_pipeline_layout(&pipeline_layout_info, None)? };
 
     let mut shader_stages: Vec<vk::PipelineShaderStageCreateInfo> = Vec::new();
     let mut shader_groups: Vec<vk::RayTracingShaderGroupCreateInfoKHR> = Vec::new();
 
     {
-        shader_stages.push(device.load_shader(raygen_shader, vk::ShaderStageFlags::RAYGEN_KHR));
+        shader_stages.push(device.load_shader(raygen_shader, vk::ShaderStageFlags::RAYGEN_KHR)?);
         shader_groups.push(
             vk::RayTracingShaderGroupCreateInfoKHR::builder()
                 .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
@@ -226,7 +254,7 @@ fn create_raytracing_pipeline(
     }
 
     {
-        shader_stages.push(device.load_shader(miss_shader, vk::ShaderStageFlags::MISS_KHR));
+        shader_stages.push(device.load_shader(miss_shader, vk::ShaderStageFlags::MISS_KHR)?);
         shader_groups.push(
             vk::RayTracingShaderGroupCreateInfoKHR::builder()
                 .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
@@ -239,28 +267,36 @@ fn create_raytracing_pipeline(
     }
 
     {
-        shader_stages.push(device.load_shader(triangle_hit_shader, vk::ShaderStageFlags::CLOSEST_HIT_KHR));
+        // the any-hit shader only runs for geometries without the OPAQUE flag, i.e. alpha tested
+        // and blended materials
+        shader_stages.push(device.load_shader(triangle_hit_shader, vk::ShaderStageFlags::CLOSEST_HIT_KHR)?);
+        shader_stages.push(device.load_shader(triangle_any_hit_shader, vk::ShaderStageFlags::ANY_HIT_KHR)?);
         shader_groups.push(
             vk::RayTracingShaderGroupCreateInfoKHR::builder()
                 .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
                 .general_shader(vk::SHADER_UNUSED_KHR)
-                .closest_hit_shader(shader_stages.len() as u32 - 1)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .closest_hit_shader(shader_stages.len() as u32 - 2)
+                .any_hit_shader(shader_stages.len() as u32 - 1)
                 .intersection_shader(vk::SHADER_UNUSED_KHR)
                 .build(),
         );
 
-        shader_stages.push(device.load_shader(sphere_int_shader, vk::ShaderStageFlags::INTERSECTION_KHR));
-        shader_stages.push(device.load_shader(sphere_hit_shader, vk::ShaderStageFlags::CLOSEST_HIT_KHR));
-        shader_groups.push(
-            vk::RayTracingShaderGroupCreateInfoKHR::builder()
-                .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
-                .general_shader(vk::SHADER_UNUSED_KHR)
-                .closest_hit_shader(shader_stages.len() as u32 - 1)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
-                .intersection_shader(shader_stages.len() as u32 - 2)
-                .build(),
-        );
+        // the procedural primitives share their closest-hit shader, their intersection shaders
+        // report the object space normal it needs
+        shader_stages.push(device.load_shader(procedural_hit_shader, vk::ShaderStageFlags::CLOSEST_HIT_KHR)?);
+        let procedural_hit_stage = shader_stages.len() as u32 - 1;
+        for procedural_int_shader in procedural_int_shaders {
+            shader_stages.push(device.load_shader(procedural_int_shader, vk::ShaderStageFlags::INTERSECTION_KHR)?);
+            shader_groups.push(
+                vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
+                    .general_shader(vk::SHADER_UNUSED_KHR)
+                    .closest_hit_shader(procedural_hit_stage)
+                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .intersection_shader(shader_stages.len() as u32 - 1)
+                    .build(),
+            );
+        }
     }
 
     let pipeline_info = vk::RayTracingPipelineCreateInfoKHR::builder()
@@ -270,16 +306,12 @@ fn create_raytracing_pipeline(
         .layout(pipeline_layout);
 
     let pipeline = unsafe {
-        device
-            .exts
-            .rt_pipeline
-            .create_ray_tracing_pipelines(
-                vk::DeferredOperationKHR::null(),
-                vk::PipelineCache::null(),
-                std::slice::from_ref(&pipeline_info),
-                None,
-            )
-            .unwrap()[0]
+        device.exts.rt_pipeline.create_ray_tracing_pipelines(
+            vk::DeferredOperationKHR::null(),
+            vk::PipelineCache::null(),
+            std::slice::from_ref(&pipeline_info),
+            None,
+        )
     };
 
     for stage in shader_stages {
@@ -287,6 +319,7 @@ fn create_raytracing_pipeline(
             device.device.destroy_shader_module(stage.module, None);
         }
     }
+    let pipeline = pipeline?[0];
 
-    (descriptor_set_layout, pipeline_layout, pipeline)
+    Ok((descriptor_set_layout, pipeline_layout, pipeline))
 }
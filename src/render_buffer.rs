@@ -4,6 +4,7 @@ use gpu_allocator::*;
 use std::ops::{Index, IndexMut};
 
 use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
 
 pub struct Buffer<T> {
     pub nr_elements: u64,
@@ -56,11 +57,16 @@ impl<'a, T> IndexMut<usize> for BufferView<T> {
 }
 
 pub trait BufferProvider {
-    fn create_host_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> Buffer<T>;
+    fn create_host_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> RenderResult<Buffer<T>>;
 
-    fn create_device_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> Buffer<T>;
+    fn create_device_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> RenderResult<Buffer<T>>;
 
-    fn create_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags, location: MemoryLocation) -> Buffer<T>;
+    fn create_buffer<T>(
+        &self,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> RenderResult<Buffer<T>>;
 
     fn upload_buffer<T>(&self, cmd_buffer: vk::CommandBuffer, host_buffer: &Buffer<T>, device_buffer: &Buffer<T>);
 
@@ -70,7 +76,7 @@ pub trait BufferProvider {
 }
 
 impl BufferProvider for RenderDevice {
-    fn create_host_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> Buffer<T> {
+    fn create_host_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> RenderResult<Buffer<T>> {
         self.create_buffer(
             size,
             usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
@@ -78,7 +84,7 @@ impl BufferProvider for RenderDevice {
         )
     }
 
-    fn create_device_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> Buffer<T> {
+    fn create_device_buffer<T>(&self, size: u64, usage: vk::BufferUsageFlags) -> RenderResult<Buffer<T>> {
         self.create_buffer(
             size,
             usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
@@ -86,40 +92,53 @@ impl BufferProvider for RenderDevice {
         )
     }
 
-    fn create_buffer<T>(&self, nr_elements: u64, usage: vk::BufferUsageFlags, location: MemoryLocation) -> Buffer<T> {
+    fn create_buffer<T>(
+        &self,
+        nr_elements: u64,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> RenderResult<Buffer<T>> {
         if nr_elements == 0 {
-            return Buffer {
+            return Ok(Buffer {
                 nr_elements,
                 usage,
                 handle: vk::Buffer::null(),
                 address: 0,
                 marker: std::marker::PhantomData,
-            };
+            });
         }
         let buffer_info = vk::BufferCreateInfo::builder()
             .size(nr_elements * std::mem::size_of::<T>() as u64)
             .usage(usage);
 
-        let handle = unsafe { self.device.create_buffer(&buffer_info, None).unwrap() };
+        let handle = unsafe { self.device.create_buffer(&buffer_info, None)? };
         let requirements = unsafe { self.device.get_buffer_memory_requirements(handle) };
 
         {
             let mut alloc_impl = self.write_alloc();
-            let allocation = alloc_impl
-                .allocator
-                .allocate(&AllocationCreateDesc {
-                    name: "",
-                    requirements,
-                    location,
-                    linear: true,
-                    allocation_scheme: AllocationScheme::GpuAllocatorManaged,
-                })
-                .unwrap();
-
-            unsafe {
+            let allocation = alloc_impl.allocator.allocate(&AllocationCreateDesc {
+                name: "",
+                requirements,
+                location,
+                linear: true,
+                allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+            });
+            let allocation = match allocation {
+                Ok(allocation) => allocation,
+                Err(e) => {
+                    unsafe { self.device.destroy_buffer(handle, None) };
+                    return Err(e.into());
+                }
+            };
+
+            let bound = unsafe {
                 self.device
                     .bind_buffer_memory(handle, allocation.memory(), allocation.offset())
-                    .unwrap();
+            };
+            if let Err(e) = bound {
+                alloc_impl.allocator.free(allocation).unwrap();
+                unsafe { self.device.destroy_buffer(handle, None) };
+                return Err(e.into());
             }
 
             alloc_impl.buffer_to_allocation.insert(handle, allocation);
@@ -130,13 +149,13 @@ impl BufferProvider for RenderDevice {
                 .get_buffer_device_address(&vk::BufferDeviceAddressInfo::builder().buffer(handle).build())
         };
 
-        Buffer {
+        Ok(Buffer {
             handle,
             nr_elements,
             usage,
             address,
             marker: std::marker::PhantomData,
-        }
+        })
     }
 
     fn upload_buffer<T>(&self, cmd_buffer: vk::CommandBuffer, host_buffer: &Buffer<T>, device_buffer: &Buffer<T>) {
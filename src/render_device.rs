@@ -1,4 +1,5 @@
 use ash::extensions::khr;
+use ash::prelude::VkResult;
 use ash::vk::Handle;
 use ash::{vk, Device, Entry, Instance};
 use bevy::prelude::*;
@@ -9,6 +10,9 @@ use gpu_allocator::AllocatorDebugSettings;
 use std::ffi::{c_char, CStr};
 use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::physical_device::{describe_device, required_extensions, select_device, GpuSelection};
+use crate::render_error::{RenderError, RenderResult};
+
 const MAX_BINDLESS_IMAGES: u32 = 16536;
 const BINDLESS_IMAGES_BINDING: u32 = 16;
 
@@ -16,9 +20,14 @@ const BINDLESS_IMAGES_BINDING: u32 = 16;
 pub struct RenderDevice(Arc<RenderDeviceImpl>);
 
 impl RenderDevice {
-    pub fn from_window(window: &RawHandleWrapper) -> Self {
-        let device = Arc::new(RenderDeviceImpl::from_window(window));
-        Self(device)
+    pub fn from_window(window: &RawHandleWrapper, selection: Option<&GpuSelection>) -> RenderResult<Self> {
+        let device = Arc::new(RenderDeviceImpl::new(Some(window), selection)?);
+        Ok(Self(device))
+    }
+
+    pub fn headless(selection: Option<&GpuSelection>) -> RenderResult<Self> {
+        let device = Arc::new(RenderDeviceImpl::new(None, selection)?);
+        Ok(Self(device))
     }
 }
 
@@ -29,10 +38,48 @@ pub struct AllocImpl {
 }
 
 pub struct GDescriptorMap {
-    pub g_descriptor_map: HashMap<vk::ImageView, u32>,
+    pub g_descriptor_map: HashMap<(vk::ImageView, vk::Sampler), u32>,
     pub g_descriptor_idx_gen: u32,
 }
 
+/// The sampler state a texture asks for, samplers are created once per unique combination.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerInfo {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    /// 0.25 for the filters without mipmaps, which keeps them on the first level
+    pub max_lod: f32,
+}
+
+impl Eq for SamplerInfo {}
+
+impl std::hash::Hash for SamplerInfo {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.mag_filter.hash(state);
+        self.min_filter.hash(state);
+        self.mipmap_mode.hash(state);
+        self.address_mode_u.hash(state);
+        self.address_mode_v.hash(state);
+        self.max_lod.to_bits().hash(state);
+    }
+}
+
+impl Default for SamplerInfo {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            max_lod: vk::LOD_CLAMP_NONE,
+        }
+    }
+}
+
 impl Drop for AllocImpl {
     fn drop(&mut self) {
         if !self.buffer_to_allocation.is_empty() {
@@ -62,6 +109,7 @@ pub struct RenderDeviceImpl {
     pub single_time_fence: vk::Fence,
     pub nearest_sampler: vk::Sampler,
     pub linear_sampler: vk::Sampler,
+    pub samplers: Mutex<HashMap<SamplerInfo, vk::Sampler>>,
     pub alloc: Option<RwLock<AllocImpl>>,
 }
 
@@ -74,9 +122,11 @@ pub struct Exts {
 }
 
 impl RenderDeviceImpl {
-    pub fn from_window(window: &RawHandleWrapper) -> Self {
+    /// Creates the device, when no window is given no surface or swapchain support is requested.
+    /// Renders on the best device that supports ray tracing unless `selection` picks one.
+    pub fn new(window: Option<&RawHandleWrapper>, selection: Option<&GpuSelection>) -> RenderResult<Self> {
         unsafe {
-            let entry = Entry::load().unwrap();
+            let entry = Entry::load().map_err(|e| RenderError::Init(format!("failed to load Vulkan: {}", e)))?;
             let app_name = CStr::from_bytes_with_nul_unchecked(b"VK RAYS\0");
 
             let mut layer_names: Vec<&CStr> = Vec::new();
@@ -86,16 +136,19 @@ impl RenderDeviceImpl {
 
             println!("Validation layers:");
             for layer_name in layer_names.iter() {
-                println!("  - {}", layer_name.to_str().unwrap());
+                println!("  - {}", layer_name.to_string_lossy());
             }
 
             let layers_names_raw: Vec<*const c_char> = layer_names.iter().map(|raw_name| raw_name.as_ptr()).collect();
 
-            let instance_extensions = ash_window::enumerate_required_extensions(window.display_handle).unwrap();
+            let instance_extensions = match window {
+                Some(window) => ash_window::enumerate_required_extensions(window.display_handle)?,
+                None => &[],
+            };
 
             println!("Instance extensions:");
             for extension_name in instance_extensions.iter() {
-                println!("  - {}", CStr::from_ptr(*extension_name).to_str().unwrap());
+                println!("  - {}", CStr::from_ptr(*extension_name).to_string_lossy());
             }
 
             let app_info = vk::ApplicationInfo::builder()
@@ -110,71 +163,64 @@ impl RenderDeviceImpl {
                 .enabled_layer_names(&layers_names_raw)
                 .enabled_extension_names(&instance_extensions);
 
-            let instance = entry.create_instance(&instance_info, None).unwrap();
+            let instance = entry.create_instance(&instance_info, None)?;
 
             let ext_surface = khr::Surface::new(&entry, &instance);
-            let surface =
-                ash_window::create_surface(&entry, &instance, window.display_handle, window.window_handle, None)
-                    .unwrap();
-
-            let all_devices = instance.enumerate_physical_devices().unwrap();
+            let surface = window
+                .map(|window| {
+                    ash_window::create_surface(&entry, &instance, window.display_handle, window.window_handle, None)
+                })
+                .transpose()?;
+
+            let all_devices = instance.enumerate_physical_devices()?;
+            let descriptions = all_devices
+                .iter()
+                .map(|&d| {
+                    describe_device(&instance, d, |family| match surface {
+                        // a family that can't be queried can't be presented from either
+                        Some(surface) => ext_surface
+                            .get_physical_device_surface_support(d, family, surface)
+                            .unwrap_or(false),
+                        None => true,
+                    })
+                })
+                .collect::<VkResult<Vec<_>>>()?;
             println!("Available devices:");
-            for device in all_devices.iter() {
-                let info = instance.get_physical_device_properties(*device);
-                println!("  - {}", CStr::from_ptr(info.device_name.as_ptr()).to_str().unwrap());
+            for (i, description) in descriptions.iter().enumerate() {
+                println!("  {}: {}", i, description.name);
             }
 
-            let (physical_device, queue_family_idx) = instance
-                .enumerate_physical_devices()
-                .unwrap()
-                .into_iter()
-                .find_map(|d| {
-                    let info = instance.get_physical_device_properties(d);
-                    if !CStr::from_ptr(info.device_name.as_ptr())
-                        .to_str()
-                        .unwrap()
-                        .contains("NVIDIA")
-                    {
-                        return None;
+            let extensions = required_extensions(window.is_some());
+            // the devices are listed above, the error says why none of them will do
+            let device_idx = match select_device(&descriptions, &extensions, selection) {
+                Ok(device_idx) => device_idx,
+                Err(e) => {
+                    if let Some(surface) = surface {
+                        ext_surface.destroy_surface(surface, None);
                     }
+                    instance.destroy_instance(None);
+                    return Err(RenderError::Init(e));
+                }
+            };
+            let physical_device = all_devices[device_idx];
+            // selection only picks devices with a queue family
+            let queue_family_idx = descriptions[device_idx].queue_family.unwrap();
 
-                    let properties = instance.get_physical_device_queue_family_properties(d);
-                    properties.iter().enumerate().find_map(|(i, p)| {
-                        if p.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                            && ext_surface
-                                .get_physical_device_surface_support(d, i as u32, surface)
-                                .unwrap()
-                        {
-                            Some((d, i as u32))
-                        } else {
-                            None
-                        }
-                    })
-                })
-                .unwrap();
-
-            ext_surface.destroy_surface(surface, None);
+            if let Some(surface) = surface {
+                ext_surface.destroy_surface(surface, None);
+            }
 
             let device_properties = instance.get_physical_device_properties(physical_device);
             println!(
                 "Running on device: {}",
-                CStr::from_ptr(device_properties.device_name.as_ptr()).to_str().unwrap()
+                CStr::from_ptr(device_properties.device_name.as_ptr()).to_string_lossy()
             );
 
-            let device_extensions = [
-                khr::Swapchain::name().as_ptr(),
-                khr::Synchronization2::name().as_ptr(),
-                khr::Maintenance4::name().as_ptr(),
-                khr::AccelerationStructure::name().as_ptr(),
-                khr::RayTracingPipeline::name().as_ptr(),
-                khr::DeferredHostOperations::name().as_ptr(),
-                vk::KhrSpirv14Fn::name().as_ptr(),
-                vk::ExtDescriptorIndexingFn::name().as_ptr(),
-            ];
+            let device_extensions = extensions.iter().map(|name| name.as_ptr()).collect::<Vec<_>>();
 
             println!("Device extensions:");
             for extension_name in device_extensions.iter() {
-                println!("  - {}", CStr::from_ptr(*extension_name).to_str().unwrap());
+                println!("  - {}", CStr::from_ptr(*extension_name).to_string_lossy());
             }
 
             let queue_info = vk::DeviceQueueCreateInfo::builder()
@@ -213,9 +259,16 @@ impl RenderDeviceImpl {
                 .ray_tracing_pipeline(true)
                 .build();
 
+            // block compressed KTX2 textures are only usable when the device supports them
+            let supported_features = instance.get_physical_device_features(physical_device);
+            let features = vk::PhysicalDeviceFeatures::builder()
+                .texture_compression_bc(supported_features.texture_compression_bc == vk::TRUE)
+                .build();
+
             let device_info = vk::DeviceCreateInfo::builder()
                 .queue_create_infos(std::slice::from_ref(&queue_info))
                 .enabled_extension_names(&device_extensions)
+                .enabled_features(&features)
                 .push_next(&mut sync2_info)
                 .push_next(&mut bda_info)
                 .push_next(&mut maintaince4_info)
@@ -224,15 +277,15 @@ impl RenderDeviceImpl {
                 .push_next(&mut features_acceleration_structure)
                 .push_next(&mut features_raytracing_pipeline);
 
-            let device = instance.create_device(physical_device, &device_info, None).unwrap();
+            let device = instance.create_device(physical_device, &device_info, None)?;
             let queue = device.get_device_queue(queue_family_idx, 0);
 
             let pool_info = vk::CommandPoolCreateInfo::builder()
                 .queue_family_index(queue_family_idx)
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
 
-            let command_pool = device.create_command_pool(&pool_info, None).unwrap();
-            let asset_command_pool = device.create_command_pool(&pool_info, None).unwrap();
+            let command_pool = device.create_command_pool(&pool_info, None)?;
+            let asset_command_pool = device.create_command_pool(&pool_info, None)?;
             let alloc_info = vk::CommandBufferAllocateInfo::builder()
                 .command_pool(command_pool)
                 .level(vk::CommandBufferLevel::PRIMARY)
@@ -252,13 +305,17 @@ impl RenderDeviceImpl {
                     ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
                     descriptor_count: MAX_BINDLESS_IMAGES,
                 },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_IMAGE,
+                    descriptor_count: 1000,
+                },
             ];
             let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
                 .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
                 .pool_sizes(&pool_sizes)
                 .max_sets(1000);
 
-            let descriptor_pool = device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
+            let descriptor_pool = device.create_descriptor_pool(&descriptor_pool_info, None)?;
 
             let g_bindless_image_binding = vk::DescriptorSetLayoutBinding::builder()
                 .binding(BINDLESS_IMAGES_BINDING)
@@ -275,9 +332,7 @@ impl RenderDeviceImpl {
                 .bindings(std::slice::from_ref(&g_bindless_image_binding))
                 .push_next(&mut g_bindless_image_layout_info_ext);
 
-            let g_descriptor_set_layout = device
-                .create_descriptor_set_layout(&g_descriptor_set_layout_info, None)
-                .unwrap();
+            let g_descriptor_set_layout = device.create_descriptor_set_layout(&g_descriptor_set_layout_info, None)?;
 
             let mut g_descriptor_set_alloc_info_ext = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
                 .descriptor_counts(std::slice::from_ref(&max_binding));
@@ -287,17 +342,17 @@ impl RenderDeviceImpl {
                 .set_layouts(std::slice::from_ref(&g_descriptor_set_layout))
                 .push_next(&mut g_descriptor_set_alloc_info_ext);
 
-            let g_descriptor_set = device.allocate_descriptor_sets(&g_descriptor_set_alloc_info).unwrap()[0];
+            let g_descriptor_set = device.allocate_descriptor_sets(&g_descriptor_set_alloc_info)?[0];
 
             let g_descriptor_map = GDescriptorMap {
                 g_descriptor_map: HashMap::new(),
                 g_descriptor_idx_gen: 0,
             };
 
-            let single_time_command_buffer = device.allocate_command_buffers(&alloc_info).unwrap()[0];
+            let single_time_command_buffer = device.allocate_command_buffers(&alloc_info)?[0];
             let fence_info = vk::FenceCreateInfo::builder();
 
-            let single_time_fence = device.create_fence(&fence_info, None).unwrap();
+            let single_time_fence = device.create_fence(&fence_info, None)?;
             let nearest_sampler_info = vk::SamplerCreateInfo::builder()
                 .mag_filter(vk::Filter::NEAREST)
                 .min_filter(vk::Filter::NEAREST)
@@ -308,7 +363,7 @@ impl RenderDeviceImpl {
                 .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
                 .unnormalized_coordinates(false)
                 .mipmap_mode(vk::SamplerMipmapMode::NEAREST);
-            let nearest_sampler = device.create_sampler(&nearest_sampler_info, None).unwrap();
+            let nearest_sampler = device.create_sampler(&nearest_sampler_info, None)?;
 
             let linear_sampler_info = vk::SamplerCreateInfo::builder()
                 .mag_filter(vk::Filter::LINEAR)
@@ -320,7 +375,7 @@ impl RenderDeviceImpl {
                 .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
                 .unnormalized_coordinates(false)
                 .mipmap_mode(vk::SamplerMipmapMode::LINEAR);
-            let linear_sampler = device.create_sampler(&linear_sampler_info, None).unwrap();
+            let linear_sampler = device.create_sampler(&linear_sampler_info, None)?;
 
             let alloc = Some(RwLock::new(AllocImpl {
                 allocator: Allocator::new(&AllocatorCreateDesc {
@@ -332,13 +387,12 @@ impl RenderDeviceImpl {
                         ..default()
                     },
                     buffer_device_address: true,
-                })
-                .unwrap(),
+                })?,
                 buffer_to_allocation: HashMap::new(),
                 image_to_allocation: HashMap::new(),
             }));
 
-            Self {
+            Ok(Self {
                 entry,
                 exts: Exts {
                     surface: ext_surface,
@@ -362,8 +416,9 @@ impl RenderDeviceImpl {
                 single_time_fence,
                 nearest_sampler,
                 linear_sampler,
+                samplers: Mutex::new(HashMap::new()),
                 alloc,
-            }
+            })
         }
     }
 
@@ -372,9 +427,8 @@ impl RenderDeviceImpl {
         unsafe {
             let device_properties = self.instance.get_physical_device_properties(self.physical_device);
             CStr::from_ptr(device_properties.device_name.as_ptr())
-                .to_str()
-                .unwrap()
-                .to_string()
+                .to_string_lossy()
+                .into_owned()
         }
     }
 
@@ -386,23 +440,31 @@ impl RenderDeviceImpl {
         self.alloc.as_ref().unwrap().write().unwrap()
     }
 
-    pub fn run_asset_commands(&self, f: impl FnOnce(vk::CommandBuffer)) {
+    pub fn run_asset_commands(&self, f: impl FnOnce(vk::CommandBuffer)) -> RenderResult<()> {
         let fence_info = vk::FenceCreateInfo::builder();
-        let fence = unsafe { self.device.create_fence(&fence_info, None) }.unwrap();
+        let fence = unsafe { self.device.create_fence(&fence_info, None) }?;
+        let result = self.submit_asset_commands(f, fence);
+        unsafe {
+            self.device.destroy_fence(fence, None);
+        }
+        result
+    }
+
+    fn submit_asset_commands(&self, f: impl FnOnce(vk::CommandBuffer), fence: vk::Fence) -> RenderResult<()> {
         let asset_command_pool = self.asset_command_pool.lock().unwrap();
         let alloc_info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(*asset_command_pool)
             .level(vk::CommandBufferLevel::PRIMARY)
             .command_buffer_count(1);
-        let cmd_buffer = unsafe { self.device.allocate_command_buffers(&alloc_info) }.unwrap()[0];
+        let cmd_buffer = unsafe { self.device.allocate_command_buffers(&alloc_info) }?[0];
         let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-        unsafe { self.device.begin_command_buffer(cmd_buffer, &begin_info) }.unwrap();
+        unsafe { self.device.begin_command_buffer(cmd_buffer, &begin_info) }?;
 
         f(cmd_buffer);
 
-        unsafe { self.device.end_command_buffer(cmd_buffer) }.unwrap();
+        unsafe { self.device.end_command_buffer(cmd_buffer) }?;
 
-        unsafe { self.device.reset_fences(std::slice::from_ref(&fence)) }.unwrap();
+        unsafe { self.device.reset_fences(std::slice::from_ref(&fence)) }?;
         let submit_info = vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&cmd_buffer));
 
         {
@@ -410,58 +472,49 @@ impl RenderDeviceImpl {
             unsafe {
                 self.device
                     .queue_submit(queue.clone(), std::slice::from_ref(&submit_info), fence)
-            }
-            .unwrap();
+            }?;
         }
 
         unsafe {
             self.device
                 .wait_for_fences(std::slice::from_ref(&fence), true, u64::MAX)
-        }
-        .unwrap();
-
-        unsafe {
-            self.device.destroy_fence(fence, None);
-        }
+        }?;
+        Ok(())
     }
 
-    pub unsafe fn run_single_commands(&self, f: impl FnOnce(vk::CommandBuffer)) {
+    pub unsafe fn run_single_commands(&self, f: impl FnOnce(vk::CommandBuffer)) -> RenderResult<()> {
         let queue = self.queue.lock().unwrap();
         self.device
-            .reset_command_buffer(self.single_time_command_buffer, vk::CommandBufferResetFlags::empty())
-            .unwrap();
+            .reset_command_buffer(self.single_time_command_buffer, vk::CommandBufferResetFlags::empty())?;
         let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
         self.device
-            .begin_command_buffer(self.single_time_command_buffer, &begin_info)
-            .unwrap();
+            .begin_command_buffer(self.single_time_command_buffer, &begin_info)?;
         f(self.single_time_command_buffer);
-        self.device.end_command_buffer(self.single_time_command_buffer).unwrap();
+        self.device.end_command_buffer(self.single_time_command_buffer)?;
         self.device
-            .reset_fences(std::slice::from_ref(&self.single_time_fence))
-            .unwrap();
+            .reset_fences(std::slice::from_ref(&self.single_time_fence))?;
         let submit_info =
             vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&self.single_time_command_buffer));
+        self.device.queue_submit(
+            queue.clone(),
+            std::slice::from_ref(&submit_info),
+            self.single_time_fence,
+        )?;
         self.device
-            .queue_submit(
-                queue.clone(),
-                std::slice::from_ref(&submit_info),
-                self.single_time_fence,
-            )
-            .unwrap();
-        self.device
-            .wait_for_fences(std::slice::from_ref(&self.single_time_fence), true, u64::MAX)
-            .unwrap();
+            .wait_for_fences(std::slice::from_ref(&self.single_time_fence), true, u64::MAX)?;
+        Ok(())
     }
 
+    /// Also called while shutting down after the device was lost, so failing only gets logged
     pub fn wait_idle(&self) {
         let queue = self.queue.lock().unwrap();
-        unsafe {
-            self.device.queue_wait_idle(queue.clone()).unwrap();
+        if let Err(e) = unsafe { self.device.queue_wait_idle(queue.clone()) } {
+            println!("Waiting for the device to be idle failed: {:?}", e);
         }
     }
 
-    pub fn create_surface(&self, handles: &RawHandleWrapper) -> vk::SurfaceKHR {
-        unsafe {
+    pub fn create_surface(&self, handles: &RawHandleWrapper) -> RenderResult<vk::SurfaceKHR> {
+        let surface = unsafe {
             ash_window::create_surface(
                 &self.entry,
                 &self.instance,
@@ -469,24 +522,47 @@ impl RenderDeviceImpl {
                 handles.window_handle,
                 None,
             )
+        }?;
+        Ok(surface)
+    }
+
+    pub fn get_sampler(&self, info: SamplerInfo) -> RenderResult<vk::Sampler> {
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(sampler) = samplers.get(&info) {
+            return Ok(*sampler);
         }
-        .unwrap()
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(info.mag_filter)
+            .min_filter(info.min_filter)
+            .address_mode_u(info.address_mode_u)
+            .address_mode_v(info.address_mode_v)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .anisotropy_enable(false)
+            .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+            .unnormalized_coordinates(false)
+            .mipmap_mode(info.mipmap_mode)
+            .max_lod(info.max_lod);
+        let sampler = unsafe { self.device.create_sampler(&sampler_info, None) }?;
+        samplers.insert(info, sampler);
+        Ok(sampler)
     }
 
-    pub fn get_texture_descriptor_index(&self, view: vk::ImageView) -> u32 {
+    /// The same image view can be bound several times, once for every sampler it is used with.
+    pub fn get_texture_descriptor_index(&self, view: vk::ImageView, sampler: vk::Sampler) -> u32 {
         let mut g_descriptors = self.g_descriptors.lock().unwrap();
-        if let Some(index) = g_descriptors.g_descriptor_map.get(&view) {
+        if let Some(index) = g_descriptors.g_descriptor_map.get(&(view, sampler)) {
             return *index;
         }
 
         let index = g_descriptors.g_descriptor_idx_gen;
-        g_descriptors.g_descriptor_map.insert(view, index);
+        g_descriptors.g_descriptor_map.insert((view, sampler), index);
         g_descriptors.g_descriptor_idx_gen += 1;
 
         let descriptor_info = vk::DescriptorImageInfo::builder()
             .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
             .image_view(view)
-            .sampler(self.linear_sampler);
+            .sampler(sampler);
 
         let descriptor_write = vk::WriteDescriptorSet::builder()
             .image_info(std::slice::from_ref(&descriptor_info))
@@ -518,6 +594,9 @@ impl Drop for RenderDeviceImpl {
             self.device.destroy_fence(self.single_time_fence, None);
             self.device.destroy_sampler(self.nearest_sampler, None);
             self.device.destroy_sampler(self.linear_sampler, None);
+            for (_, sampler) in self.samplers.lock().unwrap().drain() {
+                self.device.destroy_sampler(sampler, None);
+            }
             self.device
                 .destroy_descriptor_set_layout(self.g_descriptor_set_layout, None);
             self.device.destroy_descriptor_pool(self.descriptor_pool, None);
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use ash::vk;
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::window::{PrimaryWindow, RawHandleWrapper};
+use gpu_allocator::AllocationError;
+
+use crate::accumulation::Accumulation;
+use crate::camera::{Camera3d, PitchYaw};
+use crate::render_graph::TransientImages;
+use crate::scene_description::CameraDescription;
+use crate::swapchain::Swapchain;
+use crate::vulkan_cleanup::VkCleanup;
+
+/// Where the camera is written when the device is lost
+const SAVED_CAMERA_PATH: &str = "saved_camera.ron";
+
+/// A Vulkan call that failed in a way the renderer can react to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// The renderer could not start, there is no Vulkan or no device that can run it
+    Init(String),
+    /// The GPU crashed or was reset, nothing created on it can be used anymore
+    DeviceLost,
+    /// Host or device memory, or the allocator, ran out
+    OutOfMemory,
+    /// The window surface went away, it has to be created again
+    SurfaceLost,
+    /// A shader the renderer cannot build a pipeline from
+    Shader(String),
+    /// The passes of a frame don't fit together, nothing of the frame was recorded
+    InvalidGraph(String),
+    Vulkan(vk::Result),
+    Allocator(String),
+}
+
+pub type RenderResult<T> = Result<T, RenderError>;
+
+impl From<vk::Result> for RenderError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_DEVICE_LOST => RenderError::DeviceLost,
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY
+            | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+            | vk::Result::ERROR_OUT_OF_POOL_MEMORY => RenderError::OutOfMemory,
+            vk::Result::ERROR_SURFACE_LOST_KHR => RenderError::SurfaceLost,
+            result => RenderError::Vulkan(result),
+        }
+    }
+}
+
+impl From<AllocationError> for RenderError {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => RenderError::OutOfMemory,
+            error => RenderError::Allocator(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Init(error) => write!(f, "{}", error),
+            RenderError::DeviceLost => write!(f, "device lost"),
+            RenderError::OutOfMemory => write!(f, "out of memory"),
+            RenderError::SurfaceLost => write!(f, "surface lost"),
+            RenderError::Shader(error) => write!(f, "shader: {}", error),
+            RenderError::InvalidGraph(error) => write!(f, "invalid render graph {}", error),
+            RenderError::Vulkan(result) => write!(f, "{:?}", result),
+            RenderError::Allocator(error) => write!(f, "allocator: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Piped after systems that can fail, `recover_from_render_errors` deals with what they send
+pub fn report_render_error(In(result): In<RenderResult<()>>, mut errors: EventWriter<RenderError>) {
+    if let Err(error) = result {
+        errors.send(error);
+    }
+}
+
+pub struct RenderErrorPlugin;
+
+impl Plugin for RenderErrorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RenderError>();
+        app.add_system(recover_from_render_errors.in_base_set(CoreSet::Last));
+    }
+}
+
+/// The frame that failed was dropped already, so the accumulation starts over. Running out of
+/// memory frees what can be created again, a lost surface is recreated and a lost device saves the
+/// camera and shuts down.
+#[allow(clippy::too_many_arguments)]
+pub fn recover_from_render_errors(
+    mut errors: EventReader<RenderError>,
+    cleanup: Res<VkCleanup>,
+    mut transient_images: ResMut<TransientImages>,
+    mut accumulation: ResMut<Accumulation>,
+    mut swapchain: Query<(&mut Swapchain, &Window, &RawHandleWrapper), With<PrimaryWindow>>,
+    camera: Query<(&Camera3d, &Transform, &PitchYaw)>,
+    mut exit: EventWriter<AppExit>,
+    mut exiting: Local<bool>,
+) {
+    for error in errors.iter() {
+        if *exiting {
+            break;
+        }
+        println!("Render error: {}", error);
+
+        match error {
+            RenderError::OutOfMemory => {
+                println!("Freeing the transient images, they are created again when needed");
+                transient_images.destroy(&cleanup);
+            }
+            RenderError::SurfaceLost => {
+                if let Ok((mut swapchain, window, whandles)) = swapchain.get_single_mut() {
+                    println!("Recreating the window surface");
+                    if let Err(e) = unsafe { swapchain.recreate_surface(whandles, window) } {
+                        println!("Render error: {} while recreating the surface", e);
+                        shut_down(&camera, &mut exit);
+                        *exiting = true;
+                    }
+                }
+            }
+            RenderError::Init(_) | RenderError::DeviceLost => {
+                shut_down(&camera, &mut exit);
+                *exiting = true;
+            }
+            // skipping the frame is all there is to do, a broken shader keeps the pipeline it replaces
+            RenderError::Shader(_)
+            | RenderError::InvalidGraph(_)
+            | RenderError::Vulkan(_)
+            | RenderError::Allocator(_) => {}
+        }
+        accumulation.reset();
+    }
+}
+
+/// Saves the camera so the next session can start where this one ended, the exit runs the
+/// `VkAssetCleanupPlaybook`
+fn shut_down(camera: &Query<(&Camera3d, &Transform, &PitchYaw)>, exit: &mut EventWriter<AppExit>) {
+    if let Ok((camera, transform, pitch_yaw)) = camera.get_single() {
+        let description = CameraDescription::from_camera(camera, transform, pitch_yaw);
+        let saved = ron::ser::to_string_pretty(&description, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+            .and_then(|ron| std::fs::write(SAVED_CAMERA_PATH, ron).map_err(|e| e.to_string()));
+        match saved {
+            Ok(()) => println!(
+                "Saved the camera to {}, paste it into the scene description as its camera",
+                SAVED_CAMERA_PATH
+            ),
+            Err(e) => println!("Failed to save the camera to {}: {}", SAVED_CAMERA_PATH, e),
+        }
+    }
+
+    println!("Shutting down after a fatal render error");
+    exit.send(AppExit);
+}
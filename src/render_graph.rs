@@ -0,0 +1,836 @@
+use ash::vk;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::render_device::RenderDevice;
+use crate::render_error::{RenderError, RenderResult};
+use crate::render_image::{vk_image_from_asset, Image, VkImage};
+use crate::vk_utils;
+use crate::vulkan_assets::VkAssetCleanupPlaybook;
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+/// A resource of one `RenderGraph`, meaningless to any other graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResourceId(usize);
+
+/// How a pass uses a resource. Decides the layout an image is transitioned to and what the barriers
+/// in front of the pass wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Access {
+    StorageRead(vk::PipelineStageFlags2),
+    StorageWrite(vk::PipelineStageFlags2),
+    StorageReadWrite(vk::PipelineStageFlags2),
+    Sampled(vk::PipelineStageFlags2),
+    ColorAttachment,
+    TransferSrc,
+    TransferDst,
+    /// Builds the acceleration structure in a buffer, or uses the buffer as scratch memory for it
+    AccelerationStructureBuild,
+    /// Traces rays against the acceleration structure in a buffer
+    AccelerationStructureRead(vk::PipelineStageFlags2),
+}
+
+impl Access {
+    pub fn stage(&self) -> vk::PipelineStageFlags2 {
+        match *self {
+            Access::StorageRead(stage)
+            | Access::StorageWrite(stage)
+            | Access::StorageReadWrite(stage)
+            | Access::Sampled(stage)
+            | Access::AccelerationStructureRead(stage) => stage,
+            Access::ColorAttachment => vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            Access::TransferSrc | Access::TransferDst => vk::PipelineStageFlags2::COPY,
+            Access::AccelerationStructureBuild => vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+        }
+    }
+
+    pub fn access(&self) -> vk::AccessFlags2 {
+        match self {
+            Access::StorageRead(_) => vk::AccessFlags2::SHADER_STORAGE_READ,
+            Access::StorageWrite(_) => vk::AccessFlags2::SHADER_STORAGE_WRITE,
+            Access::StorageReadWrite(_) => {
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE
+            }
+            Access::Sampled(_) => vk::AccessFlags2::SHADER_SAMPLED_READ,
+            Access::ColorAttachment => vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+            Access::TransferSrc => vk::AccessFlags2::TRANSFER_READ,
+            Access::TransferDst => vk::AccessFlags2::TRANSFER_WRITE,
+            Access::AccelerationStructureBuild => {
+                vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR | vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR
+            }
+            Access::AccelerationStructureRead(_) => vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR,
+        }
+    }
+
+    pub fn layout(&self) -> vk::ImageLayout {
+        match self {
+            Access::StorageRead(_) | Access::StorageWrite(_) | Access::StorageReadWrite(_) => vk::ImageLayout::GENERAL,
+            Access::Sampled(_) => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            Access::ColorAttachment => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            Access::TransferSrc => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            Access::TransferDst => vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            // only ever buffers
+            Access::AccelerationStructureBuild | Access::AccelerationStructureRead(_) => vk::ImageLayout::UNDEFINED,
+        }
+    }
+
+    pub fn writes(&self) -> bool {
+        matches!(
+            self,
+            Access::StorageWrite(_)
+                | Access::StorageReadWrite(_)
+                | Access::ColorAttachment
+                | Access::TransferDst
+                | Access::AccelerationStructureBuild
+        )
+    }
+}
+
+/// One use of a resource: the layout it needs and the stage and accesses it happens in. Buffers
+/// ignore the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub layout: vk::ImageLayout,
+    pub stage: vk::PipelineStageFlags2,
+    pub access: vk::AccessFlags2,
+    pub writes: bool,
+}
+
+impl Usage {
+    /// Only moves an image into `layout`, for handing it to something outside the frame like the
+    /// presentation engine
+    pub fn layout(layout: vk::ImageLayout) -> Self {
+        Self {
+            layout,
+            stage: vk::PipelineStageFlags2::ALL_COMMANDS,
+            access: vk::AccessFlags2::NONE,
+            writes: false,
+        }
+    }
+}
+
+impl From<Access> for Usage {
+    fn from(access: Access) -> Self {
+        Self {
+            layout: access.layout(),
+            stage: access.stage(),
+            access: access.access(),
+            writes: access.writes(),
+        }
+    }
+}
+
+/// A barrier for one resource. The layouts are undefined for buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Barrier {
+    pub src_stage: vk::PipelineStageFlags2,
+    pub src_access: vk::AccessFlags2,
+    pub dst_stage: vk::PipelineStageFlags2,
+    pub dst_access: vk::AccessFlags2,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+}
+
+/// What is known about a resource between two uses: its layout, the last write and who read it since
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessState {
+    pub layout: vk::ImageLayout,
+    /// The last write or layout transition
+    write_stage: vk::PipelineStageFlags2,
+    write_access: vk::AccessFlags2,
+    /// Stages reading since the last write, the next write waits for them
+    read_stages: vk::PipelineStageFlags2,
+    /// Where the last write is already visible
+    visible_stages: vk::PipelineStageFlags2,
+    visible_access: vk::AccessFlags2,
+}
+
+impl AccessState {
+    /// A resource in `layout` that anything might still be writing
+    pub fn new(layout: vk::ImageLayout) -> Self {
+        Self {
+            layout,
+            write_stage: vk::PipelineStageFlags2::ALL_COMMANDS,
+            write_access: vk::AccessFlags2::MEMORY_WRITE,
+            read_stages: vk::PipelineStageFlags2::NONE,
+            visible_stages: vk::PipelineStageFlags2::NONE,
+            visible_access: vk::AccessFlags2::NONE,
+        }
+    }
+
+    /// Moves the state on to `usage`, returning the barrier that has to come first unless the
+    /// resource is already fit for it
+    pub fn transition(&mut self, usage: Usage) -> Option<Barrier> {
+        if usage.writes || usage.layout != self.layout {
+            // a transition is a write as well, it waits for every earlier use
+            let barrier = Barrier {
+                src_stage: self.write_stage | self.read_stages,
+                src_access: self.write_access,
+                dst_stage: usage.stage,
+                dst_access: usage.access,
+                old_layout: self.layout,
+                new_layout: usage.layout,
+            };
+            *self = Self {
+                layout: usage.layout,
+                write_stage: usage.stage,
+                write_access: if usage.writes {
+                    usage.access
+                } else {
+                    vk::AccessFlags2::NONE
+                },
+                read_stages: vk::PipelineStageFlags2::NONE,
+                visible_stages: usage.stage,
+                visible_access: usage.access,
+            };
+            return Some(barrier);
+        }
+
+        self.read_stages |= usage.stage;
+        if self.visible_stages.contains(usage.stage) && self.visible_access.contains(usage.access) {
+            return None;
+        }
+        self.visible_stages |= usage.stage;
+        self.visible_access |= usage.access;
+        Some(Barrier {
+            src_stage: self.write_stage,
+            src_access: self.write_access,
+            dst_stage: usage.stage,
+            dst_access: usage.access,
+            old_layout: self.layout,
+            new_layout: self.layout,
+        })
+    }
+}
+
+/// What `compile` knows about a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceDesc {
+    /// Starts out in `initial` and is moved into `last` at the end, when there is one. Transient
+    /// images hold nothing at the start of the graph and have to be written before they are read.
+    Image {
+        initial: AccessState,
+        last: Option<vk::ImageLayout>,
+        transient: bool,
+    },
+    Buffer,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompiledGraph {
+    /// The barriers in front of every pass
+    pub pass_barriers: Vec<Vec<(ResourceId, Barrier)>>,
+    /// Moves the images into their `last` layout
+    pub final_barriers: Vec<(ResourceId, Barrier)>,
+    /// Every resource once the graph ran
+    pub final_states: Vec<AccessState>,
+}
+
+/// Derives the barriers for `passes` running in order, each one a list of the resources it uses.
+/// Needs no device, everything it works with is plain data.
+pub fn compile(resources: &[ResourceDesc], passes: &[Vec<(ResourceId, Access)>]) -> Result<CompiledGraph, String> {
+    let mut states = resources
+        .iter()
+        .map(|desc| match desc {
+            ResourceDesc::Image { initial, .. } => *initial,
+            // whatever ran before the graph might still be using it
+            ResourceDesc::Buffer => AccessState::new(vk::ImageLayout::UNDEFINED),
+        })
+        .collect::<Vec<_>>();
+    let mut written = resources
+        .iter()
+        .map(|desc| !matches!(desc, ResourceDesc::Image { transient: true, .. }))
+        .collect::<Vec<_>>();
+
+    let mut compiled = CompiledGraph::default();
+    for (pass_idx, accesses) in passes.iter().enumerate() {
+        let mut barriers = Vec::new();
+        for (i, &(resource, access)) in accesses.iter().enumerate() {
+            if accesses[..i].iter().any(|(other, _)| *other == resource) {
+                return Err(format!("pass {} uses {:?} twice", pass_idx, resource));
+            }
+            let is_image = match resources.get(resource.0) {
+                Some(desc) => matches!(desc, ResourceDesc::Image { .. }),
+                None => return Err(format!("pass {} uses unknown {:?}", pass_idx, resource)),
+            };
+            if !written[resource.0] && !access.writes() {
+                return Err(format!(
+                    "pass {} reads {:?} before anything wrote it",
+                    pass_idx, resource
+                ));
+            }
+            written[resource.0] |= access.writes();
+
+            let mut usage = Usage::from(access);
+            if !is_image {
+                usage.layout = vk::ImageLayout::UNDEFINED;
+            }
+            if let Some(barrier) = states[resource.0].transition(usage) {
+                barriers.push((resource, barrier));
+            }
+        }
+        compiled.pass_barriers.push(barriers);
+    }
+
+    for (idx, (desc, state)) in resources.iter().zip(&mut states).enumerate() {
+        if let ResourceDesc::Image { last: Some(last), .. } = *desc {
+            if last != state.layout {
+                let barrier = state.transition(Usage::layout(last)).unwrap();
+                compiled.final_barriers.push((ResourceId(idx), barrier));
+            }
+        }
+    }
+    compiled.final_states = states;
+
+    Ok(compiled)
+}
+
+/// An image sized like the graph that only lives for one frame, identified by its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransientImage {
+    pub name: &'static str,
+    pub format: vk::Format,
+}
+
+/// An image a pass wants before the graph gave it an id
+#[derive(Clone)]
+pub enum GraphImage {
+    /// Kept across frames, in whatever layout it was used last
+    Persistent(VkImage),
+    Transient(TransientImage),
+}
+
+/// The images behind `TransientImage`s, recreated whenever the graph changes size
+#[derive(Resource, Default)]
+pub struct TransientImages {
+    extent: (u32, u32),
+    images: HashMap<TransientImage, VkImage>,
+}
+
+impl TransientImages {
+    fn prepare(
+        &mut self,
+        device: &RenderDevice,
+        cleanup: &VkCleanup,
+        extent: (u32, u32),
+        wanted: &[TransientImage],
+    ) -> RenderResult<()> {
+        if extent != self.extent {
+            self.destroy(cleanup);
+            self.extent = extent;
+        }
+
+        for &transient in wanted {
+            if self.images.contains_key(&transient) {
+                continue;
+            }
+            let image = vk_image_from_asset(
+                device,
+                &Image {
+                    width: extent.0,
+                    height: extent.1,
+                    format: transient.format,
+                    usage: vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::TRANSFER_SRC,
+                    initial_layout: vk::ImageLayout::UNDEFINED,
+                },
+            )?;
+            self.images.insert(transient, image);
+        }
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, cleanup: &VkCleanup) {
+        for (_, image) in self.images.drain() {
+            cleanup.send(VkCleanupEvent::ImageView(image.view));
+            cleanup.send(VkCleanupEvent::Image(image.handle));
+        }
+    }
+}
+
+fn cleanup_transient_images(mut images: ResMut<TransientImages>, cleanup: Res<VkCleanup>) {
+    images.destroy(&cleanup);
+}
+
+enum Resource {
+    Image(GraphImage),
+    Buffer(vk::Buffer),
+}
+
+/// Handed to every pass while it records
+pub struct PassContext<'a> {
+    pub device: &'a RenderDevice,
+    pub cmd_buffer: vk::CommandBuffer,
+    transients: &'a TransientImages,
+}
+
+impl<'a> PassContext<'a> {
+    pub fn image<'b>(&'b self, image: &'b GraphImage) -> &'b VkImage {
+        match image {
+            GraphImage::Persistent(image) => image,
+            GraphImage::Transient(transient) => &self.transients.images[transient],
+        }
+    }
+}
+
+struct Pass<'a> {
+    name: &'static str,
+    accesses: Vec<(ResourceId, Access)>,
+    record: Box<dyn FnOnce(&PassContext) + 'a>,
+}
+
+/// The passes of one frame. Passes declare the resources they use and record in the order they
+/// were added, with the barriers and layout transitions between them derived by `compile` from
+/// the layouts the images are tracked in.
+pub struct RenderGraph<'a> {
+    extent: (u32, u32),
+    /// Every resource with the layout it is left in
+    resources: Vec<(Resource, Option<vk::ImageLayout>)>,
+    passes: Vec<Pass<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    /// Transient images get `width` x `height`
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            extent: (width, height),
+            resources: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    /// An image that has to be in `last` at the end of the graph
+    pub fn import(&mut self, image: VkImage, last: vk::ImageLayout) -> ResourceId {
+        let id = self.image(GraphImage::Persistent(image));
+        self.resources[id.0].1 = Some(last);
+        id
+    }
+
+    /// The id of `image`, the same one every time it is passed
+    pub fn image(&mut self, image: GraphImage) -> ResourceId {
+        let existing = self
+            .resources
+            .iter()
+            .position(|(resource, _)| match (resource, &image) {
+                (Resource::Image(GraphImage::Persistent(a)), GraphImage::Persistent(b)) => a.handle == b.handle,
+                (Resource::Image(GraphImage::Transient(a)), GraphImage::Transient(b)) => a == b,
+                _ => false,
+            });
+        match existing {
+            Some(idx) => ResourceId(idx),
+            None => self.add_resource(Resource::Image(image)),
+        }
+    }
+
+    pub fn buffer(&mut self, buffer: vk::Buffer) -> ResourceId {
+        let existing = self
+            .resources
+            .iter()
+            .position(|(resource, _)| matches!(resource, Resource::Buffer(b) if *b == buffer));
+        match existing {
+            Some(idx) => ResourceId(idx),
+            None => self.add_resource(Resource::Buffer(buffer)),
+        }
+    }
+
+    fn add_resource(&mut self, resource: Resource) -> ResourceId {
+        self.resources.push((resource, None));
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn add_pass(
+        &mut self,
+        name: &'static str,
+        accesses: &[(ResourceId, Access)],
+        record: impl FnOnce(&PassContext) + 'a,
+    ) {
+        self.passes.push(Pass {
+            name,
+            accesses: accesses.to_vec(),
+            record: Box::new(record),
+        });
+    }
+
+    /// Records every pass into `cmd_buffer`, creating the transient images first. The images are
+    /// tracked in the state the graph leaves them in. Nothing is recorded when a transient image
+    /// can't be created or the passes don't fit together.
+    pub unsafe fn execute(
+        self,
+        device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        transients: &mut TransientImages,
+        cleanup: &VkCleanup,
+    ) -> RenderResult<()> {
+        let wanted = self
+            .resources
+            .iter()
+            .filter_map(|(resource, _)| match resource {
+                Resource::Image(GraphImage::Transient(transient)) => Some(*transient),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        transients.prepare(device, cleanup, self.extent, &wanted)?;
+
+        let context = PassContext {
+            device,
+            cmd_buffer,
+            transients,
+        };
+        let image = |id: ResourceId| match &self.resources[id.0].0 {
+            Resource::Image(image) => Some(context.image(image)),
+            Resource::Buffer(_) => None,
+        };
+
+        // whatever the transient images held last frame is discarded
+        for (resource, _) in &self.resources {
+            if let Resource::Image(transient @ GraphImage::Transient(_)) = resource {
+                context
+                    .image(transient)
+                    .set_state(AccessState::new(vk::ImageLayout::UNDEFINED));
+            }
+        }
+
+        let descs = self
+            .resources
+            .iter()
+            .map(|(resource, last)| match resource {
+                Resource::Image(image) => ResourceDesc::Image {
+                    initial: context.image(image).state(),
+                    last: *last,
+                    transient: matches!(image, GraphImage::Transient(_)),
+                },
+                Resource::Buffer(_) => ResourceDesc::Buffer,
+            })
+            .collect::<Vec<_>>();
+        let accesses = self.passes.iter().map(|pass| pass.accesses.clone()).collect::<Vec<_>>();
+        let compiled = compile(&descs, &accesses).map_err(|e| {
+            let names = self.passes.iter().map(|pass| pass.name).collect::<Vec<_>>();
+            RenderError::InvalidGraph(format!("{:?}: {}", names, e))
+        })?;
+
+        for (pass, barriers) in self.passes.into_iter().zip(&compiled.pass_barriers) {
+            record_barriers(&context, &self.resources, barriers);
+            // the tracked state follows along, passes check it when they write their descriptors
+            for &(id, access) in &pass.accesses {
+                if let Some(image) = image(id) {
+                    image.track(access.into());
+                }
+            }
+            (pass.record)(&context);
+        }
+
+        record_barriers(&context, &self.resources, &compiled.final_barriers);
+        for (idx, (_, last)) in self.resources.iter().enumerate() {
+            if let (Some(image), Some(last)) = (image(ResourceId(idx)), last) {
+                image.track(Usage::layout(*last));
+                debug_assert_eq!(
+                    image.state(),
+                    compiled.final_states[idx],
+                    "an image changed during the graph"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All barriers in front of a pass go into one `cmd_pipeline_barrier2`
+unsafe fn record_barriers(
+    context: &PassContext,
+    resources: &[(Resource, Option<vk::ImageLayout>)],
+    barriers: &[(ResourceId, Barrier)],
+) {
+    if barriers.is_empty() {
+        return;
+    }
+
+    let mut image_barriers = Vec::new();
+    let mut buffer_barriers = Vec::new();
+    for (resource, barrier) in barriers {
+        match &resources[resource.0].0 {
+            Resource::Image(image) => {
+                image_barriers.push(vk_utils::image_barrier(context.image(image).handle, barrier))
+            }
+            Resource::Buffer(buffer) => buffer_barriers.push(
+                vk::BufferMemoryBarrier2::builder()
+                    .buffer(*buffer)
+                    .size(vk::WHOLE_SIZE)
+                    .src_stage_mask(barrier.src_stage)
+                    .src_access_mask(barrier.src_access)
+                    .dst_stage_mask(barrier.dst_stage)
+                    .dst_access_mask(barrier.dst_access)
+                    .build(),
+            ),
+        }
+    }
+
+    let dependency_info = vk::DependencyInfo::builder()
+        .image_memory_barriers(&image_barriers)
+        .buffer_memory_barriers(&buffer_barriers);
+    context
+        .device
+        .exts
+        .sync2
+        .cmd_pipeline_barrier2(context.cmd_buffer, &dependency_info);
+}
+
+pub struct RenderGraphPlugin;
+
+impl Plugin for RenderGraphPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TransientImages>();
+        app.world
+            .get_resource_mut::<VkAssetCleanupPlaybook>()
+            .unwrap()
+            .add_system(cleanup_transient_images);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPUTE: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::COMPUTE_SHADER;
+    const RAYTRACING: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR;
+
+    fn transient() -> ResourceDesc {
+        ResourceDesc::Image {
+            initial: AccessState::new(vk::ImageLayout::UNDEFINED),
+            last: None,
+            transient: true,
+        }
+    }
+
+    fn persistent(layout: vk::ImageLayout, last: Option<vk::ImageLayout>) -> ResourceDesc {
+        ResourceDesc::Image {
+            initial: AccessState::new(layout),
+            last,
+            transient: false,
+        }
+    }
+
+    #[test]
+    fn read_after_write() {
+        let (image, buffer) = (ResourceId(0), ResourceId(1));
+        let compiled = compile(
+            &[transient(), ResourceDesc::Buffer],
+            &[
+                vec![
+                    (image, Access::StorageWrite(COMPUTE)),
+                    (buffer, Access::StorageWrite(COMPUTE)),
+                ],
+                vec![
+                    (image, Access::Sampled(RAYTRACING)),
+                    (buffer, Access::StorageRead(RAYTRACING)),
+                ],
+                // both reads are visible already
+                vec![
+                    (image, Access::Sampled(RAYTRACING)),
+                    (buffer, Access::StorageRead(RAYTRACING)),
+                ],
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            compiled.pass_barriers[1],
+            vec![
+                (
+                    image,
+                    Barrier {
+                        src_stage: COMPUTE,
+                        src_access: vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                        dst_stage: RAYTRACING,
+                        dst_access: vk::AccessFlags2::SHADER_SAMPLED_READ,
+                        old_layout: vk::ImageLayout::GENERAL,
+                        new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    }
+                ),
+                (
+                    buffer,
+                    Barrier {
+                        src_stage: COMPUTE,
+                        src_access: vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                        dst_stage: RAYTRACING,
+                        dst_access: vk::AccessFlags2::SHADER_STORAGE_READ,
+                        old_layout: vk::ImageLayout::UNDEFINED,
+                        new_layout: vk::ImageLayout::UNDEFINED,
+                    }
+                ),
+            ]
+        );
+        assert!(compiled.pass_barriers[2].is_empty());
+    }
+
+    #[test]
+    fn write_after_read() {
+        let buffer = ResourceId(0);
+        let compiled = compile(
+            &[ResourceDesc::Buffer],
+            &[
+                vec![(buffer, Access::StorageWrite(COMPUTE))],
+                vec![(buffer, Access::StorageRead(RAYTRACING))],
+                vec![(buffer, Access::StorageWrite(COMPUTE))],
+            ],
+        )
+        .unwrap();
+
+        // the second write waits for the read in between, not only for the first write
+        assert_eq!(
+            compiled.pass_barriers[2],
+            vec![(
+                buffer,
+                Barrier {
+                    src_stage: COMPUTE | RAYTRACING,
+                    src_access: vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                    dst_stage: COMPUTE,
+                    dst_access: vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                    old_layout: vk::ImageLayout::UNDEFINED,
+                    new_layout: vk::ImageLayout::UNDEFINED,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn layout_transitions() {
+        let target = ResourceId(0);
+        let compiled = compile(
+            &[persistent(
+                vk::ImageLayout::UNDEFINED,
+                Some(vk::ImageLayout::PRESENT_SRC_KHR),
+            )],
+            &[
+                vec![(target, Access::ColorAttachment)],
+                vec![(target, Access::TransferSrc)],
+            ],
+        )
+        .unwrap();
+
+        let layouts = |barriers: &[(ResourceId, Barrier)]| {
+            barriers
+                .iter()
+                .map(|(_, barrier)| (barrier.old_layout, barrier.new_layout))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            layouts(&compiled.pass_barriers[0]),
+            vec![(vk::ImageLayout::UNDEFINED, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)]
+        );
+        assert_eq!(
+            layouts(&compiled.pass_barriers[1]),
+            vec![(
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+            )]
+        );
+        // the copy only reads, the transition to the present layout waits for it all the same
+        assert_eq!(
+            compiled.final_barriers,
+            vec![(
+                target,
+                Barrier {
+                    src_stage: vk::PipelineStageFlags2::COPY,
+                    src_access: vk::AccessFlags2::NONE,
+                    dst_stage: vk::PipelineStageFlags2::ALL_COMMANDS,
+                    dst_access: vk::AccessFlags2::NONE,
+                    old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                }
+            )]
+        );
+        assert_eq!(compiled.final_states[0].layout, vk::ImageLayout::PRESENT_SRC_KHR);
+    }
+
+    #[test]
+    fn no_final_barrier_when_already_in_last_layout() {
+        let image = ResourceId(0);
+        let compiled = compile(
+            &[persistent(
+                vk::ImageLayout::GENERAL,
+                Some(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            )],
+            &[vec![(image, Access::Sampled(COMPUTE))]],
+        )
+        .unwrap();
+        assert!(compiled.final_barriers.is_empty());
+    }
+
+    #[test]
+    fn final_states_carry_over_to_the_next_frame() {
+        let (accumulation, target) = (ResourceId(0), ResourceId(1));
+        let passes = [
+            vec![
+                (accumulation, Access::StorageReadWrite(RAYTRACING)),
+                (target, Access::StorageWrite(RAYTRACING)),
+            ],
+            vec![(accumulation, Access::Sampled(COMPUTE))],
+        ];
+        let descs = [
+            persistent(vk::ImageLayout::UNDEFINED, None),
+            persistent(vk::ImageLayout::UNDEFINED, Some(vk::ImageLayout::PRESENT_SRC_KHR)),
+        ];
+        let first = compile(&descs, &passes).unwrap();
+
+        // the next frame starts from where this one ended, like the images tracking their state
+        let descs = descs
+            .iter()
+            .zip(&first.final_states)
+            .map(|(desc, state)| match *desc {
+                ResourceDesc::Image { last, transient, .. } => ResourceDesc::Image {
+                    initial: *state,
+                    last,
+                    transient,
+                },
+                ResourceDesc::Buffer => ResourceDesc::Buffer,
+            })
+            .collect::<Vec<_>>();
+        let second = compile(&descs, &passes).unwrap();
+
+        assert_eq!(
+            second.pass_barriers[0],
+            vec![
+                (
+                    accumulation,
+                    Barrier {
+                        src_stage: COMPUTE,
+                        src_access: vk::AccessFlags2::NONE,
+                        dst_stage: RAYTRACING,
+                        dst_access: vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                        old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        new_layout: vk::ImageLayout::GENERAL,
+                    }
+                ),
+                (
+                    target,
+                    Barrier {
+                        src_stage: vk::PipelineStageFlags2::ALL_COMMANDS,
+                        src_access: vk::AccessFlags2::NONE,
+                        dst_stage: RAYTRACING,
+                        dst_access: vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                        old_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                        new_layout: vk::ImageLayout::GENERAL,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(first.final_states, second.final_states);
+    }
+
+    #[test]
+    fn invalid_graphs() {
+        let image = ResourceId(0);
+        assert!(compile(&[transient()], &[vec![(image, Access::Sampled(COMPUTE))]]).is_err());
+        assert!(compile(
+            &[transient()],
+            &[vec![
+                (image, Access::StorageWrite(COMPUTE)),
+                (image, Access::Sampled(COMPUTE)),
+            ]]
+        )
+        .is_err());
+        assert!(compile(&[], &[vec![(image, Access::StorageWrite(COMPUTE))]]).is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use std::sync::{Arc, Mutex};
+
 use ash::vk;
 use bevy::reflect::TypeUuid;
 use gpu_allocator::vulkan::*;
 use gpu_allocator::*;
 
 use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::render_graph::{AccessState, Usage};
 use crate::vk_utils;
 use crate::vulkan_assets::VulkanAsset;
 use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
@@ -18,20 +22,49 @@ pub struct Image {
     pub initial_layout: vk::ImageLayout,
 }
 
-#[derive(TypeUuid)]
+#[derive(TypeUuid, Clone)]
 #[uuid = "3785ec50-3fc4-495e-908e-ad68893f48f7"]
 pub struct VkImage {
     pub handle: vk::Image,
     pub view: vk::ImageView,
+    /// Shared by every clone, so barriers always start from the layout the image is really in
+    state: Arc<Mutex<AccessState>>,
 }
 
 impl VkImage {
-    pub fn null() -> Self {
+    pub fn new(handle: vk::Image, view: vk::ImageView, layout: vk::ImageLayout) -> Self {
         VkImage {
-            handle: vk::Image::null(),
-            view: vk::ImageView::null(),
+            handle,
+            view,
+            state: Arc::new(Mutex::new(AccessState::new(layout))),
         }
     }
+
+    pub fn null() -> Self {
+        Self::new(vk::Image::null(), vk::ImageView::null(), vk::ImageLayout::UNDEFINED)
+    }
+
+    pub fn state(&self) -> AccessState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn set_state(&self, state: AccessState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    pub fn layout(&self) -> vk::ImageLayout {
+        self.state().layout
+    }
+
+    /// The barrier that has to come before `usage`, the image is tracked in `usage` afterwards
+    pub fn track(&self, usage: Usage) -> Option<vk::ImageMemoryBarrier2> {
+        debug_assert!(self.handle != vk::Image::null(), "barrier on a null image");
+        self.state
+            .lock()
+            .unwrap()
+            .transition(usage)
+            .map(|barrier| vk_utils::image_barrier(self.handle, &barrier))
+    }
 }
 
 impl VulkanAsset for Image {
@@ -47,22 +80,22 @@ impl VulkanAsset for Image {
         Some(self.clone())
     }
 
-    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset {
-        let image = vk_image_from_asset(device, &asset);
-
-        unsafe {
-            device.run_single_commands(&|command_buffer| {
-                vk_utils::transition_image_layout(
-                    device,
-                    command_buffer,
-                    image.handle,
-                    vk::ImageLayout::UNDEFINED,
-                    asset.initial_layout,
-                );
-            });
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset> {
+        let image = vk_image_from_asset(device, &asset)?;
+
+        if asset.initial_layout != vk::ImageLayout::UNDEFINED {
+            unsafe {
+                device.run_single_commands(&|command_buffer| {
+                    vk_utils::transition_images(
+                        device,
+                        command_buffer,
+                        &[(&image, Usage::layout(asset.initial_layout))],
+                    );
+                })?;
+            }
         }
 
-        image
+        Ok(image)
     }
 
     fn destroy_asset(asset: Self::PreparedAsset, cleanup: &VkCleanup) {
@@ -71,7 +104,7 @@ impl VulkanAsset for Image {
     }
 }
 
-pub fn vk_image_from_asset(device: &RenderDevice, asset: &Image) -> VkImage {
+pub fn vk_image_from_asset(device: &RenderDevice, asset: &Image) -> RenderResult<VkImage> {
     println!(
         "Allocating an image of type {:?} and size {}x{}",
         asset.format, asset.width, asset.height
@@ -91,36 +124,44 @@ pub fn vk_image_from_asset(device: &RenderDevice, asset: &Image) -> VkImage {
         .usage(asset.usage)
         .sharing_mode(vk::SharingMode::EXCLUSIVE)
         .initial_layout(vk::ImageLayout::UNDEFINED);
-    let handle = unsafe { device.device.create_image(&image_info, None).unwrap() };
+    let handle = unsafe { device.device.create_image(&image_info, None)? };
 
     let requirements = unsafe { device.device.get_image_memory_requirements(handle) };
 
     {
         let mut alloc_impl = device.write_alloc();
 
-        let allocation = alloc_impl
-            .allocator
-            .allocate(&AllocationCreateDesc {
-                name: "",
-                requirements,
-                location: MemoryLocation::GpuOnly,
-                linear: false,
-                allocation_scheme: AllocationScheme::DedicatedImage(handle),
-            })
-            .unwrap();
-
-        unsafe {
+        let allocation = alloc_impl.allocator.allocate(&AllocationCreateDesc {
+            name: "",
+            requirements,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::DedicatedImage(handle),
+        });
+        let allocation = match allocation {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                unsafe { device.device.destroy_image(handle, None) };
+                return Err(e.into());
+            }
+        };
+
+        let bound = unsafe {
             device
                 .device
                 .bind_image_memory(handle, allocation.memory(), allocation.offset())
-                .unwrap();
+        };
+        if let Err(e) = bound {
+            alloc_impl.allocator.free(allocation).unwrap();
+            unsafe { device.device.destroy_image(handle, None) };
+            return Err(e.into());
         }
 
         alloc_impl.image_to_allocation.insert(handle, allocation);
     }
 
     let view_info = crate::initializers::image_view_info(handle.clone(), asset.format);
-    let view = unsafe { device.device.create_image_view(&view_info, None).unwrap() };
+    let view = unsafe { device.device.create_image_view(&view_info, None)? };
 
-    VkImage { handle, view }
+    Ok(VkImage::new(handle, view, vk::ImageLayout::UNDEFINED))
 }
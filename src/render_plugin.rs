@@ -1,14 +1,28 @@
-use crate::camera::{Camera3d, Camera3dPlugin};
+use crate::accumulation::Accumulation;
+use crate::camera::{Camera3d, Camera3dPlugin, DEFAULT_FOCAL_DISTANCE};
+use crate::compute_pipeline::{add_compute_passes, ComputePasses, ComputePipeline, ComputePipelinePlugin};
+use crate::denoiser::{AuxImages, DenoiserPlugin};
+use crate::environment::{
+    cleanup_placeholder_environment, resolve_sky, PlaceholderEnvironment, Sky, SkyConfig, SKY_ENVIRONMENT, SKY_FALLBACK,
+};
+use crate::headless::HeadlessPlugin;
+use crate::physical_device::GpuSelection;
+use crate::procedural::ProceduralPlugin;
 use crate::rasterization_pipeline::{RasterizationPipeline, RasterizationPipelinePlugin, RasterizationRegisters};
-use crate::raytracing_pipeline::{RaytracerRegisters, RaytracingPipeline, RaytracingPlugin};
+use crate::raytracing_pipeline::{
+    RaytracerRegisters, RaytracingPipeline, RaytracingPlugin, VkRaytracingPipeline, RAYTRACER_REGISTER_STAGES,
+};
 use crate::render_buffer::{Buffer, BufferProvider};
+use crate::render_error::{recover_from_render_errors, report_render_error, RenderErrorPlugin, RenderResult};
+use crate::render_graph::{Access, GraphImage, RenderGraph, RenderGraphPlugin, TransientImages};
+use crate::render_image::VkImage;
+use crate::sampler::{cleanup_sobol_matrices, SamplerConfig, SobolMatrices};
 use crate::scene::{Scene, ScenePlugin};
 use crate::shader_binding_table::{SBTPlugin, SBT};
-use crate::sphere_blas::{cleanup_sphere_blas, SphereBLAS, AABB};
+use crate::swapchain;
 use crate::vulkan_assets::{AddVulkanAsset, VkAssetCleanupPlaybook, VulkanAssets};
 use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent, VkCleanupPlugin};
 use crate::{render_device::RenderDevice, swapchain::Swapchain};
-use crate::{swapchain, vk_utils};
 use ash::vk;
 use bevy::app::AppExit;
 use bevy::ecs::event::ManualEventReader;
@@ -18,7 +32,6 @@ use bevy::{
     prelude::*,
     window::{PrimaryWindow, RawHandleWrapper},
 };
-use rand::RngCore;
 
 #[derive(ScheduleLabel, Debug, Hash, PartialEq, Eq, Clone)]
 pub struct RenderSchedule;
@@ -55,12 +68,19 @@ fn flush_ecs(world: &mut World) {}
 pub struct RenderConfig {
     pub rt_pipeline: Handle<RaytracingPipeline>,
     pub quad_pipeline: Handle<RasterizationPipeline>,
+    pub denoise_pipeline: Handle<ComputePipeline>,
     pub skybox: Handle<bevy::prelude::Image>,
+    pub sky: SkyConfig,
+    pub sampler: SamplerConfig,
 }
 
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct RayFocalFocus(pub Option<(u32, u32)>);
 
+/// Frames the CPU records while the GPU is still busy with earlier ones, each one has its own
+/// `RenderResources`
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
 #[derive(Resource)]
 pub struct FrameResources {
     per_frame: Vec<RenderResources>,
@@ -80,7 +100,7 @@ impl FrameResources {
         self.current_frame = (self.current_frame + 1) % self.per_frame.len();
     }
 
-    fn current_idx(&self) -> usize {
+    pub fn current_idx(&self) -> usize {
         self.current_frame
     }
 }
@@ -92,8 +112,14 @@ pub struct RenderResources {
     pub cmd_buffer: vk::CommandBuffer,
 }
 
-fn cleanup_render_resources(render_resources: Res<FrameResources>, cleanup: Res<VkCleanup>) {
-    for res in &render_resources.per_frame {
+fn cleanup_render_resources(render_resources: Option<Res<FrameResources>>, cleanup: Res<VkCleanup>) {
+    if let Some(render_resources) = render_resources {
+        destroy_render_resources(&render_resources.per_frame, &cleanup);
+    }
+}
+
+fn destroy_render_resources(per_frame: &[RenderResources], cleanup: &VkCleanup) {
+    for res in per_frame {
         cleanup.send(VkCleanupEvent::Buffer(res.uniform_buffer.handle));
         cleanup.send(VkCleanupEvent::Buffer(res.query_buffer.handle));
         cleanup.send(VkCleanupEvent::Fence(res.fence));
@@ -104,11 +130,77 @@ fn cleanup_render_resources(render_resources: Res<FrameResources>, cleanup: Res<
 pub struct UniformData {
     inverse_view: Mat4,
     inverse_proj: Mat4,
-    entropy: u32,
-    should_clear: u32,
+    /// Frames since the last reset, seeds the random numbers
+    frame: u32,
+    /// Samples already in the render target, 0 clears it
+    sample_count: u32,
+    samples_per_frame: u32,
+    seed: u32,
+    sampler: u32,
     mouse_x: u32,
     mouse_y: u32,
     exposure: f32,
+    aperture: f32,
+    light_buffer: u64,
+    light_count: u32,
+    emissive_count: u32,
+    emissive_buffer: u64,
+    emissive_power: f32,
+    environment_width: u32,
+    environment_cdf: u64,
+    environment_height: u32,
+    sky_mode: u32,
+    sky_rotation: f32,
+    sky_intensity: f32,
+    sky_horizon: Vec3,
+    sky_zenith: Vec3,
+    sobol_matrices: u64,
+    instance_materials: u64,
+    primitive_materials: u64,
+}
+
+impl UniformData {
+    pub fn new(
+        camera: &Camera3d,
+        camera_transform: &GlobalTransform,
+        aspect_ratio: f32,
+        accumulation: &Accumulation,
+        sampler: &SamplerConfig,
+        sobol_matrices: &SobolMatrices,
+        focal_focus: Option<(u32, u32)>,
+    ) -> Self {
+        let (inverse_view, inverse_proj) = camera.inverse_view_proj(camera_transform, aspect_ratio);
+        Self {
+            inverse_view,
+            inverse_proj,
+            frame: accumulation.frame(),
+            sample_count: accumulation.samples(),
+            samples_per_frame: accumulation.next_frame_samples(),
+            seed: sampler.seed,
+            sampler: sampler.kind.shader_id(),
+            mouse_x: focal_focus.map_or(0, |f| f.0),
+            mouse_y: focal_focus.map_or(0, |f| f.1),
+            exposure: camera.exposure,
+            aperture: camera.aperture,
+            // filled in by record_trace from the scene
+            light_buffer: 0,
+            light_count: 0,
+            emissive_count: 0,
+            emissive_buffer: 0,
+            emissive_power: 0.0,
+            environment_width: 0,
+            environment_cdf: 0,
+            environment_height: 0,
+            sky_mode: SKY_ENVIRONMENT,
+            sky_rotation: 0.0,
+            sky_intensity: 0.0,
+            sky_horizon: Vec3::ZERO,
+            sky_zenith: Vec3::ZERO,
+            sobol_matrices: sobol_matrices.0.address,
+            instance_materials: 0,
+            primitive_materials: 0,
+        }
+    }
 }
 
 #[repr(C)]
@@ -121,37 +213,56 @@ pub struct RenderPlugin;
 impl Plugin for RenderPlugin {
     fn build(&self, app: &mut App) {
         // Don't ask, shit will segfault otherwise
-        let mut winit_settings = app.world.get_resource_mut::<WinitSettings>().unwrap();
-        winit_settings.return_from_run = true;
+        if let Some(mut winit_settings) = app.world.get_resource_mut::<WinitSettings>() {
+            winit_settings.return_from_run = true;
+        }
 
-        let (whandles, _) = app
+        let render_device = match app
             .world
-            .query::<(&RawHandleWrapper, With<PrimaryWindow>)>()
-            .single(&mut app.world);
-        let render_device = RenderDevice::from_window(whandles);
+            .query_filtered::<&RawHandleWrapper, With<PrimaryWindow>>()
+            .get_single(&app.world)
+        {
+            Ok(whandles) => RenderDevice::from_window(whandles, app.world.get_resource::<GpuSelection>()),
+            Err(_) => RenderDevice::headless(app.world.get_resource::<GpuSelection>()),
+        };
+        // there is nothing to clean up or save yet
+        let render_device = render_device.unwrap_or_else(|e| {
+            println!("Failed to create the render device: {}", e);
+            std::process::exit(1)
+        });
         app.world.insert_resource(render_device.clone());
 
         app.init_resource::<RayFocalFocus>();
+        app.init_resource::<Accumulation>();
+        app.init_resource::<crate::gltf_assets::GltfTextureOwner>();
 
         app.add_plugin(VkCleanupPlugin);
+        app.add_plugin(RenderErrorPlugin);
 
-        app.world
-            .insert_resource(SphereBLAS::make_one(&AABB::default(), &render_device));
+        app.world.insert_resource(PlaceholderEnvironment::new(&render_device));
 
         let mut render_schedule = RenderSet::base_schedule();
-        render_schedule.add_system(wait_for_frame_finish.in_set(RenderSet::Prepare));
-        render_schedule.add_system(render.in_set(RenderSet::Render));
+        render_schedule.add_system(
+            wait_for_frame_finish
+                .pipe(report_render_error)
+                .in_set(RenderSet::Prepare),
+        );
+        render_schedule.add_system(render.pipe(report_render_error).in_set(RenderSet::Render));
+        render_schedule.add_system(crate::gltf_assets::free_unused_gltf_textures.in_set(RenderSet::Extract));
 
         app.add_schedule(RenderSchedule, render_schedule);
 
         app.add_plugin(swapchain::SwapchainPlugin);
         app.add_plugin(RaytracingPlugin);
         app.add_plugin(RasterizationPipelinePlugin);
+        app.add_plugin(RenderGraphPlugin);
+        app.add_plugin(ComputePipelinePlugin);
+        app.add_plugin(DenoiserPlugin);
         app.add_plugin(SBTPlugin);
         app.add_plugin(Camera3dPlugin);
 
         app.add_system(run_render_schedule);
-        app.add_system(shutdown.in_base_set(CoreSet::Last));
+        app.add_system(shutdown.in_base_set(CoreSet::Last).after(recover_from_render_errors));
 
         app.add_asset::<crate::shader::Shader>()
             .init_asset_loader::<crate::shader::ShaderLoader>()
@@ -164,389 +275,390 @@ impl Plugin for RenderPlugin {
             .init_debug_asset_loader::<crate::gltf_assets::GltfLoader>()
             .add_vulkan_asset::<bevy::prelude::Image>();
 
+        app.add_plugin(crate::gltf_assets::GltfScenePlugin);
+
+        app.add_plugin(ProceduralPlugin);
         app.add_plugin(ScenePlugin);
+        app.add_plugin(HeadlessPlugin);
 
         app.world
             .get_resource_mut::<VkAssetCleanupPlaybook>()
             .unwrap()
             .add_system(cleanup_render_resources)
-            .add_system(cleanup_sphere_blas);
+            .add_system(crate::gltf_assets::cleanup_gltf_textures)
+            .add_system(cleanup_placeholder_environment)
+            .add_system(cleanup_sobol_matrices);
 
-        let mk_resources = || {
-            let uniform_buffer =
-                render_device.create_host_buffer::<UniformData>(1, vk::BufferUsageFlags::UNIFORM_BUFFER);
+        app.add_startup_system(create_frame_resources.pipe(report_render_error));
+    }
+}
 
-            let mut query_buffer_host =
-                render_device.create_host_buffer::<QueryData>(1, vk::BufferUsageFlags::TRANSFER_SRC);
-            {
-                let mut query_buffer_host = render_device.map_buffer(&mut query_buffer_host);
-                query_buffer_host[0] = QueryData { focal_distance: 7.0 };
+/// Creates the `FrameResources` and the `SobolMatrices` the frames read, without them there is no
+/// frame to render and the app shuts down
+fn create_frame_resources(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    cleanup: Res<VkCleanup>,
+) -> RenderResult<()> {
+    commands.insert_resource(SobolMatrices::new(&device)?);
+
+    let mut per_frame = Vec::new();
+    for _ in 0..FRAMES_IN_FLIGHT {
+        match create_render_resources(&device, &cleanup) {
+            Ok(resources) => per_frame.push(resources),
+            Err(e) => {
+                destroy_render_resources(&per_frame, &cleanup);
+                return Err(e);
             }
+        }
+    }
 
-            let query_buffer = render_device.create_device_buffer::<QueryData>(
-                1,
-                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
-            );
-            unsafe {
-                render_device.run_single_commands(|cmd_buffer| {
-                    render_device.upload_buffer(cmd_buffer, &query_buffer_host, &query_buffer);
-                });
-            }
-            app.world
-                .get_resource::<VkCleanup>()
-                .unwrap()
-                .send(VkCleanupEvent::Buffer(query_buffer_host.handle));
-
-            let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
-            let fence = unsafe { render_device.device.create_fence(&fence_info, None) }.unwrap();
-
-            let alloc_info = vk::CommandBufferAllocateInfo::builder()
-                .command_pool(render_device.command_pool)
-                .level(vk::CommandBufferLevel::PRIMARY)
-                .command_buffer_count(1);
-
-            let cmd_buffer = unsafe { render_device.device.allocate_command_buffers(&alloc_info) }.unwrap()[0];
-
-            RenderResources {
-                uniform_buffer,
-                query_buffer,
-                fence,
-                cmd_buffer,
-            }
-        };
+    commands.insert_resource(FrameResources {
+        per_frame,
+        current_frame: 0,
+    });
+    Ok(())
+}
 
-        app.world.insert_resource(FrameResources {
-            per_frame: vec![mk_resources(), mk_resources()],
-            current_frame: 0,
-        });
+fn create_render_resources(device: &RenderDevice, cleanup: &VkCleanup) -> RenderResult<RenderResources> {
+    let uniform_buffer = device.create_host_buffer::<UniformData>(1, vk::BufferUsageFlags::UNIFORM_BUFFER)?;
+
+    let mut query_buffer_host = device.create_host_buffer::<QueryData>(1, vk::BufferUsageFlags::TRANSFER_SRC)?;
+    {
+        let mut query_buffer_host = device.map_buffer(&mut query_buffer_host);
+        query_buffer_host[0] = QueryData {
+            focal_distance: DEFAULT_FOCAL_DISTANCE,
+        };
     }
+
+    let query_buffer = device.create_device_buffer::<QueryData>(
+        1,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+    )?;
+    let uploaded = unsafe {
+        device.run_single_commands(|cmd_buffer| {
+            device.upload_buffer(cmd_buffer, &query_buffer_host, &query_buffer);
+        })
+    };
+    cleanup.send(VkCleanupEvent::Buffer(query_buffer_host.handle));
+    uploaded?;
+
+    let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+    let fence = unsafe { device.device.create_fence(&fence_info, None) }?;
+
+    let alloc_info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(device.command_pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(1);
+
+    let cmd_buffer = unsafe { device.device.allocate_command_buffers(&alloc_info) }?[0];
+
+    Ok(RenderResources {
+        uniform_buffer,
+        query_buffer,
+        fence,
+        cmd_buffer,
+    })
 }
 
-fn run_render_schedule(world: &mut World) {
-    world.run_schedule(RenderSchedule);
+fn run_render_schedule(world: &mut World, mut failed: Local<bool>) {
+    if world.contains_resource::<FrameResources>() {
+        world.run_schedule(RenderSchedule);
+    } else if !*failed {
+        println!("The frame resources could not be created, shutting down");
+        world.send_event(AppExit);
+        *failed = true;
+    }
 }
 
-fn wait_for_frame_finish(
+/// Moves on to the next frame in flight once the GPU finished the frame that used its resources
+/// last. The fence is only reset right before the next submit, a frame that fails before that must
+/// not leave it unsignaled for the one after.
+pub fn wait_for_frame_finish(
     device: Res<RenderDevice>,
     cleanup: Res<VkCleanup>,
-    mut swapchain: Query<&mut Swapchain>,
-    render_resources: ResMut<FrameResources>,
-) {
-    // get the next image to render to
-    let mut swapchain = swapchain.single_mut();
-    swapchain.aquire_next_image(&device);
-
-    // TODO use two scene tlasses
-    // render_resources.cycle();
+    mut swapchain: Query<(&mut Swapchain, &Window)>,
+    mut render_resources: ResMut<FrameResources>,
+    mut accumulation: ResMut<Accumulation>,
+) -> RenderResult<()> {
+    render_resources.cycle();
     unsafe {
         device
             .device
-            .wait_for_fences(std::slice::from_ref(&render_resources.get().fence), true, u64::MAX)
-            .unwrap();
-        device
-            .device
-            .reset_fences(std::slice::from_ref(&render_resources.get().fence))
-            .unwrap();
+            .wait_for_fences(std::slice::from_ref(&render_resources.get().fence), true, u64::MAX)?;
     }
     cleanup.send(VkCleanupEvent::SignalNextFrame);
+
+    // get the next image to render to, its semaphores belong to the frame
+    if let Ok((mut swapchain, window)) = swapchain.get_single_mut() {
+        if swapchain.outdated {
+            unsafe { swapchain.on_resize(window)? };
+            accumulation.reset();
+        }
+        swapchain.aquire_next_image(&device, render_resources.current_idx())?;
+    }
+    Ok(())
 }
 
 fn render(
     device: Res<RenderDevice>,
-    scene: Res<Scene>,
+    mut scene: ResMut<Scene>,
     mut swapchain: Query<&mut Swapchain>,
     textures: Res<VulkanAssets<bevy::prelude::Image>>,
     gtransforms: Query<Ref<GlobalTransform>>,
-    render_config: Res<RenderConfig>,
+    render_config: Option<Res<RenderConfig>>,
     mut render_resources: ResMut<FrameResources>,
     rt_pipelines: Res<VulkanAssets<RaytracingPipeline>>,
     rast_pipelines: Res<VulkanAssets<RasterizationPipeline>>,
     sbt: Res<SBT>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
     camera: Query<(Entity, &Camera3d)>,
-    focal_focus: Res<RayFocalFocus>,
-) {
-    let mut swapchain = swapchain.single_mut();
+    placeholder_environment: Res<PlaceholderEnvironment>,
+    asset_server: Res<AssetServer>,
+    // grouped, systems take at most 16 parameters
+    (mut accumulation, sobol_matrices, focal_focus): (ResMut<Accumulation>, Res<SobolMatrices>, Res<RayFocalFocus>),
+    (compute_pipelines, mut compute_passes, mut transient_images, cleanup): (
+        Res<VulkanAssets<ComputePipeline>>,
+        ResMut<ComputePasses>,
+        ResMut<TransientImages>,
+        Res<VkCleanup>,
+    ),
+) -> RenderResult<()> {
+    let Ok(mut swapchain) = swapchain.get_single_mut() else {
+        return Ok(());
+    };
+    // an outdated swapchain is resized before the next frame
+    if !swapchain.image_acquired {
+        return Ok(());
+    }
     let (camera_e, camera) = camera.single();
 
     // wait for the previous frame to finish
     unsafe {
-        let (swapchain_image, swapchain_view) = swapchain.current_framebuffer();
+        let swapchain_image = swapchain.current_framebuffer().clone();
 
         let cmd_buffer = render_resources.get().cmd_buffer;
-        device
+        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let begun = device
             .device
             .reset_command_buffer(cmd_buffer, vk::CommandBufferResetFlags::empty())
-            .unwrap();
+            .and_then(|()| device.device.begin_command_buffer(cmd_buffer, &begin_info));
+        if let Err(e) = begun {
+            swapchain.skip_frame();
+            return Err(e.into());
+        }
 
-        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-        device.device.begin_command_buffer(cmd_buffer, &begin_info).unwrap();
+        let frame_idx = render_resources.current_idx();
+        let uniform_buffer_address = render_resources.get().uniform_buffer.address;
+        let (width, height) = (swapchain.width, swapchain.height);
+
+        let mut graph = RenderGraph::new(width, height);
+        let mut traced = false;
+        let swapchain_view = swapchain_image.view;
+        let swapchain_image = graph.import(swapchain_image, vk::ImageLayout::PRESENT_SRC_KHR);
+
+        // the scene description inserts the config once it has loaded
+        let rt_pipeline = render_config
+            .as_ref()
+            .and_then(|config| Some((config, rt_pipelines.get(&config.rt_pipeline)?)));
+        if let Some((render_config, compiled)) = rt_pipeline {
+            // meshes still streaming in would leave their absence in the accumulated image
+            if focal_focus.0.is_some() || camera.moved || render_config.is_changed() || !scene.is_complete(frame_idx) {
+                accumulation.reset();
+            }
 
-        swapchain.on_begin_render(cmd_buffer);
+            // a converged image stays in the render target and only gets presented
+            let sky = resolve_sky(render_config, &textures, &placeholder_environment, &asset_server)
+                .filter(|_| !accumulation.is_converged() && ready_to_trace(&scene, &sbt, frame_idx));
+            if let Some(sky) = sky {
+                let camera_transform = gtransforms.get(camera_e).unwrap();
+                let uniforms = UniformData::new(
+                    camera,
+                    &camera_transform,
+                    width as f32 / height as f32,
+                    &accumulation,
+                    &render_config.sampler,
+                    &sobol_matrices,
+                    focal_focus.0,
+                );
+                let samples = accumulation.next_frame_samples();
+                accumulation.advance(samples);
+
+                let swapchain = &*swapchain;
+                let (scene, sbt, frame_resources) = (&*scene, &*sbt, &mut *render_resources);
+                let tlas = scene.add_tlas_pass(&mut graph, frame_idx);
+                traced = true;
+                let traced = Access::StorageReadWrite(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR);
+                let mut accesses = [
+                    &swapchain.render_target,
+                    &swapchain.aux.albedo,
+                    &swapchain.aux.normal_depth,
+                ]
+                .map(|image| (graph.image(GraphImage::Persistent(image.clone())), traced))
+                .to_vec();
+                accesses.push((
+                    tlas,
+                    Access::AccelerationStructureRead(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR),
+                ));
+                graph.add_pass("trace", &accesses, move |context| {
+                    record_trace(
+                        context.device,
+                        context.cmd_buffer,
+                        frame_resources,
+                        compiled,
+                        &sky,
+                        scene,
+                        sbt,
+                        &swapchain.render_target,
+                        &swapchain.aux,
+                        width,
+                        height,
+                        uniforms,
+                    );
+                });
+            }
 
-        // Make swapchain available for rendering
-        vk_utils::transition_image_layout(
-            &device,
-            cmd_buffer,
-            swapchain_image,
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        );
+            let displayed = add_compute_passes(&mut graph, &compute_pipelines, &mut compute_passes, frame_idx)
+                .unwrap_or(GraphImage::Persistent(swapchain.render_target.clone()));
 
-        if let Some(compiled) = rt_pipelines.get(&render_config.rt_pipeline) {
-            if let Some(skybox) = textures.get(&render_config.skybox) {
-                if scene.is_ready() {
-                    let ray_descriptor_set = compiled.descriptor_sets[render_resources.current_idx()];
-                    let mut writes = Vec::new();
+            if let Some(compiled) = rast_pipelines.get(&render_config.quad_pipeline) {
+                let rast_descriptor_set = compiled.descriptor_sets[frame_idx];
+                let srgb_target = swapchain.encodes_srgb();
+                let sampled = Access::Sampled(vk::PipelineStageFlags2::FRAGMENT_SHADER);
+                let accesses = [
+                    (graph.image(displayed.clone()), sampled),
+                    (swapchain_image, Access::ColorAttachment),
+                ];
+                graph.add_pass("quad", &accesses, move |context| {
+                    let (device, cmd_buffer) = (context.device, context.cmd_buffer);
                     // update the descriptor set
                     let render_target_image_binding = vk::DescriptorImageInfo::builder()
-                        .image_layout(vk::ImageLayout::GENERAL)
-                        .image_view(swapchain.render_target.view)
-                        .build();
-
-                    writes.push(
-                        vk::WriteDescriptorSet::builder()
-                            .dst_set(ray_descriptor_set)
-                            .dst_binding(0)
-                            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                            .image_info(std::slice::from_ref(&render_target_image_binding))
-                            .build(),
-                    );
-
-                    let mut p_acceleration_structure_write = vk::WriteDescriptorSetAccelerationStructureKHR::builder()
-                        .acceleration_structures(std::slice::from_ref(&scene.tlas.handle))
+                        .image_layout(sampled.layout())
+                        .image_view(context.image(&displayed).view)
+                        .sampler(device.nearest_sampler)
                         .build();
 
-                    let mut write_acceleration_structure = vk::WriteDescriptorSet::builder()
-                        .dst_set(ray_descriptor_set)
-                        .dst_binding(1)
-                        .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                        .push_next(&mut p_acceleration_structure_write)
+                    let descriptor_write = vk::WriteDescriptorSet::builder()
+                        .dst_set(rast_descriptor_set)
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(std::slice::from_ref(&render_target_image_binding))
                         .build();
-                    write_acceleration_structure.descriptor_count = 1;
 
-                    writes.push(write_acceleration_structure);
+                    device
+                        .device
+                        .update_descriptor_sets(std::slice::from_ref(&descriptor_write), &[]);
 
-                    let skybox_image_binding = vk::DescriptorImageInfo::builder()
-                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-                        .image_view(skybox.view)
-                        .sampler(device.linear_sampler)
-                        .build();
+                    let render_area = vk::Rect2D {
+                        offset: vk::Offset2D { x: 0, y: 0 },
+                        extent: vk::Extent2D { width, height },
+                    };
 
-                    writes.push(
-                        vk::WriteDescriptorSet::builder()
-                            .dst_set(ray_descriptor_set)
-                            .dst_binding(2)
-                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                            .image_info(std::slice::from_ref(&skybox_image_binding))
-                            .build(),
+                    let attachment_info = vk::RenderingAttachmentInfoKHR::builder()
+                        .image_view(swapchain_view)
+                        .image_layout(Access::ColorAttachment.layout())
+                        .load_op(vk::AttachmentLoadOp::CLEAR)
+                        .store_op(vk::AttachmentStoreOp::STORE)
+                        .clear_value(vk::ClearValue {
+                            color: vk::ClearColorValue {
+                                float32: [0.0, 0.0, 0.0, 0.0],
+                            },
+                        });
+
+                    let render_info = vk::RenderingInfo::builder()
+                        .layer_count(1)
+                        .render_area(render_area)
+                        .color_attachments(std::slice::from_ref(&attachment_info));
+
+                    device.device.cmd_begin_rendering(cmd_buffer, &render_info);
+
+                    device
+                        .device
+                        .cmd_set_scissor(cmd_buffer, 0, std::slice::from_ref(&render_area));
+                    device.device.cmd_set_viewport(
+                        cmd_buffer,
+                        0,
+                        std::slice::from_ref(&vk::Viewport {
+                            x: 0.0,
+                            y: 0.0,
+                            width: width as f32,
+                            height: height as f32,
+                            min_depth: 0.0,
+                            max_depth: 1.0,
+                        }),
                     );
 
-                    device.device.update_descriptor_sets(&writes, &[]);
+                    device
+                        .device
+                        .cmd_bind_pipeline(cmd_buffer, vk::PipelineBindPoint::GRAPHICS, compiled.vk_pipeline);
 
-                    device.device.cmd_bind_pipeline(
+                    device.device.cmd_bind_descriptor_sets(
                         cmd_buffer,
-                        vk::PipelineBindPoint::RAY_TRACING_KHR,
-                        compiled.vk_pipeline,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        compiled.pipeline_layout,
+                        0,
+                        std::slice::from_ref(&rast_descriptor_set),
+                        &[],
                     );
 
-                    {
-                        let mut uniform_view = device.map_buffer(&mut render_resources.get_mut().uniform_buffer);
-                        let mut rng = rand::thread_rng();
-                        let camera_transform = gtransforms.get(camera_e).unwrap();
-                        let (_, rotation, translation) = camera_transform.to_scale_rotation_translation();
-                        let camera_view = Mat4::from_quat(rotation) * Mat4::from_translation(translation);
-                        let projection = Mat4::perspective_rh(
-                            camera.fov,
-                            swapchain.width as f32 / swapchain.height as f32,
-                            camera.min_t,
-                            camera.max_t,
-                        );
-                        let entropy = rng.next_u32();
-                        uniform_view[0] = UniformData {
-                            inverse_view: camera_view.inverse(),
-                            inverse_proj: projection.inverse(),
-                            entropy,
-                            should_clear: (focal_focus.0.is_some() || camera.moved) as u32,
-                            mouse_x: focal_focus.0.map_or(0, |f| f.0),
-                            mouse_y: focal_focus.0.map_or(0, |f| f.1),
-                            exposure: camera.exposure,
-                        };
-                    }
-
-                    let push_constants = RaytracerRegisters {
-                        uniform_buffer_address: render_resources.get().uniform_buffer.address,
-                        query_buffer_address: render_resources.get().query_buffer.address,
+                    let push_constants = RasterizationRegisters {
+                        uniforms: uniform_buffer_address,
+                        srgb_target: srgb_target as u32,
+                        _padding: 0,
                     };
 
                     device.device.cmd_push_constants(
                         cmd_buffer,
                         compiled.pipeline_layout,
-                        vk::ShaderStageFlags::RAYGEN_KHR,
+                        vk::ShaderStageFlags::FRAGMENT,
                         0,
                         bytemuck::bytes_of(&push_constants),
                     );
 
-                    device.device.cmd_bind_descriptor_sets(
-                        cmd_buffer,
-                        vk::PipelineBindPoint::RAY_TRACING_KHR,
-                        compiled.pipeline_layout,
-                        0,
-                        &[ray_descriptor_set, device.g_descriptor_set],
-                        &[],
-                    );
-
-                    if sbt.data.address != 0 {
-                        device.exts.rt_pipeline.cmd_trace_rays(
-                            cmd_buffer,
-                            &sbt.raygen_region,
-                            &sbt.miss_region,
-                            &sbt.hit_region,
-                            &vk::StridedDeviceAddressRegionKHR::default(),
-                            swapchain.width,
-                            swapchain.height,
-                            1,
-                        )
-                    }
-                }
-            }
-
-            // make render target available for sampling
-            vk_utils::transition_image_layout(
-                &device,
-                cmd_buffer,
-                swapchain.render_target.handle,
-                vk::ImageLayout::GENERAL,
-                vk::ImageLayout::GENERAL,
-            );
-
-            if let Some(compiled) = rast_pipelines.get(&render_config.quad_pipeline) {
-                let rast_descriptor_set = compiled.descriptor_sets[render_resources.current_idx()];
-                // update the descriptor set
-                let render_target_image_binding = vk::DescriptorImageInfo::builder()
-                    .image_layout(vk::ImageLayout::GENERAL)
-                    .image_view(swapchain.render_target.view)
-                    .sampler(device.nearest_sampler)
-                    .build();
-
-                let descriptor_write = vk::WriteDescriptorSet::builder()
-                    .dst_set(rast_descriptor_set)
-                    .dst_binding(0)
-                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .image_info(std::slice::from_ref(&render_target_image_binding))
-                    .build();
-
-                device
-                    .device
-                    .update_descriptor_sets(std::slice::from_ref(&descriptor_write), &[]);
-
-                let render_area = vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: vk::Extent2D {
-                        width: swapchain.width,
-                        height: swapchain.height,
-                    },
-                };
-
-                let attachment_info = vk::RenderingAttachmentInfoKHR::builder()
-                    .image_view(swapchain_view)
-                    .image_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
-                    .load_op(vk::AttachmentLoadOp::CLEAR)
-                    .store_op(vk::AttachmentStoreOp::STORE)
-                    .clear_value(vk::ClearValue {
-                        color: vk::ClearColorValue {
-                            float32: [0.0, 0.0, 0.0, 0.0],
-                        },
-                    });
-
-                let render_info = vk::RenderingInfo::builder()
-                    .layer_count(1)
-                    .render_area(render_area)
-                    .color_attachments(std::slice::from_ref(&attachment_info));
-
-                device.device.cmd_begin_rendering(cmd_buffer, &render_info);
-
-                device
-                    .device
-                    .cmd_set_scissor(cmd_buffer, 0, std::slice::from_ref(&render_area));
-                device.device.cmd_set_viewport(
-                    cmd_buffer,
-                    0,
-                    std::slice::from_ref(&vk::Viewport {
-                        x: 0.0,
-                        y: 0.0,
-                        width: swapchain.width as f32,
-                        height: swapchain.height as f32,
-                        min_depth: 0.0,
-                        max_depth: 1.0,
-                    }),
-                );
-
-                device
-                    .device
-                    .cmd_bind_pipeline(cmd_buffer, vk::PipelineBindPoint::GRAPHICS, compiled.vk_pipeline);
-
-                device.device.cmd_bind_descriptor_sets(
-                    cmd_buffer,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    compiled.pipeline_layout,
-                    0,
-                    std::slice::from_ref(&rast_descriptor_set),
-                    &[],
-                );
-
-                let push_constants = RasterizationRegisters {
-                    uniforms: render_resources.get().uniform_buffer.address,
-                };
-
-                device.device.cmd_push_constants(
-                    cmd_buffer,
-                    compiled.pipeline_layout,
-                    vk::ShaderStageFlags::FRAGMENT,
-                    0,
-                    bytemuck::bytes_of(&push_constants),
-                );
-
-                device.device.cmd_draw(cmd_buffer, 3, 1, 0, 0);
-                device.device.cmd_end_rendering(cmd_buffer);
+                    device.device.cmd_draw(cmd_buffer, 3, 1, 0, 0);
+                    device.device.cmd_end_rendering(cmd_buffer);
+                });
             }
         }
 
-        // Make swapchain available for presentation
-        vk_utils::transition_image_layout(
-            &device,
-            cmd_buffer,
-            swapchain_image,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-            vk::ImageLayout::PRESENT_SRC_KHR,
-        );
+        // nothing consumed the passes when the raytracing pipeline is still compiling
+        compute_passes.0.clear();
 
-        device.device.end_command_buffer(cmd_buffer).unwrap();
+        // also moves the swapchain image into the present layout
+        let recorded = graph
+            .execute(&device, cmd_buffer, &mut transient_images, &cleanup)
+            .and_then(|()| Ok(device.device.end_command_buffer(cmd_buffer)?));
+        if let Err(e) = recorded {
+            swapchain.skip_frame();
+            return Err(e);
+        }
 
         // submit the command buffer to the queue
+        let (image_ready_sem, render_finished_sem) = (swapchain.image_ready_sem(), swapchain.render_finished_sem());
         let submit_info = vk::SubmitInfo::builder()
             .command_buffers(std::slice::from_ref(&cmd_buffer))
-            .wait_semaphores(std::slice::from_ref(&swapchain.image_ready_sem))
+            .wait_semaphores(std::slice::from_ref(&image_ready_sem))
             .wait_dst_stage_mask(std::slice::from_ref(&vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT))
-            .signal_semaphores(std::slice::from_ref(&swapchain.render_finished_sem))
+            .signal_semaphores(std::slice::from_ref(&render_finished_sem))
             .build();
 
         {
+            let fence = render_resources.get().fence;
+            device.device.reset_fences(std::slice::from_ref(&fence))?;
             let queue = device.queue.lock().unwrap();
             device
                 .device
-                .queue_submit(
-                    queue.clone(),
-                    std::slice::from_ref(&submit_info),
-                    render_resources.get().fence,
-                )
-                .unwrap();
+                .queue_submit(queue.clone(), std::slice::from_ref(&submit_info), fence)?;
+        }
+        swapchain.image_acquired = false;
+        if traced {
+            scene.tlas_submitted(frame_idx);
         }
 
         let image_idx = swapchain.current_image_idx as u32;
 
         let present_info = vk::PresentInfoKHR::builder()
-            .wait_semaphores(std::slice::from_ref(&swapchain.render_finished_sem))
+            .wait_semaphores(std::slice::from_ref(&render_finished_sem))
             .swapchains(std::slice::from_ref(&swapchain.handle))
             .image_indices(std::slice::from_ref(&image_idx))
             .build();
@@ -560,12 +672,163 @@ fn render(
             Err(vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::SUBOPTIMAL_KHR) => {
                 println!("------ SWAPCHAIN OUT OF DATE ------");
                 let primary_window = primary_window.get_single().unwrap();
-                swapchain.on_resize(primary_window);
+                accumulation.reset();
+                swapchain.on_resize(primary_window)?;
             }
-            Err(e) => panic!("Failed to present swapchain image: {:?}", e),
+            Err(e) => return Err(e.into()),
             Ok(_) => {}
         }
     }
+    Ok(())
+}
+
+/// Whether the TLAS of the frame and the shader binding table are there for `record_trace`
+pub fn ready_to_trace(scene: &Scene, sbt: &SBT, frame_idx: usize) -> bool {
+    scene.is_ready(frame_idx) && sbt.data.address != 0
+}
+
+/// Records the path tracer into `cmd_buffer`, accumulating into `target` and the guides of `aux`.
+/// Check `ready_to_trace` first, the pass tracing has to come after `Scene::add_tlas_pass`.
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn record_trace(
+    device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    render_resources: &mut FrameResources,
+    compiled: &VkRaytracingPipeline,
+    sky: &Sky,
+    scene: &Scene,
+    sbt: &SBT,
+    target: &VkImage,
+    aux: &AuxImages,
+    width: u32,
+    height: u32,
+    mut uniforms: UniformData,
+) {
+    let scene = scene.frame(render_resources.current_idx());
+    uniforms.light_buffer = scene.light_buffer.address;
+    uniforms.light_count = scene.light_count;
+    uniforms.emissive_buffer = scene.emissive_buffer.address;
+    uniforms.emissive_count = scene.emissive_count;
+    uniforms.emissive_power = scene.emissive_power;
+    uniforms.instance_materials = scene.instance_material_buffer.address;
+    uniforms.primitive_materials = scene.primitive_material_buffer.address;
+    uniforms.environment_cdf = sky.environment.cdf.address;
+    uniforms.environment_width = sky.environment.width;
+    uniforms.environment_height = sky.environment.height;
+    uniforms.sky_rotation = sky.config.rotation;
+    uniforms.sky_intensity = sky.config.intensity;
+    if let Some(fallback) = sky.fallback {
+        uniforms.sky_mode = SKY_FALLBACK;
+        (uniforms.sky_horizon, uniforms.sky_zenith) = fallback.colors();
+    }
+
+    let ray_descriptor_set = compiled.descriptor_sets[render_resources.current_idx()];
+    let mut writes = Vec::new();
+    // update the descriptor set
+    let render_target_image_binding = vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::GENERAL)
+        .image_view(target.view)
+        .build();
+
+    writes.push(
+        vk::WriteDescriptorSet::builder()
+            .dst_set(ray_descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .image_info(std::slice::from_ref(&render_target_image_binding))
+            .build(),
+    );
+
+    let mut p_acceleration_structure_write = vk::WriteDescriptorSetAccelerationStructureKHR::builder()
+        .acceleration_structures(std::slice::from_ref(&scene.tlas.handle))
+        .build();
+
+    let mut write_acceleration_structure = vk::WriteDescriptorSet::builder()
+        .dst_set(ray_descriptor_set)
+        .dst_binding(1)
+        .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+        .push_next(&mut p_acceleration_structure_write)
+        .build();
+    write_acceleration_structure.descriptor_count = 1;
+
+    writes.push(write_acceleration_structure);
+
+    let skybox_image_binding = vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .image_view(sky.environment.image.view)
+        .sampler(device.linear_sampler)
+        .build();
+
+    writes.push(
+        vk::WriteDescriptorSet::builder()
+            .dst_set(ray_descriptor_set)
+            .dst_binding(2)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(std::slice::from_ref(&skybox_image_binding))
+            .build(),
+    );
+
+    let guide_bindings = [&aux.albedo, &aux.normal_depth].map(|image| {
+        vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::GENERAL)
+            .image_view(image.view)
+            .build()
+    });
+
+    for (binding, info) in (3..).zip(&guide_bindings) {
+        writes.push(
+            vk::WriteDescriptorSet::builder()
+                .dst_set(ray_descriptor_set)
+                .dst_binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(std::slice::from_ref(info))
+                .build(),
+        );
+    }
+
+    device.device.update_descriptor_sets(&writes, &[]);
+
+    device
+        .device
+        .cmd_bind_pipeline(cmd_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, compiled.vk_pipeline);
+
+    {
+        let mut uniform_view = device.map_buffer(&mut render_resources.get_mut().uniform_buffer);
+        uniform_view[0] = uniforms;
+    }
+
+    let push_constants = RaytracerRegisters {
+        uniform_buffer_address: render_resources.get().uniform_buffer.address,
+        query_buffer_address: render_resources.get().query_buffer.address,
+    };
+
+    device.device.cmd_push_constants(
+        cmd_buffer,
+        compiled.pipeline_layout,
+        RAYTRACER_REGISTER_STAGES,
+        0,
+        bytemuck::bytes_of(&push_constants),
+    );
+
+    device.device.cmd_bind_descriptor_sets(
+        cmd_buffer,
+        vk::PipelineBindPoint::RAY_TRACING_KHR,
+        compiled.pipeline_layout,
+        0,
+        &[ray_descriptor_set, device.g_descriptor_set],
+        &[],
+    );
+
+    device.exts.rt_pipeline.cmd_trace_rays(
+        cmd_buffer,
+        &sbt.raygen_region,
+        &sbt.miss_region,
+        &sbt.hit_region,
+        &vk::StridedDeviceAddressRegionKHR::default(),
+        width,
+        height,
+        1,
+    );
 }
 
 fn shutdown(world: &mut World) {
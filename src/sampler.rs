@@ -0,0 +1,215 @@
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::render_buffer::{Buffer, BufferProvider};
+use crate::render_device::RenderDevice;
+use crate::render_error::RenderResult;
+use crate::vulkan_cleanup::{VkCleanup, VkCleanupEvent};
+
+pub const SAMPLER_PCG: u32 = 0;
+pub const SAMPLER_SOBOL: u32 = 1;
+
+/// Dimensions of the Sobol sequence in `sobol_matrices`, SOBOL_DIMENSIONS in common.glsl
+pub const SOBOL_DIMENSIONS: u32 = 4;
+
+/// Dimensions raygen.rgen reserves for the camera ray, the path of every sample starts after them
+pub const CAMERA_DIMENSIONS: u32 = 16;
+
+/// (degree, coefficients, initial direction numbers) of the primitive polynomials behind the Sobol
+/// dimensions after the first, from Joe and Kuo's new-joe-kuo-6.21201
+const SOBOL_POLYNOMIALS: [(usize, u32, &[u32]); 3] = [(1, 0, &[1]), (2, 1, &[1, 3]), (3, 1, &[1, 3, 1])];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SamplerKind {
+    /// Independent random numbers from a per-pixel PCG generator
+    #[default]
+    Pcg,
+    /// Owen scrambled Sobol points, converges faster on smooth integrands
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            SamplerKind::Pcg => SAMPLER_PCG,
+            SamplerKind::Sobol => SAMPLER_SOBOL,
+        }
+    }
+}
+
+/// Together with the frame index the seed fixes every random number of a render, two runs with
+/// the same seed produce the same image. Inserted from the command line, the scene description
+/// hands it on through `RenderConfig`.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct SamplerConfig {
+    pub kind: SamplerKind,
+    pub seed: u32,
+}
+
+/// 32 direction numbers per dimension, most significant bit first. The first dimension is the van
+/// der Corput sequence.
+pub fn sobol_matrices() -> Vec<u32> {
+    let mut matrices = (0..32).map(|bit| 1u32 << (31 - bit)).collect::<Vec<_>>();
+
+    for (degree, coefficients, initial) in SOBOL_POLYNOMIALS {
+        let mut directions = [0u32; 32];
+        for i in 0..32 {
+            directions[i] = if i < degree {
+                initial[i] << (31 - i)
+            } else {
+                let mut v = directions[i - degree] ^ (directions[i - degree] >> degree);
+                for k in 1..degree {
+                    if (coefficients >> (degree - 1 - k)) & 1 != 0 {
+                        v ^= directions[i - k];
+                    }
+                }
+                v
+            };
+        }
+        matrices.extend_from_slice(&directions);
+    }
+
+    matrices
+}
+
+/// wang_hash in rand.glsl
+pub fn wang_hash(mut seed: u32) -> u32 {
+    seed = (seed ^ 61) ^ (seed >> 16);
+    seed = seed.wrapping_mul(9);
+    seed ^= seed >> 4;
+    seed = seed.wrapping_mul(0x27d4eb2d);
+    seed ^ (seed >> 15)
+}
+
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    seed ^ v.wrapping_add(seed << 6).wrapping_add(seed >> 2)
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// An Owen scramble of the bits of `x`, from "Practical Hash-based Owen Scrambling" (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn sobol(matrices: &[u32], index: u32, dimension: u32) -> u32 {
+    (0..32)
+        .filter(|bit| (index >> bit) & 1 != 0)
+        .fold(0, |x, bit| x ^ matrices[(dimension * 32 + bit) as usize])
+}
+
+/// The state sobolRandf in rand.glsl walks through. Every SOBOL_DIMENSIONS consecutive dimensions
+/// share a Sobol sequence whose order is shuffled by the block they are in.
+pub struct SobolSequence<'a> {
+    matrices: &'a [u32],
+    scramble_seed: u32,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl<'a> SobolSequence<'a> {
+    pub fn new(matrices: &'a [u32], scramble_seed: u32) -> Self {
+        Self {
+            matrices,
+            scramble_seed,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    /// startSample in rand.glsl
+    pub fn start_sample(&mut self, sample_index: u32, dimension: u32) {
+        self.sample_index = sample_index;
+        self.dimension = dimension;
+    }
+
+    pub fn next(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        let seed = hash_combine(self.scramble_seed, wang_hash(dimension / SOBOL_DIMENSIONS));
+        let index = nested_uniform_scramble(self.sample_index, seed);
+        let component = dimension % SOBOL_DIMENSIONS;
+        let x = nested_uniform_scramble(sobol(self.matrices, index, component), hash_combine(seed, component));
+        // 24 bits, more would round up to 1
+        (x >> 8) as f32 / 16777216.0
+    }
+}
+
+/// `sobol_matrices` on the GPU, read through UniformData
+#[derive(Resource)]
+pub struct SobolMatrices(pub Buffer<u32>);
+
+impl SobolMatrices {
+    pub fn new(device: &RenderDevice) -> RenderResult<Self> {
+        let matrices = sobol_matrices();
+        let mut buffer =
+            device.create_host_buffer::<u32>(matrices.len() as u64, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+        device.map_buffer(&mut buffer).as_slice_mut().copy_from_slice(&matrices);
+        Ok(Self(buffer))
+    }
+}
+
+pub fn cleanup_sobol_matrices(matrices: Option<Res<SobolMatrices>>, cleanup: Res<VkCleanup>) {
+    if let Some(matrices) = matrices {
+        cleanup.send(VkCleanupEvent::Buffer(matrices.0.handle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_unit(x: u32) -> f32 {
+        x as f32 / 4294967296.0
+    }
+
+    #[test]
+    fn first_points_match_the_sobol_sequence() {
+        let matrices = sobol_matrices();
+        let first = |dimension| {
+            (0..8)
+                .map(|i| to_unit(sobol(&matrices, i, dimension)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(first(0), [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+        assert_eq!(first(1), [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875]);
+    }
+
+    #[test]
+    fn scrambled_points_are_stratified() {
+        let matrices = sobol_matrices();
+        let samples = 256;
+
+        for seed in [0, 1, 0xdeadbeef] {
+            let mut sequence = SobolSequence::new(&matrices, seed);
+            // both the first block of dimensions and one further along
+            for dimension in (0..SOBOL_DIMENSIONS).chain(CAMERA_DIMENSIONS..CAMERA_DIMENSIONS + SOBOL_DIMENSIONS) {
+                let mut strata = vec![false; samples as usize];
+                for i in 0..samples {
+                    sequence.start_sample(i, dimension);
+                    let x = sequence.next();
+                    assert!((0.0..1.0).contains(&x), "dimension {} sample {} is {}", dimension, i, x);
+
+                    let stratum = (x * samples as f32) as usize;
+                    assert!(
+                        !strata[stratum],
+                        "dimension {} puts two samples into [{}, {})",
+                        dimension,
+                        stratum as f32 / samples as f32,
+                        (stratum + 1) as f32 / samples as f32
+                    );
+                    strata[stratum] = true;
+                }
+            }
+        }
+    }
+}
@@ -1,29 +1,207 @@
 use ash::vk::{self, AccelerationStructureReferenceKHR, Packed24_8};
+use bevy::math::Affine3A;
 use bevy::prelude::*;
 
 use crate::{
-    acceleration_structure::AccelerationStructure,
-    gltf_assets::GltfMesh,
+    acceleration_structure::{allocate_acceleration_structure, AccelerationStructure},
+    gltf_assets::{GltfMesh, GltfScene, GltfSceneSpawned},
+    instance_material::{
+        instance_appearance, GpuInstanceMaterial, InstanceAppearance, InstanceMaterialOverride, RayVisibility,
+    },
+    lights::{build_emissive_cdf, GpuEmissiveTriangle, GpuPunctualLight, PunctualLight},
+    procedural::{GpuPrimitiveMaterial, PrimitiveInstance, ProceduralBlasses},
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
-    shader_binding_table::SBT,
-    sphere_blas::{Sphere, SphereBLAS},
+    render_error::{report_render_error, RenderResult},
+    render_graph::{Access, RenderGraph, ResourceId},
+    render_plugin::{FrameResources, RenderSchedule, RenderSet, FRAMES_IN_FLIGHT},
+    shader_binding_table::{update_sbt, SBT},
     vk_utils,
     vulkan_assets::{VkAssetCleanupPlaybook, VulkanAssets},
     vulkan_cleanup::{VkCleanup, VkCleanupEvent},
 };
 
-#[derive(Resource, Default)]
-pub struct Scene {
+/// Updates need the flags the TLAS was built with
+const TLAS_FLAGS: vk::BuildAccelerationStructureFlagsKHR = vk::BuildAccelerationStructureFlagsKHR::from_raw(
+    vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE.as_raw()
+        | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE.as_raw(),
+);
+
+/// Bumped whenever the instances change, so every frame in flight can tell how far behind its TLAS
+/// is. A new topology means new instances, new BLASes or new hit groups and needs a full build,
+/// moved instances only need a refit.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+struct SceneVersion {
+    topology: u64,
+    transforms: u64,
+}
+
+/// What one frame in flight traces against. Every frame has its own, so the next frame can write
+/// them while the GPU is still tracing the previous one.
+#[derive(Default)]
+pub struct SceneFrame {
     pub tlas: AccelerationStructure,
     scratch_buffer: Buffer<u8>,
+    /// `scratch_buffer` aligned for the build
+    scratch_address: u64,
+    /// Grows in powers of two, only the first `instance_count` are used
     instance_buffer: Buffer<vk::AccelerationStructureInstanceKHR>,
+    /// Instances the TLAS is built from by the frame's graph, 0 leaves the frame without a TLAS
+    instance_count: u32,
+    /// How the graph brings the TLAS up to date this frame, `None` when it already is
+    build_mode: Option<vk::BuildAccelerationStructureModeKHR>,
+    /// What the TLAS was built for in a submitted frame, and what `build_mode` brings it to
+    built_version: SceneVersion,
+    pending_version: SceneVersion,
+    /// One for every instance, indexed by its custom index
+    pub instance_material_buffer: Buffer<GpuInstanceMaterial>,
+    /// Indexed the same way, read by procedural.rchit
+    pub primitive_material_buffer: Buffer<GpuPrimitiveMaterial>,
+    materials_version: u64,
+    pub light_buffer: Buffer<GpuPunctualLight>,
+    pub light_count: u32,
+    pub emissive_buffer: Buffer<GpuEmissiveTriangle>,
+    pub emissive_count: u32,
+    /// Sum of `GpuEmissiveTriangle::power` over the emissive triangles
+    pub emissive_power: f32,
+}
+
+impl SceneFrame {
+    unsafe fn record_build(
+        &self,
+        device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        mode: vk::BuildAccelerationStructureModeKHR,
+    ) {
+        let geometry = instance_geometry(self.instance_buffer.address);
+        // updates happen in place, a full build ignores the source
+        let build_geometry = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(TLAS_FLAGS)
+            .mode(mode)
+            .src_acceleration_structure(self.tlas.handle)
+            .dst_acceleration_structure(self.tlas.handle)
+            .geometries(std::slice::from_ref(&geometry))
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: self.scratch_address,
+            });
+
+        let build_range = vk::AccelerationStructureBuildRangeInfoKHR::builder()
+            .primitive_count(self.instance_count)
+            .primitive_offset(0)
+            .first_vertex(0)
+            .transform_offset(0)
+            .build();
+
+        let build_range_infos = std::slice::from_ref(&build_range);
+        device.exts.rt_acc_struct.cmd_build_acceleration_structures(
+            cmd_buffer,
+            std::slice::from_ref(&build_geometry),
+            std::slice::from_ref(&build_range_infos),
+        );
+    }
+}
+
+#[derive(Resource)]
+pub struct Scene {
+    /// One for every frame in flight, indexed like `FrameResources`
+    frames: Vec<SceneFrame>,
+    /// What the frames' instance buffers are filled with
+    instances: Vec<vk::AccelerationStructureInstanceKHR>,
+    version: SceneVersion,
+    /// What the frames' `instance_material_buffer`s and `primitive_material_buffer`s are filled
+    /// with, materials only change the buffers and not the TLAS
+    instance_materials: Vec<GpuInstanceMaterial>,
+    primitive_materials: Vec<GpuPrimitiveMaterial>,
+    materials_version: u64,
+    pending_meshes: usize,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            frames: (0..FRAMES_IN_FLIGHT).map(|_| SceneFrame::default()).collect(),
+            instances: Vec::new(),
+            version: SceneVersion::default(),
+            instance_materials: Vec::new(),
+            primitive_materials: Vec::new(),
+            materials_version: 0,
+            pending_meshes: 0,
+        }
+    }
 }
 
 impl Scene {
-    pub fn is_ready(&self) -> bool {
-        self.tlas.is_ready()
+    pub fn frame(&self, frame_idx: usize) -> &SceneFrame {
+        &self.frames[frame_idx]
+    }
+
+    pub fn is_ready(&self, frame_idx: usize) -> bool {
+        let frame = &self.frames[frame_idx];
+        frame.tlas.is_ready() && frame.instance_count > 0
+    }
+
+    /// Whether every mesh entity made it into the TLAS, as opposed to still waiting for its BLAS
+    /// or for its gltf scene to be spawned.
+    pub fn is_complete(&self, frame_idx: usize) -> bool {
+        self.is_ready(frame_idx) && self.pending_meshes == 0
     }
+
+    /// Adds the pass that brings the TLAS of the frame up to date, when it isn't already. Passes
+    /// tracing against it use the returned buffer with `Access::AccelerationStructureRead`. Call
+    /// `tlas_submitted` once the graph was submitted.
+    pub fn add_tlas_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, frame_idx: usize) -> ResourceId {
+        let frame = &self.frames[frame_idx];
+        let tlas = graph.buffer(frame.tlas.buffer.handle);
+        let Some(mode) = frame.build_mode else {
+            return tlas;
+        };
+
+        let scratch = graph.buffer(frame.scratch_buffer.handle);
+        let accesses = [
+            (tlas, Access::AccelerationStructureBuild),
+            (scratch, Access::AccelerationStructureBuild),
+        ];
+        graph.add_pass("tlas", &accesses, move |context| unsafe {
+            frame.record_build(context.device, context.cmd_buffer, mode);
+        });
+        tlas
+    }
+
+    /// The build from `add_tlas_pass` is on its way, the next time the frame comes around its TLAS
+    /// only has to catch up with what changed since. A frame that didn't trace builds it again.
+    pub fn tlas_submitted(&mut self, frame_idx: usize) {
+        let frame = &mut self.frames[frame_idx];
+        if frame.build_mode.take().is_some() {
+            frame.built_version = frame.pending_version;
+        }
+    }
+}
+
+/// Whether `a` and `b` only differ in their transforms, so a TLAS built from one can be refit to
+/// the other
+fn same_topology(a: &[vk::AccelerationStructureInstanceKHR], b: &[vk::AccelerationStructureInstanceKHR]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| unsafe {
+            a.instance_custom_index_and_mask == b.instance_custom_index_and_mask
+                && a.instance_shader_binding_table_record_offset_and_flags
+                    == b.instance_shader_binding_table_record_offset_and_flags
+                && a.acceleration_structure_reference.device_handle == b.acceleration_structure_reference.device_handle
+        })
+}
+
+fn instance_geometry(instance_address: u64) -> vk::AccelerationStructureGeometryKHR {
+    vk::AccelerationStructureGeometryKHR::builder()
+        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR {
+            instances: vk::AccelerationStructureGeometryInstancesDataKHR::builder()
+                .array_of_pointers(false)
+                .data(vk::DeviceOrHostAddressConstKHR {
+                    device_address: instance_address,
+                })
+                .build(),
+        })
+        .build()
 }
 
 pub struct ScenePlugin;
@@ -31,7 +209,19 @@ pub struct ScenePlugin;
 impl Plugin for ScenePlugin {
     fn build(&self, app: &mut App) {
         app.world.init_resource::<Scene>();
-        app.add_system(update_scene);
+        // after the frame waited for the GPU to be done with its buffers, and after the SBT the
+        // instances point into
+        app.edit_schedule(RenderSchedule, |schedule| {
+            schedule.add_systems(
+                (
+                    update_scene.pipe(report_render_error),
+                    update_lights.pipe(report_render_error),
+                    update_emissive_triangles.pipe(report_render_error),
+                )
+                    .in_set(RenderSet::Extract)
+                    .after(update_sbt),
+            );
+        });
 
         app.world
             .get_resource_mut::<VkAssetCleanupPlaybook>()
@@ -40,6 +230,7 @@ impl Plugin for ScenePlugin {
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn update_scene(
     cleanup: Res<VkCleanup>,
     mut scene: ResMut<Scene>,
@@ -48,31 +239,262 @@ fn update_scene(
     sbt: Res<SBT>,
     meshes: Query<(Entity, &Handle<GltfMesh>)>,
     blasses: Res<VulkanAssets<GltfMesh>>,
-    sphere_blas: Res<SphereBLAS>,
-    spheres: Query<(Entity, With<Sphere>)>,
+    procedural_blasses: Res<ProceduralBlasses>,
+    primitives: Query<(Entity, &PrimitiveInstance)>,
+    unspawned_scenes: Query<(), (With<Handle<GltfScene>>, Without<GltfSceneSpawned>)>,
+    frames: Res<FrameResources>,
+    appearance: InstanceAppearance,
+    // grouped, systems take at most 16 parameters
+    (moved, changed_meshes, changed_primitives, changed_appearance): (
+        Query<
+            (),
+            (
+                Changed<GlobalTransform>,
+                Or<(With<Handle<GltfMesh>>, With<PrimitiveInstance>)>,
+            ),
+        >,
+        Query<(), Changed<Handle<GltfMesh>>>,
+        Query<(), Changed<PrimitiveInstance>>,
+        Query<(), Or<(Changed<InstanceMaterialOverride>, Changed<RayVisibility>)>>,
+    ),
+    (mut removed_meshes, mut removed_primitives, mut removed_materials, mut removed_visibility): (
+        RemovedComponents<Handle<GltfMesh>>,
+        RemovedComponents<PrimitiveInstance>,
+        RemovedComponents<InstanceMaterialOverride>,
+        RemovedComponents<RayVisibility>,
+    ),
+) -> RenderResult<()> {
+    // read every removal, so they don't show up again next frame
+    let removed = removed_meshes.iter().count()
+        + removed_primitives.iter().count()
+        + removed_materials.iter().count()
+        + removed_visibility.iter().count()
+        > 0;
+    let changed = removed
+        || scene.pending_meshes > 0
+        || !moved.is_empty()
+        || !changed_meshes.is_empty()
+        || !changed_primitives.is_empty()
+        || !changed_appearance.is_empty()
+        || blasses.is_changed()
+        || procedural_blasses.is_changed()
+        || sbt.is_changed();
+    if changed {
+        let (instances, materials, primitive_materials, pending_meshes) = collect_instances(
+            &gtransforms,
+            &appearance,
+            &sbt,
+            &meshes,
+            &blasses,
+            &procedural_blasses,
+            &primitives,
+            &unspawned_scenes,
+        );
+        scene.pending_meshes = pending_meshes;
+
+        if !same_topology(&scene.instances, &instances) {
+            scene.version.topology += 1;
+            scene.version.transforms += 1;
+        } else if instances
+            .iter()
+            .zip(&scene.instances)
+            .any(|(a, b)| a.transform.matrix != b.transform.matrix)
+        {
+            scene.version.transforms += 1;
+        }
+        scene.instances = instances;
+
+        if materials != scene.instance_materials || primitive_materials != scene.primitive_materials {
+            scene.materials_version += 1;
+            scene.instance_materials = materials;
+            scene.primitive_materials = primitive_materials;
+        }
+    }
+
+    let scene = &mut *scene;
+    let version = scene.version;
+    let instances = &scene.instances;
+    let frame = &mut scene.frames[frames.current_idx()];
+    if frame.materials_version != scene.materials_version {
+        upload_per_instance(
+            &mut frame.instance_material_buffer,
+            &scene.instance_materials,
+            &device,
+            &cleanup,
+        )?;
+        upload_per_instance(
+            &mut frame.primitive_material_buffer,
+            &scene.primitive_materials,
+            &device,
+            &cleanup,
+        )?;
+        frame.materials_version = scene.materials_version;
+    }
+
+    frame.build_mode = None;
+    // static frames skip the build entirely
+    if frame.built_version == version {
+        return Ok(());
+    }
+
+    frame.instance_count = 0;
+    if instances.is_empty() {
+        frame.built_version = version;
+        return Ok(());
+    }
+
+    // a buffer that failed to be replaced is left empty, so it is created again next frame
+    if instances.len() > frame.instance_buffer.nr_elements as usize {
+        cleanup.send(VkCleanupEvent::Buffer(frame.instance_buffer.handle));
+        frame.instance_buffer = Buffer::default();
+        frame.instance_buffer = device.create_host_buffer::<vk::AccelerationStructureInstanceKHR>(
+            instances.len().next_power_of_two() as u64,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+        )?;
+    }
+
+    device.map_buffer(&mut frame.instance_buffer).as_slice_mut()[..instances.len()].copy_from_slice(instances);
+    let primitive_count = instances.len() as u32;
+
+    // moved instances are refit into the TLAS that was built for them
+    if frame.built_version.topology == version.topology {
+        frame.build_mode = Some(vk::BuildAccelerationStructureModeKHR::UPDATE);
+        frame.pending_version = version;
+        frame.instance_count = primitive_count;
+        return Ok(());
+    }
+
+    let geometry = instance_geometry(frame.instance_buffer.address);
+    let build_geometry = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+        .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+        .flags(TLAS_FLAGS)
+        .geometries(std::slice::from_ref(&geometry))
+        .build();
+
+    let build_sizes = unsafe {
+        device.exts.rt_acc_struct.get_acceleration_structure_build_sizes(
+            vk::AccelerationStructureBuildTypeKHR::DEVICE,
+            &build_geometry,
+            std::slice::from_ref(&primitive_count),
+        )
+    };
+
+    // a TLAS that is big enough is built again in place
+    if build_sizes.acceleration_structure_size > frame.tlas.buffer.nr_elements {
+        // better to destroy it before the underlying buffer
+        cleanup.send(VkCleanupEvent::AccelerationStructure(frame.tlas.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.tlas.buffer.handle));
+        frame.tlas = AccelerationStructure::default();
+        frame.tlas =
+            allocate_acceleration_structure(&device, vk::AccelerationStructureTypeKHR::TOP_LEVEL, &build_sizes)?;
+    }
+
+    // the refits that follow use the same scratch buffer
+    let as_props = vk_utils::get_acceleration_structure_properties(&device);
+    let scratch_alignment = as_props.min_acceleration_structure_scratch_offset_alignment as u64;
+    let scratch_size = build_sizes.build_scratch_size.max(build_sizes.update_scratch_size) + scratch_alignment;
+
+    if scratch_size > frame.scratch_buffer.nr_elements {
+        cleanup.send(VkCleanupEvent::Buffer(frame.scratch_buffer.handle));
+        frame.scratch_buffer = Buffer::default();
+        frame.scratch_buffer = device.create_device_buffer(scratch_size, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+    }
+
+    frame.scratch_address =
+        frame.scratch_buffer.address + scratch_alignment - frame.scratch_buffer.address % scratch_alignment;
+    frame.build_mode = Some(vk::BuildAccelerationStructureModeKHR::BUILD);
+    frame.pending_version = version;
+    frame.instance_count = primitive_count;
+    Ok(())
+}
+
+/// Fills a buffer indexed by the custom index of the instances. It grows like the instance buffer
+/// and is left empty while there are no instances.
+fn upload_per_instance<T: Copy>(
+    buffer: &mut Buffer<T>,
+    data: &[T],
+    device: &RenderDevice,
+    cleanup: &VkCleanup,
+) -> RenderResult<()> {
+    if data.len() > buffer.nr_elements as usize {
+        cleanup.send(VkCleanupEvent::Buffer(buffer.handle));
+        *buffer = Buffer::default();
+        *buffer = device.create_host_buffer::<T>(
+            data.len().next_power_of_two() as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+    }
+    if !data.is_empty() {
+        device.map_buffer(buffer).as_slice_mut()[..data.len()].copy_from_slice(data);
+    }
+    Ok(())
+}
+
+/// The TLAS instances of every procedural primitive and every mesh whose BLAS is ready, what goes in the per
+/// instance buffers, and how many meshes are still missing
+#[allow(clippy::too_many_arguments)]
+fn collect_instances(
+    gtransforms: &Query<&GlobalTransform>,
+    appearance: &InstanceAppearance,
+    sbt: &SBT,
+    meshes: &Query<(Entity, &Handle<GltfMesh>)>,
+    blasses: &VulkanAssets<GltfMesh>,
+    procedural_blasses: &ProceduralBlasses,
+    primitives: &Query<(Entity, &PrimitiveInstance)>,
+    unspawned_scenes: &Query<(), (With<Handle<GltfScene>>, Without<GltfSceneSpawned>)>,
+) -> (
+    Vec<vk::AccelerationStructureInstanceKHR>,
+    Vec<GpuInstanceMaterial>,
+    Vec<GpuPrimitiveMaterial>,
+    usize,
 ) {
-    let mut resolved_blasses: Vec<(u32, &GlobalTransform, AccelerationStructureReferenceKHR)> = Vec::new();
+    let mut resolved_blasses: Vec<(
+        Entity,
+        u32,
+        AccelerationStructureReferenceKHR,
+        Option<&PrimitiveInstance>,
+    )> = Vec::new();
+    // a gltf scene that has not been spawned yet will add mesh entities later on
+    let mut pending_meshes = unspawned_scenes.iter().count();
 
-    for (sphere_e, _) in spheres.iter() {
-        resolved_blasses.push((0, gtransforms.get(sphere_e).unwrap(), sphere_blas.get_reference()));
+    for (primitive_e, primitive) in primitives.iter() {
+        let Some(blas) = procedural_blasses.get(primitive.kind) else {
+            continue;
+        };
+        resolved_blasses.push((
+            primitive_e,
+            primitive.kind.sbt_offset(),
+            blas.get_reference(),
+            Some(primitive),
+        ));
     }
 
     for (mesh_e, mesh) in meshes.iter() {
-        let Some(blas) = blasses.get(&mesh) else {
+        let Some(blas) = blasses.get(mesh) else {
+            pending_meshes += 1;
             continue;
         };
 
         let Some(hit_offset) = sbt.triangle_offsets.get(&mesh.id()) else {
+            pending_meshes += 1;
             continue;
         };
-        resolved_blasses.push((*hit_offset, gtransforms.get(mesh_e).unwrap(), blas.get_reference()));
+        resolved_blasses.push((mesh_e, *hit_offset, blas.get_reference(), None));
     }
 
+    let mut materials = Vec::with_capacity(resolved_blasses.len());
+    let mut primitive_materials = Vec::with_capacity(resolved_blasses.len());
     let instances = resolved_blasses
         .into_iter()
         .enumerate()
-        .map(|(i, (hit_offset, transform, blas))| {
-            let columns = transform.affine().to_cols_array_2d();
+        .map(|(i, (entity, hit_offset, blas, primitive))| {
+            let (material, mask) = instance_appearance(entity, appearance);
+            materials.push(material);
+            primitive_materials
+                .push(primitive.map_or_else(GpuPrimitiveMaterial::default, PrimitiveInstance::gpu_material));
+
+            let blas_transform = primitive.map_or(Affine3A::IDENTITY, |primitive| primitive.blas_transform);
+            let columns = (gtransforms.get(entity).unwrap().affine() * blas_transform).to_cols_array_2d();
             let transform = vk::TransformMatrixKHR {
                 matrix: [
                     columns[0][0],
@@ -92,7 +514,8 @@ fn update_scene(
 
             vk::AccelerationStructureInstanceKHR {
                 transform,
-                instance_custom_index_and_mask: Packed24_8::new(i as u32, 0xFF),
+                // the custom index finds the materials in the per instance buffers
+                instance_custom_index_and_mask: Packed24_8::new(i as u32, mask),
                 instance_shader_binding_table_record_offset_and_flags: Packed24_8::new(
                     hit_offset, 0b1, //vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE,
                 ),
@@ -101,131 +524,117 @@ fn update_scene(
         })
         .collect::<Vec<_>>();
 
-    if instances.is_empty() {
-        return;
-    }
+    (instances, materials, primitive_materials, pending_meshes)
+}
 
-    if instances.len() != scene.instance_buffer.nr_elements as usize {
-        //println!("Scene: Resizing instance buffer to {} elements", instances.len());
-        cleanup.send(VkCleanupEvent::Buffer(scene.instance_buffer.handle));
-        scene.instance_buffer = device.create_host_buffer::<vk::AccelerationStructureInstanceKHR>(
-            instances.len() as u64,
-            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
-        );
-    }
+fn update_lights(
+    cleanup: Res<VkCleanup>,
+    mut scene: ResMut<Scene>,
+    device: Res<RenderDevice>,
+    lights: Query<(&PunctualLight, &GlobalTransform)>,
+    frames: Res<FrameResources>,
+) -> RenderResult<()> {
+    let lights = lights
+        .iter()
+        .map(|(light, transform)| GpuPunctualLight::new(light, transform))
+        .collect::<Vec<_>>();
 
-    let mut instance_buffer_view = device.map_buffer(&mut scene.instance_buffer);
-    for (i, instance) in instances.iter().enumerate() {
-        instance_buffer_view[i] = instance.clone();
+    let frame = &mut scene.frames[frames.current_idx()];
+    if lights.len() != frame.light_buffer.nr_elements as usize {
+        println!("Scene: {} punctual lights", lights.len());
+        cleanup.send(VkCleanupEvent::Buffer(frame.light_buffer.handle));
+        frame.light_buffer = Buffer::default();
+        frame.light_count = 0;
+        // vulkan does not allow empty buffers
+        if !lights.is_empty() {
+            frame.light_buffer = device
+                .create_host_buffer::<GpuPunctualLight>(lights.len() as u64, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+        }
     }
-    drop(instance_buffer_view);
 
-    // we always rebuild the tlas, better to destroy it before the underlying buffer
-    cleanup.send(VkCleanupEvent::AccelerationStructure(scene.tlas.handle));
-
-    let geometry = vk::AccelerationStructureGeometryKHR::builder()
-        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
-        .flags(vk::GeometryFlagsKHR::OPAQUE)
-        .geometry(vk::AccelerationStructureGeometryDataKHR {
-            instances: vk::AccelerationStructureGeometryInstancesDataKHR::builder()
-                .array_of_pointers(false)
-                .data(vk::DeviceOrHostAddressConstKHR {
-                    device_address: scene.instance_buffer.address,
-                })
-                .build(),
-        })
-        .build();
-
-    let build_geometry = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
-        .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
-        .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-        .geometries(std::slice::from_ref(&geometry))
-        .build();
+    frame.light_count = lights.len() as u32;
+    if lights.is_empty() {
+        return Ok(());
+    }
 
-    let primitive_count = instances.len() as u32;
+    let mut light_buffer_view = device.map_buffer(&mut frame.light_buffer);
+    for (i, light) in lights.into_iter().enumerate() {
+        light_buffer_view[i] = light;
+    }
+    Ok(())
+}
 
-    let build_sizes = unsafe {
-        device.exts.rt_acc_struct.get_acceleration_structure_build_sizes(
-            vk::AccelerationStructureBuildTypeKHR::DEVICE,
-            &build_geometry,
-            std::slice::from_ref(&primitive_count),
-        )
-    };
+/// Gathers the emissive triangles of every mesh in the TLAS and the light triangles of the
+/// procedural primitives in world space, so raygen.rgen can pick them proportional to their power.
+#[allow(clippy::too_many_arguments)]
+fn update_emissive_triangles(
+    cleanup: Res<VkCleanup>,
+    mut scene: ResMut<Scene>,
+    device: Res<RenderDevice>,
+    sbt: Res<SBT>,
+    meshes: Query<(&Handle<GltfMesh>, &GlobalTransform)>,
+    primitives: Query<(&PrimitiveInstance, &GlobalTransform)>,
+    blasses: Res<VulkanAssets<GltfMesh>>,
+    frames: Res<FrameResources>,
+) -> RenderResult<()> {
+    let mut triangles = Vec::new();
+    for (mesh, transform) in meshes.iter() {
+        // the same meshes update_scene puts in the TLAS, anything else could not block shadow rays
+        let Some(blas) = blasses.get(mesh) else {
+            continue;
+        };
+        if !sbt.triangle_offsets.contains_key(&mesh.id()) {
+            continue;
+        }
 
-    if build_sizes.acceleration_structure_size != scene.tlas.buffer.nr_elements {
-        //println!("Scene: Resizing TLAS to {} bytes", build_sizes.acceleration_structure_size);
-        cleanup.send(VkCleanupEvent::Buffer(scene.tlas.buffer.handle));
-        scene.tlas.buffer = device.create_device_buffer(
-            build_sizes.acceleration_structure_size,
-            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
+        triangles.extend(
+            blas.emissive_triangles
+                .iter()
+                .map(|triangle| GpuEmissiveTriangle::new(triangle, transform)),
         );
     }
-
-    let acceleration_structure_info = vk::AccelerationStructureCreateInfoKHR::builder()
-        .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
-        .buffer(scene.tlas.buffer.handle)
-        .size(build_sizes.acceleration_structure_size)
-        .build();
-
-    scene.tlas.handle = unsafe {
-        device
-            .exts
-            .rt_acc_struct
-            .create_acceleration_structure(&acceleration_structure_info, None)
+    for (primitive, transform) in primitives.iter() {
+        triangles.extend(primitive.emissive_triangles(transform));
     }
-    .unwrap();
-
-    let as_props = vk_utils::get_acceleration_structure_properties(&device);
-    let scratch_alignment = as_props.min_acceleration_structure_scratch_offset_alignment as u64;
-    let scratch_size = build_sizes.build_scratch_size + scratch_alignment;
+    let power = build_emissive_cdf(&mut triangles);
 
-    if scratch_size != scene.scratch_buffer.nr_elements {
-        //println!("Scene: Resizing scratch buffer to {} bytes", build_sizes.build_scratch_size);
-        cleanup.send(VkCleanupEvent::Buffer(scene.scratch_buffer.handle));
-        scene.scratch_buffer = device.create_device_buffer(scratch_size, vk::BufferUsageFlags::STORAGE_BUFFER);
+    let frame = &mut scene.frames[frames.current_idx()];
+    if triangles.len() != frame.emissive_buffer.nr_elements as usize {
+        println!("Scene: {} emissive triangles", triangles.len());
+        cleanup.send(VkCleanupEvent::Buffer(frame.emissive_buffer.handle));
+        frame.emissive_buffer = Buffer::default();
+        frame.emissive_count = 0;
+        // vulkan does not allow empty buffers
+        if !triangles.is_empty() {
+            frame.emissive_buffer = device.create_host_buffer::<GpuEmissiveTriangle>(
+                triangles.len() as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )?;
+        }
     }
 
-    let build_geometry = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
-        .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
-        .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-        .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
-        .dst_acceleration_structure(scene.tlas.handle)
-        .geometries(std::slice::from_ref(&geometry))
-        .scratch_data(vk::DeviceOrHostAddressKHR {
-            device_address: scene.scratch_buffer.address + scratch_alignment
-                - scene.scratch_buffer.address % scratch_alignment,
-        });
-
-    let build_range = vk::AccelerationStructureBuildRangeInfoKHR::builder()
-        .primitive_count(primitive_count)
-        .primitive_offset(0)
-        .first_vertex(0)
-        .transform_offset(0)
-        .build();
-
-    let build_range_infos = std::slice::from_ref(&build_range);
-    unsafe {
-        device.run_single_commands(&|command_buffer| {
-            device.exts.rt_acc_struct.cmd_build_acceleration_structures(
-                command_buffer,
-                std::slice::from_ref(&build_geometry),
-                std::slice::from_ref(&build_range_infos),
-            );
-        });
+    frame.emissive_count = triangles.len() as u32;
+    frame.emissive_power = power;
+    if triangles.is_empty() {
+        return Ok(());
     }
 
-    scene.tlas.address = unsafe {
-        device.exts.rt_acc_struct.get_acceleration_structure_device_address(
-            &vk::AccelerationStructureDeviceAddressInfoKHR::builder()
-                .acceleration_structure(scene.tlas.handle)
-                .build(),
-        )
-    };
+    device
+        .map_buffer(&mut frame.emissive_buffer)
+        .as_slice_mut()
+        .copy_from_slice(&triangles);
+    Ok(())
 }
 
 fn destroy_scene(scene: Res<Scene>, cleanup: Res<VkCleanup>) {
-    cleanup.send(VkCleanupEvent::Buffer(scene.tlas.buffer.handle));
-    cleanup.send(VkCleanupEvent::AccelerationStructure(scene.tlas.handle));
-    cleanup.send(VkCleanupEvent::Buffer(scene.instance_buffer.handle));
-    cleanup.send(VkCleanupEvent::Buffer(scene.scratch_buffer.handle));
+    for frame in &scene.frames {
+        cleanup.send(VkCleanupEvent::AccelerationStructure(frame.tlas.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.tlas.buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.instance_buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.scratch_buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.light_buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.emissive_buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.instance_material_buffer.handle));
+        cleanup.send(VkCleanupEvent::Buffer(frame.primitive_material_buffer.handle));
+    }
 }
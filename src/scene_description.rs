@@ -0,0 +1,546 @@
+use std::path::PathBuf;
+
+use bevy::asset::{AssetLoader, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{Camera3d, Camera3dBundle, PitchYaw};
+use crate::compute_pipeline::ComputePipeline;
+use crate::environment::{SkyConfig, SkyFallback};
+use crate::gltf_assets::GltfScene;
+use crate::instance_material::RayVisibility;
+use crate::procedural::{Cuboid, Cylinder, Disk, PrimitiveKind, PrimitiveMaterial, Quad, Sdf, Sphere};
+use crate::rasterization_pipeline::RasterizationPipeline;
+use crate::raytracing_pipeline::RaytracingPipeline;
+use crate::render_plugin::RenderConfig;
+use crate::sampler::SamplerConfig;
+
+/// A `.scene.ron` file declaring everything `startup` used to hardcode. Editing the file while the
+/// app runs respawns the scene through the asset server's `watch_for_changes`.
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "6a3f1d0e-2b7c-4f8e-9d51-3c7a0e5b9f12"]
+pub struct SceneDescription {
+    #[serde(default)]
+    pub camera: CameraDescription,
+    #[serde(default = "default_skybox")]
+    pub skybox: String,
+    #[serde(default)]
+    pub sky: SkyDescription,
+    #[serde(default)]
+    pub pipelines: PipelineDescription,
+    #[serde(default)]
+    pub meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    pub spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    pub primitives: Vec<PrimitiveDescription>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CameraDescription {
+    pub position: [f32; 3],
+    /// Degrees
+    pub pitch: f32,
+    /// Degrees
+    pub yaw: f32,
+    /// Vertical field of view in degrees
+    pub fov: f32,
+    pub exposure: f32,
+    pub aperture: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        let camera = Camera3d::default();
+        Self {
+            position: [0.0, 0.0, -3.0],
+            pitch: 0.0,
+            yaw: 0.0,
+            fov: camera.fov.to_degrees(),
+            exposure: camera.exposure,
+            aperture: camera.aperture,
+        }
+    }
+}
+
+impl CameraDescription {
+    /// Where the camera is now, in the form the scene description loads it
+    pub fn from_camera(camera: &Camera3d, transform: &Transform, pitch_yaw: &PitchYaw) -> Self {
+        Self {
+            position: transform.translation.into(),
+            pitch: pitch_yaw.pitch.to_degrees(),
+            yaw: pitch_yaw.yaw.to_degrees(),
+            fov: camera.fov.to_degrees(),
+            exposure: camera.exposure,
+            aperture: camera.aperture,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SkyDescription {
+    /// Degrees around the world up axis
+    pub rotation: f32,
+    pub intensity: f32,
+    /// Shown when the skybox fails to load, e.g. `Some(Gradient(horizon: (0.8, 0.9, 1.0), zenith: (0.2, 0.4, 0.9)))`
+    pub fallback: Option<SkyFallback>,
+}
+
+impl Default for SkyDescription {
+    fn default() -> Self {
+        let sky = SkyConfig::default();
+        Self {
+            rotation: sky.rotation.to_degrees(),
+            intensity: sky.intensity,
+            fallback: sky.fallback,
+        }
+    }
+}
+
+impl SkyDescription {
+    fn config(&self) -> SkyConfig {
+        SkyConfig {
+            rotation: self.rotation.to_radians(),
+            intensity: self.intensity,
+            fallback: self.fallback,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PipelineDescription {
+    pub raygen: String,
+    pub triangle_hit: String,
+    pub triangle_any_hit: String,
+    pub miss: String,
+    pub procedural_hit: String,
+    pub sphere_int: String,
+    pub cuboid_int: String,
+    pub cylinder_int: String,
+    pub quad_int: String,
+    pub disk_int: String,
+    /// A `.sdf` snippet defining `float sdf(vec3 p)`, compiled into the intersection shader of
+    /// every `Sdf` in the scene
+    pub sdf_int: String,
+    pub quad_vert: String,
+    pub quad_frag: String,
+    pub denoise: String,
+}
+
+impl Default for PipelineDescription {
+    fn default() -> Self {
+        Self {
+            raygen: "shaders/raygen.rgen".into(),
+            triangle_hit: "shaders/hit.rchit".into(),
+            triangle_any_hit: "shaders/hit.rahit".into(),
+            miss: "shaders/miss.rmiss".into(),
+            procedural_hit: "shaders/procedural.rchit".into(),
+            sphere_int: "shaders/sphere.rint".into(),
+            cuboid_int: "shaders/box.rint".into(),
+            cylinder_int: "shaders/cylinder.rint".into(),
+            quad_int: "shaders/quad.rint".into(),
+            disk_int: "shaders/disk.rint".into(),
+            sdf_int: "shaders/rounded_box.sdf".into(),
+            quad_vert: "shaders/quad.vert".into(),
+            quad_frag: "shaders/quad.frag".into(),
+            denoise: "shaders/denoise.comp".into(),
+        }
+    }
+}
+
+impl PipelineDescription {
+    fn procedural_int(&self, kind: PrimitiveKind) -> &str {
+        match kind {
+            PrimitiveKind::Sphere => &self.sphere_int,
+            PrimitiveKind::Cuboid => &self.cuboid_int,
+            PrimitiveKind::Cylinder => &self.cylinder_int,
+            PrimitiveKind::Quad => &self.quad_int,
+            PrimitiveKind::Disk => &self.disk_int,
+            PrimitiveKind::Sdf => &self.sdf_int,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MeshDescription {
+    pub path: String,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// XYZ euler angles in degrees
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: [f32; 3],
+    #[serde(default)]
+    pub visibility: VisibilityDescription,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SphereDescription {
+    pub translation: [f32; 3],
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub material: MaterialDescription,
+    #[serde(default)]
+    pub visibility: VisibilityDescription,
+}
+
+/// A procedural primitive other than a sphere, spheres have their own list
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrimitiveDescription {
+    pub shape: ShapeDescription,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// XYZ euler angles in degrees
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default)]
+    pub material: MaterialDescription,
+    #[serde(default)]
+    pub visibility: VisibilityDescription,
+}
+
+/// Sizes are halved like the ones of the colliders. Quads and disks lie in the XZ plane, only
+/// boxes and cylinders collide.
+#[derive(Debug, Clone, Deserialize)]
+pub enum ShapeDescription {
+    Cuboid {
+        half_extents: [f32; 3],
+    },
+    Cylinder {
+        radius: f32,
+        half_height: f32,
+    },
+    Quad {
+        half_size: [f32; 2],
+    },
+    Disk {
+        radius: f32,
+    },
+    /// The field of `PipelineDescription::sdf_int`
+    Sdf {
+        size: f32,
+    },
+}
+
+/// See `PrimitiveMaterial`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MaterialDescription {
+    pub color: [f32; 3],
+    pub roughness: f32,
+    pub metallic: f32,
+    pub emission: [f32; 3],
+    pub transmission: f32,
+    pub ior: f32,
+    pub absorption: [f32; 3],
+}
+
+impl Default for MaterialDescription {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            roughness: 0.0,
+            metallic: 1.0,
+            emission: [0.0, 0.0, 0.0],
+            transmission: 0.0,
+            ior: 1.33,
+            absorption: [1.5, 1.5, 1.5],
+        }
+    }
+}
+
+impl MaterialDescription {
+    fn to_material(&self) -> PrimitiveMaterial {
+        PrimitiveMaterial {
+            color: Vec3::from(self.color),
+            roughness: self.roughness,
+            metallic: self.metallic,
+            emission: Vec3::from(self.emission),
+            transmission: self.transmission,
+            ior: self.ior,
+            absorption: Vec3::from(self.absorption),
+        }
+    }
+}
+
+/// Which rays see the object, see `RayVisibility`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VisibilityDescription {
+    pub camera: bool,
+    pub shadow: bool,
+    pub reflection: bool,
+}
+
+impl Default for VisibilityDescription {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            reflection: true,
+        }
+    }
+}
+
+impl VisibilityDescription {
+    fn to_ray_visibility(&self) -> RayVisibility {
+        let mut visibility = RayVisibility::ALL;
+        for (visible, rays) in [
+            (self.camera, RayVisibility::CAMERA),
+            (self.shadow, RayVisibility::SHADOW),
+            (self.reflection, RayVisibility::REFLECTION),
+        ] {
+            if !visible {
+                visibility = visibility.without(rays);
+            }
+        }
+        visibility
+    }
+}
+
+fn default_skybox() -> String {
+    "textures/sky.exr".into()
+}
+
+fn default_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_radius() -> f32 {
+    0.5
+}
+
+#[derive(Default)]
+pub struct SceneDescriptionLoader;
+
+impl AssetLoader for SceneDescriptionLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            println!("Loading scene description: {:?}", load_context.path());
+            let description: SceneDescription = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(description));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["scene.ron"]
+    }
+}
+
+#[derive(Resource)]
+pub struct ActiveSceneDescription(pub Handle<SceneDescription>);
+
+/// Marks entities spawned from the active scene description, they are despawned when it reloads.
+#[derive(Component)]
+pub struct SceneDescriptionEntity;
+
+pub struct SceneDescriptionPlugin {
+    /// Relative to the assets folder
+    pub path: PathBuf,
+}
+
+impl Plugin for SceneDescriptionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SceneDescription>()
+            .init_asset_loader::<SceneDescriptionLoader>();
+
+        let handle = app.world.resource::<AssetServer>().load(self.path.clone());
+        app.insert_resource(ActiveSceneDescription(handle));
+
+        app.add_system(apply_scene_description);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_scene_description(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<SceneDescription>>,
+    active: Res<ActiveSceneDescription>,
+    descriptions: Res<Assets<SceneDescription>>,
+    assets: Res<AssetServer>,
+    mut rt_pipelines: ResMut<Assets<RaytracingPipeline>>,
+    mut rast_pipelines: ResMut<Assets<RasterizationPipeline>>,
+    mut compute_pipelines: ResMut<Assets<ComputePipeline>>,
+    spawned: Query<Entity, With<SceneDescriptionEntity>>,
+    mut camera: Query<(&mut Camera3d, &mut Transform, &mut PitchYaw)>,
+    render_config: Option<ResMut<RenderConfig>>,
+    sampler: Option<Res<SamplerConfig>>,
+    mut applied_config: Local<Option<(PipelineDescription, String)>>,
+) {
+    let mut changed = false;
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed |= *handle == active.0;
+            }
+            AssetEvent::Removed { .. } => {}
+        }
+    }
+
+    if !changed {
+        return;
+    }
+
+    let Some(description) = descriptions.get(&active.0) else {
+        return;
+    };
+
+    println!(
+        "Applying scene description: {} meshes, {} spheres, {} primitives",
+        description.meshes.len(),
+        description.spheres.len(),
+        description.primitives.len()
+    );
+
+    for entity in spawned.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    for sphere in &description.spheres {
+        commands.spawn((
+            Sphere {
+                radius: sphere.radius,
+                material: sphere.material.to_material(),
+            },
+            TransformBundle::from_transform(Transform::from_translation(Vec3::from(sphere.translation))),
+            sphere.visibility.to_ray_visibility(),
+            RigidBody::Fixed,
+            Collider::ball(sphere.radius),
+            SceneDescriptionEntity,
+        ));
+    }
+
+    for primitive in &description.primitives {
+        let [x, y, z] = primitive.rotation.map(f32::to_radians);
+        let material = primitive.material.to_material();
+        let mut entity = match primitive.shape {
+            ShapeDescription::Cuboid { half_extents } => {
+                let [hx, hy, hz] = half_extents;
+                commands.spawn((
+                    Cuboid {
+                        half_extents: Vec3::from(half_extents),
+                        material,
+                    },
+                    RigidBody::Fixed,
+                    Collider::cuboid(hx, hy, hz),
+                ))
+            }
+            ShapeDescription::Cylinder { radius, half_height } => commands.spawn((
+                Cylinder {
+                    radius,
+                    half_height,
+                    material,
+                },
+                RigidBody::Fixed,
+                Collider::cylinder(half_height, radius),
+            )),
+            ShapeDescription::Quad { half_size } => commands.spawn(Quad {
+                half_size: Vec2::from(half_size),
+                material,
+            }),
+            ShapeDescription::Disk { radius } => commands.spawn(Disk { radius, material }),
+            ShapeDescription::Sdf { size } => commands.spawn(Sdf { size, material }),
+        };
+        entity.insert((
+            TransformBundle::from_transform(
+                Transform::from_translation(Vec3::from(primitive.translation)).with_rotation(Quat::from_euler(
+                    EulerRot::XYZ,
+                    x,
+                    y,
+                    z,
+                )),
+            ),
+            primitive.visibility.to_ray_visibility(),
+            SceneDescriptionEntity,
+        ));
+    }
+
+    for mesh in &description.meshes {
+        let [x, y, z] = mesh.rotation.map(f32::to_radians);
+        let handle: Handle<GltfScene> = assets.load(mesh.path.as_str());
+        commands.spawn((
+            handle,
+            TransformBundle::from_transform(Transform {
+                translation: Vec3::from(mesh.translation),
+                rotation: Quat::from_euler(EulerRot::XYZ, x, y, z),
+                scale: Vec3::from(mesh.scale),
+            }),
+            mesh.visibility.to_ray_visibility(),
+            SceneDescriptionEntity,
+        ));
+    }
+
+    let camera_description = &description.camera;
+    let pitch_yaw = PitchYaw {
+        pitch: camera_description.pitch.to_radians(),
+        yaw: camera_description.yaw.to_radians(),
+    };
+    let camera_transform = Transform::from_translation(Vec3::from(camera_description.position)).with_rotation(
+        Quat::from_axis_angle(-Vec3::X, pitch_yaw.pitch) * Quat::from_axis_angle(Vec3::Y, pitch_yaw.yaw),
+    );
+
+    if let Ok((mut camera, mut transform, mut current_pitch_yaw)) = camera.get_single_mut() {
+        camera.fov = camera_description.fov.to_radians();
+        camera.exposure = camera_description.exposure;
+        camera.aperture = camera_description.aperture;
+        *transform = camera_transform;
+        *current_pitch_yaw = pitch_yaw;
+    } else {
+        commands.spawn(Camera3dBundle {
+            camera: Camera3d {
+                fov: camera_description.fov.to_radians(),
+                exposure: camera_description.exposure,
+                aperture: camera_description.aperture,
+                ..default()
+            },
+            pitch_yaw,
+            transform: camera_transform,
+            ..default()
+        });
+    }
+
+    // rebuilding the pipelines recompiles them, so only do it when they actually changed
+    let config = Some((description.pipelines.clone(), description.skybox.clone()));
+    if *applied_config != config {
+        let pipelines = &description.pipelines;
+        commands.insert_resource(RenderConfig {
+            rt_pipeline: rt_pipelines.add(RaytracingPipeline {
+                raygen_shader: assets.load(pipelines.raygen.as_str()),
+                triangle_hit_shader: assets.load(pipelines.triangle_hit.as_str()),
+                triangle_any_hit_shader: assets.load(pipelines.triangle_any_hit.as_str()),
+                miss_shader: assets.load(pipelines.miss.as_str()),
+                procedural_hit_shader: assets.load(pipelines.procedural_hit.as_str()),
+                procedural_int_shaders: PrimitiveKind::ALL
+                    .iter()
+                    .map(|&kind| assets.load(pipelines.procedural_int(kind)))
+                    .collect(),
+            }),
+            quad_pipeline: rast_pipelines.add(RasterizationPipeline {
+                vs_shader: assets.load(pipelines.quad_vert.as_str()),
+                fs_shader: assets.load(pipelines.quad_frag.as_str()),
+            }),
+            denoise_pipeline: compute_pipelines.add(ComputePipeline {
+                shader: assets.load(pipelines.denoise.as_str()),
+            }),
+            skybox: assets.load(description.skybox.as_str()),
+            sky: description.sky.config(),
+            sampler: sampler.map_or_else(SamplerConfig::default, |sampler| *sampler),
+        });
+        *applied_config = config;
+    } else if let Some(mut render_config) = render_config {
+        // the sky is only read by the shaders, changing it does not need new pipelines
+        render_config.sky = description.sky.config();
+    }
+}
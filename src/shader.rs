@@ -1,4 +1,5 @@
 use crate::render_device::*;
+use crate::render_error::{RenderError, RenderResult};
 use ash::{util::read_spv, vk};
 use bevy::{
     asset::{AssetLoader, LoadedAsset},
@@ -41,8 +42,9 @@ impl AssetLoader for ShaderLoader {
                 "frag" => Some(shaderc::ShaderKind::Fragment),
                 "comp" => Some(shaderc::ShaderKind::Compute),
                 "rgen" => Some(shaderc::ShaderKind::RayGeneration),
-                "rint" => Some(shaderc::ShaderKind::Intersection),
+                "rint" | "sdf" => Some(shaderc::ShaderKind::Intersection),
                 "rchit" => Some(shaderc::ShaderKind::ClosestHit),
+                "rahit" => Some(shaderc::ShaderKind::AnyHit),
                 "rmiss" => Some(shaderc::ShaderKind::Miss),
                 _ => None,
             }) else {
@@ -66,8 +68,15 @@ impl AssetLoader for ShaderLoader {
                 })
             });
 
+            // an sdf is only the distance function, sdf.glsl sphere traces it
+            let source = std::str::from_utf8(bytes).unwrap();
+            let source = match ext.as_str() {
+                "sdf" => Cow::Owned(format!("#version 460\n#include \"sdf.glsl\"\n#line 1\n{}", source)),
+                _ => Cow::Borrowed(source),
+            };
+
             let binary_result = self.compiler.compile_into_spirv(
-                std::str::from_utf8(bytes).unwrap(),
+                &source,
                 kind,
                 load_context.path().to_str().unwrap(),
                 "main",
@@ -91,27 +100,37 @@ impl AssetLoader for ShaderLoader {
     }
 
     fn extensions(&self) -> &[&str] {
-        &["comp", "vert", "frag", "rgen", "rchit", "rint", "rmiss", "glsl"]
+        &[
+            "comp", "vert", "frag", "rgen", "rchit", "rahit", "rint", "rmiss", "glsl", "sdf",
+        ]
     }
 }
 
 pub trait ShaderProvider {
-    fn load_shader(&self, shader: &Shader, stage: vk::ShaderStageFlags) -> vk::PipelineShaderStageCreateInfo;
+    fn load_shader(
+        &self,
+        shader: &Shader,
+        stage: vk::ShaderStageFlags,
+    ) -> RenderResult<vk::PipelineShaderStageCreateInfo>;
 }
 
 impl ShaderProvider for RenderDevice {
-    fn load_shader(&self, shader: &Shader, stage: vk::ShaderStageFlags) -> vk::PipelineShaderStageCreateInfo {
-        let code = read_spv(&mut Cursor::new(&shader.spirv)).unwrap();
+    fn load_shader(
+        &self,
+        shader: &Shader,
+        stage: vk::ShaderStageFlags,
+    ) -> RenderResult<vk::PipelineShaderStageCreateInfo> {
+        let code = read_spv(&mut Cursor::new(&shader.spirv))
+            .map_err(|e| RenderError::Shader(format!("failed to read {}: {}", shader.path, e)))?;
         let shader_module = unsafe {
             self.device
-                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&code), None)
-                .unwrap()
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&code), None)?
         };
 
-        vk::PipelineShaderStageCreateInfo::builder()
+        Ok(vk::PipelineShaderStageCreateInfo::builder()
             .stage(stage)
             .module(shader_module)
-            .name(std::ffi::CStr::from_bytes_with_nul(b"main\0").unwrap())
-            .build()
+            .name(c"main")
+            .build())
     }
 }
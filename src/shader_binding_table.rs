@@ -6,6 +6,7 @@ use crate::{
     raytracing_pipeline::RaytracingPipeline,
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
+    render_error::{report_render_error, RenderResult},
     render_plugin::{RenderSchedule, RenderSet},
     vk_utils,
     vulkan_assets::{VkAssetCleanupPlaybook, VulkanAssets},
@@ -30,7 +31,7 @@ pub struct SBTRegionMiss {
 #[repr(C)]
 pub enum SBTRegionHitEntry {
     Triangle(SBTRegionHitTriangle),
-    Sphere(SBTRegionHitSphere),
+    Procedural(SBTRegionHitProcedural),
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -45,7 +46,7 @@ pub struct SBTRegionHitTriangle {
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
-pub struct SBTRegionHitSphere {
+pub struct SBTRegionHitProcedural {
     pub handle: RTGroupHandle,
 }
 
@@ -56,6 +57,8 @@ pub struct SBT {
     pub hit_region: vk::StridedDeviceAddressRegionKHR,
     pub data: Buffer<u8>,
     pub triangle_offsets: HashMap<HandleId, u32>,
+    /// Set when the table couldn't be written, it is tried again next frame
+    pub outdated: bool,
 }
 
 pub struct SBTPlugin;
@@ -66,9 +69,11 @@ impl Plugin for SBTPlugin {
         app.edit_schedule(RenderSchedule, |schedule| {
             schedule.add_system(
                 update_sbt
+                    .pipe(report_render_error)
                     .run_if(
                         resource_changed::<VulkanAssets<GltfMesh>>()
-                            .or_else(resource_changed::<VulkanAssets<RaytracingPipeline>>()),
+                            .or_else(resource_changed::<VulkanAssets<RaytracingPipeline>>())
+                            .or_else(|sbt: Res<SBT>| sbt.outdated),
                     )
                     .in_set(RenderSet::Extract),
             );
@@ -79,16 +84,16 @@ impl Plugin for SBTPlugin {
     }
 }
 
-fn update_sbt(
+pub fn update_sbt(
     mut me: ResMut<SBT>,
     device: Res<RenderDevice>,
     cleanup: Res<VkCleanup>,
     pipeline: Res<VulkanAssets<RaytracingPipeline>>,
     triangle_meshes: Res<VulkanAssets<GltfMesh>>,
-) {
+) -> RenderResult<()> {
     let Some(pipeline) = pipeline.get_single() else {
         println!("Bailing, No pipeline");
-        return;
+        return Ok(());
     };
     println!("Updating SBT");
     let rtprops = vk_utils::get_raytracing_properties(&device);
@@ -100,10 +105,12 @@ fn update_sbt(
         handle: pipeline.miss_handle,
     };
 
-    let mut hit_region_data = Vec::new();
-    hit_region_data.push(SBTRegionHitEntry::Sphere(SBTRegionHitSphere {
-        handle: pipeline.sphere_hit_handle,
-    }));
+    // the procedural primitives come first, at the offsets of their `PrimitiveKind`
+    let mut hit_region_data = pipeline
+        .procedural_hit_handles
+        .iter()
+        .map(|&handle| SBTRegionHitEntry::Procedural(SBTRegionHitProcedural { handle }))
+        .collect::<Vec<_>>();
 
     me.triangle_offsets.clear();
     for (handle, mesh) in triangle_meshes.items() {
@@ -133,7 +140,7 @@ fn update_sbt(
     let hit_entry_size = vk_utils::aligned_size(
         [
             std::mem::size_of::<SBTRegionHitTriangle>(),
-            std::mem::size_of::<SBTRegionHitSphere>(),
+            std::mem::size_of::<SBTRegionHitProcedural>(),
         ]
         .into_iter()
         .max()
@@ -148,10 +155,12 @@ fn update_sbt(
 
     let sbt_size = me.raygen_region.size + me.miss_region.size + me.hit_region.size;
 
-    if me.data.nr_elements != sbt_size {
-        cleanup.send(VkCleanupEvent::Buffer(me.data.handle));
-        me.data = device.create_host_buffer::<u8>(sbt_size, vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR);
-    }
+    // a frame in flight may still trace with the old table, so it is never written in place
+    cleanup.send(VkCleanupEvent::Buffer(me.data.handle));
+    me.data = Buffer::default();
+    me.outdated = true;
+    me.data = device.create_host_buffer::<u8>(sbt_size, vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR)?;
+    me.outdated = false;
 
     me.raygen_region.device_address = me.data.address;
     me.miss_region.device_address = me.data.address + me.raygen_region.size;
@@ -175,14 +184,15 @@ fn update_sbt(
                     SBTRegionHitEntry::Triangle(data) => {
                         (dst as *mut SBTRegionHitTriangle).write(*data);
                     }
-                    SBTRegionHitEntry::Sphere(data) => {
-                        (dst as *mut SBTRegionHitSphere).write(*data);
+                    SBTRegionHitEntry::Procedural(data) => {
+                        (dst as *mut SBTRegionHitProcedural).write(*data);
                     }
                 }
                 dst = dst.add(me.hit_region.stride as usize);
             }
         }
     }
+    Ok(())
 }
 
 fn destroy_sbt(me: Res<SBT>, cleanup: Res<VkCleanup>) {
@@ -0,0 +1,440 @@
+use ash::vk;
+use bevy::utils::HashMap;
+
+// Just enough of a SPIR-V parser to build pipeline layouts from a shader, see the "Binary Form"
+// section of the SPIR-V specification for the numbers below.
+
+const MAGIC: u32 = 0x07230203;
+const HEADER_WORDS: usize = 5;
+
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_TYPE_FORWARD_POINTER: u32 = 39;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+const STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER: u32 = 5349;
+
+const DIM_BUFFER: u32 = 5;
+const IMAGE_SAMPLED_STORAGE: u32 = 2;
+
+/// A descriptor a shader declares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// 0 for runtime sized arrays
+    pub count: u32,
+}
+
+/// What a pipeline layout needs to know about a shader
+#[derive(Debug, Clone, Default)]
+pub struct ShaderReflection {
+    /// Sorted by set and binding
+    pub bindings: Vec<ReflectedBinding>,
+    /// Bytes of the push constant block, 0 without one
+    pub push_constant_size: u32,
+    /// Workgroup size of compute shaders
+    pub local_size: Option<[u32; 3]>,
+}
+
+impl ShaderReflection {
+    pub fn set_bindings(&self, set: u32) -> impl Iterator<Item = &ReflectedBinding> {
+        self.bindings.iter().filter(move |binding| binding.set == set)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Scalar { bytes: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { storage_class: u32, pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    /// (id, pointer type, storage class) of every global variable
+    variables: Vec<(u32, u32, u32)>,
+    local_size: Option<[u32; 3]>,
+}
+
+impl Module {
+    fn parse(words: &[u32]) -> Result<Self, String> {
+        if words.len() < HEADER_WORDS || words[0] != MAGIC {
+            return Err("not a SPIR-V module".into());
+        }
+
+        let mut module = Module::default();
+        let mut pos = HEADER_WORDS;
+        while pos < words.len() {
+            let word_count = (words[pos] >> 16) as usize;
+            let opcode = words[pos] & 0xFFFF;
+            if word_count == 0 || pos + word_count > words.len() {
+                return Err(format!("truncated instruction at word {}", pos));
+            }
+            let operands = &words[pos + 1..pos + word_count];
+            module.instruction(opcode, operands);
+            pos += word_count;
+        }
+        Ok(module)
+    }
+
+    fn instruction(&mut self, opcode: u32, ops: &[u32]) {
+        let ty = match opcode {
+            OP_EXECUTION_MODE if ops.get(1) == Some(&EXECUTION_MODE_LOCAL_SIZE) && ops.len() >= 5 => {
+                self.local_size = Some([ops[2], ops[3], ops[4]]);
+                None
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT => Some(Type::Scalar { bytes: ops[1] / 8 }),
+            OP_TYPE_VECTOR => Some(Type::Vector {
+                component: ops[1],
+                count: ops[2],
+            }),
+            OP_TYPE_MATRIX => Some(Type::Matrix {
+                column: ops[1],
+                count: ops[2],
+            }),
+            OP_TYPE_IMAGE => Some(Type::Image {
+                dim: ops[2],
+                sampled: ops[6],
+            }),
+            OP_TYPE_SAMPLER => Some(Type::Sampler),
+            OP_TYPE_SAMPLED_IMAGE => Some(Type::SampledImage),
+            OP_TYPE_ARRAY => Some(Type::Array {
+                element: ops[1],
+                length: ops[2],
+            }),
+            OP_TYPE_RUNTIME_ARRAY => Some(Type::RuntimeArray { element: ops[1] }),
+            OP_TYPE_STRUCT => Some(Type::Struct {
+                members: ops[1..].to_vec(),
+            }),
+            OP_TYPE_POINTER => Some(Type::Pointer {
+                storage_class: ops[1],
+                pointee: ops[2],
+            }),
+            OP_TYPE_ACCELERATION_STRUCTURE => Some(Type::AccelerationStructure),
+            OP_TYPE_FORWARD_POINTER => {
+                // buffer references are declared before the struct they point to
+                self.types.entry(ops[0]).or_insert(Type::Pointer {
+                    storage_class: ops[1],
+                    pointee: 0,
+                });
+                None
+            }
+            OP_CONSTANT => {
+                self.constants.insert(ops[1], ops[2]);
+                None
+            }
+            OP_VARIABLE => {
+                self.variables.push((ops[1], ops[0], ops[2]));
+                None
+            }
+            OP_DECORATE => {
+                self.decorations
+                    .insert((ops[0], ops[1]), ops.get(2).copied().unwrap_or(0));
+                None
+            }
+            OP_MEMBER_DECORATE => {
+                self.member_decorations
+                    .insert((ops[0], ops[1], ops[2]), ops.get(3).copied().unwrap_or(0));
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(ty) = ty {
+            self.types.insert(ops[0], ty);
+        }
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    /// Size in bytes of a type inside an explicitly laid out block
+    fn size_of(&self, id: u32) -> u32 {
+        match self.types.get(&id) {
+            Some(Type::Scalar { bytes }) => *bytes,
+            Some(Type::Vector { component, count }) => self.size_of(*component) * count,
+            Some(Type::Matrix { column, count }) => self.size_of(*column) * count,
+            Some(Type::Array { element, length }) => {
+                let stride = self
+                    .decoration(id, DECORATION_ARRAY_STRIDE)
+                    .unwrap_or_else(|| self.size_of(*element));
+                stride * self.constants.get(length).copied().unwrap_or(1)
+            }
+            Some(Type::Struct { members }) => (0..members.len() as u32)
+                .map(|i| {
+                    let offset = self
+                        .member_decorations
+                        .get(&(id, i, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(0);
+                    // the column stride of matrices is decorated on the member using them
+                    let size = match (
+                        self.types.get(&members[i as usize]),
+                        self.member_decorations.get(&(id, i, DECORATION_MATRIX_STRIDE)),
+                    ) {
+                        (Some(Type::Matrix { count, .. }), Some(stride)) => stride * count,
+                        _ => self.size_of(members[i as usize]),
+                    };
+                    offset + size
+                })
+                .max()
+                .unwrap_or(0),
+            Some(Type::Pointer {
+                storage_class: STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER,
+                ..
+            }) => 8,
+            _ => 0,
+        }
+    }
+
+    /// The descriptor type and count of a variable of type `id`
+    fn descriptor(&self, id: u32, storage_class: u32) -> Option<(vk::DescriptorType, u32)> {
+        let descriptor_type = match self.types.get(&id)? {
+            Type::Array { element, length } => {
+                let (descriptor_type, _) = self.descriptor(*element, storage_class)?;
+                return Some((descriptor_type, self.constants.get(length).copied().unwrap_or(1)));
+            }
+            Type::RuntimeArray { element } => {
+                let (descriptor_type, _) = self.descriptor(*element, storage_class)?;
+                return Some((descriptor_type, 0));
+            }
+            Type::Image { dim, sampled } => match (*dim == DIM_BUFFER, *sampled == IMAGE_SAMPLED_STORAGE) {
+                (false, true) => vk::DescriptorType::STORAGE_IMAGE,
+                (false, false) => vk::DescriptorType::SAMPLED_IMAGE,
+                (true, true) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (true, false) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+            },
+            Type::Sampler => vk::DescriptorType::SAMPLER,
+            Type::SampledImage => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            Type::AccelerationStructure => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            Type::Struct { .. } if storage_class == STORAGE_CLASS_STORAGE_BUFFER => vk::DescriptorType::STORAGE_BUFFER,
+            Type::Struct { .. } if self.decoration(id, DECORATION_BUFFER_BLOCK).is_some() => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            Type::Struct { .. } if self.decoration(id, DECORATION_BLOCK).is_some() => {
+                vk::DescriptorType::UNIFORM_BUFFER
+            }
+            _ => return None,
+        };
+        Some((descriptor_type, 1))
+    }
+}
+
+/// Collects the descriptors, the push constant size and the workgroup size from SPIR-V words
+pub fn reflect(words: &[u32]) -> Result<ShaderReflection, String> {
+    let module = Module::parse(words)?;
+    let mut reflection = ShaderReflection {
+        local_size: module.local_size,
+        ..Default::default()
+    };
+
+    for &(id, pointer_type, storage_class) in &module.variables {
+        let Some(Type::Pointer { pointee, .. }) = module.types.get(&pointer_type) else {
+            continue;
+        };
+
+        match storage_class {
+            STORAGE_CLASS_PUSH_CONSTANT => {
+                reflection.push_constant_size = reflection.push_constant_size.max(module.size_of(*pointee));
+            }
+            STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+                let (Some(set), Some(binding)) = (
+                    module.decoration(id, DECORATION_DESCRIPTOR_SET),
+                    module.decoration(id, DECORATION_BINDING),
+                ) else {
+                    continue;
+                };
+                let (descriptor_type, count) = module
+                    .descriptor(*pointee, storage_class)
+                    .ok_or_else(|| format!("unsupported descriptor at set {} binding {}", set, binding))?;
+                reflection.bindings.push(ReflectedBinding {
+                    set,
+                    binding,
+                    descriptor_type,
+                    count,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    reflection
+        .bindings
+        .sort_by_key(|binding| (binding.set, binding.binding));
+    Ok(reflection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str, kind: shaderc::ShaderKind) -> Vec<u32> {
+        let compiler = shaderc::Compiler::new().unwrap();
+        let mut options = shaderc::CompileOptions::new().unwrap();
+        options.set_target_env(shaderc::TargetEnv::Vulkan, vk::make_api_version(0, 1, 3, 0));
+        options.set_target_spirv(shaderc::SpirvVersion::V1_6);
+        compiler
+            .compile_into_spirv(source, kind, "test", "main", Some(&options))
+            .unwrap()
+            .as_binary()
+            .to_vec()
+    }
+
+    fn binding(set: u32, binding: u32, descriptor_type: vk::DescriptorType, count: u32) -> ReflectedBinding {
+        ReflectedBinding {
+            set,
+            binding,
+            descriptor_type,
+            count,
+        }
+    }
+
+    const COMPUTE_SHADER: &str = r#"
+        #version 460
+        #extension GL_EXT_nonuniform_qualifier : require
+        layout(local_size_x = 8, local_size_y = 4) in;
+
+        layout(set = 0, binding = 0, rgba32f) uniform image2D target;
+        layout(set = 0, binding = 1) uniform sampler2D source;
+        layout(set = 0, binding = 2, std430) readonly buffer Weights { float weights[]; };
+        layout(set = 0, binding = 3) uniform Uniforms { mat4 transform; vec4 tint; } uniforms;
+        layout(set = 0, binding = 4) uniform texture2D layers[3];
+        layout(set = 0, binding = 5) uniform sampler nearest;
+        layout(set = 1, binding = 0) uniform sampler2D textures[];
+
+        layout(push_constant) uniform Registers { vec4 scale; uint index; } registers;
+
+        void main() {
+            const ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+            vec4 color = texelFetch(source, pixel, 0) * weights[registers.index];
+            color += texture(sampler2D(layers[2], nearest), vec2(0.5));
+            color += texture(textures[nonuniformEXT(registers.index)], vec2(0.5));
+            imageStore(target, pixel, uniforms.transform * color * uniforms.tint * registers.scale);
+        }
+    "#;
+
+    #[test]
+    fn compute_shader_bindings() {
+        let reflection = reflect(&compile(COMPUTE_SHADER, shaderc::ShaderKind::Compute)).unwrap();
+        assert_eq!(
+            reflection.bindings,
+            vec![
+                binding(0, 0, vk::DescriptorType::STORAGE_IMAGE, 1),
+                binding(0, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+                binding(0, 2, vk::DescriptorType::STORAGE_BUFFER, 1),
+                binding(0, 3, vk::DescriptorType::UNIFORM_BUFFER, 1),
+                binding(0, 4, vk::DescriptorType::SAMPLED_IMAGE, 3),
+                binding(0, 5, vk::DescriptorType::SAMPLER, 1),
+                binding(1, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 0),
+            ]
+        );
+        assert_eq!(reflection.set_bindings(1).count(), 1);
+    }
+
+    #[test]
+    fn compute_shader_push_constants_and_local_size() {
+        let reflection = reflect(&compile(COMPUTE_SHADER, shaderc::ShaderKind::Compute)).unwrap();
+        // a vec4 followed by a uint
+        assert_eq!(reflection.push_constant_size, 20);
+        assert_eq!(reflection.local_size, Some([8, 4, 1]));
+    }
+
+    #[test]
+    fn push_constant_matrices_and_buffer_references() {
+        let source = r#"
+            #version 460
+            #extension GL_EXT_buffer_reference : require
+            layout(local_size_x = 64) in;
+
+            layout(buffer_reference, std430) buffer Values { float values[]; };
+            layout(push_constant) uniform Registers { mat3x4 transform; Values values; float weights[3]; } registers;
+
+            void main() {
+                registers.values.values[gl_GlobalInvocationID.x] *= registers.transform[0].x * registers.weights[2];
+            }
+        "#;
+        let reflection = reflect(&compile(source, shaderc::ShaderKind::Compute)).unwrap();
+        // 3 columns of 16 bytes, an 8 byte pointer and 3 tightly packed floats
+        assert_eq!(reflection.push_constant_size, 48 + 8 + 3 * 4);
+        assert_eq!(reflection.local_size, Some([64, 1, 1]));
+        assert!(reflection.bindings.is_empty());
+    }
+
+    #[test]
+    fn raygen_shader_bindings() {
+        let source = r#"
+            #version 460
+            #extension GL_EXT_ray_tracing : require
+            layout(set = 0, binding = 0, rgba32f) uniform image2D image;
+            layout(set = 0, binding = 1) uniform accelerationStructureEXT scene;
+            layout(location = 0) rayPayloadEXT vec4 payload;
+
+            void main() {
+                traceRayEXT(scene, gl_RayFlagsNoneEXT, 0xFF, 0, 0, 0, vec3(0.0), 0.001, vec3(0.0, 0.0, 1.0), 100.0, 0);
+                imageStore(image, ivec2(gl_LaunchIDEXT.xy), payload);
+            }
+        "#;
+        let reflection = reflect(&compile(source, shaderc::ShaderKind::RayGeneration)).unwrap();
+        assert_eq!(
+            reflection.bindings,
+            vec![
+                binding(0, 0, vk::DescriptorType::STORAGE_IMAGE, 1),
+                binding(0, 1, vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, 1),
+            ]
+        );
+        assert_eq!(reflection.push_constant_size, 0);
+        assert_eq!(reflection.local_size, None);
+    }
+
+    #[test]
+    fn rejects_what_is_not_spirv() {
+        assert!(reflect(&[]).is_err());
+        assert!(reflect(&[0xdeadbeef, 0, 0, 0, 0]).is_err());
+
+        // an instruction claiming 3 words with 1 left in the module
+        assert!(reflect(&[MAGIC, 0x00010600, 0, 1, 0, (3 << 16) | OP_EXECUTION_MODE, 0]).is_err());
+    }
+}
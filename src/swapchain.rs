@@ -1,7 +1,9 @@
 use crate::{
+    denoiser::AuxImages,
     render_device::RenderDevice,
+    render_error::{RenderError, RenderResult},
     render_image::{vk_image_from_asset, Image, VkImage},
-    vk_utils,
+    render_plugin::FRAMES_IN_FLIGHT,
     vulkan_cleanup::{VkCleanup, VkCleanupEvent},
 };
 use ash::vk;
@@ -18,13 +20,22 @@ impl Plugin for SwapchainPlugin {
         let mut system_state: SystemState<Query<(Entity, &Window, &RawHandleWrapper), With<PrimaryWindow>>> =
             SystemState::new(&mut app.world);
         let query = system_state.get(&app.world);
-        let (primary_window_e, primary_window, whandles) = query.get_single().unwrap();
+        let Ok((primary_window_e, primary_window, whandles)) = query.get_single() else {
+            // running headless, there is nothing to present to
+            return;
+        };
 
         let render_device = app.world.get_resource::<RenderDevice>().unwrap();
         let cleanup = app.world.get_resource::<VkCleanup>().unwrap();
-        let swapchain = Swapchain::new(render_device.clone(), cleanup.clone(), whandles, primary_window);
-
-        app.world.entity_mut(primary_window_e).insert(swapchain);
+        match Swapchain::new(render_device.clone(), cleanup.clone(), whandles, primary_window) {
+            Ok(swapchain) => {
+                app.world.entity_mut(primary_window_e).insert(swapchain);
+            }
+            // there is nothing to present to, recover_from_render_errors shuts down
+            Err(e) => app
+                .world
+                .send_event(RenderError::Init(format!("failed to create the swapchain: {}", e))),
+        }
     }
 }
 
@@ -34,24 +45,40 @@ pub struct Swapchain {
     device: RenderDevice,
     pub surface: vk::SurfaceKHR,
     pub handle: vk::SwapchainKHR,
-    pub images: Vec<vk::Image>,
-    pub views: Vec<vk::ImageView>,
+    pub images: Vec<VkImage>,
     pub width: u32,
     pub height: u32,
-    pub image_ready_sem: vk::Semaphore,
-    pub render_finished_sem: vk::Semaphore,
+    pub format: vk::Format,
+    /// One per frame in flight, a frame can't reuse them while the one before is still presenting
+    image_ready_sems: Vec<vk::Semaphore>,
+    render_finished_sems: Vec<vk::Semaphore>,
+    /// The frame in flight the semaphores of the current image belong to
+    frame_idx: usize,
     pub current_image_idx: usize,
+    /// Whether `current_image_idx` was acquired for this frame and `image_ready_sem` will signal
+    pub image_acquired: bool,
+    /// Set when the swapchain no longer matches the surface, it is resized before the next frame
+    pub outdated: bool,
     pub render_target: VkImage,
-    render_target_needs_transition: bool,
+    pub aux: AuxImages,
 }
 
 impl Swapchain {
-    pub fn new(device: RenderDevice, cleanup: VkCleanup, whandles: &RawHandleWrapper, window: &Window) -> Self {
+    pub fn new(
+        device: RenderDevice,
+        cleanup: VkCleanup,
+        whandles: &RawHandleWrapper,
+        window: &Window,
+    ) -> RenderResult<Self> {
         unsafe {
-            let surface = device.create_surface(whandles);
+            let surface = device.create_surface(whandles)?;
             let semaphore_info = vk::SemaphoreCreateInfo::builder();
-            let image_ready_sem = device.device.create_semaphore(&semaphore_info, None).unwrap();
-            let render_finished_sem = device.device.create_semaphore(&semaphore_info, None).unwrap();
+            let mut image_ready_sems = Vec::new();
+            let mut render_finished_sems = Vec::new();
+            for _ in 0..FRAMES_IN_FLIGHT {
+                image_ready_sems.push(device.device.create_semaphore(&semaphore_info, None)?);
+                render_finished_sems.push(device.device.create_semaphore(&semaphore_info, None)?);
+            }
 
             let mut ret = Self {
                 cleanup,
@@ -59,63 +86,134 @@ impl Swapchain {
                 surface,
                 handle: vk::SwapchainKHR::null(),
                 images: Vec::new(),
-                views: Vec::new(),
                 width: 0,
                 height: 0,
-                image_ready_sem,
-                render_finished_sem,
+                format: vk::Format::UNDEFINED,
+                image_ready_sems,
+                render_finished_sems,
+                frame_idx: 0,
                 current_image_idx: 0,
+                image_acquired: false,
+                outdated: true,
                 render_target: VkImage::null(),
-                render_target_needs_transition: true,
+                aux: AuxImages::null(),
             };
 
-            ret.on_resize(window);
-            ret
+            ret.on_resize(window)?;
+            Ok(ret)
         }
     }
 
-    pub fn on_begin_render(&mut self, cmd_buffer: vk::CommandBuffer) {
-        if self.render_target_needs_transition {
-            println!("transitioning render target");
-            vk_utils::transition_image_layout(
-                &self.device,
-                cmd_buffer,
-                self.render_target.handle,
-                vk::ImageLayout::UNDEFINED,
-                vk::ImageLayout::GENERAL,
-            );
-            self.render_target_needs_transition = false;
-        }
+    /// Whether writes to the swapchain images are sRGB encoded by the hardware
+    pub fn encodes_srgb(&self) -> bool {
+        matches!(
+            self.format,
+            vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32
+        )
+    }
+
+    pub fn current_framebuffer(&self) -> &VkImage {
+        &self.images[self.current_image_idx]
     }
 
-    pub fn current_framebuffer(&self) -> (vk::Image, vk::ImageView) {
-        (self.images[self.current_image_idx], self.views[self.current_image_idx])
+    /// Signaled once the current image can be rendered to
+    pub fn image_ready_sem(&self) -> vk::Semaphore {
+        self.image_ready_sems[self.frame_idx]
     }
 
-    pub fn aquire_next_image(&mut self, device: &RenderDevice) {
+    /// To signal when rendering the current image finished, presenting waits on it
+    pub fn render_finished_sem(&self) -> vk::Semaphore {
+        self.render_finished_sems[self.frame_idx]
+    }
+
+    /// Acquires the image for the frame in flight `frame_idx`, leaves `image_acquired` unset when
+    /// the swapchain turned out to be outdated
+    pub fn aquire_next_image(&mut self, device: &RenderDevice, frame_idx: usize) -> RenderResult<()> {
+        self.image_acquired = false;
+        self.frame_idx = frame_idx;
         let result = unsafe {
             device
                 .exts
                 .swapchain
-                .acquire_next_image(self.handle, u64::MAX, self.image_ready_sem, vk::Fence::null())
+                .acquire_next_image(self.handle, u64::MAX, self.image_ready_sem(), vk::Fence::null())
+        };
+        match result {
+            Ok((idx, _suboptimal)) => {
+                self.current_image_idx = idx as usize;
+                self.image_acquired = true;
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.outdated = true,
+            Err(e) => return Err(e.into()),
         }
-        .unwrap();
-        self.current_image_idx = result.0 as usize;
+        Ok(())
     }
 
-    pub unsafe fn on_resize(&mut self, window: &Window) {
-        let surface_format = self
+    /// Gives up on the acquired image when a frame fails before it is submitted. The wait is
+    /// submitted on its own so `image_ready_sem` can be signaled again, the image itself is only
+    /// released with the swapchain so it is marked `outdated`.
+    pub unsafe fn skip_frame(&mut self) {
+        if !self.image_acquired {
+            return;
+        }
+        self.image_acquired = false;
+        self.outdated = true;
+
+        let image_ready_sem = self.image_ready_sem();
+        let submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(std::slice::from_ref(&image_ready_sem))
+            .wait_dst_stage_mask(std::slice::from_ref(&vk::PipelineStageFlags::ALL_COMMANDS))
+            .build();
+        let queue = self.device.queue.lock().unwrap();
+        if let Err(e) = self
+            .device
+            .device
+            .queue_submit(*queue, std::slice::from_ref(&submit_info), vk::Fence::null())
+        {
+            println!("Failed to skip the frame: {:?}", e);
+        }
+    }
+
+    /// Creates a new surface for the window after the old one was lost, together with a new
+    /// swapchain for it.
+    pub unsafe fn recreate_surface(&mut self, whandles: &RawHandleWrapper, window: &Window) -> RenderResult<()> {
+        // the swapchain has to go before its surface, it can't be handed to the new one either
+        self.device.wait_idle();
+        for image in self.images.drain(..) {
+            self.device.device.destroy_image_view(image.view, None);
+        }
+        self.device.exts.swapchain.destroy_swapchain(self.handle, None);
+        self.handle = vk::SwapchainKHR::null();
+        self.device.exts.surface.destroy_surface(self.surface, None);
+        self.surface = vk::SurfaceKHR::null();
+        self.image_acquired = false;
+
+        self.surface = self.device.create_surface(whandles)?;
+        self.on_resize(window)
+    }
+
+    /// Leaves the swapchain `outdated` when it fails, so it is tried again the next frame
+    pub unsafe fn on_resize(&mut self, window: &Window) -> RenderResult<()> {
+        self.outdated = true;
+        let surface_formats = self
             .device
             .exts
             .surface
-            .get_physical_device_surface_formats(self.device.physical_device, self.surface)
-            .unwrap()[0];
+            .get_physical_device_surface_formats(self.device.physical_device, self.surface)?;
+        // let the hardware do the sRGB encoding when it can, quad.frag handles either kind
+        let surface_format = surface_formats
+            .iter()
+            .copied()
+            .find(|format| {
+                matches!(format.format, vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB)
+                    && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+            })
+            .unwrap_or(surface_formats[0]);
+        self.format = surface_format.format;
         let surface_caps = self
             .device
             .exts
             .surface
-            .get_physical_device_surface_capabilities(self.device.physical_device, self.surface)
-            .unwrap();
+            .get_physical_device_surface_capabilities(self.device.physical_device, self.surface)?;
 
         let mut desired_image_count = surface_caps.min_image_count + 1;
         if surface_caps.max_image_count > 0 && desired_image_count > surface_caps.max_image_count {
@@ -145,8 +243,7 @@ impl Swapchain {
             .device
             .exts
             .surface
-            .get_physical_device_surface_present_modes(self.device.physical_device, self.surface)
-            .unwrap();
+            .get_physical_device_surface_present_modes(self.device.physical_device, self.surface)?;
 
         let present_mode = present_modes
             .iter()
@@ -174,28 +271,25 @@ impl Swapchain {
             .device
             .exts
             .swapchain
-            .create_swapchain(&swapchain_create_info, None)
-            .unwrap();
+            .create_swapchain(&swapchain_create_info, None)?;
 
         // Cleanup old swapchain
-        for view in self.views.iter() {
-            self.cleanup.send(VkCleanupEvent::ImageView(*view));
+        for image in self.images.drain(..) {
+            self.cleanup.send(VkCleanupEvent::ImageView(image.view));
         }
         self.cleanup.send(VkCleanupEvent::Swapchain(old_swapchain));
 
-        self.images = self.device.exts.swapchain.get_swapchain_images(self.handle).unwrap();
-
-        self.views = self
-            .images
-            .iter()
-            .map(|image| {
-                let view_info = crate::initializers::image_view_info(image.clone(), surface_format.format);
-                self.device.device.create_image_view(&view_info, None).unwrap()
-            })
-            .collect();
+        for image in self.device.exts.swapchain.get_swapchain_images(self.handle)? {
+            let view_info = crate::initializers::image_view_info(image, surface_format.format);
+            let view = self.device.device.create_image_view(&view_info, None)?;
+            self.images.push(VkImage::new(image, view, vk::ImageLayout::UNDEFINED));
+        }
 
         self.cleanup.send(VkCleanupEvent::ImageView(self.render_target.view));
         self.cleanup.send(VkCleanupEvent::Image(self.render_target.handle));
+        self.aux.destroy(&self.cleanup);
+        self.render_target = VkImage::null();
+        self.aux = AuxImages::null();
 
         self.render_target = vk_image_from_asset(
             &self.device,
@@ -206,10 +300,12 @@ impl Swapchain {
                 usage: vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
                 initial_layout: vk::ImageLayout::UNDEFINED,
             },
-        );
-        self.render_target_needs_transition = true;
+        )?;
+        self.aux = AuxImages::new(&self.device, self.width, self.height)?;
+        self.outdated = false;
 
         println!("Swapchain Resized: {}x{}", self.width, self.height);
+        Ok(())
     }
 }
 
@@ -221,10 +317,15 @@ impl Drop for Swapchain {
         unsafe {
             dv.destroy_image_view(self.render_target.view, None);
             dv.destroy_image(self.render_target.handle, None);
-            dv.destroy_semaphore(self.render_finished_sem, None);
-            dv.destroy_semaphore(self.image_ready_sem, None);
-            for view in self.views.iter() {
-                dv.destroy_image_view(*view, None);
+            for image in self.aux.images() {
+                dv.destroy_image_view(image.view, None);
+                dv.destroy_image(image.handle, None);
+            }
+            for &semaphore in self.render_finished_sems.iter().chain(&self.image_ready_sems) {
+                dv.destroy_semaphore(semaphore, None);
+            }
+            for image in self.images.iter() {
+                dv.destroy_image_view(image.view, None);
             }
             self.device.exts.swapchain.destroy_swapchain(self.handle, None);
 
@@ -1,165 +1,339 @@
 use crate::{
-    initializers, render_buffer::BufferProvider, render_device::RenderDevice, render_image::VkImage, vk_utils,
-    vulkan_assets::VulkanAsset,
+    initializers, render_buffer::BufferProvider, render_device::RenderDevice, render_error::RenderResult,
+    render_image::VkImage,
 };
 use ash::vk;
+use bevy::math::Vec4;
 use gpu_allocator::{
     vulkan::{AllocationCreateDesc, AllocationScheme},
     MemoryLocation,
 };
 
-impl VulkanAsset for bevy::prelude::Image {
-    type ExtractedAsset = bevy::prelude::Image;
-    type PreparedAsset = VkImage;
-    type ExtractParam = ();
+pub fn load_texture_from_bytes(
+    device: &RenderDevice,
+    format: vk::Format,
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+) -> RenderResult<VkImage> {
+    load_texture(
+        device,
+        &TextureData {
+            format,
+            width,
+            height,
+            levels: vec![bytes.to_vec()],
+            components: vk::ComponentMapping::default(),
+            generate_mips: false,
+        },
+    )
+}
 
-    fn extract_asset(
-        &self,
-        _param: &mut bevy::ecs::system::SystemParamItem<Self::ExtractParam>,
-    ) -> Option<Self::ExtractedAsset> {
-        Some(self.clone())
-    }
+/// The transfer function texels are stored with. gltf decides it per material slot: base color and
+/// emissive textures are sRGB encoded, all other textures hold linear data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
 
-    fn prepare_asset(device: &crate::render_device::RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset {
-        load_texture_from_bytes(
-            device,
-            vk::Format::R32G32B32A32_SFLOAT,
-            &asset.data,
-            asset.texture_descriptor.size.width,
-            asset.texture_descriptor.size.height,
-        )
+impl ColorSpace {
+    /// What sampling an `_SRGB` format returns, alpha is always stored linearly
+    pub fn decode(self, texel: Vec4) -> Vec4 {
+        match self {
+            ColorSpace::Srgb => {
+                let [r, g, b] = texel.truncate().to_array().map(|c| {
+                    if c <= 0.04045 {
+                        c / 12.92
+                    } else {
+                        ((c + 0.055) / 1.055).powf(2.4)
+                    }
+                });
+                Vec4::new(r, g, b, texel.w)
+            }
+            ColorSpace::Linear => texel,
+        }
     }
+}
+
+/// Pixel data ready to be uploaded: either a single level that gets a generated mip chain,
+/// or every level of a preprocessed (possibly block compressed) texture.
+pub struct TextureData {
+    pub format: vk::Format,
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed levels, largest first
+    pub levels: Vec<Vec<u8>>,
+    /// Lets single and dual channel images read as grayscale (with alpha) in the shaders
+    pub components: vk::ComponentMapping,
+    pub generate_mips: bool,
+}
 
-    fn destroy_asset(asset: Self::PreparedAsset, cleanup: &crate::vulkan_cleanup::VkCleanup) {
-        cleanup.send(crate::vulkan_cleanup::VkCleanupEvent::ImageView(asset.view));
-        cleanup.send(crate::vulkan_cleanup::VkCleanupEvent::Image(asset.handle));
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+pub fn format_features(device: &RenderDevice, format: vk::Format) -> vk::FormatFeatureFlags {
+    unsafe {
+        device
+            .instance
+            .get_physical_device_format_properties(device.physical_device, format)
+            .optimal_tiling_features
     }
 }
 
-pub fn load_texture_from_bytes(
-    device: &RenderDevice,
-    format: vk::Format,
-    bytes: &[u8],
-    width: u32,
-    height: u32,
-) -> VkImage {
-    let target_bytes_per_pixel = match format {
-        vk::Format::R8G8B8A8_UNORM => 4,
-        vk::Format::R32G32B32A32_SFLOAT => 16,
-        _ => panic!("unsupported format"),
+fn mip_barrier(
+    image: vk::Image,
+    levels: std::ops::Range<u32>,
+    from: vk::ImageLayout,
+    to: vk::ImageLayout,
+) -> vk::ImageMemoryBarrier2 {
+    let mut barrier = initializers::layout_transition2(image, from, to);
+    barrier.subresource_range.base_mip_level = levels.start;
+    barrier.subresource_range.level_count = levels.end - levels.start;
+    barrier
+}
+
+pub fn load_texture(device: &RenderDevice, data: &TextureData) -> RenderResult<VkImage> {
+    let blit_features = vk::FormatFeatureFlags::BLIT_SRC
+        | vk::FormatFeatureFlags::BLIT_DST
+        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+    let generate_mips =
+        data.generate_mips && data.levels.len() == 1 && format_features(device, data.format).contains(blit_features);
+    let mip_levels = if generate_mips {
+        mip_level_count(data.width, data.height)
+    } else {
+        data.levels.len() as u32
     };
 
-    assert!(
-        bytes.len() == (width * height) as usize * target_bytes_per_pixel,
-        "expected {} bytes, got {}",
-        (width * height) as usize * target_bytes_per_pixel,
-        bytes.len()
-    );
-    let mut staging_buffer = device.create_host_buffer::<u8>(
-        (width * height * target_bytes_per_pixel as u32) as u64,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-    );
+    let total_bytes = data.levels.iter().map(|level| level.len()).sum::<usize>();
+    let mut staging_buffer = device.create_host_buffer::<u8>(total_bytes as u64, vk::BufferUsageFlags::TRANSFER_SRC)?;
+    let mut copy_regions = Vec::with_capacity(data.levels.len());
     {
         let mut staging_buffer = device.map_buffer(&mut staging_buffer);
-        staging_buffer.as_slice_mut().copy_from_slice(bytes);
+        let mut offset = 0;
+        for (level, bytes) in data.levels.iter().enumerate() {
+            staging_buffer.as_slice_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+            let mut copy_region =
+                initializers::buffer_image_copy((data.width >> level).max(1), (data.height >> level).max(1));
+            copy_region.buffer_offset = offset as u64;
+            copy_region.image_subresource.mip_level = level as u32;
+            copy_regions.push(copy_region);
+            offset += bytes.len();
+        }
+    }
+
+    let mut usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+    if generate_mips {
+        usage |= vk::ImageUsageFlags::TRANSFER_SRC;
     }
 
     let image_info = vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::TYPE_2D)
-        .format(format)
+        .format(data.format)
         .extent(vk::Extent3D {
-            width,
-            height,
+            width: data.width,
+            height: data.height,
             depth: 1,
         })
-        .mip_levels(1)
+        .mip_levels(mip_levels)
         .array_layers(1)
         .samples(vk::SampleCountFlags::TYPE_1)
         .tiling(vk::ImageTiling::OPTIMAL)
-        .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+        .usage(usage)
         .sharing_mode(vk::SharingMode::EXCLUSIVE)
         .initial_layout(vk::ImageLayout::UNDEFINED);
 
-    let image_handle = unsafe { device.device.create_image(&image_info, None).unwrap() };
+    let image_handle = match unsafe { device.device.create_image(&image_info, None) } {
+        Ok(image_handle) => image_handle,
+        Err(e) => {
+            device.destroy_buffer(staging_buffer);
+            return Err(e.into());
+        }
+    };
 
     let requirements = unsafe { device.device.get_image_memory_requirements(image_handle) };
 
     {
         let mut alloc_impl = device.write_alloc();
 
-        let allocation = alloc_impl
-            .allocator
-            .allocate(&AllocationCreateDesc {
-                name: "",
-                requirements,
-                location: MemoryLocation::GpuOnly,
-                linear: false,
-                allocation_scheme: AllocationScheme::DedicatedImage(image_handle),
-            })
-            .unwrap();
-
-        unsafe {
-            device
-                .device
-                .bind_image_memory(image_handle, allocation.memory(), allocation.offset())
-                .unwrap();
-        }
+        let allocation = alloc_impl.allocator.allocate(&AllocationCreateDesc {
+            name: "",
+            requirements,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::DedicatedImage(image_handle),
+        });
+        let bound = allocation.map_err(|e| e.into()).and_then(|allocation| {
+            let bound = unsafe {
+                device
+                    .device
+                    .bind_image_memory(image_handle, allocation.memory(), allocation.offset())
+            };
+            match bound {
+                Ok(()) => Ok(allocation),
+                Err(e) => {
+                    alloc_impl.allocator.free(allocation).unwrap();
+                    Err(e.into())
+                }
+            }
+        });
+        let allocation = match bound {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                drop(alloc_impl);
+                unsafe { device.device.destroy_image(image_handle, None) };
+                device.destroy_buffer(staging_buffer);
+                return Err(e);
+            }
+        };
 
         alloc_impl.image_to_allocation.insert(image_handle, allocation);
     }
 
-    device.run_asset_commands(|cmd_buffer| {
-        vk_utils::transition_image_layout(
-            &device,
-            cmd_buffer,
+    let uploaded = device.run_asset_commands(|cmd_buffer| {
+        let pipeline_barrier = |barrier: vk::ImageMemoryBarrier2| {
+            let barrier_info = vk::DependencyInfo::builder().image_memory_barriers(std::slice::from_ref(&barrier));
+            unsafe { device.exts.sync2.cmd_pipeline_barrier2(cmd_buffer, &barrier_info) };
+        };
+
+        pipeline_barrier(mip_barrier(
             image_handle,
+            0..mip_levels,
             vk::ImageLayout::UNDEFINED,
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        );
-        let copy_region = initializers::buffer_image_copy(width, height);
+        ));
         unsafe {
             device.device.cmd_copy_buffer_to_image(
                 cmd_buffer,
                 staging_buffer.handle,
                 image_handle,
                 vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                std::slice::from_ref(&copy_region),
+                &copy_regions,
             );
         };
-        vk_utils::transition_image_layout(
-            &device,
-            cmd_buffer,
+
+        // every level is blitted from the previous one, which then moves on to the shaders
+        for level in 1..if generate_mips { mip_levels } else { 0 } {
+            pipeline_barrier(mip_barrier(
+                image_handle,
+                level - 1..level,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ));
+
+            let offsets = |level: u32| {
+                [
+                    vk::Offset3D::default(),
+                    vk::Offset3D {
+                        x: (data.width >> level).max(1) as i32,
+                        y: (data.height >> level).max(1) as i32,
+                        z: 1,
+                    },
+                ]
+            };
+            let subresource = |level: u32| vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            };
+            let blit = vk::ImageBlit::builder()
+                .src_offsets(offsets(level - 1))
+                .src_subresource(subresource(level - 1))
+                .dst_offsets(offsets(level))
+                .dst_subresource(subresource(level));
+            unsafe {
+                device.device.cmd_blit_image(
+                    cmd_buffer,
+                    image_handle,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image_handle,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    std::slice::from_ref(&blit),
+                    vk::Filter::LINEAR,
+                );
+            }
+
+            pipeline_barrier(mip_barrier(
+                image_handle,
+                level - 1..level,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ));
+        }
+
+        let uploaded_levels = if generate_mips {
+            mip_levels - 1..mip_levels
+        } else {
+            0..mip_levels
+        };
+        pipeline_barrier(mip_barrier(
             image_handle,
+            uploaded_levels,
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
             vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-        );
+        ));
     });
 
     device.destroy_buffer(staging_buffer);
+    uploaded?;
 
-    let view_info = crate::initializers::image_view_info(image_handle.clone(), format);
-    let view = unsafe { device.device.create_image_view(&view_info, None).unwrap() };
+    let mut view_info = crate::initializers::image_view_info(image_handle.clone(), data.format);
+    view_info.subresource_range.level_count = mip_levels;
+    view_info.components = data.components;
+    let view = unsafe { device.device.create_image_view(&view_info, None)? };
 
-    VkImage {
-        handle: image_handle,
+    Ok(VkImage::new(
+        image_handle,
         view,
-    }
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    ))
 }
 
-pub fn padd_pixel_bytes_rgba_unorm(bytes: &[u8], src_bytes_per_pixel: u32, width: usize, height: usize) -> Vec<u8> {
-    let mut padded_bytes = vec![0u8; (width * height * 4) as usize];
-
-    for pixel_idx in 0..width * height {
-        for channel_idx in 0..4 {
-            if channel_idx < src_bytes_per_pixel {
-                padded_bytes[pixel_idx * 4 + channel_idx as usize] =
-                    bytes[pixel_idx * src_bytes_per_pixel as usize + channel_idx as usize];
-            } else {
-                // padd alpha white, color black
-                padded_bytes[pixel_idx * 4 + channel_idx as usize] = if channel_idx == 3 { 255 } else { 0 };
-            }
-        }
+/// Reads a KTX2 container with all of its mip levels. Zstandard supercompression is undone here,
+/// Basis Universal payloads would need a transcoder and are rejected.
+pub fn ktx2_texture_data(bytes: &[u8]) -> Result<TextureData, String> {
+    let reader = ktx2::Reader::new(bytes).map_err(|e| format!("{:?}", e))?;
+    let header = reader.header();
+
+    let Some(format) = header.format else {
+        return Err("KTX2 files without a vkFormat (Basis Universal) are not supported".into());
+    };
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+        return Err("only 2D KTX2 textures are supported".into());
     }
 
-    padded_bytes
+    let levels = reader
+        .levels()
+        .map(|level| match header.supercompression_scheme {
+            None => Ok(level.to_vec()),
+            Some(ktx2::SupercompressionScheme::Zstandard) => {
+                let mut level = level;
+                let mut decoder = ruzstd::StreamingDecoder::new(&mut level)?;
+                let mut decompressed = Vec::new();
+                std::io::Read::read_to_end(&mut decoder, &mut decompressed).map_err(|e| e.to_string())?;
+                Ok(decompressed)
+            }
+            Some(scheme) => Err(format!("KTX2 supercompression {:?} is not supported", scheme)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(TextureData {
+        format: vk::Format::from_raw(format.0.get() as i32),
+        width: header.pixel_width,
+        height: header.pixel_height,
+        levels,
+        components: vk::ComponentMapping::default(),
+        generate_mips: true,
+    })
+}
+
+/// Appends an alpha channel to tightly packed rgb pixels, `alpha` holds the bytes of one channel.
+pub fn padd_pixel_bytes_rgba(bytes: &[u8], alpha: &[u8]) -> Vec<u8> {
+    bytes
+        .chunks_exact(alpha.len() * 3)
+        .flat_map(|pixel| pixel.iter().chain(alpha).copied())
+        .collect()
 }
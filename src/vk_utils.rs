@@ -1,20 +1,72 @@
 use ash::vk;
 
 use crate::render_device::RenderDevice;
+use crate::render_graph::{Barrier, Usage};
+use crate::render_image::VkImage;
 
 pub fn aligned_size(value: u32, alignment: u32) -> u32 {
     (value + alignment - 1) & !(alignment - 1)
 }
 
-pub fn transition_image_layout(
+/// A barrier over every mip level of `image`
+pub fn image_barrier(image: vk::Image, barrier: &Barrier) -> vk::ImageMemoryBarrier2 {
+    vk::ImageMemoryBarrier2::builder()
+        .image(image)
+        .old_layout(barrier.old_layout)
+        .new_layout(barrier.new_layout)
+        .src_stage_mask(barrier.src_stage)
+        .src_access_mask(barrier.src_access)
+        .dst_stage_mask(barrier.dst_stage)
+        .dst_access_mask(barrier.dst_access)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            layer_count: 1,
+        })
+        .build()
+}
+
+/// Moves every image from the state it is tracked in to `usage`, with one `cmd_pipeline_barrier2` for
+/// all of them. Images that are already fit for their usage get no barrier.
+pub fn transition_images(device: &RenderDevice, cmd_buffer: vk::CommandBuffer, images: &[(&VkImage, Usage)]) {
+    debug_assert!(
+        images
+            .iter()
+            .enumerate()
+            .all(|(i, (image, _))| images[..i].iter().all(|(other, _)| other.handle != image.handle)),
+        "an image can only be transitioned once per batch"
+    );
+
+    let barriers = images
+        .iter()
+        .filter_map(|(image, usage)| image.track(*usage))
+        .collect::<Vec<_>>();
+    if barriers.is_empty() {
+        return;
+    }
+
+    let barrier_info = vk::DependencyInfo::builder().image_memory_barriers(&barriers);
+    unsafe {
+        device.exts.sync2.cmd_pipeline_barrier2(cmd_buffer, &barrier_info);
+    }
+}
+
+/// A global barrier, for resources that stay in the general layout
+pub fn memory_barrier(
     device: &RenderDevice,
     cmd_buffer: vk::CommandBuffer,
-    image: vk::Image,
-    from: vk::ImageLayout,
-    to: vk::ImageLayout,
+    src: (vk::PipelineStageFlags2, vk::AccessFlags2),
+    dst: (vk::PipelineStageFlags2, vk::AccessFlags2),
 ) {
-    let image_barrier = crate::initializers::layout_transition2(image, from, to);
-    let barrier_info = vk::DependencyInfo::builder().image_memory_barriers(std::slice::from_ref(&image_barrier));
+    let barrier = vk::MemoryBarrier2::builder()
+        .src_stage_mask(src.0)
+        .src_access_mask(src.1)
+        .dst_stage_mask(dst.0)
+        .dst_access_mask(dst.1)
+        .build();
+    let barrier_info = vk::DependencyInfo::builder().memory_barriers(std::slice::from_ref(&barrier));
     unsafe {
         device.exts.sync2.cmd_pipeline_barrier2(cmd_buffer, &barrier_info);
     }
@@ -6,16 +6,20 @@ use bevy::utils::HashMap;
 use crossbeam_channel::{Receiver, Sender};
 
 use crate::render_device::RenderDevice;
+use crate::render_error::{RenderError, RenderResult};
 use crate::render_plugin::{RenderSchedule, RenderSet};
 use crate::vulkan_cleanup::VkCleanup;
 
+/// Assets that ran out of memory are extracted and prepared again this many times
+const MAX_PREPARE_ATTEMPTS: u32 = 3;
+
 pub trait VulkanAsset: Asset {
     type ExtractedAsset: Send + Sync + 'static;
     type PreparedAsset: Send + Sync + 'static;
     type ExtractParam: SystemParam;
 
     fn extract_asset(&self, param: &mut SystemParamItem<Self::ExtractParam>) -> Option<Self::ExtractedAsset>;
-    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> Self::PreparedAsset;
+    fn prepare_asset(device: &RenderDevice, asset: Self::ExtractedAsset) -> RenderResult<Self::PreparedAsset>;
 
     fn destroy_asset(asset: Self::PreparedAsset, cleanup: &VkCleanup);
 }
@@ -38,7 +42,11 @@ impl VkAssetCleanupPlaybook {
 pub struct VulkanAssets<T: VulkanAsset> {
     lookup: HashMap<HandleId, T::PreparedAsset>,
     send_extracted: Sender<(HandleId, T::ExtractedAsset)>,
-    recv_prepared: Receiver<(HandleId, T::PreparedAsset)>,
+    recv_prepared: Receiver<(HandleId, RenderResult<T::PreparedAsset>)>,
+    /// How often the assets that ran out of memory tried to prepare
+    attempts: HashMap<HandleId, u32>,
+    /// Extracted again next frame
+    retry: Vec<HandleId>,
 }
 
 impl<T: VulkanAsset> VulkanAssets<T> {
@@ -88,6 +96,8 @@ impl<T: VulkanAsset> Plugin for VulkanAssetPlugin<T> {
             lookup: HashMap::default(),
             send_extracted,
             recv_prepared,
+            attempts: HashMap::default(),
+            retry: Vec::new(),
         });
 
         app.edit_schedule(RenderSchedule, |schedule| {
@@ -106,10 +116,23 @@ impl<T: VulkanAsset> Plugin for VulkanAssetPlugin<T> {
 fn extract_vulkan_asset<T: VulkanAsset>(
     mut asset_events: EventReader<AssetEvent<T>>,
     assets: Res<Assets<T>>,
-    vk_assets: Res<VulkanAssets<T>>,
+    mut vk_assets: ResMut<VulkanAssets<T>>,
+    cleanup: Res<VkCleanup>,
     param: StaticSystemParam<T::ExtractParam>,
 ) {
     let mut param = param.into_inner();
+
+    // the memory the last attempt ran out of may have been freed by now
+    for handle_id in std::mem::take(&mut vk_assets.retry) {
+        let extracted_asset = assets
+            .get(&Handle::weak(handle_id))
+            .and_then(|asset| asset.extract_asset(&mut param));
+        if let Some(extracted_asset) = extracted_asset {
+            println!("Preparing a {} again", std::any::type_name::<T>());
+            vk_assets.send_extracted.send((handle_id, extracted_asset)).unwrap();
+        }
+    }
+
     for event in asset_events.iter() {
         match event {
             AssetEvent::Created { handle } => {
@@ -136,19 +159,44 @@ fn extract_vulkan_asset<T: VulkanAsset>(
                     );
                 }
             }
-            AssetEvent::Removed { handle: _handle } => {
-                println!("AAAAAAAAAAAAAAAAAAA AssetEvent::Removed");
+            AssetEvent::Removed { handle } => {
+                // nothing holds the handle anymore, so nothing renders with the prepared asset either
+                vk_assets.attempts.remove(&handle.id());
+                if let Some(old) = vk_assets.lookup.remove(&handle.id()) {
+                    T::destroy_asset(old, &cleanup);
+                }
             }
         }
     }
 }
 
-fn publish_vulkan_asset<T: VulkanAsset>(mut vk_assets: ResMut<VulkanAssets<T>>, cleanup: Res<VkCleanup>) {
+fn publish_vulkan_asset<T: VulkanAsset>(
+    mut vk_assets: ResMut<VulkanAssets<T>>,
+    cleanup: Res<VkCleanup>,
+    mut errors: EventWriter<RenderError>,
+) {
     while let Ok((handle_id, prepared_asset)) = vk_assets.recv_prepared.try_recv() {
+        let prepared_asset = match prepared_asset {
+            Ok(prepared_asset) => prepared_asset,
+            Err(error) => {
+                println!("A {} failed to prepare: {}", std::any::type_name::<T>(), error);
+                if error == RenderError::OutOfMemory {
+                    let attempts = vk_assets.attempts.entry(handle_id).or_default();
+                    *attempts += 1;
+                    if *attempts < MAX_PREPARE_ATTEMPTS {
+                        vk_assets.retry.push(handle_id);
+                    }
+                }
+                errors.send(error);
+                continue;
+            }
+        };
+
         println!(
             "{} asset received, inserting into world",
             std::any::type_name::<T::PreparedAsset>()
         );
+        vk_assets.attempts.remove(&handle_id);
         if let Some(old) = vk_assets.lookup.insert(handle_id, prepared_asset) {
             T::destroy_asset(old, &cleanup);
         }
@@ -159,7 +207,7 @@ fn publish_vulkan_asset<T: VulkanAsset>(mut vk_assets: ResMut<VulkanAssets<T>>,
 fn prepare_asset<T: VulkanAsset>(
     mut device: RenderDevice,
     recv_extracted: Receiver<(HandleId, T::ExtractedAsset)>,
-    send_prepared: Sender<(HandleId, T::PreparedAsset)>,
+    send_prepared: Sender<(HandleId, RenderResult<T::PreparedAsset>)>,
 ) {
     println!(
         "Prepare asset thread for {} started",
@@ -6,7 +6,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use crate::render_device::RenderDevice;
+use crate::{render_device::RenderDevice, render_plugin::FRAMES_IN_FLIGHT};
 
 #[derive(Debug)]
 pub enum VkCleanupEvent {
@@ -114,8 +114,10 @@ impl VkCleanupImpl {
 
 fn vulkan_cleanup_worker(device: RenderDevice, recv: Receiver<VkCleanupEvent>) {
     println!("Vulkan cleanup thread started");
+    // events wait for as many frame signals as there are frames in flight, by then every frame
+    // that could have used what they destroy has finished
     let mut cycle_buffer: VecDeque<Vec<VkCleanupEvent>> = VecDeque::new();
-    for _ in 0..2 {
+    for _ in 0..FRAMES_IN_FLIGHT {
         cycle_buffer.push_back(Vec::new());
     }
 